@@ -6,16 +6,20 @@ use reqwest;
 use reqwest::multipart;
 use reqwest::header::CONTENT_TYPE;
 
+use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde::de::Deserializer;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, Write};
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::time::SystemTime;
 use uuid::Uuid;
 
+use super::error::{http_client, ApiError};
+pub use super::filter::{parse_product_filter, ProductFilter};
+
 pub struct ProductFieldAssignments {
     headers: Vec<String>,
     items: VecDeque<Vec<String>>,
@@ -31,29 +35,276 @@ impl ProductFieldAssignments {
         if item.len() != self.headers.len() {
             return Err(anyhow!("bad item length"))
         }
-        let fields: Vec<String> = item.iter().map(|x| (*x).to_owned())
-            .filter(|x| !x.contains(',')).collect();
-        if fields.len() != self.headers.len() {
-            return Err(anyhow!("values with commas not supported"));
-        }
+        let fields: Vec<String> = item.iter().map(|x| (*x).to_owned()).collect();
         self.items.push_back(fields);
         Ok(())
     }
     pub fn form_header(&self) -> String {
         format!("[{}]", self.headers.iter().map(|x| format!("\"{}\"", x)).collect::<Vec<String>>().join(","))
     }
+    pub fn rows(&self) -> usize {
+        self.items.len()
+    }
+    pub fn chunks(&self, rows: usize) -> Vec<ProductFieldAssignments> {
+        let rows = rows.max(1);
+        let items: Vec<&Vec<String>> = self.items.iter().collect();
+        items.chunks(rows).map(|chunk| ProductFieldAssignments{
+            headers: self.headers.clone(),
+            items: chunk.iter().map(|x| (*x).clone()).collect(),
+        }).collect()
+    }
     pub fn as_csv(&self) -> String {
         let mut csv = self.headers.join(",");
         csv.push_str("\r\n");
-        csv.push_str(&self.items.iter().map(|x| x.join(",")).collect::<Vec<String>>().join("\r\n"));
+        csv.push_str(&self.items.iter()
+            .map(|x| x.iter().map(|f| csv_field(f)).collect::<Vec<String>>().join(","))
+            .collect::<Vec<String>>().join("\r\n"));
         csv.push_str("\r\n");
         csv
     }
 }
+// RFC 4180 quoting: wrap fields that need it and double any embedded quotes.
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+// Excel on Windows only reads a CSV as UTF-8 when it starts with a byte order mark.
+// Files we send to IT Retail never get one.
+pub(crate) fn csv_bom(csv: String, bom: bool) -> String {
+    if bom {
+        format!("\u{feff}{}", csv)
+    } else {
+        csv
+    }
+}
+pub struct ODataQuery {
+    endpoint: String,
+    params: Vec<(&'static str, String)>,
+}
+impl ODataQuery {
+    pub fn new(endpoint: &str) -> Self {
+        ODataQuery{
+            endpoint: endpoint.to_owned(),
+            params: vec![],
+        }
+    }
+    fn set(mut self, key: &'static str, value: String) -> Self {
+        match self.params.iter_mut().find(|(k, _)| *k == key) {
+            Some(existing) => {
+                existing.1.push(',');
+                existing.1.push_str(&value);
+            },
+            None => self.params.push((key, value)),
+        }
+        self
+    }
+    pub fn select(self, fields: &[&str]) -> Self {
+        self.set("$select", fields.join(","))
+    }
+    pub fn filter(self, expr: &str) -> Self {
+        self.set("$filter", expr.to_owned())
+    }
+    pub fn expand(self, expr: &str) -> Self {
+        self.set("$expand", expr.to_owned())
+    }
+    pub fn orderby(self, field: &str) -> Self {
+        self.set("$orderby", field.to_owned())
+    }
+    pub fn top(self, n: u32) -> Self {
+        self.set("$top", n.to_string())
+    }
+    pub fn skip(self, n: usize) -> Self {
+        self.set("$skip", n.to_string())
+    }
+    // OData punctuation (commas, parens, quotes, colons) is left alone; everything else is percent-encoded.
+    fn encode(value: &str) -> String {
+        value.bytes().map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' |
+            b',' | b'(' | b')' | b'\'' | b':' | b'$' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        }).collect()
+    }
+    pub fn build(&self) -> String {
+        if self.params.is_empty() {
+            return self.endpoint.to_string();
+        }
+        let qs = self.params.iter()
+            .map(|(k, v)| format!("{}={}", k, ODataQuery::encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+        format!("{}?{}", self.endpoint, qs)
+    }
+}
 pub struct PLUAssignment {
     pub upc: String,
     pub plu: u16,
 }
+// Where a weighed-item barcode embeds the scale item code: `length` digits
+// starting `offset` into the IT Retail UPC.
+#[derive(Debug, Clone, Copy)]
+pub struct ItemCodeRange {
+    offset: usize,
+    length: usize,
+}
+impl Default for ItemCodeRange {
+    fn default() -> Self {
+        ItemCodeRange{ offset: 3, length: 5 }
+    }
+}
+impl ItemCodeRange {
+    pub fn new(offset: usize, length: usize) -> Self {
+        ItemCodeRange{ offset, length }
+    }
+    // None when the UPC is too short to hold the code; a non-numeric code is 0.
+    pub fn item_code(&self, upc: &str) -> Option<u32> {
+        let code = upc.get(self.offset..self.offset + self.length)?;
+        Some(code.trim_start_matches('0').parse::<u32>().unwrap_or(0))
+    }
+}
+
+// Where a price-embedded weighed barcode carries the price in cents: `length`
+// digits starting `offset` into the IT Retail UPC.  With check_digit the digit
+// just before the price is its price check digit, and a price that doesn't
+// match it is rejected.
+#[derive(Debug, Clone, Copy)]
+pub struct PriceCodeRange {
+    offset: usize,
+    length: usize,
+    check_digit: bool,
+}
+impl Default for PriceCodeRange {
+    fn default() -> Self {
+        PriceCodeRange{ offset: 8, length: 5, check_digit: false }
+    }
+}
+impl PriceCodeRange {
+    pub fn new(offset: usize, length: usize, check_digit: bool) -> Self {
+        PriceCodeRange{ offset, length, check_digit }
+    }
+    pub fn price(&self, upc: &str) -> Option<f64> {
+        let code = upc.get(self.offset..self.offset + self.length)?;
+        let cents = code.parse::<u32>().ok()?;
+        if self.check_digit {
+            let digits = code.chars().map(|c| c.to_digit(10)).collect::<Option<Vec<u32>>>()?;
+            let check = upc.get(self.offset.checked_sub(1)?..self.offset)?.parse::<u32>().ok()?;
+            if price_check_digit(&digits)? != check {
+                return None;
+            }
+        }
+        Some(cents as f64 / 100.0)
+    }
+}
+
+// The GS1 price check digit, for four and five digit prices; None for any
+// other length.  Each digit is weighted by one of these tables, indexed by the digit.
+fn price_check_digit(digits: &[u32]) -> Option<u32> {
+    const TWO_MINUS: [u32; 10] = [0, 2, 4, 6, 8, 9, 1, 3, 5, 7];
+    const THREE: [u32; 10] = [0, 3, 6, 9, 2, 5, 8, 1, 4, 7];
+    const FIVE_PLUS: [u32; 10] = [0, 5, 1, 6, 2, 7, 3, 8, 4, 9];
+    const FIVE_MINUS: [u32; 10] = [0, 5, 9, 4, 8, 3, 7, 2, 6, 1];
+    match digits {
+        [a, b, c, d] => Some((TWO_MINUS[*a as usize] + TWO_MINUS[*b as usize] + THREE[*c as usize] + FIVE_MINUS[*d as usize]) * 3 % 10),
+        [a, b, c, d, e] => {
+            let sum = FIVE_PLUS[*a as usize] + TWO_MINUS[*b as usize] + FIVE_MINUS[*c as usize] + FIVE_PLUS[*d as usize] + TWO_MINUS[*e as usize];
+            let want = (10 - sum % 10) % 10;
+            FIVE_MINUS.iter().position(|w| *w == want).map(|d| d as u32)
+        },
+        _ => None,
+    }
+}
+
+// A weighed (type 2) barcode taken apart.
+#[derive(Debug, PartialEq)]
+pub struct WeighedBarcode {
+    pub item_code: u32,
+    // None when no price layout was given.
+    pub price: Option<f64>,
+}
+
+// Takes IT Retail's 13 digit UPC or a 12 digit UPC-A as scanned, whose check
+// digit must be right.  None for anything that isn't a type 2 barcode.
+pub fn parse_weighed_barcode(barcode: &str, items: &ItemCodeRange, prices: Option<&PriceCodeRange>) -> Option<WeighedBarcode> {
+    let digits = barcode.chars().map(|c| c.to_digit(10)).collect::<Option<Vec<u32>>>()?;
+    let upc = match digits.len() {
+        13 if barcode.starts_with("00") => barcode.to_string(),
+        12 if upca_check_digit(&digits[..11]) == digits[11] => format!("00{}", &barcode[..11]),
+        _ => return None,
+    };
+    if !upc[2..].starts_with('2') {
+        return None;
+    }
+    let price = match prices {
+        Some(p) => Some(p.price(&upc)?),
+        None => None,
+    };
+    Some(WeighedBarcode{ item_code: items.item_code(&upc)?, price })
+}
+
+// What makes a product internal: a description prefix ("(I)" by default), its
+// department or section, or a --where style expression.  Any one is enough.
+pub struct InternalProducts {
+    prefix: String,
+    departments: HashSet<i32>,
+    sections: HashSet<i32>,
+    filter: Option<ProductFilter>,
+}
+impl InternalProducts {
+    pub fn new(prefix: &str, departments: &[i32], sections: &[i32], filter: &str) -> Result<Self> {
+        let filter = if filter.trim().is_empty() {
+            None
+        } else {
+            Some(parse_product_filter(filter).map_err(|e| anyhow!("bad scales.internal_where: {}", e))?)
+        };
+        Ok(InternalProducts {
+            prefix: prefix.to_string(),
+            departments: departments.iter().copied().collect(),
+            sections: sections.iter().copied().collect(),
+            filter,
+        })
+    }
+    pub fn from_settings(scales: &super::settings::Scales) -> Result<Self> {
+        Self::new(&scales.internal_prefix, &scales.internal_departments, &scales.internal_sections, &scales.internal_where)
+    }
+    pub fn is_internal(&self, p: &ProductData) -> bool {
+        (!self.prefix.is_empty() && p.description.starts_with(&self.prefix))
+            || self.departments.contains(&p.department_id)
+            || p.section_id.is_some_and(|s| self.sections.contains(&s))
+            || self.filter.as_ref().is_some_and(|f| f.matches(p))
+    }
+}
+
+// Internal items get PLUs up to internal_max, everything else above it.
+pub struct PLURange {
+    internal_max: u16,
+    internal: InternalProducts,
+}
+impl PLURange {
+    pub fn new(internal_max: u16, internal: InternalProducts) -> Self {
+        PLURange{ internal_max, internal }
+    }
+    pub fn is_internal(&self, plu: u16) -> bool {
+        plu <= self.internal_max
+    }
+    pub fn wrong_range(&self, item: &ProductData, plu: u16) -> bool {
+        self.internal.is_internal(item) != self.is_internal(plu)
+    }
+    pub fn next_plu(&self, hs: &mut HashSet<u16>, item: &ProductData) -> u16 {
+        // External assignment has always skipped the first PLU past the boundary.
+        let mut probe: u16 = if self.internal.is_internal(item) {
+            1
+        } else {
+            self.internal_max.saturating_add(2)
+        };
+        while hs.contains(&probe) {
+            probe += 1;
+        }
+        hs.insert(probe);
+        probe
+    }
+}
 #[derive(Serialize)]
 struct Empty {}
 #[derive(Serialize, Deserialize, Debug)]
@@ -85,7 +336,7 @@ pub struct ITRSectionsAnswer {
     pub value: Vec<ITRSection>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Section {
     pub id: Option<i32>,
     pub name: String,
@@ -125,7 +376,7 @@ pub struct ITRDepartmentsAnswer {
     pub value: Vec<ITRDepartment>
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Department {
     pub id: Option<i32>,
     pub name: String,
@@ -249,10 +500,12 @@ pub struct Customer {
 }
 #[derive(Deserialize, Debug)]
 pub struct CustomersAnswer {
-    pub value: Vec<Customer>
+    pub value: Vec<Customer>,
+    #[serde(rename = "@odata.nextLink")]
+    pub next_link: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy)]
 pub struct ITRTaxId(pub Option<i32>);
 fn deserialize_itrtaxid<'de, D>(deserializer: D) -> Result<ITRTaxId, D::Error>
 where D: Deserializer<'de> {
@@ -263,7 +516,12 @@ where D: Deserializer<'de> {
     }
 }
 
-#[derive(Deserialize, Debug)]
+fn deserialize_price<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where D: Deserializer<'de> {
+    Ok(Option::<f64>::deserialize(deserializer)?.unwrap_or(0.0))
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Tax {
     #[serde(rename = "Id")]
     pub id: ITRTaxId,
@@ -303,6 +561,20 @@ pub struct ShrinkItem {
     pub weight: Option<f32>,
 }
 
+// Scale items shrink by weight, everything else by a whole quantity.
+pub fn shrink_amount_for(item: &ProductData, amount: f32) -> Result<ShrinkAmount> {
+    if amount <= 0.0 {
+        return Err(anyhow!("shrink amount must be positive"));
+    }
+    if item.scale {
+        return Ok(ShrinkAmount::Weight(amount));
+    }
+    if amount.fract() != 0.0 {
+        return Err(anyhow!("{} is not a weight item, quantity must be a whole number", item.upc));
+    }
+    Ok(ShrinkAmount::Quantity(amount as u32))
+}
+
 pub fn make_shrink_item(item: &ProductData, reason: u32, amount: ShrinkAmount) -> ShrinkItem {
     ShrinkItem{
         product: MinimalShrinkProduct{
@@ -327,12 +599,14 @@ pub fn make_shrink_item(item: &ProductData, reason: u32, amount: ShrinkAmount) -
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct ProductData {
     pub upc: String,
     pub description: String,
     #[serde(rename = "secondDescription")]
     pub second_description: Option<String>,
+    // IT Retail sends null for a product nobody priced; that reads as 0.
+    #[serde(default, deserialize_with = "deserialize_price")]
     pub normal_price: f64,
     pub special_price: Option<f64>,
     pub start_date: Option<String>,
@@ -364,6 +638,221 @@ pub struct ProductData {
     #[serde(skip)]
     pub squareup_id: Option<String>,
 }
+// One way the side database's copy of a product differs from IT Retail.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ProductDrift {
+    pub upc: String,
+    pub field: &'static str,
+    pub sidedb: String,
+    pub live: String,
+}
+
+impl ProductDrift {
+    pub fn csv_line(&self) -> String {
+        [self.upc.as_str(), self.field, self.sidedb.as_str(), self.live.as_str()]
+            .iter().map(|f| csv_field(f)).collect::<Vec<String>>().join(",")
+    }
+}
+
+fn fmt_price(price: Option<f64>) -> String {
+    price.map(|p| format!("{:.2}", p)).unwrap_or_default()
+}
+
+// Compare live (non-deleted) IT Retail products against the side database.
+// Products only on one side are reported with field "product".
+pub fn product_drift(live: &[ProductData], sidedb: &[ProductData]) -> Vec<ProductDrift> {
+    let live: HashMap<&String, &ProductData> = live.iter().filter(|p| !p.deleted).map(|p| (&p.upc, p)).collect();
+    let side: HashMap<&String, &ProductData> = sidedb.iter().map(|p| (&p.upc, p)).collect();
+    let mut drift = vec![];
+    for (upc, l) in live.iter() {
+        let s = match side.get(upc) {
+            Some(s) => s,
+            None => {
+                drift.push(ProductDrift{ upc: upc.to_string(), field: "product", sidedb: "".to_string(), live: l.description.clone() });
+                continue;
+            }
+        };
+        let mut differs = |field: &'static str, a: String, b: String| {
+            if a != b {
+                drift.push(ProductDrift{ upc: upc.to_string(), field, sidedb: a, live: b });
+            }
+        };
+        differs("description", s.description.clone(), l.description.clone());
+        differs("normal_price", fmt_price(Some(s.normal_price)), fmt_price(Some(l.normal_price)));
+        differs("special_price", fmt_price(s.special_price), fmt_price(l.special_price));
+        differs("active", s.active.to_string(), l.active.to_string());
+        differs("discountable", (s.discountable != 0).to_string(), (l.discountable != 0).to_string());
+        differs("scale", s.scale.to_string(), l.scale.to_string());
+        differs("plu", s.plu.clone().unwrap_or_default(), l.plu.clone().unwrap_or_default());
+        differs("department_id", s.department_id.to_string(), l.department_id.to_string());
+        differs("section_id", s.section_id.map(|v| v.to_string()).unwrap_or_default(),
+                l.section_id.map(|v| v.to_string()).unwrap_or_default());
+    }
+    for (upc, s) in side.iter() {
+        if !live.contains_key(upc) {
+            drift.push(ProductDrift{ upc: upc.to_string(), field: "product", sidedb: s.description.clone(), live: "".to_string() });
+        }
+    }
+    drift.sort_by(|a, b| a.upc.cmp(&b.upc).then(a.field.cmp(b.field)));
+    drift
+}
+
+// A product whose cost or case cost rose more than the cost-alert threshold.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CostAlert {
+    pub upc: String,
+    pub description: String,
+    pub field: &'static str,
+    pub old: f64,
+    pub new: f64,
+    pub pct: f64,
+}
+
+impl CostAlert {
+    pub fn csv_line(&self) -> String {
+        format!("{},{},{},{:.2},{:.2},{:.1}", csv_field(&self.upc), csv_field(&self.description), self.field,
+                self.old, self.new, self.pct)
+    }
+}
+
+// Costs are f32s; go through their shortest decimal form so 1.1 reports as 1.1.
+fn cost_f64(cost: f32) -> f64 {
+    cost.to_string().parse().unwrap_or(cost as f64)
+}
+
+// Compare current (non-deleted) products against an earlier snapshot, reporting
+// cost and case_cost rises above `threshold_pct` percent, biggest first.
+// A missing or zero earlier cost has nothing to compare against.
+pub fn cost_alerts(current: &[ProductData], previous: &[ProductData], threshold_pct: f64) -> Vec<CostAlert> {
+    let previous: HashMap<&String, &ProductData> = previous.iter().map(|p| (&p.upc, p)).collect();
+    let mut alerts = vec![];
+    for c in current.iter().filter(|p| !p.deleted) {
+        let p = match previous.get(&c.upc) {
+            Some(p) => p,
+            None => continue,
+        };
+        for (field, old, new) in [("cost", p.cost, c.cost), ("case_cost", p.case_cost, c.case_cost)] {
+            if let (Some(old), Some(new)) = (old.map(cost_f64), new.map(cost_f64)) {
+                if old <= 0.0 {
+                    continue;
+                }
+                let pct = (new - old) / old * 100.0;
+                if pct > threshold_pct {
+                    alerts.push(CostAlert{ upc: c.upc.clone(), description: c.description.clone(), field, old, new, pct });
+                }
+            }
+        }
+    }
+    alerts.sort_by(|a, b| b.pct.total_cmp(&a.pct).then(a.upc.cmp(&b.upc)));
+    alerts
+}
+
+// One system's copy of a product, as lookup shows it.  Fields a system
+// doesn't keep are None.
+#[derive(Serialize, Debug, Default)]
+pub struct ProductLookup {
+    pub description: String,
+    // The price right now, a running special included.
+    pub price: Option<f64>,
+    pub price_with_tax: Option<f64>,
+    pub tax: Option<String>,
+    pub plu: Option<String>,
+    pub scale: Option<bool>,
+    pub active: Option<bool>,
+    pub square_id: Option<String>,
+}
+
+// Every system's copy of one UPC.
+#[derive(Serialize, Debug)]
+pub struct Lookup {
+    pub upc: String,
+    pub upca: Option<String>,
+    pub itretail: Option<ProductLookup>,
+    pub sidedb: Option<ProductLookup>,
+    pub square: Option<ProductLookup>,
+}
+
+impl Lookup {
+    // A field per row, a system per column; "-" where a system has no such product.
+    pub fn table(&self) -> String {
+        let systems = [&self.itretail, &self.sidedb, &self.square];
+        let row = |name: &str, field: &dyn Fn(&ProductLookup) -> Option<String>| {
+            let cells: Vec<String> = systems.iter()
+                .map(|p| p.as_ref().map(|p| field(p).unwrap_or_default()).unwrap_or("-".to_string()))
+                .collect();
+            format!("{:<15}{:<32}{:<32}{}", name, cells[0], cells[1], cells[2]).trim_end().to_string() + "\n"
+        };
+        let mut out = format!("UPC {} (UPC-A {})\n", self.upc, self.upca.as_deref().unwrap_or("none"));
+        out.push_str(&format!("{:<15}{:<32}{:<32}{}\n", "", "IT Retail", "sidedb", "Square"));
+        out.push_str(&row("description", &|p| Some(p.description.clone())));
+        out.push_str(&row("price", &|p| p.price.map(|v| format!("{:.2}", v))));
+        out.push_str(&row("with tax", &|p| p.price_with_tax.map(|v| format!("{:.2}", v))));
+        out.push_str(&row("tax", &|p| p.tax.clone()));
+        out.push_str(&row("plu", &|p| p.plu.clone()));
+        out.push_str(&row("scale", &|p| p.scale.map(|v| v.to_string())));
+        out.push_str(&row("active", &|p| p.active.map(|v| v.to_string())));
+        out.push_str(&row("square id", &|p| p.square_id.clone()));
+        out
+    }
+}
+
+// Why a product has the price it does at some moment: the sale dates as IT Retail
+// has them, the floored start and ceilinged end they're compared as, and which
+// way that went.
+#[derive(Debug)]
+pub struct PriceExplanation {
+    pub upc: String,
+    pub description: String,
+    pub whence: DateTime<Local>,
+    pub normal_price: f64,
+    pub special_price: Option<f64>,
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+    pub sale_start: Option<DateTime<Utc>>,
+    pub sale_end: Option<DateTime<Utc>>,
+    pub price: f64,
+    pub reason: &'static str,
+}
+
+impl PriceExplanation {
+    pub fn text(&self) -> String {
+        let price = |p: Option<f64>| p.map(|p| format!("{:.2}", p)).unwrap_or("none".to_string());
+        let raw = |d: &Option<String>| d.clone().unwrap_or("none".to_string());
+        let when = |d: Option<DateTime<Utc>>| d.map(|d| format!("{} ({})", d.to_rfc3339_opts(SecondsFormat::Secs, true),
+            d.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %Z"))).unwrap_or("-".to_string());
+        let mut out = format!("{} {}\n", self.upc, self.description);
+        out.push_str(&format!("{:<16}{}\n", "as of", self.whence.format("%Y-%m-%d %H:%M:%S %Z")));
+        out.push_str(&format!("{:<16}{}\n", "normal price", price(Some(self.normal_price))));
+        out.push_str(&format!("{:<16}{}\n", "special price", price(self.special_price)));
+        out.push_str(&format!("{:<16}{}\n", "start date", raw(&self.start_date)));
+        out.push_str(&format!("{:<16}{}\n", "end date", raw(&self.end_date)));
+        out.push_str(&format!("{:<16}{}\n", "start, floored", when(self.sale_start)));
+        out.push_str(&format!("{:<16}{}\n", "end, ceilinged", when(self.sale_end)));
+        out.push_str(&format!("{:<16}{} ({})\n", "price", price(Some(self.price)), self.reason));
+        out
+    }
+}
+
+// Round a price half away from zero at `precision` decimals, working from its
+// shortest decimal form so 4.005 (really 4.00499...) still rounds up to 4.01.
+pub fn round_price(price: f64, precision: u32) -> f64 {
+    match Decimal::from_f64(price) {
+        Some(d) => d.round_dp_with_strategy(precision, RoundingStrategy::MidpointAwayFromZero)
+            .to_f64()
+            .unwrap_or(price),
+        None => price,
+    }
+}
+
+// Spreadsheet number format showing `precision` decimals, e.g. "0.00".
+pub fn price_num_format(precision: u32) -> String {
+    if precision == 0 {
+        "0".to_string()
+    } else {
+        format!("0.{}", "0".repeat(precision as usize))
+    }
+}
+
 pub fn itr_upc_to_upca(upc: &String) -> Option<String> {
     if &upc[0..2] != "00" { return None; }
     let a = &upc.chars().collect::<Vec<char>>()[2..];
@@ -382,13 +871,7 @@ pub fn itr_upc_to_upca(upc: &String) -> Option<String> {
             }
         }
         else {
-            let mut check_digit: u32 = 0;
-            for i in 0..11 {
-                let multiplier = if (i%2) == 0 { 3 } else { 1 };
-                check_digit += d[i].unwrap() * multiplier;
-            }
-            check_digit %= 10;
-            check_digit = if check_digit > 0 { 10 - check_digit } else { check_digit };
+            let check_digit = upca_check_digit(&d.iter().map(|x| x.unwrap()).collect::<Vec<u32>>());
             // feature gated.
             //let new_upca = std::iter::chain(a, vec![&(((check_digit % 10) as u8) as char)]);
             Some(format!("{}{}", a.into_iter().collect::<String>(), ((check_digit % 10 + 48) as u8) as char))
@@ -396,58 +879,134 @@ pub fn itr_upc_to_upca(upc: &String) -> Option<String> {
     }
 }
 
+// The IT Retail UPC for a UPC-A or 5 digit PLU, the reverse of itr_upc_to_upca.
+pub fn upca_to_itr_upc(upca: &str) -> Option<String> {
+    if !upca.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match upca.len() {
+        5 => Some(format!("002{}00000", upca)),
+        12 => Some(format!("00{}", &upca[..11])),
+        _ => None,
+    }
+}
+
+// The UPC-A check digit for its first 11 digits.
+fn upca_check_digit(digits: &[u32]) -> u32 {
+    let mut check_digit: u32 = 0;
+    for (i, d) in digits.iter().take(11).enumerate() {
+        let multiplier = if (i%2) == 0 { 3 } else { 1 };
+        check_digit += d * multiplier;
+    }
+    check_digit %= 10;
+    if check_digit > 0 { 10 - check_digit } else { check_digit }
+}
+
 impl ProductData {
     pub fn upca(&self) -> Option<String> {
         itr_upc_to_upca(&self.upc)
     }
+    pub fn weighed_barcode(&self, items: &ItemCodeRange, prices: Option<&PriceCodeRange>) -> Option<WeighedBarcode> {
+        parse_weighed_barcode(&self.upc, items, prices)
+    }
+    // Deleted items are never wanted; inactive ones only when we aren't restricted to active items.
+    pub fn is_available(&self, active_only: bool) -> bool {
+        !self.deleted && (self.active || !active_only)
+    }
+    // A missing or zero price would go out as free; scales and Square skip these
+    // unless --allow-zero-price.
+    pub fn has_price(&self) -> bool {
+        self.normal_price > 0.0
+    }
     pub fn get_price_as_of(&self, whence: DateTime<Local>) -> f64 {
+        self.explain_price_as_of(whence).price
+    }
+    // get_price_as_of with its working shown, for explain-price.
+    pub fn explain_price_as_of(&self, whence: DateTime<Local>) -> PriceExplanation {
         // IT Retail is a distater.  It can't get timestamps on sales start/end right.
         // The DB/API has hours set, but the UI doesn't allow specifying them and they seem somewhat randomly assigned.
         // More of a crime, the hours for the end date appear to be KNOWN to be wrong and the app ignores them and pretends they are 23:59:59.
         // WTF writes code like this?
 
         // As such, it is on us to floor the start and ceiling the end dates for the purposes of comparison
-        if self.start_date.is_some() {
-            let start_date = self.start_date.as_ref().unwrap().replace("T", " ");
-            let itr_start = match NaiveDateTime::parse_from_str(&start_date, "%Y-%m-%d %H:%M:%S") {
-                Ok(utc_start) => { Ok(DateTime::<Utc>::from_naive_utc_and_offset(utc_start, Utc)
-                                                        .with_hour(0).unwrap()
-                                                        .with_minute(0).unwrap()
-                                                        .with_second(0).unwrap()) },
+        let parse = |raw: &Option<String>, h: u32, m: u32, s: u32| raw.as_ref().and_then(|d| {
+            match NaiveDateTime::parse_from_str(&d.replace("T", " "), "%Y-%m-%d %H:%M:%S") {
+                Ok(utc) => Some(DateTime::<Utc>::from_naive_utc_and_offset(utc, Utc)
+                                .with_hour(h).unwrap()
+                                .with_minute(m).unwrap()
+                                .with_second(s).unwrap()),
                 Err(e) => {
-                    error!("Failed to parse '{}': {:?}", self.start_date.as_ref().unwrap(), e);
-                    Err(())
+                    error!("Failed to parse '{}': {:?}", d, e);
+                    None
                 }
-            };
-            if itr_start.is_ok() && itr_start.unwrap() <= whence {
-                let end_date = self.end_date.as_ref().unwrap().replace("T", " ");
-                if self.end_date.is_some() {
-                    let itr_end = match NaiveDateTime::parse_from_str(&end_date, "%Y-%m-%d %H:%M:%S") {
-                        Ok(utc_end) => { Ok(DateTime::<Utc>::from_naive_utc_and_offset(utc_end, Utc)
-                                                            .with_hour(23).unwrap()
-                                                            .with_minute(59).unwrap()
-                                                            .with_second(59).unwrap()) },
-                        Err(e) => {
-                            error!("Failed to parse '{}': {:?}", self.end_date.as_ref().unwrap(), e);
-                            Err(())
-                        }
-                    };
-                    if itr_end.is_ok() && itr_end.unwrap() <= whence {
-                        debug!("Product {} has sale in past ({:#?}) {} <= {}", self.description, self.end_date, itr_end.as_ref().unwrap(), whence);
-                        return self.normal_price; // expired
-                    }
-                }
-                debug!("Product {} has sale now", self.description);
-                return self.special_price.unwrap_or(self.normal_price)
-            } else {
-                debug!("Product {} has sale in future {} > {}", self.description, itr_start.as_ref().unwrap(), whence);
             }
+        });
+        let mut explained = PriceExplanation {
+            upc: self.upc.clone(),
+            description: self.description.clone(),
+            whence,
+            normal_price: self.normal_price,
+            special_price: self.special_price,
+            start_date: self.start_date.clone(),
+            end_date: self.end_date.clone(),
+            sale_start: parse(&self.start_date, 0, 0, 0),
+            sale_end: parse(&self.end_date, 23, 59, 59),
+            price: self.normal_price,
+            reason: "no sale start date, normal price",
+        };
+        match (&self.start_date, explained.sale_start, explained.sale_end) {
+            (None, _, _) => {},
+            (Some(_), None, _) => explained.reason = "sale start date unreadable, normal price",
+            (Some(_), Some(start), _) if start > whence => {
+                debug!("Product {} has sale in future {} > {}", self.description, start, whence);
+                explained.reason = "sale starts after the date, normal price";
+            },
+            (Some(_), Some(_), Some(end)) if end <= whence => {
+                debug!("Product {} has sale in past ({:#?}) {} <= {}", self.description, self.end_date, end, whence);
+                explained.reason = "sale ended before the date, normal price"; // expired
+            },
+            (Some(_), Some(_), end) => {
+                debug!("Product {} has sale now", self.description);
+                explained.price = self.special_price.unwrap_or(self.normal_price);
+                explained.reason = match (self.special_price, end) {
+                    (None, _) => "sale in effect but no special price, normal price",
+                    (Some(_), None) if self.end_date.is_some() => "sale in effect (end date unreadable), special price",
+                    (Some(_), None) => "sale in effect with no end date, special price",
+                    (Some(_), Some(_)) => "sale in effect, special price",
+                };
+            },
         }
-        self.normal_price
+        explained
     }
     pub fn get_price(&self) -> f64 {
         self.get_price_as_of(Local::now())
     }
+    pub fn lookup(&self, tax_rates: &HashMap<i32, f64>) -> ProductLookup {
+        let price = self.get_price();
+        ProductLookup {
+            description: self.description.clone(),
+            price: Some(price),
+            price_with_tax: Some(self.with_tax(price, tax_rates)),
+            tax: Some(match self.taxclass.0 {
+                Some(id) => match tax_rates.get(&id) {
+                    Some(rate) => format!("class {} ({}%)", id, rate),
+                    None => format!("class {}", id),
+                },
+                None => "none".to_string(),
+            }),
+            plu: self.plu.clone(),
+            scale: Some(self.scale),
+            active: Some(self.active && !self.deleted),
+            square_id: self.squareup_id.clone(),
+        }
+    }
+    // Display only: gross up a price by this item's tax class, if it has one we know about.
+    pub fn with_tax(&self, price: f64, tax_rates: &HashMap<i32, f64>) -> f64 {
+        match self.taxclass.0.and_then(|id| tax_rates.get(&id)) {
+            Some(rate) => price * (1.0 + rate / 100.0),
+            None => price,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -493,10 +1052,17 @@ impl Default for BearerToken {
     }
 }
 
+pub const DEFAULT_UPDATE_BATCH_ROWS: usize = 1000;
+pub const DEFAULT_BASE_URL: &str = "https://retailnext.itretail.com";
+
 pub struct ITRApi {
     backingfile: File,
     store_id: String,
     bearer_token: BearerToken,
+    update_batch_rows: usize,
+    connect_timeout_seconds: u64,
+    request_timeout_seconds: u64,
+    base_url: String,
 }
 
 fn bearer_token_from_json(json: String) -> BearerToken {
@@ -524,7 +1090,7 @@ fn bearer_token_from_json(json: String) -> BearerToken {
     bto
 }
 
-pub fn get_dotfile(filename: &str, writeable: bool) -> Result<File, anyhow::Error> {
+fn dotfile_dir() -> Result<PathBuf, anyhow::Error> {
     let mut token_filepath = PathBuf::new();
     match home::home_dir() {
         Some(path) => token_filepath.push(path),
@@ -537,6 +1103,11 @@ pub fn get_dotfile(filename: &str, writeable: bool) -> Result<File, anyhow::Erro
             Err(err) => return Err(err.into()),
         }
     }
+    Ok(token_filepath)
+}
+
+pub fn get_dotfile(filename: &str, writeable: bool) -> Result<File, anyhow::Error> {
+    let mut token_filepath = dotfile_dir()?;
     token_filepath.push(filename);
     let file = if writeable {
         OpenOptions::new()
@@ -556,12 +1127,71 @@ pub fn get_dotfile(filename: &str, writeable: bool) -> Result<File, anyhow::Erro
     }
 }
 
+// Replace a dotfile by writing a temporary file next to it and renaming that
+// into place, so a crash mid-write can't leave a truncated file behind.
+// Returns a handle on the new file.
+pub fn replace_dotfile(filename: &str, contents: &[u8]) -> Result<File, anyhow::Error> {
+    let dir = dotfile_dir()?;
+    let tmpname = format!("{}.tmp", filename);
+    let mut tmp = get_dotfile(&tmpname, true)?;
+    tmp.set_len(0)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    drop(tmp);
+    std::fs::rename(dir.join(&tmpname), dir.join(filename))?;
+    get_dotfile(filename, true)
+}
+
+const PRODUCTS_CACHE: &str = "products_cache.json";
+
+// Keep the last GetAllProducts answer so get-plu can run without the network.
+pub fn cache_products(json: &str) -> Result<()> {
+    replace_dotfile(PRODUCTS_CACHE, json.as_bytes())?;
+    Ok(())
+}
+
+// The cached GetAllProducts answer and how long ago it was saved.
+pub fn cached_products() -> Result<(String, std::time::Duration)> {
+    let path = dotfile_dir()?.join(PRODUCTS_CACHE);
+    let json = std::fs::read_to_string(&path)
+        .map_err(|e| anyhow!("no product cache at {}: {}", path.display(), e))?;
+    let age = std::fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default();
+    Ok((json, age))
+}
+
+// Resolve the file an export subcommand writes to.  An explicit --output
+// is used as given; otherwise the default name is placed in --output-dir
+// (created if needed) and, with --timestamp, suffixed with the local time
+// so earlier exports are kept, e.g. labels-2024-06-01T1200.xlsx.
+pub fn export_filename(args: &clap::ArgMatches, default: &str) -> Result<String> {
+    if let Some(output) = args.get_one::<String>("output") {
+        return Ok(output.to_string());
+    }
+    let mut path = PathBuf::new();
+    if let Some(dir) = args.get_one::<String>("output-dir") {
+        std::fs::create_dir_all(dir)?;
+        path.push(dir);
+    }
+    if args.get_flag("timestamp") {
+        let (stem, ext) = default.rsplit_once('.').unwrap_or((default, ""));
+        let stamp = Local::now().format("%Y-%m-%dT%H%M");
+        path.push(if ext.is_empty() { format!("{}-{}", stem, stamp) } else { format!("{}-{}.{}", stem, stamp, ext) });
+    } else {
+        path.push(default);
+    }
+    Ok(path.to_string_lossy().to_string())
+}
+
 pub fn create_api() -> Result<ITRApi> {
     let backingfile = get_dotfile("token.json", true)?;
     Ok(ITRApi {
         backingfile: backingfile,
         store_id: env::var("ITRETAIL_STOREID")?,
         bearer_token: BearerToken::default(),
+        update_batch_rows: DEFAULT_UPDATE_BATCH_ROWS,
+        connect_timeout_seconds: 0,
+        request_timeout_seconds: 0,
+        base_url: DEFAULT_BASE_URL.to_string(),
     })
 }
 
@@ -589,14 +1219,14 @@ impl ITRApi {
         self.clear_token()?;
 
         debug!("Fetching token");
-        let client = reqwest::Client::new();
+        let client = self.client()?;
         let user = match env::var("ITRETAIL_USERNAME") {
             Ok(p) => p,
-            Err(..) => return Err(anyhow!("no username provided")),
+            Err(..) => return Err(ApiError::MissingUsername.into()),
         };
         let pass = match env::var("ITRETAIL_PASSWORD") {
             Ok(p) => p,
-            Err(..) => return Err(anyhow!("no password provided")),
+            Err(..) => return Err(ApiError::MissingPassword.into()),
         };
         let params = [
             ("grant_type", "password"),
@@ -604,25 +1234,23 @@ impl ITRApi {
             ("password", &pass),
         ];
         let res = client
-            .post("https://retailnext.itretail.com/token?accesslevel=0&securityCode=undefined")
+            .post(format!("{}/token?accesslevel=0&securityCode=undefined", self.base_url))
             .form(&params)
             .send().await;
         match res {
             Ok(result) => {
                 let text_response = result.text().await;
                 let bt = bearer_token_from_json(text_response.ok().unwrap());
-                self.backingfile.set_len(0)?;
-                self.backingfile.rewind()?;
-                self.backingfile.write_all(
+                self.backingfile = replace_dotfile(
+                    "token.json",
                     serde_json::to_string(&bt)
                         .ok()
                         .unwrap_or(r"".to_string())
                         .as_bytes(),
                 )?;
-                self.backingfile.sync_all()?;
                 bt
             }
-            Err(e) => return Err(anyhow!("{}", e.to_string())),
+            Err(e) => return Err(ApiError::from_reqwest(e).into()),
         };
 
         return Ok(());
@@ -635,8 +1263,8 @@ impl ITRApi {
         headers: Option<reqwest::header::HeaderMap>,
         json: Option<&T>,
     ) -> Result<String> {
-        let client = reqwest::Client::new();
-        let url = "https://retailnext.itretail.com".to_owned() + endpoint;
+        let client = self.client()?;
+        let url = self.base_url.clone() + endpoint;
         let mut builder = client.request(method, url);
         if let Some(headers) = headers {
             builder = builder.headers(headers)
@@ -649,21 +1277,16 @@ impl ITRApi {
         match res {
             Ok(result) => {
                 if result.status().is_success() {
-                    let text_response = result.text().await?;
+                    let text_response = result.text().await.map_err(ApiError::from_reqwest)?;
                     Ok(text_response)
                 } else {
                     let status = result.status();
-                    let text_response = &result.text().await?;
+                    let text_response = &result.text().await.map_err(ApiError::from_reqwest)?;
                     debug!("{}", text_response);
-                    Err(anyhow!(
-                        "{}",
-                        status
-                            .canonical_reason()
-                            .unwrap_or(&format!("UNKNOWN CODE: {}", status.as_str()))
-                    ))
+                    Err(ApiError::from_status(status).into())
                 }
             }
-            Err(e) => Err(anyhow!("{}", e.to_string())),
+            Err(e) => Err(ApiError::from_reqwest(e).into()),
         }
     }
 
@@ -674,8 +1297,8 @@ impl ITRApi {
         headers: Option<reqwest::header::HeaderMap>,
         form: multipart::Form,
     ) -> Result<String> {
-        let client = reqwest::Client::new();
-        let url = "https://retailnext.itretail.com".to_owned() + endpoint;
+        let client = self.client()?;
+        let url = self.base_url.clone() + endpoint;
         let mut builder = client.request(method, url);
         if let Some(headers) = headers {
             builder = builder.headers(headers)
@@ -689,20 +1312,55 @@ impl ITRApi {
                     let text_response = result.text().await?;
                     Ok(text_response)
                 } else {
-                    Err(anyhow!(
-                        "{}",
-                        result
-                            .status()
-                            .canonical_reason()
-                            .unwrap_or(&format!("UNKNOWN CODE: {}", result.status().as_str()))
-                    ))
+                    Err(ApiError::from_status(result.status()).into())
                 }
             }
-            Err(e) => Err(anyhow!("{}", e.to_string())),
+            Err(e) => Err(ApiError::from_reqwest(e).into()),
         }
     }
 
+    // Zero leaves the corresponding reqwest timeout unset.
+    pub fn set_timeouts(&mut self, connect_seconds: u64, request_seconds: u64) {
+        self.connect_timeout_seconds = connect_seconds;
+        self.request_timeout_seconds = request_seconds;
+    }
+
+    fn client(&self) -> Result<reqwest::Client> {
+        Ok(http_client(self.connect_timeout_seconds, self.request_timeout_seconds).build()?)
+    }
+
+    pub fn set_base_url(&mut self, base_url: &str) {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+    }
+
+    pub fn set_update_batch_rows(&mut self, rows: usize) {
+        self.update_batch_rows = rows.max(1);
+    }
+
     pub async fn set_product_fields(&mut self, pfa: &ProductFieldAssignments) -> Result<String> {
+        if pfa.rows() <= self.update_batch_rows {
+            return self.post_product_fields(pfa).await;
+        }
+        let chunks = pfa.chunks(self.update_batch_rows);
+        let total = chunks.len();
+        let mut results = vec![];
+        for (idx, chunk) in chunks.iter().enumerate() {
+            match self.post_product_fields(chunk).await {
+                Ok(r) => {
+                    info!("product field update chunk {}/{} ({} rows) succeeded", idx + 1, total, chunk.rows());
+                    results.push(r);
+                },
+                Err(e) => {
+                    error!("product field update chunk {}/{} ({} rows) failed: {}", idx + 1, total, chunk.rows(), e);
+                    return Err(anyhow!("chunk {}/{} failed after {} of {} rows applied: {}",
+                        idx + 1, total, idx * self.update_batch_rows, pfa.rows(), e));
+                },
+            }
+        }
+        Ok(results.join("\n"))
+    }
+
+    async fn post_product_fields(&mut self, pfa: &ProductFieldAssignments) -> Result<String> {
         let endpoint = &"/api/ProductsData/UpdateOnly".to_string();
         let csvcontents = pfa.as_csv();
         let part = reqwest::multipart::Part::text(csvcontents)
@@ -757,18 +1415,40 @@ impl ITRApi {
         self.call::<Empty>(reqwest::Method::GET, endpoint, None, None).await
     }
 
+    // IT Retail may cap how many customers one response holds, so keep asking,
+    // following @odata.nextLink when offered and $skip past what we have when not,
+    // until a page brings nothing new.
     pub async fn get_customers(&mut self) -> Result<Vec<Customer>> {
-        let results = self
-            .get(&"/api/CustomersData/Get?$select=%2A".to_string())
-            .await
-            .expect("no results from API call");
-        let answer: CustomersAnswer = serde_json::from_str(&results)?;
-        Ok(answer.value)
+        let query = || ODataQuery::new("/api/CustomersData/Get").select(&["*"]).orderby("Id");
+        let mut customers: Vec<Customer> = vec![];
+        let mut seen = HashSet::<Uuid>::new();
+        let mut url = query().build();
+        let mut pages = 0;
+        loop {
+            let results = self.get(&url).await?;
+            let answer: CustomersAnswer = parse_response(&results)?;
+            pages += 1;
+            let before = customers.len();
+            for c in answer.value {
+                if seen.insert(c.id) {
+                    customers.push(c);
+                }
+            }
+            if customers.len() == before {
+                break;
+            }
+            url = match answer.next_link {
+                Some(link) => link.strip_prefix(&self.base_url).map(|l| l.to_string()).unwrap_or(link),
+                None => query().skip(customers.len()).build(),
+            };
+        }
+        info!("Pulled {} IT Retail customers in {} requests", customers.len(), pages);
+        Ok(customers)
     }
 
     pub async fn get_customer(&mut self, cid: &Uuid) -> Result<Option<Customer>> {
         let url = format!("/api/CustomersData/GetOne/?Id={}", cid);
-        let results = self.get(&url).await.expect("no results from API call");
+        let results = self.get(&url).await?;
         if results.trim() == "null" {
             return Ok(None);
         }
@@ -786,20 +1466,18 @@ impl ITRApi {
 
     pub async fn get_departments(&mut self) -> Result<Vec<Department>> {
         let results = self
-            .get(&"/api/DepartmentsData/Get?$select=dept_name,dept_no".to_string())
-            .await
-            .expect("no results from API call");
-        let itrdepts: ITRDepartmentsAnswer = serde_json::from_str(&results)?;
+            .get(&ODataQuery::new("/api/DepartmentsData/Get").select(&["dept_name", "dept_no"]).build())
+            .await?;
+        let itrdepts: ITRDepartmentsAnswer = parse_response(&results)?;
         let depts: Vec<Department> = itrdepts.value.iter().map(|x| x.into()).collect();
         Ok(depts)
     }
 
     pub async fn get_sections(&mut self) -> Result<Vec<Section>> {
         let results = self
-            .get(&"/api/SectionsData/Get?$select=*".to_string())
-            .await
-            .expect("no results from API call");
-        let itrsections: ITRSectionsAnswer = serde_json::from_str(&results)?;
+            .get(&ODataQuery::new("/api/SectionsData/Get").select(&["*"]).build())
+            .await?;
+        let itrsections: ITRSectionsAnswer = parse_response(&results)?;
         let sections: Vec<Section> = itrsections.value.iter().map(|x| x.into()).collect();
         Ok(sections)
     }
@@ -807,21 +1485,30 @@ impl ITRApi {
     pub async fn get_products(&mut self) -> Result<Vec<ProductData>> {
         let results = self
             .get(&"/api/ProductsData/GetAllProducts".to_string())
-            .await
-            .expect("no results from API call");
-        let products: Vec<ProductData> = serde_json::from_str(&results)?;
+            .await?;
+        let products: Vec<ProductData> = parse_response(&results)?;
         Ok(products)
     }
 
     pub async fn get_tax(&mut self) -> Result<Vec<Tax>> {
         let results = self
-            .get(&"/api/TaxesData/Get?$orderby=Id&$select=Id,Description,Identifier,TaxRate".to_string())
-            .await
-            .expect("no results from API call");
-        let taxanswer: ITRTaxAnswer = serde_json::from_str(&results)?;
+            .get(&ODataQuery::new("/api/TaxesData/Get")
+                 .orderby("Id")
+                 .select(&["Id", "Description", "Identifier", "TaxRate"])
+                 .build())
+            .await?;
+        let taxanswer: ITRTaxAnswer = parse_response(&results)?;
         Ok(taxanswer.value)
     }
 
+    // Tax rates by tax class id; IT Retail reports rates as percentages.
+    pub async fn get_tax_rates(&mut self) -> Result<HashMap<i32, f64>> {
+        Ok(self.get_tax().await?
+            .iter()
+            .filter_map(|t| t.id.0.map(|id| (id, t.rate)))
+            .collect())
+    }
+
     pub async fn get_categories(&mut self) -> Result<Vec<Category>> {
         let mut hdrs = reqwest::header::HeaderMap::new();
         hdrs.insert(
@@ -835,9 +1522,8 @@ impl ITRApi {
                 Some(hdrs),
                 None,
             )
-            .await
-            .expect("no results from API call");
-        let cats: Vec<Category> = serde_json::from_str(&results)?;
+            .await?;
+        let cats: Vec<Category> = parse_response(&results)?;
         Ok(cats)
     }
 
@@ -856,30 +1542,27 @@ impl ITRApi {
         let start = start_o.unwrap_or(&start_default);
         // This returns a productId that is a uuid.  Nowhere else in the APIs can I find a uuid attached to
         // rows of the products, so we don't have a mapping from productid <-> upc
-        let url = format!(
         /*
           Looks like ITR broke this 2024-07-30
           Could not find a property named 'TransactionProducts' on type 'ITRetail.Web.Models.ElectronicJournal.TransactionDto'
-            "/api/ElectronicJournalData/Get?\
-            $expand=TransactionTenders($select+%3D+TenderCode,LastCardDigits),TransactionProducts($select+%3D+%2A),TransactionProducts($expand=ProductChange($select+%3D+upc))&\
-            $filter=(TransactionDate+ge+{}+and++TransactionDate+lt+{})+and+(Total+ne+null)&\
-            $orderby=TransactionDate&$select=Id,EmployeeId,TransactionDate,Total,Canceled,CustomerId,CustomerFirstName,CustomerLastName",
-            start.to_rfc3339_opts(SecondsFormat::Secs, true),
-            end.to_rfc3339_opts(SecondsFormat::Secs, true));
+            .expand("TransactionProducts($select=*)")
+            .expand("TransactionProducts($expand=ProductChange($select=upc))")
         */
         /* This one is shit b/c their API return CustomerId, but it is always null!
             "/api/ElectronicJournalData/GetTransactions?from={}&to={}&pageSize=10000",
             start.format("%Y-%m-%d"), end.format("%Y-%m-%d"));
         */
-            "/api/ElectronicJournalData/Get?\
-            $expand=TransactionTenders($select+%3D+TenderCode,LastCardDigits)&\
-            $filter=(TransactionDate+ge+{}+and++TransactionDate+lt+{})+and+(Total+ne+null)&\
-            $orderby=TransactionDate&$select=Id,EmployeeId,TransactionDate,Total,Canceled,CustomerId,CustomerFirstName,CustomerLastName",
-            start.to_rfc3339_opts(SecondsFormat::Secs, true),
-            end.to_rfc3339_opts(SecondsFormat::Secs, true));
+        let url = ODataQuery::new("/api/ElectronicJournalData/Get")
+            .expand("TransactionTenders($select=TenderCode,LastCardDigits)")
+            .filter(&format!("(TransactionDate ge {} and TransactionDate lt {}) and (Total ne null)",
+                start.to_rfc3339_opts(SecondsFormat::Secs, true),
+                end.to_rfc3339_opts(SecondsFormat::Secs, true)))
+            .orderby("TransactionDate")
+            .select(&["Id", "EmployeeId", "TransactionDate", "Total", "Canceled", "CustomerId", "CustomerFirstName", "CustomerLastName"])
+            .build();
         match self.get(&url).await {
             Ok(r) => {
-                let answer: EJTAnswer = serde_json::from_str(&r)?;
+                let answer: EJTAnswer = parse_response(&r)?;
                 Ok(answer.value)
             },
             Err(e) => {
@@ -911,11 +1594,141 @@ impl ITRApi {
         debug!("Shrink: {}", output);
         Ok(())
     }
+
+    // Fetch a record from each endpoint we depend on and make sure the fields we
+    // deserialize are still there.  IT Retail changes shapes without notice and
+    // that otherwise shows up as silently empty results.
+    // One check per endpoint, passing or not.
+    pub async fn check_schema(&mut self) -> Result<Vec<HealthCheck>> {
+        let checks: Vec<(&str, String, &[&str])> = vec![
+            ("products", ODataQuery::new("/api/ProductsData/GetAllProducts").top(1).build(), PRODUCT_FIELDS),
+            ("customers", ODataQuery::new("/api/CustomersData/Get").select(&["*"]).top(1).build(), CUSTOMER_FIELDS),
+            ("sections", ODataQuery::new("/api/SectionsData/Get").select(&["*"]).top(1).build(), SECTION_FIELDS),
+            ("departments", ODataQuery::new("/api/DepartmentsData/Get").select(&["dept_name", "dept_no"]).top(1).build(), DEPARTMENT_FIELDS),
+            ("transactions", ODataQuery::new("/api/ElectronicJournalData/Get")
+                .select(&["Id", "EmployeeId", "TransactionDate", "Total", "Canceled", "CustomerId", "CustomerFirstName", "CustomerLastName"])
+                .top(1).build(), TRANSACTION_FIELDS),
+        ];
+        let mut results = vec![];
+        for (name, url, fields) in checks {
+            let problem = match self.get(&url).await {
+                Ok(body) => match missing_fields(&body, fields) {
+                    Ok(Some(missing)) if missing.is_empty() => None,
+                    Ok(Some(missing)) => Some(format!("missing fields: {}", missing.join(", "))),
+                    Ok(None) => {
+                        debug!("schema check for {}: no records returned", name);
+                        None
+                    },
+                    Err(e) => Some(e.to_string()),
+                },
+                Err(e) => Some(format!("request failed: {}", e)),
+            };
+            match problem {
+                Some(problem) => results.push(HealthCheck::new(&format!("itretail_{}", name), false, &problem)),
+                None => {
+                    debug!("schema check for {} passed", name);
+                    results.push(HealthCheck::new(&format!("itretail_{}", name), true, "expected fields present"));
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+// The fields, as IT Retail names them, that our structs need from each endpoint.
+const PRODUCT_FIELDS: &[&str] = &["upc", "description", "normal_price", "special_price", "scale", "active", "Deleted", "PLU", "departmentId", "sectionId", "taxes"];
+const CUSTOMER_FIELDS: &[&str] = &["Id", "LastName", "FirstName", "Phone", "Email", "Discount", "Deleted"];
+const SECTION_FIELDS: &[&str] = &["section", "section_name", "departmentId", "Deleted"];
+const DEPARTMENT_FIELDS: &[&str] = &["dept_name", "dept_no"];
+const TRANSACTION_FIELDS: &[&str] = &["Id", "TransactionDate", "Total", "Canceled", "CustomerId"];
+
+// One line of doctor output.
+#[derive(Debug, Serialize)]
+pub struct HealthCheck {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl HealthCheck {
+    pub fn new(name: &str, ok: bool, detail: &str) -> Self {
+        HealthCheck { name: name.to_string(), ok, detail: detail.to_string() }
+    }
+}
+
+// The upstream message when `json` is an error envelope rather than data: OData's
+// {"error": {"message": ...}} (v3 JSON light calls it "odata.error") or Web API's {"Message": ..., "ExceptionMessage": ...}.
+fn odata_error(json: &serde_json::Value) -> Option<String> {
+    let o = json.as_object()?;
+    if let Some(e) = o.get("error").or_else(|| o.get("odata.error")) {
+        // OData v3 nests the text as {"message": {"lang": ..., "value": ...}}.
+        let message = e.get("message").map(|m| m.get("value").unwrap_or(m)).unwrap_or(e);
+        return Some(message.as_str().map(|m| m.to_string()).unwrap_or(message.to_string()));
+    }
+    if o.contains_key("value") {
+        return None;
+    }
+    let message = o.get("Message").and_then(|m| m.as_str())?;
+    Some(match o.get("ExceptionMessage").and_then(|m| m.as_str()) {
+        Some(detail) => format!("{} {}", message, detail),
+        None => message.to_string(),
+    })
+}
+
+// Deserialize an IT Retail response, telling an error IT Retail sent back (ApiError::Upstream)
+// apart from a body that isn't JSON or isn't shaped as expected (ApiError::Parse).
+pub(crate) fn parse_response<T: serde::de::DeserializeOwned>(body: &str) -> Result<T> {
+    match serde_json::from_str::<T>(body) {
+        Ok(t) => Ok(t),
+        Err(e) => match serde_json::from_str::<serde_json::Value>(body).ok().as_ref().and_then(odata_error) {
+            Some(message) => Err(ApiError::Upstream(message).into()),
+            None => Err(ApiError::Parse(e).into()),
+        },
+    }
+}
+
+// Which of `fields` the first record of a response lacks; None when there are no records.
+// Responses are either a bare array or an OData {"value": [...]} wrapper.
+fn missing_fields(body: &str, fields: &[&str]) -> Result<Option<Vec<String>>> {
+    let json: serde_json::Value = serde_json::from_str(body).map_err(ApiError::Parse)?;
+    if let Some(message) = odata_error(&json) {
+        return Err(ApiError::Upstream(message).into());
+    }
+    let records = match &json {
+        serde_json::Value::Array(a) => a,
+        serde_json::Value::Object(o) => match o.get("value") {
+            Some(serde_json::Value::Array(a)) => a,
+            _ => return Err(anyhow!("response has no record list")),
+        },
+        _ => return Err(anyhow!("unexpected response shape")),
+    };
+    match records.first() {
+        Some(serde_json::Value::Object(record)) => {
+            Ok(Some(fields.iter().filter(|f| !record.contains_key(**f)).map(|f| f.to_string()).collect()))
+        },
+        Some(_) => Err(anyhow!("records are not objects")),
+        None => Ok(None),
+    }
+}
+
+// A product for tests: upc 0001 "Apples" at 1.00 in department 1, with the
+// fields in `json_extra` (object members, e.g. r#""upc":"0002","scale":true"#)
+// replacing or adding to those.
+#[cfg(test)]
+pub(crate) fn product(json_extra: &str) -> ProductData {
+    let mut json: serde_json::Value = serde_json::from_str(r#"{"upc":"0001","description":"Apples","normal_price":1.0,
+        "scale":false,"active":true,"discountable":1,"Deleted":false,"departmentId":1,"taxes":""}"#).unwrap();
+    let extra: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&format!("{{{}}}", json_extra)).unwrap();
+    json.as_object_mut().unwrap().extend(extra);
+    serde_json::from_value(json).unwrap()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Seek;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{header, method, path, query_param, query_param_is_missing};
 
     #[test]
     fn test_itr_to_upca() {
@@ -939,6 +1752,13 @@ mod tests {
         assert_eq!(None, itr_upc_to_upca(&"0020163404000".to_owned()));
     }
     #[test]
+    fn test_upca_to_itr() {
+        assert_eq!(upca_to_itr_upc("810012110099"), Some("0081001211009".to_owned()));
+        assert_eq!(upca_to_itr_upc("01634"), Some("0020163400000".to_owned()));
+        assert_eq!(upca_to_itr_upc("0081001211009"), None);
+        assert_eq!(upca_to_itr_upc("8100121100x9"), None);
+    }
+    #[test]
     fn test_generic_plu_assignment() {
         let plus: Vec<PLUAssignment> = vec![
             PLUAssignment{ upc: "01230123".to_owned(), plu: 123 },
@@ -956,4 +1776,313 @@ mod tests {
         plus.iter().for_each(|x| pfa.add(&vec![&x.upc, &x.plu.to_string()]).expect("good item"));
         assert_eq!(csvcontents, pfa.as_csv());
     }
+    #[test]
+    fn test_plu_range_boundary() {
+        let (ribeye, trim) = (product(r#""description":"Ribeye","scale":true"#), product(r#""description":"(I) Trim","scale":true"#));
+        let default = PLURange::new(999, InternalProducts::new("(I)", &[], &[], "").unwrap());
+        assert!(default.is_internal(999));
+        assert!(!default.is_internal(1000));
+        assert!(!default.wrong_range(&ribeye, 1500));
+        assert!(default.wrong_range(&trim, 1500));
+
+        let wide = PLURange::new(1999, InternalProducts::new("(I)", &[], &[], "").unwrap());
+        assert!(wide.is_internal(1500));
+        assert!(!wide.wrong_range(&trim, 1500));
+        assert!(wide.wrong_range(&ribeye, 1500));
+        assert!(!wide.wrong_range(&ribeye, 2000));
+
+        let mut used = HashSet::from([1, 2001]);
+        assert_eq!(default.next_plu(&mut used, &ribeye), 1001);
+        assert_eq!(wide.next_plu(&mut used, &ribeye), 2002);
+        assert_eq!(wide.next_plu(&mut used, &trim), 2);
+    }
+    #[test]
+    fn test_internal_products() {
+        let item = |description: &str, department: i32| product(&format!(r#""description":"{}","departmentId":{}"#, description, department));
+        let by_department = InternalProducts::new("", &[7], &[], "").unwrap();
+        assert!(by_department.is_internal(&item("Trim", 7)));
+        assert!(!by_department.is_internal(&item("(I) Trim", 1)));
+        let by_filter = InternalProducts::new("(I)", &[], &[], "normal_price < 2 && !active").unwrap();
+        assert!(by_filter.is_internal(&item("(I) Trim", 1)));
+        assert!(!by_filter.is_internal(&item("Trim", 1)));
+        assert!(InternalProducts::new("(I)", &[], &[], "normal_price <").is_err());
+    }
+    #[test]
+    fn test_product_field_quoting() {
+        let mut pfa = ProductFieldAssignments::new(vec!["UPC".to_owned(),"Description".to_owned()]);
+        pfa.add(&vec![&"0001".to_owned(), &"Apples, Honeycrisp".to_owned()]).expect("comma value");
+        pfa.add(&vec![&"0002".to_owned(), &"12\" Sub".to_owned()]).expect("quote value");
+        assert_eq!(pfa.as_csv(), "UPC,Description\r\n0001,\"Apples, Honeycrisp\"\r\n0002,\"12\"\" Sub\"\r\n");
+    }
+    #[test]
+    fn test_product_field_chunks() {
+        let mut pfa = ProductFieldAssignments::new(vec!["UPC".to_owned(),"PLU".to_owned()]);
+        for i in 0..5 {
+            pfa.add(&vec![&format!("0000{}", i), &i.to_string()]).expect("good item");
+        }
+        let chunks = pfa.chunks(2);
+        assert_eq!(chunks.iter().map(|x| x.rows()).collect::<Vec<usize>>(), vec![2, 2, 1]);
+        assert_eq!(chunks[2].as_csv(), "UPC,PLU\r\n00004,4\r\n");
+        assert_eq!(pfa.chunks(10).len(), 1);
+    }
+    #[test]
+    fn test_missing_fields() {
+        let fields = &["Id", "Total", "TransactionProducts"];
+        assert_eq!(missing_fields(r#"{"value":[{"Id":1,"Total":2.0}]}"#, fields).unwrap(), Some(vec!["TransactionProducts".to_owned()]));
+        assert_eq!(missing_fields(r#"[{"Id":1,"Total":2.0,"TransactionProducts":[]}]"#, fields).unwrap(), Some(vec![]));
+        assert_eq!(missing_fields(r#"{"value":[]}"#, fields).unwrap(), None);
+        assert!(missing_fields(r#"{"Message":"error"}"#, fields).is_err());
+    }
+    #[test]
+    fn test_parse_response() {
+        let upstream = |body: &str| match parse_response::<ITRTaxAnswer>(body).unwrap_err().downcast::<ApiError>() {
+            Ok(ApiError::Upstream(m)) => Some(m),
+            _ => None,
+        };
+        assert_eq!(parse_response::<ITRTaxAnswer>(r#"{"value":[]}"#).unwrap().value.len(), 0);
+        assert_eq!(upstream(r#"{"error":{"code":"","message":"Could not find a property named 'Rate'"}}"#),
+            Some("Could not find a property named 'Rate'".to_owned()));
+        assert_eq!(upstream(r#"{"odata.error":{},"error":{"message":{"lang":"en-US","value":"Bad $filter"}}}"#),
+            Some("Bad $filter".to_owned()));
+        assert_eq!(upstream(r#"{"odata.error":{"code":"","message":{"lang":"en-US","value":"Invalid $top"}}}"#),
+            Some("Invalid $top".to_owned()));
+        assert_eq!(upstream(r#"{"Message":"An error has occurred.","ExceptionMessage":"Timeout expired."}"#),
+            Some("An error has occurred. Timeout expired.".to_owned()));
+        assert_eq!(upstream(r#"{"value":"oops"}"#), None);
+        assert_eq!(upstream("<html>"), None);
+    }
+    #[test]
+    fn test_product_drift() {
+        let item = |upc: &str, price: f64, deleted: bool|
+            product(&format!(r#""upc":"{}","normal_price":{},"Deleted":{}"#, upc, price, deleted));
+        let live = vec![item("0001", 1.99, false), item("0002", 2.50, false), item("0004", 1.0, true)];
+        let side = vec![item("0001", 1.99, false), item("0002", 2.25, false), item("0003", 3.0, false)];
+        let drift = product_drift(&live, &side);
+        assert_eq!(drift.len(), 2);
+        assert_eq!(drift[0], ProductDrift{ upc: "0002".to_string(), field: "normal_price", sidedb: "2.25".to_string(), live: "2.50".to_string() });
+        assert_eq!(drift[1].upc, "0003");
+        assert_eq!(drift[1].field, "product");
+        assert_eq!(drift[1].csv_line(), "0003,product,Apples,");
+    }
+    #[test]
+    fn test_cost_alerts() {
+        let item = |upc: &str, cost: &str, case_cost: &str| product(&format!(
+            r#""upc":"{}","description":"Oats, rolled","normal_price":3.99,"cost":{},"case_cost":{}"#, upc, cost, case_cost));
+        let previous = vec![item("0001", "1.00", "12.00"), item("0002", "2.00", "null"), item("0003", "0", "10")];
+        let current = vec![item("0001", "1.10", "14.40"), item("0002", "2.50", "24"), item("0003", "5", "10"),
+                           item("0004", "9", "9")];
+        let alerts = cost_alerts(&current, &previous, 15.0);
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].upc, "0002");
+        assert_eq!(alerts[0].pct, 25.0);
+        assert_eq!(alerts[1].field, "case_cost");
+        assert_eq!(alerts[1].csv_line(), "0001,\"Oats, rolled\",case_cost,12.00,14.40,20.0");
+        assert_eq!(cost_alerts(&current, &previous, 5.0).len(), 3);
+    }
+    #[test]
+    fn test_null_price() {
+        let free = product(r#""normal_price":null"#);
+        assert_eq!(free.normal_price, 0.0);
+        assert!(!free.has_price());
+        assert!(product(r#""normal_price":1.25"#).has_price());
+    }
+    #[test]
+    fn test_explain_price() {
+        use chrono::TimeZone;
+        let item = |dates: &str| product(&format!(r#""normal_price":2.00,"special_price":1.50{}"#, dates));
+        let at = |d: &str| Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(d, "%Y-%m-%d %H:%M:%S").unwrap()).with_timezone(&Local);
+        let sale = item(r#","start_date":"2026-10-10T14:30:00","end_date":"2026-10-12T03:00:00""#);
+        let e = sale.explain_price_as_of(at("2026-10-10 01:00:00"));
+        assert_eq!((e.price, e.reason), (1.5, "sale in effect, special price"));
+        assert_eq!(e.sale_start, Some(Utc.with_ymd_and_hms(2026, 10, 10, 0, 0, 0).unwrap()));
+        assert_eq!(e.sale_end, Some(Utc.with_ymd_and_hms(2026, 10, 12, 23, 59, 59).unwrap()));
+        assert_eq!(sale.get_price_as_of(at("2026-10-12 23:00:00")), 1.5);
+        assert_eq!(sale.explain_price_as_of(at("2026-10-13 00:00:00")).reason, "sale ended before the date, normal price");
+        assert_eq!(sale.get_price_as_of(at("2026-10-09 23:59:59")), 2.0);
+        let open = item(r#","start_date":"2026-10-10T00:00:00""#);
+        assert_eq!(open.explain_price_as_of(at("2027-01-01 00:00:00")).reason, "sale in effect with no end date, special price");
+        let garbled = item(r#","start_date":"10/10/2026""#);
+        assert_eq!(garbled.get_price_as_of(at("2026-10-11 00:00:00")), 2.0);
+        assert_eq!(item("").explain_price_as_of(at("2026-10-11 00:00:00")).reason, "no sale start date, normal price");
+        assert!(e.text().contains("price           1.50 (sale in effect, special price)"));
+    }
+    #[test]
+    fn test_lookup_table() {
+        let apples = product(r#""upc":"0081001211009","normal_price":2.00,"scale":true,"taxes":"2","PLU":"1234""#);
+        let live = apples.lookup(&HashMap::from([(2, 6.625)]));
+        assert_eq!((live.price, live.tax.as_deref(), live.scale), (Some(2.0), Some("class 2 (6.625%)"), Some(true)));
+        assert_eq!(live.price_with_tax.map(|p| round_price(p, 2)), Some(2.13));
+        let lookup = Lookup { upc: apples.upc.clone(), upca: apples.upca(), itretail: Some(live), sidedb: None,
+                              square: Some(ProductLookup { description: "Apples".to_string(), price: Some(1.5), ..Default::default() }) };
+        let table = lookup.table();
+        assert!(table.starts_with("UPC 0081001211009 (UPC-A 810012110099)\n"));
+        assert!(table.contains(&format!("{:<15}{:<32}{:<32}{}\n", "price", "2.00", "-", "1.50")));
+        assert!(table.contains(&format!("{:<15}{:<32}-\n", "plu", "1234")));
+    }
+    #[test]
+    fn test_item_code_range() {
+        let upc = "0020123400000".to_string();
+        assert_eq!(ItemCodeRange::default().item_code(&upc), Some(1234));
+        // A scheme with a 4 digit code one digit further in.
+        let shifted = ItemCodeRange::new(4, 4);
+        assert_eq!(shifted.item_code(&upc), Some(1234));
+        assert_eq!(shifted.item_code("0020987600000"), Some(9876));
+        assert_eq!(ItemCodeRange::new(6, 5).item_code("0021234500000"), Some(45000));
+        assert_eq!(ItemCodeRange::new(10, 5).item_code(&upc), None);
+        assert_eq!(ItemCodeRange::default().item_code("00200000"), Some(0));
+    }
+    #[test]
+    fn test_weighed_barcode() {
+        let items = ItemCodeRange::default();
+        // Item code layout: IT Retail's product UPC, price digits zeroed.
+        assert_eq!(parse_weighed_barcode("0021234500000", &items, None), Some(WeighedBarcode{ item_code: 12345, price: None }));
+        assert_eq!(parse_weighed_barcode("0021234500000", &items, Some(&PriceCodeRange::default())),
+                   Some(WeighedBarcode{ item_code: 12345, price: Some(0.0) }));
+        // Price-embedded layout as scanned: 2 IIIII PPPPP C, $4.99.
+        assert_eq!(parse_weighed_barcode("212345004999", &items, Some(&PriceCodeRange::default())),
+                   Some(WeighedBarcode{ item_code: 12345, price: Some(4.99) }));
+        assert_eq!(parse_weighed_barcode("212345004998", &items, None), None);
+        // 2 IIIII V PPPP C, with a price check digit ahead of a four digit price.
+        let checked = PriceCodeRange::new(9, 4, true);
+        assert_eq!(parse_weighed_barcode("212345804995", &items, Some(&checked)),
+                   Some(WeighedBarcode{ item_code: 12345, price: Some(4.99) }));
+        // Same price behind the wrong price check digit, UPC-A check digit right.
+        assert_eq!(parse_weighed_barcode("212345104996", &items, Some(&checked)), None);
+        assert_eq!(parse_weighed_barcode("212345104996", &items, Some(&PriceCodeRange::new(9, 4, false))),
+                   Some(WeighedBarcode{ item_code: 12345, price: Some(4.99) }));
+        assert_eq!(parse_weighed_barcode("0021234500000", &items, Some(&checked)),
+                   Some(WeighedBarcode{ item_code: 12345, price: Some(0.0) }));
+        assert_eq!(price_check_digit(&[2, 8, 7, 5]), Some(9));
+        assert_eq!(price_check_digit(&[1, 4, 6, 8, 5]), Some(6));
+        assert_eq!(price_check_digit(&[4, 9, 9]), None);
+        assert_eq!(parse_weighed_barcode("0088579290537", &items, None), None);
+        assert_eq!(parse_weighed_barcode("00212345X0000", &items, None), None);
+        assert_eq!(itr_upc_to_upca(&"0088579290537".to_string()), Some("885792905371".to_string()));
+    }
+    #[test]
+    fn test_round_price() {
+        assert_eq!(round_price(4.005, 2), 4.01);
+        assert_eq!(round_price(4.004, 2), 4.0);
+        assert_eq!(round_price(0.1 + 0.2, 2), 0.3);
+        assert_eq!(round_price(1.23456, 3), 1.235);
+        assert_eq!(price_num_format(2), "0.00");
+        assert_eq!(price_num_format(0), "0");
+    }
+    fn mock_api(base_url: &str) -> ITRApi {
+        // Unlinked already, so nothing is left behind when the test ends.
+        let backingfile = tempfile::tempfile().unwrap();
+        let mut api = ITRApi {
+            backingfile,
+            store_id: "1".to_string(),
+            bearer_token: BearerToken::default(),
+            update_batch_rows: DEFAULT_UPDATE_BATCH_ROWS,
+            connect_timeout_seconds: 0,
+            request_timeout_seconds: 0,
+            base_url: DEFAULT_BASE_URL.to_string(),
+        };
+        api.set_base_url(base_url);
+        api
+    }
+    #[tokio::test]
+    async fn test_api_uses_stored_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/CustomersData/Get"))
+            .and(query_param("$select", "*"))
+            .and(header("authorization", "Bearer stored-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"value":[]}"#))
+            .expect(1)
+            .mount(&server).await;
+        let mut api = mock_api(&(server.uri() + "/"));
+        let token = r#"{"access_token":"stored-token","token_type":"bearer","expires_in":3600,"expires_at":99999999999}"#;
+        api.backingfile.write_all(token.as_bytes()).unwrap();
+        api.backingfile.rewind().unwrap();
+        // An unexpired stored token means no trip to /token.
+        api.auth().await.unwrap();
+        assert!(api.get_customers().await.unwrap().is_empty());
+    }
+    #[tokio::test]
+    async fn test_api_odata_query() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/ElectronicJournalData/Get"))
+            .and(query_param("$filter", "(Total ne null)"))
+            .and(query_param("$top", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(1)
+            .mount(&server).await;
+        let mut api = mock_api(&server.uri());
+        let url = ODataQuery::new("/api/ElectronicJournalData/Get").filter("(Total ne null)").top(1).build();
+        assert_eq!(api.get(&url).await.unwrap(), "[]");
+    }
+    #[tokio::test]
+    async fn test_check_schema_products() {
+        let server = MockServer::start().await;
+        Mock::given(path("/api/ProductsData/GetAllProducts"))
+            .and(query_param("$top", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"[{"upc":"0001","description":"Apples","normal_price":1.0,
+                "special_price":null,"scale":false,"active":true,"Deleted":false,"PLU":null,"departmentId":1,"sectionId":null,"taxes":""}]"#))
+            .expect(1)
+            .mount(&server).await;
+        let mut api = mock_api(&server.uri());
+        let checks = api.check_schema().await.unwrap();
+        let products = checks.iter().find(|c| c.name == "itretail_products").unwrap();
+        assert!(products.ok, "{}", products.detail);
+    }
+    #[tokio::test]
+    async fn test_api_customers_paging() {
+        let server = MockServer::start().await;
+        let customers = |ids: &[u32]| -> String {
+            let value = ids.iter()
+                .map(|i| format!(r#"{{"Id":"00000000-0000-0000-0000-{:012}","LastName":"L","FirstName":"F","Deleted":false}}"#, i))
+                .collect::<Vec<String>>().join(",");
+            format!(r#"{{"value":[{}]}}"#, value)
+        };
+        Mock::given(path("/api/CustomersData/Get"))
+            .and(query_param_is_missing("$skip"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(customers(&[1, 2])))
+            .expect(1)
+            .mount(&server).await;
+        // A page overlapping the last must not duplicate anyone.
+        Mock::given(path("/api/CustomersData/Get"))
+            .and(query_param("$skip", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(customers(&[2, 3])))
+            .expect(1)
+            .mount(&server).await;
+        Mock::given(path("/api/CustomersData/Get"))
+            .and(query_param("$skip", "3"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(customers(&[])))
+            .expect(1)
+            .mount(&server).await;
+        let mut api = mock_api(&server.uri());
+        let got = api.get_customers().await.unwrap();
+        assert_eq!(got.len(), 3);
+        assert_eq!(got[2].id.to_string(), "00000000-0000-0000-0000-000000000003");
+    }
+    #[tokio::test]
+    async fn test_api_error_mapping() {
+        let server = MockServer::start().await;
+        Mock::given(path("/unauthorized")).respond_with(ResponseTemplate::new(401)).mount(&server).await;
+        Mock::given(path("/broken")).respond_with(ResponseTemplate::new(500)).mount(&server).await;
+        let mut api = mock_api(&server.uri());
+        let err = api.get(&"/unauthorized".to_string()).await.unwrap_err();
+        assert!(super::super::error::is_unauthorized(&err));
+        let err = api.get(&"/broken".to_string()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ApiError>(), Some(ApiError::Status(s)) if s == "Internal Server Error"));
+    }
+    #[test]
+    fn test_odata_query_encoding() {
+        let url = ODataQuery::new("/api/ElectronicJournalData/Get")
+            .expand("TransactionTenders($select=TenderCode,LastCardDigits)")
+            .filter("(TransactionDate ge 2024-07-30T00:00:00+02:00) and (Total ne null)")
+            .select(&["Id", "Total"])
+            .select(&["CustomerId"])
+            .top(10)
+            .build();
+        assert_eq!(url, "/api/ElectronicJournalData/Get?\
+            $expand=TransactionTenders($select%3DTenderCode,LastCardDigits)&\
+            $filter=(TransactionDate%20ge%202024-07-30T00:00:00%2B02:00)%20and%20(Total%20ne%20null)&\
+            $select=Id,Total,CustomerId&$top=10");
+        assert_eq!(ODataQuery::new("/api/CustomersData/Get").select(&["*"]).build(), "/api/CustomersData/Get?$select=%2A");
+    }
 }
\ No newline at end of file
@@ -1,14 +1,11 @@
 use anyhow::{anyhow, Result};
-use chrono::Local;
 use clap::ArgMatches;
-use fancy_regex::Regex;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use libloading::os::windows::Library;
 use libloading::os::windows::Symbol;
 use log::*;
 use process_path::get_executable_path;
-use rust_xlsxwriter::{Format, Workbook};
 use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr, CString};
 use std::path::{Path, PathBuf};
@@ -19,7 +16,8 @@ use std::{
     time::{self, Instant},
 };
 
-use super::api::{PLUAssignment, ProductData};
+use super::api::{ItemCodeRange, ProductData};
+use super::scales::{ingredients, DepartmentMap, Scales};
 
 type LPSTR = *const std::ffi::c_char;
 type WORD = u16;
@@ -429,10 +427,6 @@ impl Default for TD_ST_PLU_V06 {
     }
 }
 
-//const SHRINK_LABEL_ID: u16 = 51;
-//const STANDARD_LABEL_ID: u16 = 61;
-const INGREDIENT_LABEL_ID: u16 = 62;
-
 fn jam(string: &String, out: &mut [i8]) {
     let bs = string.as_bytes();
     let bsr = bs.as_ptr() as *const i8;
@@ -449,22 +443,14 @@ impl From<&ProductData> for TD_ST_PLU_V06 {
         cp.wdDepart = p.department_id as WORD;
         cp.dwPLU = p.plu.as_ref().unwrap().parse::<DWORD>().unwrap();
         jam(&p.description, &mut cp.chName1);
-        let itemcode_str = p.upc.get(3..8).unwrap();
-        let itemcode = itemcode_str
-            .trim_start_matches('0')
-            .parse::<u32>()
-            .or::<u32>(Ok(0))
-            .unwrap();
-        cp.dwItemCode = itemcode;
+        // The configured scheme replaces this when pushing; see DepartmentMap::item_code.
+        cp.dwItemCode = p.weighed_barcode(&ItemCodeRange::default(), None).map(|b| b.item_code).unwrap_or(0);
         cp.dwUnitPrice = (p.normal_price * 100.0) as u32;
         cp.btWeightUnit = 1; // by 1 lb
+        // The label template is picked when pushing; see DepartmentMap::label_id.
         cp.wdLabel1 = 0;
-        if p.second_description.is_some() {
-            let ingredients = p.second_description.as_ref().unwrap();
-            if ingredients.len() > 0 {
-                cp.wdLabel1 = INGREDIENT_LABEL_ID; // butcher's club specific
-                jam(&ingredients, &mut cp.chDirectIngredient);
-            }
+        if let Some(ingredients) = ingredients(p) {
+            jam(ingredients, &mut cp.chDirectIngredient);
         }
         cp.btPLUType = 1; // weighed
         cp
@@ -478,6 +464,27 @@ type FnScale = Symbol<unsafe extern "C" fn(LPSTR, std::ffi::c_short) -> i32>;
 type FnScaleInt = Symbol<unsafe extern "C" fn(LPSTR, std::ffi::c_short, i32) -> i32>;
 type FnSendDataEx = Symbol<unsafe extern "C" fn(TD_ST_TRANSDATA_V02) -> i32>;
 
+impl DepartmentMap {
+    fn set_label_fields(&self, plu: &mut TD_ST_PLU_V06, item: &ProductData) {
+        plu.wdLabel1 = self.label_id(item);
+        plu.wdOrigin = self.origin(item.department_id);
+        let days = self.sell_by_days(item.department_id);
+        if days > 0 {
+            plu.dwSellDate = days;
+            plu.btSellByDateFlag = 1;
+        }
+    }
+}
+
+// What a scale reported back for a PLU during a verify.
+#[derive(Debug, Clone)]
+pub struct ScalePlu {
+    department: WORD,
+    plu: DWORD,
+    name: String,
+    unit_price: DWORD,
+}
+
 #[derive(Debug)]
 pub struct Scale {
     ip: String,
@@ -490,7 +497,13 @@ pub struct Scale {
     plus_downloaded: u32,
     product_idx: u32,
     products: Arc<Vec<ProductData>>,
+    departments: Option<Arc<DepartmentMap>>,
     notified: bool,
+    // Verify mode reads (department, PLU) pairs back instead of pushing products.
+    verify: bool,
+    verify_queue: Arc<Vec<(WORD, DWORD)>>,
+    verify_received: u32,
+    found: HashMap<(WORD, DWORD), ScalePlu>,
 }
 
 impl Scale {
@@ -506,15 +519,27 @@ impl Scale {
             plus_downloaded: 0,
             product_idx: 0,
             products: Arc::new(vec![]),
+            departments: None,
             notified: false,
+            verify: false,
+            verify_queue: Arc::new(vec![]),
+            verify_received: 0,
+            found: HashMap::new(),
         }
     }
     pub fn complete(&self) -> bool {
+        if self.verify {
+            // Replies can outnumber the queue; an empty queue has nothing to read.
+            return self.verify_received as usize >= self.verify_queue.len();
+        }
         (self.plus_downloaded as usize == self.products.len())
             && (self.should_delete == self.delete_completed)
     }
     pub fn status_str(&self) -> String {
-        if self.complete() {
+        if self.verify && !self.complete() {
+            let pcomplete = (100.0 * (self.verify_received as f32 / self.verify_queue.len() as f32)).min(100.0);
+            format!("{} [{:7.2}% read]", self.ip, pcomplete)
+        } else if self.complete() {
             format!("{} [complete]", self.ip)
         } else if self.should_delete && !self.delete_completed {
             format!("{} [deleting]", self.ip)
@@ -629,22 +654,6 @@ lazy_static! {
     static ref DLLAPI: Mutex<ScaleAPI> = Mutex::new(cas_api_init());
 }
 
-fn wrong_range(item: &super::api::ProductData, plu: u16) -> bool {
-    (item.description.starts_with("(I)") && plu >= 1000)
-        || (!item.description.starts_with("(I)") && plu < 1000)
-}
-fn next_plu(hs: &mut HashSet<u16>, item: &super::api::ProductData) -> u16 {
-    let mut probe: u16 = if item.description.starts_with("(I)") {
-        1
-    } else {
-        1001
-    };
-    while hs.contains(&probe) {
-        probe = probe + 1;
-    }
-    hs.insert(probe);
-    probe
-}
 pub extern "C" fn recvproc(data: TD_ST_TRANSDATA_V02) -> i32 {
     let ip = lpstr_to_strref(data.lpIP); // as * const i8).to_str().unwrap() };
     let cas = DLLAPI.lock().unwrap();
@@ -685,6 +694,35 @@ pub extern "C" fn recvproc(data: TD_ST_TRANSDATA_V02) -> i32 {
                 }
             }
         }
+        DfAction::GETINFO => {
+            debug!("RECV: {:?}", data);
+            // An unknown PLU comes back as an empty record.
+            if !data.pData.is_null() && data.wdDataSize as usize >= std::mem::size_of::<TD_ST_PLU_V06>() {
+                let pdata = data.pData as *const TD_ST_PLU_V06;
+                let found = unsafe {
+                    ScalePlu {
+                        department: std::ptr::read_unaligned(std::ptr::addr_of!((*pdata).wdDepart)),
+                        plu: std::ptr::read_unaligned(std::ptr::addr_of!((*pdata).dwPLU)),
+                        name: lpstr_to_strref(&(*pdata).chName1 as *const i8),
+                        unit_price: std::ptr::read_unaligned(std::ptr::addr_of!((*pdata).dwUnitPrice)),
+                    }
+                };
+                if found.plu != 0 && !found.name.is_empty() {
+                    scale.found.insert((found.department, found.plu), found);
+                }
+            }
+            scale.verify_received += 1;
+            let rc = cas.request_plu(&mut scale);
+            match rc {
+                Ok(_r) => {
+                    scale.product_idx += 1;
+                }
+                Err(e) => {
+                    error!("{} errored: {}", scale.ip, e);
+                    cas.disconnect_scale(&scale);
+                }
+            }
+        }
         _ => {
             debug!("RECV: {:?}", data);
         }
@@ -721,7 +759,19 @@ pub extern "C" fn stateproc(data: TD_ST_TRANSDATA_V02) -> i32 {
     match state {
         DfState::CONNECT => {
             info!("{} Connected: {}", ip, description);
-            if scale.should_delete {
+            if scale.verify {
+                match cas.request_plu(&mut scale) {
+                    Ok(r) => {
+                        if r {
+                            scale.product_idx = scale.product_idx + 1;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Scale {}: {}", scale.ip, e);
+                        cas.disconnect_scale(&scale);
+                    }
+                }
+            } else if scale.should_delete {
                 cas.delete_plus(&mut scale);
             } else {
                 let rc = cas.push_plu(&mut scale);
@@ -834,6 +884,11 @@ impl ScaleAPI {
 
         let item = &scale.products[scale.product_idx as usize];
         let mut plu: TD_ST_PLU_V06 = item.into();
+        if let Some(departments) = &scale.departments {
+            plu.wdDepart = departments.scale_department(item.department_id);
+            departments.set_label_fields(&mut plu, item);
+            plu.dwItemCode = departments.item_code(item);
+        }
         let dw_plu = std::ptr::addr_of!(plu.dwPLU);
         debug!(
             "Pushing PLU {} to {}",
@@ -853,6 +908,50 @@ impl ScaleAPI {
         }
         Ok(scale.products.len() > scale.product_idx as usize + 1)
     }
+    // Asks the scale for the next queued PLU; the answer arrives in recvproc as GETINFO.
+    pub fn request_plu(&self, scale: &mut Scale) -> Result<bool> {
+        if scale.product_idx as usize >= scale.verify_queue.len() {
+            return Ok(false);
+        }
+        match scale.state {
+            DfState::CONNECT => {}
+            _ => {
+                return Err(anyhow!(
+                    "Scale {} in unexpected state: {:?}",
+                    scale.ip,
+                    scale.state
+                ));
+            }
+        }
+        let lp_ip = CString::new(scale.ip.to_string()).unwrap();
+        let mut td = {
+            self.make_transdata(
+                lp_ip.as_ptr(),
+                scale.idx,
+                DfAction::GETINFO,
+                DfData::PLU_V06,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        let (department, plu_no) = scale.verify_queue[scale.product_idx as usize];
+        let mut plu = TD_ST_PLU_V06::default();
+        plu.wdDepart = department;
+        plu.dwPLU = plu_no;
+        debug!("Requesting PLU {}/{} from {}", department, plu_no, scale.ip);
+        td.wdDataSize = std::mem::size_of::<TD_ST_PLU_V06>() as u16;
+        td.pData = std::ptr::addr_of_mut!(plu) as *mut std::ffi::c_void;
+
+        scale.last_send_action = DfAction::GETINFO;
+        let ret = unsafe {
+            trace!("SEND {} <- {:?}", lpstr_to_strref(td.lpIP), td);
+            (self.cas_senddata_ex)(td)
+        };
+        if ret == 0 {
+            return Err(anyhow!("error requesting PLU data"));
+        }
+        Ok(scale.verify_queue.len() > scale.product_idx as usize + 1)
+    }
     pub fn add_scale(&mut self, ip: &str, idx: std::ffi::c_short, should_delete: bool) -> bool {
         let lp_ip = CString::new(ip).unwrap();
         let td = TD_ST_CONNECTION_V02 {
@@ -944,107 +1043,7 @@ impl ScaleAPI {
     }
 }
 
-// TBC
-pub struct Scales {}
-
 impl Scales {
-    pub async fn filtered_items(
-        &mut self,
-        api: &mut super::api::ITRApi,
-        args: &ArgMatches,
-    ) -> Result<Vec<super::api::ProductData>> {
-        let dump_internal = !args.get_flag("external");
-        let re = args.get_one::<String>("upc").unwrap();
-        let upc_pat = Regex::new(re)?;
-        let filter = |x: &super::api::ProductData| !x.deleted && upc_pat.is_match(&x.upc).unwrap();
-
-        let json = api
-            .get(&"/api/ProductsData/GetAllProducts".to_string())
-            .await
-            .expect("no results from API call");
-        let mut items: Vec<super::api::ProductData> = serde_json::from_str(&json)?;
-        items = items
-            .into_iter()
-            .filter(filter)
-            .sorted_by(|x, y| (&x.description).cmp(&y.description))
-            .sorted_by_key(|x| x.section_id.unwrap_or(0))
-            .collect::<Vec<super::api::ProductData>>();
-
-        let mut existing_plu = HashSet::<u16>::new();
-        let mut seen_plu = HashSet::<u16>::new();
-        let mut plu_assignment: Vec<PLUAssignment> = Vec::new();
-        for item in &items {
-            if item.plu.is_some() {
-                let plu = item.plu.as_ref().unwrap().parse::<u16>().unwrap();
-                existing_plu.insert(plu);
-            }
-        }
-        for item in &items {
-            if item.plu.is_some() {
-                let plu = item.plu.as_ref().unwrap().parse::<u16>().unwrap();
-                if seen_plu.contains(&plu) || wrong_range(&item, plu) {
-                    let new_plu = next_plu(&mut existing_plu, &item);
-                    info!(
-                        "PLU assigned {} bad previous was {} - {}",
-                        new_plu, plu, item.description
-                    );
-                    plu_assignment.push(PLUAssignment {
-                        upc: item.upc.to_string(),
-                        plu: new_plu,
-                    });
-                    seen_plu.insert(new_plu);
-                } else {
-                    seen_plu.insert(plu);
-                }
-            } else {
-                let new_plu = next_plu(&mut existing_plu, &item);
-                plu_assignment.push(PLUAssignment {
-                    upc: item.upc.to_string(),
-                    plu: new_plu,
-                });
-                info!("PLU assigned {} - {}", new_plu, item.description);
-                seen_plu.insert(new_plu);
-            }
-        }
-        if plu_assignment.len() > 0 {
-            let r = api.set_plu(plu_assignment).await;
-            if r.is_err() {
-                return Err(r.err().unwrap());
-            }
-            let json = api
-                .get(&"/api/ProductsData/GetAllProducts".to_string())
-                .await
-                .expect("no results from API call");
-            items = serde_json::from_str(&json)?;
-            items = items
-                .into_iter()
-                .filter(filter)
-                .sorted_by_key(|x| x.section_id.unwrap_or(0))
-                .collect::<Vec<super::api::ProductData>>();
-        }
-
-        items = items
-            .into_iter()
-            .filter(|item| {
-                if item.plu.is_none() {
-                    return false;
-                }
-                let plu = item.plu.as_ref().unwrap().parse::<u16>();
-                if plu.is_err() {
-                    return false;
-                }
-                if !dump_internal && plu.unwrap() < 1000 {
-                    return false;
-                }
-                if item.upc.get(3..8).is_none() {
-                    return false;
-                }
-                true
-            })
-            .collect::<Vec<super::api::ProductData>>();
-        Ok(items)
-    }
-
     pub async fn send(
         &mut self,
         api: &mut super::api::ITRApi,
@@ -1053,13 +1052,8 @@ impl Scales {
     ) -> Result<()> {
         let progress = args.get_flag("progress");
         let delete_plus = args.get_flag("wipe");
-        let weighed_items = self.filtered_items(api, args).await?;
-        let plufile = args.get_one::<String>("output").unwrap();
-        self.build_plu_xlsx(api, &weighed_items, plufile, &args).await?;
-        match args.get_one::<String>("scale-file") {
-            Some(scalefile) => self.build_scale_xlsx(&weighed_items, scalefile)?,
-            _ => (),
-        }
+        let (weighed_items, departments) = self.export(api, settings, args).await?;
+        let departments = Arc::new(departments);
         let weighed_items_ref = Arc::new(weighed_items);
         let timeout = match args.get_one::<u32>("timeout-seconds") {
             Some(secs) => secs,
@@ -1095,6 +1089,7 @@ impl Scales {
                 let cas = DLLAPI.lock().unwrap();
                 let mut scale = cas.scales.get(scale_ip).unwrap().lock().unwrap();
                 scale.products = weighed_items_ref.clone();
+                scale.departments = Some(departments.clone());
             }
 
             for scale_ip in ips.iter() {
@@ -1143,170 +1138,138 @@ impl Scales {
         Ok(())
     }
 
-    pub async fn build_plu_xlsx(
+    // Reads each scale's PLUs back and diffs them against what scale-export would send.
+    // Returns the number of discrepancies across all scales.
+    pub async fn verify(
         &mut self,
         api: &mut super::api::ITRApi,
-        weighed_items: &Vec<ProductData>,
-        filename: &String,
+        settings: &super::settings::Settings,
         args: &ArgMatches,
-    ) -> Result<()> {
-        let qlimit = args.get_one::<f32>("at-least").unwrap();
-        let by_section = args.get_flag("by-section");
-        let mut workbook = Workbook::new();
-        let bold_format = Format::new().set_bold();
-        let decimal_format = Format::new().set_num_format("0.00");
-
-        let sections: HashMap<i32, String> = api
-            .get_sections()
-            .await?
-            .iter()
-            .map(|s| (s.id.unwrap_or(0) as i32, s.name.to_owned()))
-            .collect();
-
-        const FIELDS: [&str; 3] = ["PLU", "Name", "Price"];
-
-        struct XSection {
-            name: String,
-            row: u32,
+    ) -> Result<u32> {
+        let progress = args.get_flag("progress");
+        let weighed_items = self.filtered_items(api, settings, args, false).await?;
+        let departments = DepartmentMap::new(settings)?;
+        let mut expected: HashMap<(WORD, DWORD), &ProductData> = HashMap::new();
+        for item in weighed_items.iter() {
+            let plu = item.plu.as_ref().unwrap().parse::<DWORD>().unwrap();
+            expected.insert((departments.scale_department(item.department_id), plu), item);
         }
-        let mut sheets: HashMap<i32, XSection> = HashMap::new();
-        for item in weighed_items {
-            if item.quantity_on_hand.unwrap_or(0.0) <= *qlimit {
-                continue;
-            }
-            let section_id = match by_section {
-                true => item.section_id.unwrap_or(-1),
-                false => 0,
-            };
-            let unknown_section: String = match by_section {
-                true => String::from("Unknown"),
-                false => String::from("Products"),
-            };
-            let section_name = sections.get(&section_id).unwrap_or(&unknown_section);
-            let section = match sheets.get_mut(&section_id) {
-                Some(sheet) => sheet,
-                None => {
-                    let worksheet = workbook.add_worksheet().set_name(section_name)?;
-                    sheets.insert(
-                        section_id,
-                        XSection {
-                            name: worksheet.name(),
-                            row: 1,
-                        },
-                    );
-                    let section = sheets.get_mut(&section_id).unwrap();
-                    for idx in 0..FIELDS.len() {
-                        worksheet.write_with_format(
-                            0,
-                            idx.try_into().unwrap(),
-                            FIELDS[idx],
-                            &bold_format,
-                        )?;
+        // Extras can only be found by asking; --scan-to probes every PLU up to N in the
+        // departments we use, otherwise only the expected PLUs are read back.
+        let mut queue: Vec<(WORD, DWORD)> = expected.keys().cloned().sorted().collect();
+        if let Some(scan_to) = args.get_one::<u32>("scan-to") {
+            let depts: HashSet<WORD> = expected.keys().map(|(d, _)| *d).collect();
+            for dept in depts.iter().sorted() {
+                for plu in 1..=*scan_to {
+                    if !expected.contains_key(&(*dept, plu)) {
+                        queue.push((*dept, plu));
                     }
-                    section
                 }
-            };
-            let worksheet = workbook.worksheet_from_name(&section.name)?;
-            let plu = item.plu.as_ref().unwrap().parse::<u16>().unwrap();
-            worksheet.write_number(section.row, 0, plu)?;
-            worksheet.write_string(section.row, 1, &item.description)?;
-            worksheet.write_number_with_format(
-                section.row,
-                2,
-                item.normal_price,
-                &decimal_format,
-            )?;
-            section.row += 1;
+            }
         }
-
-        workbook.save(filename)?;
-
-        Ok(())
-    }
-    pub fn build_scale_xlsx(
-        &mut self,
-        weighed_items: &Vec<ProductData>,
-        filename: &String,
-    ) -> Result<()> {
-        const FIELDS: [&str; 19] = [
-            "Department No",
-            "PLU No",
-            "Name1",
-            "Name2",
-            "Itemcode",
-            "Unit Price",
-            "Origin No",
-            "Label No",
-            " Category No",
-            "Direct Ingredient",
-            "Sell By Time",
-            "Sell By Date",
-            "Packed Date",
-            "Group No",
-            "Unit Weight",
-            "Nutrifact No",
-            "PLU Type",
-            "Packed Time",
-            "Update Date",
-        ];
-
-        let mut workbook = Workbook::new();
-        let bold_format = Format::new().set_bold();
-        let decimal_format = Format::new().set_num_format("0.00");
-        let date_format = Format::new().set_num_format("yyyy-mm-dd");
-
-        let date = Local::now().naive_local();
-
-        let worksheet = workbook.add_worksheet();
-        for idx in 0..FIELDS.len() {
-            worksheet.write_with_format(0, idx.try_into().unwrap(), FIELDS[idx], &bold_format)?;
+        let queue = Arc::new(queue);
+        let timeout = match args.get_one::<u32>("timeout-seconds") {
+            Some(secs) => secs,
+            None => &settings.scales.timeout_seconds,
+        };
+        let scales: Vec<&String> = match args.get_many::<String>("scale") {
+            Some(set) => set.collect::<Vec<_>>(),
+            None => settings.scales.addresses.iter().collect::<Vec<_>>(),
+        };
+        if scales.is_empty() {
+            return Err(anyhow!("no scales to verify"));
+        }
+        let mut idx: std::ffi::c_short = 1;
+        for scale in scales.into_iter() {
+            let mut cas = DLLAPI.lock().unwrap();
+            if cas.add_scale(scale, idx, false) {
+                idx = idx + 1;
+            } else {
+                error!("Error adding scale {}", scale);
+            }
+        }
+        let ips: Vec<String> = {
+            let cas = DLLAPI.lock().unwrap();
+            cas.scales.keys().map(|k| k.to_string()).collect()
+        };
+        for scale_ip in ips.iter() {
+            let cas = DLLAPI.lock().unwrap();
+            let mut scale = cas.scales.get(scale_ip).unwrap().lock().unwrap();
+            scale.verify = true;
+            scale.verify_queue = queue.clone();
+        }
+        for scale_ip in ips.iter() {
+            let cas = DLLAPI.lock().unwrap();
+            if !cas.connect_scale(scale_ip) {
+                error!("Connect to scale failed {}", scale_ip);
+            }
         }
 
-        let mut row: u32 = 1;
-        for item in weighed_items {
-            worksheet.write_number(row, 0, item.department_id)?;
-            let plu = item.plu.as_ref().unwrap().parse::<u16>().unwrap();
-            worksheet.write_number(row, 1, plu)?;
-            worksheet.write_string(row, 2, &item.description)?;
-            // 3 Name2 (blank)
-            let itemcode_str = item.upc.get(3..8).unwrap();
-            let itemcode = itemcode_str
-                .trim_start_matches('0')
-                .parse::<u32>()
-                .or::<u32>(Ok(0))
-                .unwrap();
-
-            worksheet.write_number(row, 4, itemcode)?;
-            worksheet.write_number_with_format(row, 5, item.normal_price, &decimal_format)?;
-            worksheet.write_number(row, 6, 0)?; // Origin
-            worksheet.write_number(row, 7, 0)?; // Label ID
-            worksheet.write_number(row, 8, 0)?; // Category
-            if item.second_description.is_some() {
-                let ingredients = item.second_description.as_ref().unwrap();
-                if ingredients.len() > 0 {
-                    worksheet.write_number(row, 7, INGREDIENT_LABEL_ID)?; // Label ID
-                    worksheet.write_string(row, 9, ingredients)?; // Direct Ingredient
+        let start = Instant::now();
+        loop {
+            let mut done = true;
+            let mut scale_status = vec!["\rProgress".to_string()];
+            for scale_ip in ips.iter() {
+                let cas = DLLAPI.lock().unwrap();
+                let scale = cas.scales.get(scale_ip).unwrap().lock().unwrap();
+                if !scale.complete() {
+                    done = false;
                 }
+                scale_status.push(scale.status_str())
             }
-            worksheet.write_number(row, 10, 0)?; // Sell by Time
-            worksheet.write_number(row, 11, 0)?; // Sell by Date
-            worksheet.write_number(row, 12, 0)?; // Packed Date
-            worksheet.write_number(row, 13, 0)?; // Group No
-            worksheet.write_number(row, 14, 1)?; // Unit Weight (1 lb)
-            worksheet.write_number(row, 15, 0)?; // Nutrifact No
-            worksheet.write_number(row, 16, 1)?; // PLU Type: 1 - weighed
-            worksheet.write_number(row, 17, 0)?; // Packed Time
-            worksheet.write_with_format(row, 18, &date, &date_format)?;
-
-            row = row + 1;
-            info!(
-                "Writing: [{}] {} : {} : {}",
-                plu, item.upc, item.description, item.normal_price
-            );
+            if progress {
+                print!("{}", scale_status.join(" "));
+                io::stdout().flush().unwrap();
+            }
+            if done {
+                break;
+            }
+            if *timeout != 0 && start.elapsed().as_secs() as u32 > *timeout {
+                error!("Verify timed out after {} seconds.", start.elapsed().as_secs());
+                return Err(anyhow!("timeout"));
+            }
+            thread::sleep(time::Duration::from_secs(1));
         }
 
-        workbook.save(filename)?;
-
-        Ok(())
+        let mut problems = 0;
+        for scale_ip in ips.iter() {
+            let cas = DLLAPI.lock().unwrap();
+            let scale = cas.scales.get(scale_ip).unwrap().lock().unwrap();
+            let mut missing = vec![];
+            let mut mismatched = vec![];
+            for (key, item) in expected.iter().sorted_by_key(|(k, _)| *k) {
+                let want: TD_ST_PLU_V06 = (*item).into();
+                let want_price = want.dwUnitPrice;
+                match scale.found.get(key) {
+                    None => missing.push(format!("{}/{} {}", key.0, key.1, item.description)),
+                    Some(have) => {
+                        let want_name = lpstr_to_strref(&want.chName1 as *const i8);
+                        if have.name != want_name || have.unit_price != want_price {
+                            mismatched.push(format!("{}/{} scale has \"{}\" @ {:.2}, expected \"{}\" @ {:.2}",
+                                key.0, key.1, have.name, have.unit_price as f64 / 100.0,
+                                want_name, want_price as f64 / 100.0));
+                        }
+                    }
+                }
+            }
+            let extra: Vec<String> = scale.found.iter()
+                .filter(|(k, _)| !expected.contains_key(k))
+                .sorted_by_key(|(k, _)| *k)
+                .map(|(k, v)| format!("{}/{} {}", k.0, k.1, v.name))
+                .collect();
+            println!("Scale {}: {} missing, {} extra, {} mismatched", scale.ip, missing.len(), extra.len(), mismatched.len());
+            for m in missing.iter() {
+                println!("  missing: {}", m);
+            }
+            for e in extra.iter() {
+                println!("  extra: {}", e);
+            }
+            for m in mismatched.iter() {
+                println!("  mismatch: {}", m);
+            }
+            problems += (missing.len() + extra.len() + mismatched.len()) as u32;
+            cas.disconnect_scale(&scale);
+        }
+        Ok(problems)
     }
 }
@@ -5,8 +5,12 @@ use regex::Regex;
 use reqwest::{self, Method};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, SecondsFormat, Utc};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::io::Read;
+
+use super::error::{http_client, ApiError};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Tag {
@@ -34,6 +38,11 @@ pub struct Member {
     pub source: String,
     #[allow(dead_code)]
     pub tags: Vec<Tag>,
+    // ISO 8601, or "" when Mailchimp doesn't know.
+    #[serde(default)]
+    pub timestamp_signup: String,
+    #[serde(default)]
+    pub last_changed: String,
 }
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct UpdateMember {
@@ -63,6 +72,73 @@ pub struct MCLists {
     pub lists: Vec<MCList>,
 }
 
+#[derive(Deserialize, Debug)]
+struct MergeField {
+    tag: String,
+}
+#[derive(Deserialize, Debug)]
+struct MergeFields {
+    merge_fields: Vec<MergeField>,
+}
+
+// IT Retail fields mailchimp.merge_fields can map.
+const MERGE_FIELD_KEYS: [&str; 4] = ["first_name", "last_name", "phone", "discount"];
+
+// The list's merge tags for the IT Retail fields the sync writes; None leaves a field out.
+#[derive(Debug, Clone)]
+pub struct MergeTags {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub phone: Option<String>,
+    pub discount: Option<String>,
+}
+
+impl MergeTags {
+    pub fn new(settings: &super::settings::Settings) -> Self {
+        let fields = &settings.mailchimp.merge_fields;
+        for key in fields.keys().filter(|k| !MERGE_FIELD_KEYS.contains(&k.as_str())) {
+            warn!("Ignoring unknown IT Retail field {} in mailchimp.merge_fields", key);
+        }
+        let tag = |key: &str| fields.get(key).map(|t| t.trim().to_uppercase()).filter(|t| !t.is_empty());
+        MergeTags {
+            first_name: tag("first_name"),
+            last_name: tag("last_name"),
+            phone: tag("phone"),
+            discount: tag("discount"),
+        }
+    }
+    // Forget tags the list doesn't define; Mailchimp rejects writes to them.
+    pub fn retain_defined(&mut self, defined: &HashSet<String>) {
+        for (key, tag) in [("first_name", &mut self.first_name), ("last_name", &mut self.last_name),
+                           ("phone", &mut self.phone), ("discount", &mut self.discount)] {
+            if tag.as_ref().is_some_and(|t| !defined.contains(t)) {
+                warn!("Mailchimp list has no merge field {} for {}, not syncing it", tag.as_ref().unwrap(), key);
+                *tag = None;
+            }
+        }
+    }
+}
+
+// A member's merge field as a string, "" when the tag is unmapped, absent or not
+// text.  Members imported before a merge field existed simply lack it.
+fn merge_str(member: &Member, tag: &Option<String>) -> String {
+    let tag = match tag {
+        Some(tag) => tag,
+        None => return "".to_string(),
+    };
+    match member.merge_fields.get(tag).map(|v| v.as_str()) {
+        Some(Some(value)) => value.to_string(),
+        Some(None) => {
+            warn!("Mailchimp member {} has a non-text {} merge field", member.email_address, tag);
+            "".to_string()
+        }
+        None => {
+            warn!("Mailchimp member {} has no {} merge field", member.email_address, tag);
+            "".to_string()
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct NewMember {
     pub email_address: String,
@@ -77,12 +153,21 @@ pub fn quick_new_member(
     last_name: &String,
     phone: &String,
     discount: &u8,
+    tags: &MergeTags,
 ) -> NewMember {
     let mut merge_fields = serde_json::Map::new();
-    merge_fields.insert("FNAME".to_owned(), json!(first_name));
-    merge_fields.insert("LNAME".to_owned(), json!(last_name));
-    merge_fields.insert("PHONE".to_owned(), json!(phone));
-    merge_fields.insert("ITDISCOUNT".to_owned(), json!(discount));
+    if let Some(tag) = &tags.first_name {
+        merge_fields.insert(tag.to_owned(), json!(first_name));
+    }
+    if let Some(tag) = &tags.last_name {
+        merge_fields.insert(tag.to_owned(), json!(last_name));
+    }
+    if let Some(tag) = &tags.phone {
+        merge_fields.insert(tag.to_owned(), json!(phone));
+    }
+    if let Some(tag) = &tags.discount {
+        merge_fields.insert(tag.to_owned(), json!(discount));
+    }
 
     // We're creating new members from paying customers at a till. We can skip double opt-in.
     // and just set them to subscribed.
@@ -106,9 +191,84 @@ pub fn normalize_phone(phone: &String) -> String {
     shorter
 }
 
+// Mailchimp birthday merge fields are MM/DD.  IT Retail birth dates are
+// sometimes missing, placeholders like 0001-01-01, or not dates at all.
+pub fn mailchimp_birthday(birth_date: Option<&String>) -> Option<String> {
+    let bd = birth_date?.trim();
+    let date = NaiveDate::parse_from_str(bd.get(0..10).unwrap_or(bd), "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(bd, "%m/%d/%Y"))
+        .ok()?;
+    if date.year() < 1900 || date > Utc::now().date_naive() {
+        return None;
+    }
+    Some(date.format("%m/%d").to_string())
+}
+
+// Syntax check only: one @, a sane local part, and a dotted domain with an alphabetic TLD.
+pub fn valid_email(email: &str) -> bool {
+    let (local, domain) = match email.trim().rsplit_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    if local.is_empty() || local.len() > 64 || domain.len() > 253 {
+        return false;
+    }
+    if local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        return false;
+    }
+    if !local.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c)) {
+        return false;
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() < 2 {
+        return false;
+    }
+    let labels_ok = labels.iter().all(|l| {
+        !l.is_empty() && l.len() <= 63 && !l.starts_with('-') && !l.ends_with('-')
+            && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    let tld = labels.last().unwrap();
+    labels_ok && tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+#[derive(Deserialize, Debug)]
+struct DohAnswer {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer")]
+    answer: Option<Vec<serde_json::Value>>,
+}
+
+// Looks the domain's MX records up over DNS-over-HTTPS (JSON API); lookup failures,
+// timeouts included, count as valid.  The client carries the Mailchimp timeouts.
+async fn domain_has_mx(client: &reqwest::Client, doh_url: &str, domain: &str) -> bool {
+    let res = client
+        .get(doh_url)
+        .query(&[("name", domain), ("type", "MX")])
+        .header(reqwest::header::ACCEPT, "application/dns-json")
+        .send().await;
+    let body = match res {
+        Ok(r) => r.text().await.unwrap_or_default(),
+        Err(e) => {
+            warn!("MX lookup for {} failed: {}", domain, e);
+            return true;
+        }
+    };
+    match serde_json::from_str::<DohAnswer>(&body) {
+        Ok(a) => match a.status {
+            0 => a.answer.is_some_and(|x| !x.is_empty()),
+            3 => false, // NXDOMAIN
+            _ => true,
+        },
+        Err(_) => true,
+    }
+}
+
 pub struct MCApi {
     dc: String,
     api_token: String,
+    connect_timeout_seconds: u64,
+    request_timeout_seconds: u64,
 }
 
 pub fn mailchimp_api_new(settings: &super::settings::Settings, token: Option<&String>) -> MCApi {
@@ -124,6 +284,8 @@ pub fn mailchimp_api_new(settings: &super::settings::Settings, token: Option<&St
                 }
             },
         },
+        connect_timeout_seconds: settings.mailchimp.connect_timeout_seconds,
+        request_timeout_seconds: settings.mailchimp.request_timeout_seconds,
     }
 }
 
@@ -166,6 +328,13 @@ impl MCApi {
         }
     }
 
+    // The merge tags the list defines.
+    pub async fn get_merge_tags(&mut self, listid: &String) -> Result<HashSet<String>> {
+        let url = format!("lists/{}/merge-fields?count=1000", listid);
+        let fields = serde_json::from_str::<MergeFields>(&self.get(&url).await?)?;
+        Ok(fields.merge_fields.into_iter().map(|f| f.tag).collect())
+    }
+
     pub async fn get_subscriber(&mut self, listid: &String, email: &String) -> Result<HashMap<String, Member>> {
         let mut set = HashMap::new();
         let batch_size = 500;
@@ -198,13 +367,21 @@ impl MCApi {
     }
 
     pub async fn get_subscribers(&mut self, listid: &String) -> Result<HashMap<String, Member>> {
+        self.get_subscribers_since(listid, None).await
+    }
+
+    // With a timestamp, only members changed since then are returned.
+    pub async fn get_subscribers_since(&mut self, listid: &String, since: Option<&DateTime<Utc>>) -> Result<HashMap<String, Member>> {
         let mut set = HashMap::new();
         let batch_size = 500;
         let mut start = 0;
+        let since_param = since
+            .map(|t| format!("&since_last_changed={}", t.to_rfc3339_opts(SecondsFormat::Secs, true)))
+            .unwrap_or_default();
         loop {
             let url = format!(
-                "lists/{}/members?count={}&offset={}",
-                listid, batch_size, start
+                "lists/{}/members?count={}&offset={}{}",
+                listid, batch_size, start, since_param
             );
             let subs = serde_json::from_str::<Members>(&self.get(&url).await?)?
                 .members
@@ -222,13 +399,18 @@ impl MCApi {
         Ok(set)
     }
 
+    fn client(&self) -> Result<reqwest::Client> {
+        Ok(http_client(self.connect_timeout_seconds, self.request_timeout_seconds).build()?)
+    }
+
     pub async fn get(&mut self, url: &str) -> Result<String> {
-        let client = reqwest::Client::new();
+        let client = self.client()?;
         let result = client
             .get(format!("https://{}.api.mailchimp.com/3.0/{}", self.dc, url))
             .basic_auth("anything", Some(&self.api_token))
-            .send().await?;
-        let text_response = result.text().await?;
+            .send().await
+            .map_err(ApiError::from_reqwest)?;
+        let text_response = result.text().await.map_err(ApiError::from_reqwest)?;
         Ok(text_response)
     }
 
@@ -237,20 +419,19 @@ impl MCApi {
         method: Method,
         url: &str,
         json: &T,
-    ) -> reqwest::RequestBuilder {
-        let client = reqwest::Client::new();
+    ) -> Result<reqwest::RequestBuilder> {
+        let client = self.client()?;
         let url = format!("https://{}.api.mailchimp.com/3.0/{}", self.dc, url);
         let mut headers = reqwest::header::HeaderMap::new();
         headers.insert(
             reqwest::header::CONTENT_TYPE,
             reqwest::header::HeaderValue::from_static("application/json"),
         );
-        let builder = client
+        Ok(client
             .request(method, url)
             .basic_auth("anything", Some(&self.api_token))
             .headers(headers)
-            .json(json);
-        builder
+            .json(json))
     }
     pub async fn do_json<T: Serialize + ?Sized>(
         &mut self,
@@ -258,7 +439,7 @@ impl MCApi {
         url: &str,
         json: &T,
     ) -> Result<String> {
-        let builder = self.build_req(method, url, json);
+        let builder = self.build_req(method, url, json)?;
         let res = builder.send().await;
         match res {
             Ok(result) => {
@@ -275,7 +456,7 @@ impl MCApi {
                     ))
                 }
             }
-            Err(e) => Err(anyhow!("{}", e.to_string())),
+            Err(e) => Err(ApiError::from_reqwest(e).into()),
         }
     }
     pub async fn post_json<T: Serialize + ?Sized>(&mut self, url: &str, json: &T) -> Result<String> {
@@ -287,23 +468,33 @@ impl MCApi {
         list_id: &String,
         member: &Member,
         customer: &super::api::Customer,
+        tags: &MergeTags,
+        birthday_field: Option<&String>,
+        id_field: Option<&str>,
     ) -> Result<String> {
         let mut merge_fields = serde_json::Map::new();
-        if customer.first_name.len() > 0 {
-            merge_fields.insert("FNAME".to_owned(), json!(customer.first_name));
+        if let Some(field) = id_field {
+            merge_fields.insert(field.to_owned(), json!(customer.id.to_string()));
         }
-        if customer.last_name.len() > 0 {
-            merge_fields.insert("LNAME".to_owned(), json!(customer.last_name));
+        if let Some(tag) = tags.first_name.as_ref().filter(|_| !customer.first_name.is_empty()) {
+            merge_fields.insert(tag.to_owned(), json!(customer.first_name));
+        }
+        if let Some(tag) = tags.last_name.as_ref().filter(|_| !customer.last_name.is_empty()) {
+            merge_fields.insert(tag.to_owned(), json!(customer.last_name));
         }
         if let Some(phone) = customer.phone.as_ref() {
-            if phone.len() > 0 {
-                merge_fields.insert("PHONE".to_owned(), json!(customer.phone.as_ref().unwrap()));
+            if let Some(tag) = tags.phone.as_ref().filter(|_| !phone.is_empty()) {
+                merge_fields.insert(tag.to_owned(), json!(phone));
+            }
+        }
+        if let Some(tag) = &tags.discount {
+            merge_fields.insert(tag.to_owned(), json!(customer.discount.unwrap_or(0)));
+        }
+        if let Some(field) = birthday_field {
+            if let Some(birthday) = mailchimp_birthday(customer.birth_date.as_ref()) {
+                merge_fields.insert(field.to_string(), json!(birthday));
             }
         }
-        merge_fields.insert(
-            "ITDISCOUNT".to_owned(),
-            json!(customer.discount.unwrap_or(0)),
-        );
         let interests = serde_json::Map::new();
         let tags: Vec<Tag> = vec![];
         let um = UpdateMember {
@@ -320,6 +511,157 @@ impl MCApi {
         let url = format!("/lists/{}/members/{}", list_id, member.id);
         self.do_json(Method::PATCH, &url, &um).await
     }
+    // Mailchimp ignores a pending member being set to pending again, and has no
+    // call to resend the opt-in email.  Archiving the member and adding them
+    // back as pending sends it.  Only the merge fields are carried over, so
+    // tags and interests may be lost, and the signup time starts over.  Should
+    // adding them back fail, the member is put back as they were rather than
+    // left archived.
+    pub async fn resend_confirmation(&mut self, list_id: &String, member: &Member) -> Result<String> {
+        let url = format!("/lists/{}/members/{}", list_id, member.id);
+        self.do_json(Method::DELETE, &url, &json!({})).await?;
+        let merge_fields: serde_json::Map<String, serde_json::Value> = member.merge_fields.iter()
+            .filter(|(_, v)| !v.as_str().is_some_and(|s| s.is_empty()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let added = self.post_json(&format!("/lists/{}/members", list_id), &json!({
+            "email_address": member.email_address,
+            "status": "pending",
+            "merge_fields": merge_fields,
+        })).await;
+        match added {
+            Ok(r) => Ok(r),
+            Err(e) => {
+                let restored = self.do_json(Method::PUT, &url, &json!({
+                    "email_address": member.email_address,
+                    "status_if_new": member.status,
+                    "status": member.status,
+                    "merge_fields": member.merge_fields,
+                })).await;
+                match restored {
+                    Ok(_) => Err(anyhow!("re-adding {} failed, restored as {}: {}", member.email_address, member.status, e)),
+                    Err(re) => Err(anyhow!("re-adding {} failed: {}; restoring failed too, the member is archived: {}",
+                                           member.email_address, e, re)),
+                }
+            }
+        }
+    }
+    pub async fn change_member_email(&mut self, list_id: &String, member: &Member, email: &str) -> Result<String> {
+        let url = format!("/lists/{}/members/{}", list_id, member.id);
+        self.do_json(Method::PATCH, &url, &json!({ "email_address": email })).await
+    }
+}
+
+// A member who signed up but never confirmed the double opt-in.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PendingMember {
+    pub email: String,
+    pub source: String,
+    pub signup: String,
+    // Days since signup (or the last change, when Mailchimp has no signup time).
+    pub age_days: Option<i64>,
+}
+
+impl PendingMember {
+    pub fn csv_line(&self) -> String {
+        format!("{},{},{},{}", super::api::csv_field(&self.email), super::api::csv_field(&self.source), self.signup,
+                self.age_days.map(|d| d.to_string()).unwrap_or_default())
+    }
+}
+
+// Pending members at least min_days old, oldest first.  mailchimp-sync only
+// confirms those whose source contains "API"; the rest wait on the member.
+fn pending_members<'a>(members: impl Iterator<Item = &'a Member>, now: DateTime<Utc>, min_days: i64) -> Vec<PendingMember> {
+    let mut pending: Vec<PendingMember> = members
+        .filter(|m| m.status == "pending")
+        .map(|m| {
+            let signup = if m.timestamp_signup.is_empty() { &m.last_changed } else { &m.timestamp_signup };
+            PendingMember {
+                email: m.email_address.to_string(),
+                source: m.source.to_string(),
+                signup: signup.to_string(),
+                age_days: DateTime::parse_from_rfc3339(signup).ok().map(|t| (now - t.with_timezone(&Utc)).num_days()),
+            }
+        })
+        .filter(|p| p.age_days.is_none_or(|d| d >= min_days))
+        .collect();
+    pending.sort_by(|a, b| b.age_days.cmp(&a.age_days).then(a.email.cmp(&b.email)));
+    pending
+}
+
+// The list's pending members, sending those named by --resend their confirmation
+// again.  The first resend that fails stops the rest.
+pub async fn mailchimp_pending(settings: &super::settings::Settings, args: &ArgMatches) -> Result<Vec<PendingMember>> {
+    let mc_token = args.get_one::<String>("mc_token").or(
+        if !settings.mailchimp.token.is_empty() { Some(&settings.mailchimp.token) } else { None });
+    let mut mc_api = mailchimp_api_new(settings, mc_token);
+    let list = mc_api.get_list(args.get_one::<String>("listid")).await?;
+    let subscribers = mc_api.get_subscribers(&list.id).await?;
+    let pending = pending_members(subscribers.values(), Utc::now(), *args.get_one::<i64>("min-days").unwrap());
+    if let Some(emails) = args.get_many::<String>("resend") {
+        let mut resent = 0;
+        for email in emails {
+            if !pending.iter().any(|p| p.email.eq_ignore_ascii_case(email)) {
+                warn!("{} is not among the pending members reported, not resending", email);
+                continue;
+            }
+            let member = subscribers.get(&email.to_lowercase()).unwrap();
+            mc_api.resend_confirmation(&list.id, member).await
+                .map_err(|e| anyhow!("resending the confirmation to {} (after {} resent): {}", email, resent, e))?;
+            resent += 1;
+        }
+        info!("Resent {} Mailchimp confirmations.", resent);
+    }
+    Ok(pending)
+}
+
+// What we knew about the list at the end of the last successful sync, so
+// incremental runs can tell "unchanged" from "not in Mailchimp".
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct MCSyncState {
+    last_sync: Option<DateTime<Utc>>,
+    last_full_sync: Option<DateTime<Utc>>,
+    members: HashSet<String>,
+    // Addresses Mailchimp has cleaned (hard bounces); never recreate these.
+    #[serde(default)]
+    bounced: HashSet<String>,
+    // IT Retail id -> member email, so incremental runs can follow an email
+    // change for a member Mailchimp didn't report as changed.
+    #[serde(default)]
+    itrids: HashMap<String, String>,
+}
+
+// The IT Retail id merge field of every member: the saved ids, less members
+// that changed, plus what the changed members carry now.
+fn itrid_emails<'a>(saved: &HashMap<String, String>, changed: impl Iterator<Item = &'a Member>, id_field: &str) -> HashMap<String, String> {
+    let changed: Vec<&Member> = changed.collect();
+    let emails: HashSet<String> = changed.iter().map(|m| m.email_address.to_lowercase()).collect();
+    let mut ids: HashMap<String, String> = saved.iter()
+        .filter(|(_, email)| !emails.contains(*email))
+        .map(|(id, email)| (id.clone(), email.clone()))
+        .collect();
+    for m in changed {
+        if let Some(id) = m.merge_fields.get(id_field).and_then(|x| x.as_str()).filter(|id| !id.is_empty()) {
+            ids.insert(id.to_string(), m.email_address.to_lowercase());
+        }
+    }
+    ids
+}
+
+fn load_sync_state() -> MCSyncState {
+    let mut contents = String::new();
+    match super::api::get_dotfile("mailchimp_sync.json", true) {
+        Ok(mut f) => match f.read_to_string(&mut contents) {
+            Ok(_) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(_) => MCSyncState::default(),
+        },
+        Err(_) => MCSyncState::default(),
+    }
+}
+
+fn save_sync_state(state: &MCSyncState) -> Result<()> {
+    super::api::replace_dotfile("mailchimp_sync.json", serde_json::to_string(state)?.as_bytes())?;
+    Ok(())
 }
 
 pub async fn mailchimp_sync(
@@ -358,22 +700,137 @@ pub async fn mailchimp_sync(
     };
     let mut mc_api = mailchimp_api_new(&settings, mc_token);
     let list = mc_api.get_list(args.get_one::<String>("listid")).await?;
+    // Between full pulls only members changed since the last sync are fetched; the
+    // membership list saved by the last sync stands in for everyone else. Full pulls
+    // pick up members that left and IT Retail-only changes to unchanged members.
+    let started = Utc::now();
+    let mut state = load_sync_state();
+    let full_due = args.get_flag("full") || match state.last_full_sync {
+        Some(t) => started - t >= Duration::hours(settings.mailchimp.full_sync_hours as i64),
+        None => true,
+    };
+    let incremental = just_one.is_none() && !full_due && state.last_sync.is_some();
     let subscribers: HashMap<String, Member> = match just_one {
         Some(email) => mc_api.get_subscriber(&list.id, email).await?,
+        _ if incremental => mc_api.get_subscribers_since(&list.id, state.last_sync.as_ref()).await?,
         _ => mc_api.get_subscribers(&list.id).await?
     };
 
-    debug!("Pulled {} mailchimp subscribers.", subscribers.len());
-    let to_mc: Vec<&String> = itr_customers
-        .keys()
-        .filter(|s| !subscribers.contains_key(*s))
-        .collect();
+    debug!("Pulled {} {}mailchimp subscribers.", subscribers.len(), if incremental { "changed " } else { "" });
+    let mut known: HashSet<String> = if incremental { state.members.clone() } else { HashSet::new() };
+    known.extend(subscribers.keys().cloned());
+    state.bounced.extend(subscribers.values().filter(|m| m.status == "cleaned").map(|m| m.email_address.to_lowercase()));
+    let check_mx = args.get_flag("check-mx");
+    // Only write merge fields the list actually has.
+    let defined = mc_api.get_merge_tags(&list.id).await?;
+    let mut tags = MergeTags::new(settings);
+    tags.retain_defined(&defined);
+    let birthday_field = if args.get_flag("birthday") { Some(&settings.mailchimp.birthday_merge_field) } else { None };
+    let birthday_field = birthday_field.filter(|f| {
+        let ok = defined.contains(*f);
+        if !ok {
+            warn!("Mailchimp list has no merge field {} for birthdays, not syncing them", f);
+        }
+        ok
+    });
+    let id_field = Some(settings.mailchimp.id_merge_field.as_str()).filter(|f| {
+        let ok = defined.contains(*f);
+        if !ok {
+            warn!("Mailchimp list has no merge field {} for IT Retail ids, email changes can't be followed", f);
+        }
+        ok
+    });
+    // A member carrying an IT Retail id under a different email is that customer
+    // after an email change in IT Retail.  Unless every member was pulled, the
+    // ids saved by the last sync cover members that didn't change.
+    let no_ids = HashMap::new();
+    let mut by_itrid: HashMap<String, String> = match id_field {
+        Some(id_field) => itrid_emails(if incremental || just_one.is_some() { &state.itrids } else { &no_ids },
+                                       subscribers.values(), id_field),
+        None => HashMap::new(),
+    };
+    let mut to_rename: Vec<(&String, String)> = vec![];
+    let mut mx_cache: HashMap<String, bool> = HashMap::new();
+    let mx_client = mc_api.client()?;
+    let mut to_mc: Vec<&String> = vec![];
+    let mut invalid = 0;
+    for email in itr_customers.keys().filter(|s| !known.contains(*s)) {
+        if state.bounced.contains(email) {
+            debug!("not adding previously bounced {} to Mailchimp.", email);
+            continue;
+        }
+        if !valid_email(email) {
+            warn!("Skipping invalid email {} for Mailchimp.", email);
+            invalid += 1;
+            continue;
+        }
+        if check_mx {
+            let domain = email.rsplit_once('@').unwrap().1.to_string();
+            let has_mx = match mx_cache.get(&domain) {
+                Some(v) => *v,
+                None => {
+                    let v = domain_has_mx(&mx_client, &settings.mailchimp.doh_url, &domain).await;
+                    mx_cache.insert(domain.clone(), v);
+                    v
+                }
+            };
+            if !has_mx {
+                warn!("Skipping {} for Mailchimp, {} has no mail exchanger.", email, domain);
+                invalid += 1;
+                continue;
+            }
+        }
+        let c = itr_customers.get(email).unwrap();
+        if let Some(old) = by_itrid.get(&c.id.to_string()) {
+            if old != email && !itr_customers.contains_key(old) {
+                to_rename.push((email, old.clone()));
+                continue;
+            }
+        }
+        to_mc.push(email);
+    }
+    if invalid > 0 {
+        info!("Skipped {} IT Retail customers with unusable email addresses.", invalid);
+    }
+    let renamed: HashSet<String> = to_rename.iter().map(|(_, old)| old.clone()).collect();
     let to_itr: Vec<&String> = subscribers
         .keys()
-        .filter(|s| !itr_customers.contains_key(*s))
+        .filter(|s| !itr_customers.contains_key(*s) && !renamed.contains(*s))
         .collect();
 
     let mut errors = 0;
+    let mut renamed_mc = 0;
+    for (email, old) in to_rename.iter() {
+        // Members that didn't change since the last sync weren't pulled.
+        let member = match subscribers.get(old) {
+            Some(m) => Some(m.clone()),
+            None => mc_api.get_subscriber(&list.id, old).await?.remove(old),
+        };
+        let Some(m) = member else {
+            debug!("Mailchimp member {} is gone, adding {} instead.", old, email);
+            to_mc.push(email);
+            continue;
+        };
+        match mc_api.change_member_email(&list.id, &m, email).await {
+            Ok(_) => {
+                debug!("Changed Mailchimp member {} to {}.", m.email_address, email);
+                renamed_mc += 1;
+                known.remove(old);
+                known.insert(email.to_string());
+                let id = by_itrid.iter().find(|(_, e)| *e == old).map(|(id, _)| id.clone());
+                if let Some(id) = id {
+                    by_itrid.insert(id, email.to_string());
+                }
+            }
+            Err(e) => {
+                warn!("failed changing Mailchimp email {} to {}: {}", m.email_address, email, e);
+                errors += 1;
+            }
+        }
+    }
+    if renamed_mc > 0 {
+        info!("Changed {} email addresses in Mailchimp.", renamed_mc);
+    }
     let mut added_to_itr = 0;
     let mut added_to_mc = 0;
 
@@ -387,29 +844,10 @@ pub async fn mailchimp_sync(
             continue;
         }
         let min_itr = super::api::MinimalCustomer {
-            first_name: nc
-                .merge_fields
-                .get("FNAME")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string(),
-            last_name: nc
-                .merge_fields
-                .get("LNAME")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string(),
+            first_name: merge_str(nc, &tags.first_name),
+            last_name: merge_str(nc, &tags.last_name),
             email: nc.email_address.to_string(),
-            phone: normalize_phone(
-                &nc.merge_fields
-                    .get("PHONE")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string(),
-            ),
+            phone: normalize_phone(&merge_str(nc, &tags.phone)),
             frequent_shopper: true,
         };
         match api.make_customer(&min_itr).await {
@@ -436,17 +874,27 @@ pub async fn mailchimp_sync(
             Some(phone) => phone.to_string(),
             _ => "".to_owned(),
         };
-        let new_member = quick_new_member(
+        let mut new_member = quick_new_member(
             &c.email.as_ref().unwrap().to_string(),
             &c.first_name,
             &c.last_name,
             &c_phone,
             &c.discount.unwrap_or(0),
+            &tags,
         );
+        if let Some(id_field) = id_field {
+            new_member.merge_fields.insert(id_field.to_string(), json!(c.id.to_string()));
+        }
+        if let Some(field) = birthday_field {
+            if let Some(birthday) = mailchimp_birthday(c.birth_date.as_ref()) {
+                new_member.merge_fields.insert(field.to_string(), json!(birthday));
+            }
+        }
         match mc_api.post_json(&format!("/lists/{}/members", &list.id), &new_member).await {
             Ok(_) => {
                 debug!("Added {} to Mailchimp.", new_member.email_address);
                 added_to_mc = added_to_mc + 1;
+                known.insert(new_member.email_address.to_lowercase());
             }
             Err(e) => {
                 warn!("failed adding to mailchimp: {} for {:?}", e, &new_member);
@@ -473,49 +921,41 @@ pub async fn mailchimp_sync(
             } else if mc_c.status == "unsubscribed" {
                 continue;
             }
-            let mc_first_name = mc_c
-                .merge_fields
-                .get("FNAME")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string();
-            let mc_last_name = mc_c
-                .merge_fields
-                .get("LNAME")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string();
-            let mc_phone = mc_c
-                .merge_fields
-                .get("PHONE")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string();
+            let mc_first_name = merge_str(&mc_c, &tags.first_name);
+            let mc_last_name = merge_str(&mc_c, &tags.last_name);
+            let mc_phone = merge_str(&mc_c, &tags.phone);
             let c_phone = match &itr_c.phone {
                 Some(phone) => phone.to_string(),
                 _ => "".to_owned(),
             };
-            let mc_discount = mc_c
-                .merge_fields
-                .get("ITDISCOUNT")
-                .unwrap_or(&json!(0))
-                .as_u64()
+            let mc_discount = tags.discount.as_ref()
+                .and_then(|t| mc_c.merge_fields.get(t))
+                .and_then(|x| x.as_u64())
                 .unwrap_or(0) as u8;
             let c_discount = itr_c.discount.unwrap_or(0);
+            // Unmapped fields can't differ.
+            if !differ {
+                differ = (tags.first_name.is_some() && mc_first_name.ne(&itr_c.first_name))
+                    || (tags.last_name.is_some() && mc_last_name.ne(&itr_c.last_name))
+                    || (tags.phone.is_some() && mc_phone.ne(&c_phone));
+            }
             if !differ {
-                differ = mc_first_name.ne(&itr_c.first_name)
-                    || mc_last_name.ne(&itr_c.last_name)
-                    || mc_phone.ne(&c_phone);
+                differ = tags.discount.is_some() && mc_discount != c_discount
             }
             if !differ {
-                differ = mc_discount != c_discount
+                if let Some(id_field) = id_field {
+                    differ = mc_c.merge_fields.get(id_field).and_then(|x| x.as_str()) != Some(itr_c.id.to_string().as_str());
+                }
+            }
+            if !differ {
+                if let Some(field) = birthday_field {
+                    let c_birthday = mailchimp_birthday(itr_c.birth_date.as_ref());
+                    differ = c_birthday.is_some() && mc_c.merge_fields.get(field).and_then(|x| x.as_str()) != c_birthday.as_deref();
+                }
             }
             if differ {
                 trace!("{} records differ ({:?} : {:?}).", mc_key, mc_c, itr_c);
-                let r = mc_api.update_member(&list.id, &mc_c, itr_c).await;
+                let r = mc_api.update_member(&list.id, &mc_c, itr_c, &tags, birthday_field, id_field).await;
                 if r.is_err() {
                     warn!(
                         "Failure to update {} in mailchimp: {}",
@@ -570,5 +1010,310 @@ pub async fn mailchimp_sync(
     if errors > 0 {
         return Err(anyhow!("There where {} syncing errors", errors));
     }
+    if just_one.is_none() {
+        state.last_sync = Some(started);
+        if !incremental {
+            state.last_full_sync = Some(started);
+        }
+        state.members = known;
+        state.itrids = by_itrid;
+        save_sync_state(&state)?;
+    }
     Ok(())
 }
+
+pub fn same_email(a: &Option<String>, b: &Option<String>) -> bool {
+    let norm = |x: &Option<String>| x.as_ref().map(|e| e.trim().to_lowercase()).filter(|e| !e.is_empty());
+    norm(a) == norm(b)
+}
+
+pub fn same_phone(a: &Option<String>, b: &Option<String>) -> bool {
+    let norm = |x: &Option<String>| x.as_ref().map(normalize_phone).filter(|p| !p.is_empty());
+    norm(a) == norm(b)
+}
+
+// LocalExpress customers IT Retail doesn't know by email or phone, once each,
+// as given on their newest order.  Those with neither are left out.
+fn missing_le_customers(le: &[super::sidedb::LECustomer], itr: &[super::api::Customer]) -> Vec<super::api::MinimalCustomer> {
+    let email_of = |e: &Option<String>| e.as_ref().map(|e| e.trim().to_lowercase()).filter(|e| valid_email(e));
+    let phone_of = |p: &Option<String>| p.as_ref().map(normalize_phone).filter(|p| p.len() == 10);
+    let mut emails: HashSet<String> = itr.iter().filter_map(|c| email_of(&c.email)).collect();
+    let mut phones: HashSet<String> = itr.iter().filter_map(|c| phone_of(&c.phone)).collect();
+    let mut missing = vec![];
+    for c in le.iter() {
+        let email = email_of(&c.email);
+        let phone = phone_of(&c.phone);
+        if email.is_none() && phone.is_none() {
+            continue;
+        }
+        if email.as_ref().is_some_and(|e| emails.contains(e)) || phone.as_ref().is_some_and(|p| phones.contains(p)) {
+            continue;
+        }
+        missing.push(super::api::MinimalCustomer {
+            first_name: c.first_name.trim().to_string(),
+            last_name: c.last_name.trim().to_string(),
+            email: email.clone().unwrap_or_default(),
+            phone: phone.clone().unwrap_or_default(),
+            frequent_shopper: true,
+        });
+        emails.extend(email);
+        phones.extend(phone);
+    }
+    missing
+}
+
+// Creates IT Retail customers for LocalExpress shoppers it doesn't have, returning how many
+// were (or, with dry_run, would be) added.
+pub async fn le_customers(api: &mut super::api::ITRApi, sidedb: &mut super::sidedb::SideDb, dry_run: bool) -> Result<u32> {
+    let le = sidedb.get_le_customers().await?;
+    let itr = api.get_customers().await?;
+    let missing = missing_le_customers(&le, &itr);
+    info!("{} LocalExpress customers (from {} orders) are not in IT Retail.", missing.len(), le.len());
+    let mut added = 0;
+    let mut errors = 0;
+    for c in missing.iter() {
+        if dry_run {
+            info!("Would add {} {} <{}> {} to IT Retail.", c.first_name, c.last_name, c.email, c.phone);
+            added += 1;
+            continue;
+        }
+        match api.make_customer(c).await {
+            Ok(_) => {
+                debug!("Added {} {} <{}> to IT Retail.", c.first_name, c.last_name, c.email);
+                added += 1;
+            }
+            Err(e) => {
+                warn!("failed adding to IT Retail: {} for {:?}", e, c);
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        return Err(anyhow!("{} LocalExpress customers could not be added to IT Retail ({} were)", errors, added));
+    }
+    Ok(added)
+}
+
+// Shows how one email/phone resolves in each system and returns the number of inconsistencies found.
+pub async fn identity_audit(
+    sidedb: &mut super::sidedb::SideDb,
+    square: &super::square::SquareConnect,
+    settings: &super::settings::Settings,
+    args: &ArgMatches,
+) -> Result<u32> {
+    let email = args.get_one::<String>("email").map(|e| e.trim().to_lowercase());
+    let phone = args.get_one::<String>("phone").map(normalize_phone);
+    if email.is_none() && phone.is_none() {
+        return Err(anyhow!("identity-audit needs --email or --phone"));
+    }
+    let mut problems: Vec<String> = vec![];
+
+    let itr: Vec<super::api::Customer> = sidedb.get_customers_all().await?
+        .into_iter()
+        .filter(|c| {
+            email.as_ref().is_some_and(|e| same_email(&c.email, &Some(e.to_string()))) ||
+            phone.as_ref().is_some_and(|p| same_phone(&c.phone, &Some(p.to_string())))
+        })
+        .collect();
+    println!("IT Retail:");
+    if itr.is_empty() {
+        println!("  no match");
+    }
+    for c in itr.iter() {
+        println!("  {} {} {} email={} phone={} square={}{}",
+            c.id, c.first_name, c.last_name,
+            c.email.as_deref().unwrap_or("-"), c.phone.as_deref().unwrap_or("-"),
+            c.squareup_id.as_deref().unwrap_or("-"),
+            if c.deleted { " (deleted)" } else { "" });
+    }
+    let live: Vec<&super::api::Customer> = itr.iter().filter(|c| !c.deleted).collect();
+    if live.len() > 1 {
+        problems.push(format!("{} live IT Retail customers match", live.len()));
+    }
+
+    let square_ids: Vec<String> = live.iter().filter_map(|c| c.squareup_id.clone()).collect();
+    let sq = square.find_customers(email.as_ref(), phone.as_ref(), &square_ids).await?;
+    println!("Square:");
+    if sq.is_empty() {
+        println!("  no match");
+    }
+    for (how, sc) in sq.iter() {
+        println!("  {} (by {}) email={} phone={} reference={}",
+            sc.id.as_deref().unwrap_or("-"), how,
+            sc.email_address.as_deref().unwrap_or("-"), sc.phone_number.as_deref().unwrap_or("-"),
+            sc.reference_id.as_deref().unwrap_or("-"));
+    }
+    for c in live.iter() {
+        match &c.squareup_id {
+            None => problems.push(format!("IT Retail {} is not associated with a Square customer", c.id)),
+            Some(sqid) => match sq.iter().find(|(_, sc)| sc.id.as_ref() == Some(sqid)) {
+                None => problems.push(format!("IT Retail {} points at Square {} which does not exist", c.id, sqid)),
+                Some((_, sc)) => {
+                    if !same_email(&c.email, &sc.email_address) {
+                        problems.push(format!("Square {} email {} differs from IT Retail {}", sqid,
+                            sc.email_address.as_deref().unwrap_or("-"), c.email.as_deref().unwrap_or("-")));
+                    }
+                    if !same_phone(&c.phone, &sc.phone_number) {
+                        problems.push(format!("Square {} phone {} differs from IT Retail {}", sqid,
+                            sc.phone_number.as_deref().unwrap_or("-"), c.phone.as_deref().unwrap_or("-")));
+                    }
+                    if sc.reference_id != Some(c.id.to_string()) {
+                        problems.push(format!("Square {} reference id {} is not IT Retail {}", sqid,
+                            sc.reference_id.as_deref().unwrap_or("-"), c.id));
+                    }
+                }
+            },
+        }
+    }
+    for (how, sc) in sq.iter() {
+        if *how != "id" && !square_ids.contains(sc.id.as_ref().unwrap_or(&"".to_string())) {
+            problems.push(format!("Square {} matched by {} but no IT Retail customer points at it",
+                sc.id.as_deref().unwrap_or("-"), how));
+        }
+    }
+
+    // compare-stripe-square covers Stripe for the whole customer list.
+
+    println!("Mailchimp:");
+    let mc_email = email.clone().or_else(|| live.iter().find_map(|c| c.email.clone()).map(|e| e.to_lowercase()));
+    match mc_email {
+        None => println!("  no email to look up"),
+        Some(mc_email) => {
+            let mc_token = args.get_one::<String>("mc_token").or(
+                if !settings.mailchimp.token.is_empty() { Some(&settings.mailchimp.token) } else { None });
+            let mut mc_api = mailchimp_api_new(settings, mc_token);
+            let list = mc_api.get_list(args.get_one::<String>("listid")).await?;
+            let subscribers = mc_api.get_subscriber(&list.id, &mc_email).await?;
+            match subscribers.get(&mc_email) {
+                None => {
+                    println!("  no match");
+                    if !live.is_empty() {
+                        problems.push(format!("{} is in IT Retail but not in Mailchimp", mc_email));
+                    }
+                },
+                Some(m) => {
+                    let mc_phone = MergeTags::new(settings).phone
+                        .and_then(|t| m.merge_fields.get(&t).and_then(|x| x.as_str()).map(|x| x.to_string()));
+                    println!("  {} status={} phone={}", m.email_address, m.status, mc_phone.as_deref().unwrap_or("-"));
+                    for c in live.iter() {
+                        if mc_phone.as_ref().is_some_and(|p| !p.is_empty()) && !same_phone(&c.phone, &mc_phone) {
+                            problems.push(format!("Mailchimp phone {} differs from IT Retail {} phone {}",
+                                mc_phone.as_deref().unwrap_or("-"), c.id, c.phone.as_deref().unwrap_or("-")));
+                        }
+                    }
+                },
+            }
+        },
+    }
+
+    if problems.is_empty() {
+        println!("No inconsistencies found.");
+    } else {
+        println!("Inconsistencies:");
+        for p in problems.iter() {
+            println!("  {}", p);
+        }
+    }
+    Ok(problems.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_le_customers() {
+        let le = |first: &str, email: Option<&str>, phone: Option<&str>| super::super::sidedb::LECustomer {
+            first_name: first.to_string(), last_name: "Shopper".to_string(),
+            email: email.map(|e| e.to_string()), phone: phone.map(|p| p.to_string()),
+        };
+        let itr: Vec<super::super::api::Customer> = serde_json::from_value(json!([
+            { "Id": "6c3f1a52-1f0e-4bde-9d43-0e7d2f6a5b11", "LastName": "Known", "FirstName": "Kim", "Deleted": false,
+              "Email": "Kim@Example.com", "Phone": "(555) 123-4567" }
+        ])).unwrap();
+        let orders = vec![
+            le("Kim", Some("kim@example.com "), None),            // known by email
+            le("Kimberly", None, Some("555.123.4567")),            // known by phone
+            le("Newest", Some("new@example.com"), Some("555-000-1111")),
+            le("Older", Some("NEW@example.com"), None),            // same shopper, older order
+            le("Phone", Some("not an email"), Some("+1 555 000 2222")),
+            le("Nobody", None, None),
+        ];
+        let missing = missing_le_customers(&orders, &itr);
+        assert_eq!(missing.len(), 2);
+        assert_eq!((missing[0].first_name.as_str(), missing[0].email.as_str(), missing[0].phone.as_str()),
+                   ("Newest", "new@example.com", "5550001111"));
+        assert_eq!((missing[1].first_name.as_str(), missing[1].email.as_str(), missing[1].phone.as_str()),
+                   ("Phone", "", "5550002222"));
+    }
+    #[test]
+    fn test_pending_members() {
+        let member = |email: &str, status: &str, source: &str, signup: &str| -> Member {
+            serde_json::from_value(json!({
+                "id": email, "email_address": email, "unique_email_id": "u", "contact_id": "c",
+                "full_name": "", "email_type": "html", "status": status, "interests": {}, "source": source,
+                "tags": [], "merge_fields": {}, "timestamp_signup": signup, "last_changed": "2026-10-10T08:00:00+00:00"
+            })).unwrap()
+        };
+        let members = [
+            member("new@example.com", "pending", "Hosted Signup Form", "2026-10-16T12:00:00+00:00"),
+            member("old@example.com", "pending", "Hosted Signup Form", "2026-09-01T12:00:00+00:00"),
+            member("api@example.com", "pending", "API - Generic", ""),
+            member("done@example.com", "subscribed", "Hosted Signup Form", "2026-09-01T12:00:00+00:00"),
+        ];
+        let now = DateTime::parse_from_rfc3339("2026-10-17T12:00:00+00:00").unwrap().with_timezone(&Utc);
+        let pending = pending_members(members.iter(), now, 0);
+        assert_eq!(pending.iter().map(|p| (p.email.as_str(), p.age_days)).collect::<Vec<_>>(),
+                   vec![("old@example.com", Some(46)), ("api@example.com", Some(7)), ("new@example.com", Some(1))]);
+        assert_eq!(pending[1].csv_line(), "api@example.com,API - Generic,2026-10-10T08:00:00+00:00,7");
+        assert_eq!(pending_members(members.iter(), now, 7).len(), 2);
+    }
+    #[test]
+    fn test_itrid_emails_incremental() {
+        let member = |email: &str, itrid: &str| -> Member {
+            serde_json::from_value(json!({
+                "id": email, "email_address": email, "unique_email_id": "u", "contact_id": "c",
+                "full_name": "", "email_type": "html", "status": "subscribed", "interests": {}, "source": "API",
+                "tags": [], "merge_fields": { "ITRID": itrid }
+            })).unwrap()
+        };
+        // The last sync saw three members; only two changed since.
+        let saved = HashMap::from([
+            ("id-1".to_string(), "one@example.com".to_string()),
+            ("id-2".to_string(), "two@example.com".to_string()),
+            ("id-3".to_string(), "three@example.com".to_string()),
+        ]);
+        let changed = [member("Two@example.com", ""), member("four@example.com", "id-4")];
+        let ids = itrid_emails(&saved, changed.iter(), "ITRID");
+        // id-1 didn't change in Mailchimp but is still found, so an email change
+        // in IT Retail renames that member instead of adding a duplicate.
+        assert_eq!(ids.get("id-1").map(|e| e.as_str()), Some("one@example.com"));
+        assert_eq!(ids.get("id-3").map(|e| e.as_str()), Some("three@example.com"));
+        assert_eq!(ids.get("id-4").map(|e| e.as_str()), Some("four@example.com"));
+        // two@ changed and no longer carries an id.
+        assert_eq!(ids.get("id-2"), None);
+        assert_eq!(ids.len(), 3);
+        // A full pull starts from nothing.
+        assert_eq!(itrid_emails(&HashMap::new(), changed.iter(), "ITRID").len(), 1);
+    }
+    #[test]
+    fn test_merge_str_missing_fields() {
+        let member: Member = serde_json::from_value(json!({
+            "id": "abc", "email_address": "imported@example.com", "unique_email_id": "u", "contact_id": "c",
+            "full_name": "", "email_type": "html", "status": "subscribed", "interests": {}, "source": "Import",
+            "tags": [], "merge_fields": { "LNAME": "Smith", "ITDISCOUNT": 5 }
+        })).unwrap();
+        let tags = MergeTags {
+            first_name: Some("FNAME".to_string()),
+            last_name: Some("LNAME".to_string()),
+            phone: Some("PHONE".to_string()),
+            discount: Some("ITDISCOUNT".to_string()),
+        };
+        assert_eq!(merge_str(&member, &tags.first_name), "");
+        assert_eq!(merge_str(&member, &tags.last_name), "Smith");
+        assert_eq!(merge_str(&member, &tags.phone), "");
+        assert_eq!(merge_str(&member, &tags.discount), "");
+        assert_eq!(merge_str(&member, &None), "");
+        assert_eq!(normalize_phone(&merge_str(&member, &tags.phone)), "");
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+use thiserror::Error;
+
+// Errors callers may want to act on (e.g. reauthorizing) rather than just report.
+// Functions still return anyhow::Result; use `err.downcast_ref::<ApiError>()` to match.
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("no username provided")]
+    MissingUsername,
+    #[error("no password provided")]
+    MissingPassword,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("{0}")]
+    Status(String),
+    #[error("request timed out: {0}")]
+    Timeout(reqwest::Error),
+    #[error("{0}")]
+    Network(#[from] reqwest::Error),
+    #[error("{0}")]
+    Parse(#[from] serde_json::Error),
+    // The body was an OData (or Web API) error envelope, often sent with a 200.
+    #[error("upstream error: {0}")]
+    Upstream(String),
+}
+
+impl ApiError {
+    pub fn from_status(status: reqwest::StatusCode) -> Self {
+        if status == reqwest::StatusCode::UNAUTHORIZED {
+            return ApiError::Unauthorized;
+        }
+        ApiError::Status(
+            status
+                .canonical_reason()
+                .map(|r| r.to_string())
+                .unwrap_or(format!("UNKNOWN CODE: {}", status.as_str())),
+        )
+    }
+    pub fn from_reqwest(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            return ApiError::Timeout(err);
+        }
+        ApiError::Network(err)
+    }
+}
+
+pub fn http_client(connect_seconds: u64, request_seconds: u64) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder();
+    if connect_seconds > 0 {
+        builder = builder.connect_timeout(Duration::from_secs(connect_seconds));
+    }
+    if request_seconds > 0 {
+        builder = builder.timeout(Duration::from_secs(request_seconds));
+    }
+    builder
+}
+
+pub fn is_unauthorized(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ApiError>(), Some(ApiError::Unauthorized))
+}
+
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ApiError>(), Some(ApiError::Timeout(_)))
+}
@@ -0,0 +1,266 @@
+use anyhow::{anyhow, Result};
+
+use super::api::ProductData;
+
+// A --where expression over ProductData fields, e.g.
+//   department_id == 3 && (normal_price > 5 || !scale) && plu != null
+// Comparisons are == != < <= > >=, values are numbers, 'strings' or "strings",
+// true, false and null (an unset field).  A bare field is true when set and
+// not false, zero or empty.
+#[derive(Debug, Clone)]
+pub enum ProductFilter {
+    And(Box<ProductFilter>, Box<ProductFilter>),
+    Or(Box<ProductFilter>, Box<ProductFilter>),
+    Not(Box<ProductFilter>),
+    Field(&'static str),
+    Compare(&'static str, FilterOp, FilterValue),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+    Null,
+}
+
+const FILTER_FIELDS: &[&str] = &["upc", "description", "second_description", "normal_price", "special_price",
+    "scale", "active", "discountable", "deleted", "plu", "cert_code", "vendor_id", "department_id", "section_id",
+    "wicable", "foodstamp", "quantity_on_hand", "size", "case_cost", "pack", "cost"];
+
+fn filter_field(p: &ProductData, field: &str) -> FilterValue {
+    let num = |v: Option<f64>| v.map(FilterValue::Num).unwrap_or(FilterValue::Null);
+    let text = |v: &Option<String>| v.clone().map(FilterValue::Str).unwrap_or(FilterValue::Null);
+    match field {
+        "upc" => FilterValue::Str(p.upc.clone()),
+        "description" => FilterValue::Str(p.description.clone()),
+        "second_description" => text(&p.second_description),
+        "normal_price" => FilterValue::Num(p.normal_price),
+        "special_price" => num(p.special_price),
+        "scale" => FilterValue::Bool(p.scale),
+        "active" => FilterValue::Bool(p.active),
+        "discountable" => FilterValue::Num(p.discountable as f64),
+        "deleted" => FilterValue::Bool(p.deleted),
+        "plu" => text(&p.plu),
+        "cert_code" => text(&p.cert_code),
+        "vendor_id" => num(p.vendor_id.map(|v| v as f64)),
+        "department_id" => FilterValue::Num(p.department_id as f64),
+        "section_id" => num(p.section_id.map(|v| v as f64)),
+        "wicable" => num(p.wicable.map(|v| v as f64)),
+        "foodstamp" => p.foodstamp.map(FilterValue::Bool).unwrap_or(FilterValue::Null),
+        "quantity_on_hand" => num(p.quantity_on_hand.map(|v| v as f64)),
+        "size" => text(&p.size),
+        "case_cost" => num(p.case_cost.map(|v| v as f64)),
+        "pack" => num(p.pack.map(|v| v as f64)),
+        "cost" => num(p.cost.map(|v| v as f64)),
+        _ => FilterValue::Null,
+    }
+}
+
+impl FilterValue {
+    fn truthy(&self) -> bool {
+        match self {
+            FilterValue::Num(n) => *n != 0.0,
+            FilterValue::Str(s) => !s.is_empty(),
+            FilterValue::Bool(b) => *b,
+            FilterValue::Null => false,
+        }
+    }
+    // Numeric text (a PLU, say) compares as a number against a number.
+    fn compare(&self, op: FilterOp, other: &FilterValue) -> bool {
+        use std::cmp::Ordering;
+        let ord = match (self, other) {
+            (FilterValue::Num(a), FilterValue::Num(b)) => a.partial_cmp(b),
+            (FilterValue::Str(a), FilterValue::Num(b)) => a.trim().parse::<f64>().ok().and_then(|a| a.partial_cmp(b)),
+            (FilterValue::Str(a), FilterValue::Str(b)) => Some(a.cmp(b)),
+            (a, b) if op == FilterOp::Eq => return a == b,
+            (a, b) if op == FilterOp::Ne => return a != b,
+            _ => None,
+        };
+        match (op, ord) {
+            (FilterOp::Ne, None) => true,
+            (_, None) => false,
+            (FilterOp::Eq, Some(o)) => o == Ordering::Equal,
+            (FilterOp::Ne, Some(o)) => o != Ordering::Equal,
+            (FilterOp::Lt, Some(o)) => o == Ordering::Less,
+            (FilterOp::Le, Some(o)) => o != Ordering::Greater,
+            (FilterOp::Gt, Some(o)) => o == Ordering::Greater,
+            (FilterOp::Ge, Some(o)) => o != Ordering::Less,
+        }
+    }
+}
+
+impl ProductFilter {
+    pub fn matches(&self, p: &ProductData) -> bool {
+        match self {
+            ProductFilter::And(a, b) => a.matches(p) && b.matches(p),
+            ProductFilter::Or(a, b) => a.matches(p) || b.matches(p),
+            ProductFilter::Not(a) => !a.matches(p),
+            ProductFilter::Field(f) => filter_field(p, f).truthy(),
+            ProductFilter::Compare(f, op, v) => filter_field(p, f).compare(*op, v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FilterToken {
+    Ident(String),
+    Value(FilterValue),
+    Op(FilterOp),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn filter_tokens(expr: &str) -> Result<Vec<FilterToken>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => { i += 1; continue; },
+            ('&', Some('&')) => (FilterToken::And, 2),
+            ('|', Some('|')) => (FilterToken::Or, 2),
+            ('=', Some('=')) => (FilterToken::Op(FilterOp::Eq), 2),
+            ('!', Some('=')) => (FilterToken::Op(FilterOp::Ne), 2),
+            ('<', Some('=')) => (FilterToken::Op(FilterOp::Le), 2),
+            ('>', Some('=')) => (FilterToken::Op(FilterOp::Ge), 2),
+            ('<', _) => (FilterToken::Op(FilterOp::Lt), 1),
+            ('>', _) => (FilterToken::Op(FilterOp::Gt), 1),
+            ('!', _) => (FilterToken::Not, 1),
+            ('(', _) => (FilterToken::Open, 1),
+            (')', _) => (FilterToken::Close, 1),
+            ('"', _) | ('\'', _) => {
+                let end = chars[i + 1..].iter().position(|x| *x == c)
+                    .ok_or_else(|| anyhow!("unterminated string in filter at {}", i))?;
+                (FilterToken::Value(FilterValue::Str(chars[i + 1..i + 1 + end].iter().collect())), end + 2)
+            },
+            (c, _) if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let len = chars[i..].iter().position(|x| !(x.is_ascii_digit() || *x == '.' || *x == '-')).unwrap_or(chars.len() - i);
+                let text: String = chars[i..i + len].iter().collect();
+                let n = text.parse::<f64>().map_err(|_| anyhow!("bad number in filter: {}", text))?;
+                (FilterToken::Value(FilterValue::Num(n)), len)
+            },
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[i..].iter().position(|x| !(x.is_ascii_alphanumeric() || *x == '_')).unwrap_or(chars.len() - i);
+                let word: String = chars[i..i + len].iter().collect();
+                (match word.to_lowercase().as_str() {
+                    "true" => FilterToken::Value(FilterValue::Bool(true)),
+                    "false" => FilterToken::Value(FilterValue::Bool(false)),
+                    "null" => FilterToken::Value(FilterValue::Null),
+                    _ => FilterToken::Ident(word),
+                }, len)
+            },
+            (c, _) => return Err(anyhow!("unexpected '{}' in filter at {}", c, i)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+struct FilterParser {
+    tokens: Vec<FilterToken>,
+    pos: usize,
+}
+
+impl FilterParser {
+    fn peek(&self) -> Option<&FilterToken> {
+        self.tokens.get(self.pos)
+    }
+    fn take(&mut self) -> Option<FilterToken> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+    fn or(&mut self) -> Result<ProductFilter> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&FilterToken::Or) {
+            self.pos += 1;
+            left = ProductFilter::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+    fn and(&mut self) -> Result<ProductFilter> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&FilterToken::And) {
+            self.pos += 1;
+            left = ProductFilter::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+    fn unary(&mut self) -> Result<ProductFilter> {
+        match self.take() {
+            Some(FilterToken::Not) => Ok(ProductFilter::Not(Box::new(self.unary()?))),
+            Some(FilterToken::Open) => {
+                let inner = self.or()?;
+                match self.take() {
+                    Some(FilterToken::Close) => Ok(inner),
+                    _ => Err(anyhow!("missing ) in filter")),
+                }
+            },
+            Some(FilterToken::Ident(name)) => {
+                let field = FILTER_FIELDS.iter().find(|f| f.eq_ignore_ascii_case(&name))
+                    .ok_or_else(|| anyhow!("unknown field in filter: {} (one of {})", name, FILTER_FIELDS.join(", ")))?;
+                match self.peek() {
+                    Some(FilterToken::Op(op)) => {
+                        let op = *op;
+                        self.pos += 1;
+                        match self.take() {
+                            Some(FilterToken::Value(v)) => Ok(ProductFilter::Compare(field, op, v)),
+                            _ => Err(anyhow!("expected a value after {} in filter", field)),
+                        }
+                    },
+                    _ => Ok(ProductFilter::Field(field)),
+                }
+            },
+            Some(t) => Err(anyhow!("unexpected {:?} in filter", t)),
+            None => Err(anyhow!("filter ends early")),
+        }
+    }
+}
+
+pub fn parse_product_filter(expr: &str) -> Result<ProductFilter> {
+    let mut parser = FilterParser { tokens: filter_tokens(expr)?, pos: 0 };
+    let filter = parser.or()?;
+    match parser.peek() {
+        None => Ok(filter),
+        Some(t) => Err(anyhow!("unexpected {:?} in filter", t)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_product_filter() {
+        let chops = super::super::api::product(r#""upc":"0020123400000","description":"Pork Chops","normal_price":6.99,"scale":true,"PLU":"42","departmentId":3"#);
+        let matches = |expr: &str| parse_product_filter(expr).unwrap().matches(&chops);
+        assert!(matches("department_id==3 && normal_price>5"));
+        assert!(!matches("department_id == 3 && normal_price > 7"));
+        assert!(matches("department_id == 4 || (scale && !deleted)"));
+        assert!(matches("plu == 42 && plu >= 40 && section_id == null && vendor_id != 2"));
+        assert!(matches("description == 'Pork Chops' && upc != \"0020000000000\""));
+        assert!(!matches("section_id > 0"));
+        assert!(matches("!foodstamp && discountable"));
+        assert!(parse_product_filter("price > 5").is_err());
+        assert!(parse_product_filter("normal_price >").is_err());
+        assert!(parse_product_filter("(scale").is_err());
+        assert!(parse_product_filter("scale active").is_err());
+    }
+}
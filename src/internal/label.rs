@@ -1,24 +1,178 @@
+use chrono::{DateTime, Local};
 use clap::ArgMatches;
 use fancy_regex::{Regex, RegexBuilder};
 use log::*;
-use rust_xlsxwriter::{Format, Workbook};
+use std::collections::HashMap;
+use rust_xlsxwriter::{Format, FormatAlign, Workbook, Worksheet};
 //use std::error;
 use anyhow::{anyhow, Result};
 
 //type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
+// Avery 5160 style stock: 2.625" x 1" labels.
+const LABEL_COLUMN_WIDTH: f64 = 30.0;
+const LABEL_ROW_HEIGHT: f64 = 72.0;
+
+#[derive(Debug, Clone)]
+pub struct SheetLayout {
+    pub columns: u16,
+    pub rows: u32,
+}
+
+// Layouts are given as COLUMNSxROWS, e.g. 3x10
+pub fn parse_sheet_layout(arg: &str) -> Result<SheetLayout> {
+    let (c, r) = arg.to_lowercase().split_once('x')
+        .map(|(c, r)| (c.trim().to_string(), r.trim().to_string()))
+        .ok_or(anyhow!("layout must be COLUMNSxROWS"))?;
+    let layout = SheetLayout { columns: c.parse::<u16>()?, rows: r.parse::<u32>()? };
+    if layout.columns == 0 || layout.rows == 0 {
+        return Err(anyhow!("layout must have at least one row and column"));
+    }
+    Ok(layout)
+}
+
+// Columns label-export can write, with what each holds.
+pub const LABEL_HEADERS: [(&str, &str); 7] = [
+    ("name", "product description"),
+    ("plu", "scale PLU, blank if none"),
+    ("upc", "IT Retail UPC"),
+    ("price", "price (tax inclusive with --tax-inclusive)"),
+    ("qoh", "quantity on hand"),
+    ("cost", "unit cost, blank if unknown"),
+    ("status", "active, inactive or deleted"),
+];
+
+// Where a product stands in IT Retail; deleted wins over inactive.
+pub fn product_status(item: &super::api::ProductData) -> &'static str {
+    if item.deleted {
+        "deleted"
+    } else if item.active {
+        "active"
+    } else {
+        "inactive"
+    }
+}
+
+// Whether label-export takes a product by status: current ones, plus deleted
+// ones with --include-deleted.  active_only is already off with --include-inactive.
+fn status_wanted(item: &super::api::ProductData, active_only: bool, include_deleted: bool) -> bool {
+    item.is_available(active_only) || (include_deleted && item.deleted && (item.active || !active_only))
+}
+
+// Headers are a comma separated list of LABEL_HEADERS names, in any case.
+pub fn parse_headers(arg: &str) -> Result<Vec<String>> {
+    let headers: Vec<String> = arg.split(',').map(|h| h.trim().to_string()).collect();
+    for h in headers.iter() {
+        if !LABEL_HEADERS.iter().any(|(name, _)| name.eq_ignore_ascii_case(h)) {
+            return Err(anyhow!("unknown header {:?}, expected one of {}", h,
+                LABEL_HEADERS.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(",")));
+        }
+    }
+    Ok(headers)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortField {
+    Section,
+    Department,
+    Name,
+    Price,
+    Plu,
+}
+
+#[derive(Debug, Clone)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+// Sorts are given as a comma separated list of keys, each optionally suffixed
+// with :desc (or :asc), e.g. section,name or price:desc
+pub fn parse_sort_keys(arg: &str) -> Result<Vec<SortKey>> {
+    arg.split(',').map(|k| {
+        let (name, dir) = k.trim().split_once(':').unwrap_or((k.trim(), "asc"));
+        let field = match name.to_lowercase().as_str() {
+            "section" => SortField::Section,
+            "department" | "dept" => SortField::Department,
+            "name" => SortField::Name,
+            "price" => SortField::Price,
+            "plu" => SortField::Plu,
+            other => return Err(anyhow!("unknown sort key: {}", other)),
+        };
+        let descending = match dir.to_lowercase().as_str() {
+            "asc" => false,
+            "desc" => true,
+            other => return Err(anyhow!("unknown sort direction: {}", other)),
+        };
+        Ok(SortKey { field, descending })
+    }).collect()
+}
+
+// Sorts products by each key in turn; sections sort by name, from `section_names`.
+pub fn sort_products(items: &mut [&super::api::ProductData], keys: &[SortKey], section_names: &HashMap<i32, String>) {
+    let plu = |x: &super::api::ProductData| x.plu.as_ref().and_then(|p| p.parse::<u16>().ok());
+    let section = |x: &super::api::ProductData| x.section_id
+        .and_then(|id| section_names.get(&id))
+        .map(|n| n.to_lowercase())
+        .unwrap_or_default();
+    items.sort_by(|a, b| {
+        for key in keys {
+            let ord = match key.field {
+                SortField::Section => section(a).cmp(&section(b)),
+                SortField::Department => a.department_id.cmp(&b.department_id),
+                SortField::Name => a.description.to_lowercase().cmp(&b.description.to_lowercase()),
+                SortField::Price => a.normal_price.total_cmp(&b.normal_price),
+                SortField::Plu => plu(a).cmp(&plu(b)),
+            };
+            let ord = if key.descending { ord.reverse() } else { ord };
+            if ord != std::cmp::Ordering::Equal {
+                return ord;
+            }
+        }
+        std::cmp::Ordering::Equal
+    });
+}
+
 pub struct LabelFile {
     label_file: String,
+    section_names: HashMap<i32, String>,
+    // When set, labels show the price in effect then (specials included) rather than the normal price.
+    price_as_of: Option<DateTime<Local>>,
 }
 
 pub fn create_label_file(file: &String) -> LabelFile {
     LabelFile {
         label_file: file.to_string(),
+        section_names: HashMap::new(),
+        price_as_of: None,
     }
 }
 
 impl LabelFile {
-    pub fn output_from_itretail_products(&mut self, json: &String, args: &ArgMatches) -> Result<()> {
+    pub fn set_price_as_of(&mut self, whence: DateTime<Local>) {
+        self.price_as_of = Some(whence);
+    }
+
+    fn label_price(&self, item: &super::api::ProductData) -> f64 {
+        match self.price_as_of {
+            Some(whence) => item.get_price_as_of(whence),
+            None => item.normal_price,
+        }
+    }
+
+    pub fn set_sections(&mut self, sections: &[super::api::Section]) {
+        self.section_names = sections.iter()
+            .filter_map(|s| s.id.map(|id| (id, s.name.to_string())))
+            .collect();
+    }
+
+    fn sort_items(&self, items: &mut [&super::api::ProductData], args: &ArgMatches) {
+        if let Some(keys) = args.get_one::<Vec<SortKey>>("sort") {
+            sort_products(items, keys, &self.section_names);
+        }
+    }
+
+    pub fn output_from_itretail_products(&mut self, json: &str, settings: &super::settings::Settings, args: &ArgMatches) -> Result<()> {
         let items: Vec<super::api::ProductData> = serde_json::from_str(json)?;
         let items_iter = items.into_iter();
         // we only want items that are not deleted and weighed (002...)
@@ -32,13 +186,18 @@ impl LabelFile {
             .unwrap()
             .parse::<i32>()
             .unwrap_or(0);
+        let active_only = args.get_flag("active-only") || settings.itretail.active_only;
+        let where_filter = args.get_one::<super::api::ProductFilter>("where");
         let items = items_iter.filter(|x| {
-            let wanted = !x.deleted
+            let wanted = x.is_available(active_only)
                 && upc_pat.is_match(&x.upc).unwrap()
                 && name_pat.is_match(&x.description).unwrap()
-                && (vendor_id == 0 || (x.vendor_id.is_some() && vendor_id == x.vendor_id.unwrap()));
+                && (vendor_id == 0 || (x.vendor_id.is_some() && vendor_id == x.vendor_id.unwrap()))
+                && where_filter.is_none_or(|f| f.matches(x));
             wanted && (x.quantity_on_hand.unwrap_or(0.0) > *qlimit)
-        });
+        }).collect::<Vec<super::api::ProductData>>();
+        let mut items = items.iter().collect::<Vec<&super::api::ProductData>>();
+        self.sort_items(&mut items, args);
 
         let mut row: u32 = 1;
         for item in items {
@@ -58,7 +217,7 @@ impl LabelFile {
 
         Ok(())
     }
-    pub fn build_from_itretail_products(&mut self, items: &Vec<super::api::ProductData>, args: &ArgMatches) -> Result<()> {
+    pub fn build_from_itretail_products(&mut self, items: &Vec<super::api::ProductData>, tax_rates: &HashMap<i32, f64>, settings: &super::settings::Settings, args: &ArgMatches) -> Result<()> {
         let items_iter = items.into_iter();
         // we only want items that are not deleted and weighed (002...)
         let re = args.get_one::<String>("upc").unwrap();
@@ -67,19 +226,37 @@ impl LabelFile {
         let re = args.get_one::<String>("name").unwrap();
         let name_pat = RegexBuilder::new(re).build()?;
         let use_sheets = *args.get_one::<bool>("sheets").unwrap();
-        let headers = args.get_one::<String>("headers").unwrap().split(',').collect::<Vec<&str>>();
+        let mut headers = args.get_one::<Vec<String>>("headers").unwrap().clone();
+        let include_deleted = args.get_flag("include-deleted");
+        let include_inactive = args.get_flag("include-inactive");
+        // Once non-current products can appear, say which ones they are.
+        if (include_deleted || include_inactive) && !headers.iter().any(|h| h.eq_ignore_ascii_case("status")) {
+            headers.push("status".to_string());
+        }
         let vendor_id = args
             .get_one::<String>("vendor")
             .unwrap()
             .parse::<i32>()
             .unwrap_or(0);
+        let active_only = !include_inactive && (args.get_flag("active-only") || settings.itretail.active_only);
+        let where_filter = args.get_one::<super::api::ProductFilter>("where");
         let items = items_iter.filter(|x| {
-            let wanted = !x.deleted
+            let wanted = status_wanted(x, active_only, include_deleted)
                 && upc_pat.is_match(&x.upc).unwrap()
                 && name_pat.is_match(&x.description).unwrap()
-                && (vendor_id == 0 || (x.vendor_id.is_some() && vendor_id == x.vendor_id.unwrap()));
+                && (vendor_id == 0 || (x.vendor_id.is_some() && vendor_id == x.vendor_id.unwrap()))
+                && where_filter.is_none_or(|f| f.matches(x));
             wanted && (x.quantity_on_hand.unwrap_or(0.0) > *qlimit)
         });
+        let mut items = items.collect::<Vec<&super::api::ProductData>>();
+        self.sort_items(&mut items, args);
+
+        if let Some(layout) = args.get_one::<SheetLayout>("sheet-layout") {
+            let mut workbook = Workbook::new();
+            self.layout_labels(&mut workbook, items, tax_rates, layout, use_sheets)?;
+            workbook.save(&self.label_file)?;
+            return Ok(());
+        }
 
         let mut workbook = Workbook::new();
         let bold_format = Format::new().set_bold();
@@ -108,7 +285,7 @@ impl LabelFile {
                             "name" => 40,
                             _ => 8
                         })?;
-                    worksheet.write_with_format(0, cidx, *h, &bold_format)?;
+                    worksheet.write_with_format(0, cidx, h, &bold_format)?;
                     cidx += 1;
                 }
             }
@@ -133,7 +310,7 @@ impl LabelFile {
                         worksheet.write_string(row, cidx, &item.upc)?;
                     },
                     "price" => {
-                        worksheet.write_number_with_format(row, cidx, item.normal_price, &price_format)?;
+                        worksheet.write_number_with_format(row, cidx, item.with_tax(self.label_price(item), tax_rates), &price_format)?;
                     },
                     "qoh" => {
                         worksheet.write_number_with_format(row, cidx, item.quantity_on_hand.unwrap_or(0.0), &weight_format)?;
@@ -143,6 +320,9 @@ impl LabelFile {
                             worksheet.write_number_with_format(row, cidx, item.cost.unwrap(), &price_format)?;
                         }
                     }
+                    "status" => {
+                        worksheet.write_string(row, cidx, product_status(item))?;
+                    }
                     _ => {
                         return Err(anyhow!("Unknown header: {}", h))
                     }
@@ -160,4 +340,84 @@ impl LabelFile {
 
         Ok(())
     }
+
+    fn layout_labels(&mut self, workbook: &mut Workbook, items: Vec<&super::api::ProductData>, tax_rates: &HashMap<i32, f64>, layout: &SheetLayout, use_sheets: bool) -> Result<()> {
+        let label_format = Format::new()
+            .set_text_wrap()
+            .set_align(FormatAlign::Center)
+            .set_align(FormatAlign::VerticalCenter);
+        let per_page = layout.columns as usize * layout.rows as usize;
+
+        // Each worksheet is a run of pages; with --sheets there is one run per department/section.
+        let mut groups: Vec<Vec<&super::api::ProductData>> = vec![];
+        let mut last_sheet = None;
+        for item in items {
+            let key = (item.department_id, item.section_id);
+            if groups.is_empty() || (use_sheets && last_sheet != Some(key)) {
+                groups.push(vec![]);
+            }
+            last_sheet = Some(key);
+            groups.last_mut().unwrap().push(item);
+        }
+
+        for group in groups {
+            let worksheet = workbook.add_worksheet();
+            if use_sheets {
+                let first = group[0];
+                worksheet.set_name(&format!("{}-{}", first.department_id, first.section_id.map(|x| x.to_string()).unwrap_or("None".to_string())))?;
+            }
+            for c in 0..layout.columns {
+                worksheet.set_column_width(c, LABEL_COLUMN_WIDTH)?;
+            }
+            for (idx, item) in group.iter().enumerate() {
+                let page = (idx / per_page) as u32;
+                let within = idx % per_page;
+                let row = page * layout.rows + (within / layout.columns as usize) as u32;
+                let col = (within % layout.columns as usize) as u16;
+                let plu = item.plu.as_ref().and_then(|p| p.parse::<u16>().ok());
+                let text = format!("{}\n${:.2}\n{}{}",
+                    item.description, item.with_tax(self.label_price(item), tax_rates),
+                    plu.map(|p| format!("PLU {}\n", p)).unwrap_or_default(), item.upc);
+                worksheet.write_string_with_format(row, col, &text, &label_format)?;
+                debug!("Label: [{:?}] {} : {} : {}", plu, item.upc, item.description, item.normal_price);
+            }
+            // Pad out the final page so a partial page prints on the same grid as a full one.
+            let pages = group.len().div_ceil(per_page) as u32;
+            Self::paginate(worksheet, layout, pages)?;
+        }
+        Ok(())
+    }
+    fn paginate(worksheet: &mut Worksheet, layout: &SheetLayout, pages: u32) -> Result<()> {
+        let total_rows = pages * layout.rows;
+        for row in 0..total_rows {
+            worksheet.set_row_height(row, LABEL_ROW_HEIGHT)?;
+        }
+        let breaks: Vec<u32> = (1..pages).map(|p| p * layout.rows).collect();
+        if !breaks.is_empty() {
+            worksheet.set_page_breaks(&breaks)?;
+        }
+        worksheet.set_print_area(0, 0, total_rows - 1, layout.columns - 1)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_wanted() {
+        let active = super::super::api::product(r#""active":true"#);
+        let inactive = super::super::api::product(r#""active":false"#);
+        let deleted = super::super::api::product(r#""active":true,"Deleted":true"#);
+        let deleted_inactive = super::super::api::product(r#""active":false,"Deleted":true"#);
+        let wanted = |active_only, include_deleted| [&active, &inactive, &deleted, &deleted_inactive].iter()
+            .map(|p| status_wanted(p, active_only, include_deleted)).collect::<Vec<bool>>();
+        assert_eq!(wanted(true, false), vec![true, false, false, false]);
+        assert_eq!(wanted(false, false), vec![true, true, false, false]);
+        assert_eq!(wanted(true, true), vec![true, false, true, false]);
+        assert_eq!(wanted(false, true), vec![true, true, true, true]);
+        assert_eq!(product_status(&deleted_inactive), "deleted");
+        assert_eq!(product_status(&inactive), "inactive");
+    }
 }
@@ -10,13 +10,16 @@ use reqwest::cookie::Jar;
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashSet;
 use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, Write};
+use std::io::Read;
 use std::path::PathBuf;
 use std::time::SystemTime;
 use std::sync::Arc;
 
+use super::error::{http_client, ApiError};
+
 mod le_u64_string {
     use serde::{self, Deserialize, Deserializer};
 
@@ -111,12 +114,33 @@ pub struct Order {
     pub curbside_pickup_info: Option<CurbsidePickupInfo>,
 }
 
+// Every order status LocalExpress uses, and the ones still needing work in
+// the store.  "active" on the command line stands for the latter.
+pub const ORDER_STATUSES: [&str; 10] = ["new", "confirmed", "assembling", "assembled", "packing", "packed",
+                                        "delivering", "delivered", "picked_up", "canceled"];
+pub const ACTIVE_ORDER_STATUSES: [&str; 4] = ["new", "confirmed", "assembling", "packing"];
+
 impl Order {
+    // A status we've never seen counts as active so it isn't overlooked.
     pub fn active(&self) -> bool {
-        self.status != "canceled" && self.status != "assembled" && self.status != "packed" && self.status != "delivering" && self.status != "delivered" && self.status != "picked_up"
+        let status = self.status.as_str();
+        ACTIVE_ORDER_STATUSES.contains(&status) || !ORDER_STATUSES.contains(&status)
     }
 }
 
+pub fn parse_order_status(arg: &str) -> Result<Vec<String>> {
+    if arg == "active" {
+        return Ok(ACTIVE_ORDER_STATUSES.iter().map(|s| s.to_string()).collect());
+    }
+    if ORDER_STATUSES.contains(&arg) {
+        return Ok(vec![arg.to_string()]);
+    }
+    Err(anyhow!("unknown order status {} (expected active or one of {})", arg, ORDER_STATUSES.join(", ")))
+}
+
+const ORDERS_PER_PAGE: usize = 100;
+const MAX_ORDER_PAGES: usize = 1000;
+
 #[derive(Serialize, Deserialize, Debug)]
 struct BearerToken {
     access_token: String,
@@ -140,6 +164,8 @@ pub struct LEApi {
     backingfile: File,
     bearer_token: BearerToken,
     jar: Arc<Jar>,
+    connect_timeout_seconds: u64,
+    request_timeout_seconds: u64,
 }
 
 fn bearer_token_from_json(json: String) -> BearerToken {
@@ -205,6 +231,8 @@ pub fn create_api() -> Result<LEApi> {
         backingfile: backingfile,
         bearer_token: BearerToken::default(),
         jar: Arc::new(Jar::default()),
+        connect_timeout_seconds: 0,
+        request_timeout_seconds: 0,
     })
 }
 
@@ -215,8 +243,14 @@ impl LEApi {
         Ok(())
     }
 
+    // Zero leaves the corresponding reqwest timeout unset.
+    pub fn set_timeouts(&mut self, connect_seconds: u64, request_seconds: u64) {
+        self.connect_timeout_seconds = connect_seconds;
+        self.request_timeout_seconds = request_seconds;
+    }
+
     fn client(&mut self, use_cookies: bool) -> Client {
-        let mut builder = Client::builder()
+        let mut builder = http_client(self.connect_timeout_seconds, self.request_timeout_seconds)
             .redirect(reqwest::redirect::Policy::none());
         if use_cookies {
             builder = builder
@@ -255,11 +289,11 @@ impl LEApi {
 
         let user = match env::var("LOCALEXPRESS_USERNAME") {
             Ok(p) => p,
-            Err(..) => return Err(anyhow!("no username provided")),
+            Err(..) => return Err(ApiError::MissingUsername.into()),
         };
         let pass = match env::var("LOCALEXPRESS_PASSWORD") {
             Ok(p) => p,
-            Err(..) => return Err(anyhow!("no password provided")),
+            Err(..) => return Err(ApiError::MissingPassword.into()),
         };
 
         debug!("Fetching token");
@@ -297,18 +331,16 @@ impl LEApi {
                         break;
                     }
                 }
-                self.backingfile.set_len(0)?;
-                self.backingfile.rewind()?;
-                self.backingfile.write_all(
+                self.backingfile = super::api::replace_dotfile(
+                    "localexpress.json",
                     serde_json::to_string(&bt)
                         .ok()
                         .unwrap_or(r"".to_string())
                         .as_bytes(),
                 )?;
-                self.backingfile.sync_all()?;
                 bt
             }
-            Err(e) => return Err(anyhow!("{}", e.to_string())),
+            Err(e) => return Err(ApiError::from_reqwest(e).into()),
         };
 
         return Ok(());
@@ -338,16 +370,10 @@ impl LEApi {
                     let text_response = result.text().await?;
                     Ok(text_response)
                 } else {
-                    Err(anyhow!(
-                        "{}",
-                        result
-                            .status()
-                            .canonical_reason()
-                            .unwrap_or(&format!("UNKNOWN CODE: {}", result.status().as_str()))
-                    ))
+                    Err(ApiError::from_status(result.status()).into())
                 }
             }
-            Err(e) => Err(anyhow!("{}", e.to_string())),
+            Err(e) => Err(ApiError::from_reqwest(e).into()),
         }
     }
 
@@ -374,16 +400,10 @@ impl LEApi {
                     let text_response = result.text()?;
                     Ok(text_response)
                 } else {
-                    Err(anyhow!(
-                        "{}",
-                        result
-                            .status()
-                            .canonical_reason()
-                            .unwrap_or(&format!("UNKNOWN CODE: {}", result.status().as_str()))
-                    ))
+                    Err(ApiError::from_status(result.status()).into())
                 }
             }
-            Err(e) => Err(anyhow!("{}", e.to_string())),
+            Err(e) => Err(ApiError::from_reqwest(e).into()),
         }
     }
     */
@@ -401,22 +421,58 @@ impl LEApi {
         self.call(reqwest::Method::POST, endpoint, Some(json_hdrs), Some(json)).await
     }
 
-    pub async fn get_orders(&mut self) -> Result<Vec<Order>> {
-        let endpoint = "/rest/v2/store/all/order?expand=productsCount,driverName&perPage=50&page=0".to_string();
-        let filter = json!({});//"filter":{"status":["new","confirmed","assembling","assembled","packing","packed"]},"filterType":"basic"});
-        let r = self.post_json(&endpoint, &filter).await?;
-        let response: OrdersResponse = serde_json::from_str(&r)?;
-        Ok(response.data.result)
+    // Fetch every order matching the filter, a page at a time.  Should
+    // LocalExpress ignore `page` and send the same orders again, stop on a page
+    // with nothing new; give up after MAX_ORDER_PAGES.
+    async fn fetch_orders(&mut self, expand: &str, filter: &serde_json::Value) -> Result<Vec<Order>> {
+        let mut orders = vec![];
+        let mut seen = HashSet::new();
+        let mut page = 0;
+        loop {
+            if page >= MAX_ORDER_PAGES {
+                return Err(anyhow!("LocalExpress orders still paging after {} pages of {}", MAX_ORDER_PAGES, ORDERS_PER_PAGE));
+            }
+            let endpoint = format!("/rest/v2/store/all/order?expand={}&perPage={}&page={}", expand, ORDERS_PER_PAGE, page);
+            let r = self.post_json(&endpoint, filter).await?;
+            let response: OrdersResponse = serde_json::from_str(&r).map_err(ApiError::Parse)?;
+            let count = response.data.result.len();
+            let before = orders.len();
+            orders.extend(response.data.result.into_iter().filter(|o| seen.insert(o.id)));
+            if count < ORDERS_PER_PAGE || orders.len() == before {
+                break;
+            }
+            page += 1;
+        }
+        Ok(orders)
+    }
+
+    // Orders in any of `status` (all if empty) created within the window;
+    // an open start reaches back to before the store used LocalExpress and an
+    // open end runs through today.
+    pub async fn get_orders(&mut self, status: &[String], since: Option<NaiveDate>, until: Option<NaiveDate>) -> Result<Vec<Order>> {
+        let mut criteria = serde_json::Map::new();
+        if !status.is_empty() {
+            criteria.insert("status".to_string(), json!(status));
+        }
+        if since.is_some() || until.is_some() {
+            let start = since.unwrap_or(NaiveDate::from_ymd_opt(2000, 1, 1).unwrap());
+            let end = until.unwrap_or(Local::now().date_naive());
+            criteria.insert("creation_date".to_string(),
+                            json!([start.format("%Y-%m-%d").to_string(), end.format("%Y-%m-%d").to_string()]));
+        }
+        let filter = if criteria.is_empty() {
+            json!({})
+        } else {
+            json!({"filter": criteria, "filterType": "basic"})
+        };
+        self.fetch_orders("productsCount,driverName", &filter).await
     }
 
     pub async fn get_current_orders(&mut self) -> Result<Vec<Order>> {
         let yesterday = Local::now().date_naive().checked_sub_days(Days::new(30)).unwrap();
         let future = yesterday.checked_add_months(Months::new(3)).unwrap();
         // https://api.localexpress.io/rest/v2/store/3920/order/7444491/details?expand=assembledByEmail%2CexcludeFromCollectingThrottling%2CadditionalFees%2CcurbsidePickupInfo%2Cpacks%2Cproducts%2Cwrapping%2Ctransactions%2CappliedTaxes%2CcouponDeduction%2CproductShippingPackagingBoxes%2CshippingTransactions%2CisAgeVerificationRequired%2CisAgeVerified%2CpreSelectedShippingMessage%2CshippingRate%2Cleft_to_pay%2ChasDeliProducts%2CcouponCode%2CcouponName%2CdeliveryFeeRemoval%2CcollectingFeeRemoval%2CnotFinalizedCustomerRelatedOrders%2CorderSummary&productExpand=modification%2Cdiscounts%2Cdiscount%2CdiscountPrice%2CproductPriceUnits%2CadditionalDiscount
-        let endpoint = "/rest/v2/store/all/order?expand=productsCount%2CcurbsidePickupInfo,driverName&perPage=100&page=0".to_string();
         let filter = json!({"filter":{"creation_date":[yesterday.format("%Y-%m-%d").to_string(),future.format("%Y-%m-%d").to_string()]},"filterType":"basic"});
-        let r = self.post_json(&endpoint, &filter).await?;
-        let response: OrdersResponse = serde_json::from_str(&r)?;
-        Ok(response.data.result)
+        self.fetch_orders("productsCount%2CcurbsidePickupInfo,driverName", &filter).await
     }
 }
@@ -0,0 +1,68 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::PathBuf;
+
+// An append-only log file that rotates by size: once a write would take it
+// past max_size it is renamed to FILE.1 (FILE.1 to FILE.2, and so on, keeping
+// `keep` old files) and a fresh FILE is started.  A max_size of 0 never rotates.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &PathBuf) -> Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, max_size: u64, keep: u32) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, max_size, keep, file, size })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            // rename doesn't replace an existing file on every platform, so clear the way first
+            let _ = std::fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
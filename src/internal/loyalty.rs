@@ -1,57 +1,52 @@
 use std::collections::HashMap;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::ArgMatches;
 use log::*;
 use rust_decimal::prelude::*;
+use serde::Serialize;
 use uuid::Uuid;
 
+use super::api::csv_field;
+
 pub fn valid_loyalty_levels() -> Vec<u32> {
     vec![3,4,5,6,7,8,9,10]
 }
 
+// Discount tiers by the 180 day spend that must be exceeded to earn them, highest first.
+/*
+Consider these.
+    (12400.0, 14),
+    (10400.0, 12),
+    (8600.0, 11),
+*/
+const LOYALTY_TIERS: [(f64, u8); 8] = [
+    (7000.0, 10),
+    (5600.0, 9),
+    (4200.0, 8),
+    (3000.0, 7),
+    (2000.0, 6),
+    (1200.0, 5),
+    (600.0, 4),
+    (300.0, 3),
+];
+
 pub fn spend_180_to_discount(spend: f64) -> u8 {
-    match spend {
-        /*
-        Consider these.
-        t if t > 12400.0 => 14,
-        t if t > 10400.0 => 12,
-        t if t > 8600.0 => 11,
-        */
-        t if t > 7000.0 => 10,
-        t if t > 5600.0 => 9,
-        t if t > 4200.0 => 8,
-        t if t > 3000.0 => 7,
-        t if t > 2000.0 => 6,
-        t if t > 1200.0 => 5,
-        t if t > 600.0 => 4,
-        t if t > 300.0 => 3,
-        _ => 0,
-    }
+    LOYALTY_TIERS.iter().find(|(t, _)| spend > *t).map(|(_, d)| *d).unwrap_or(0)
 }
 
-pub async fn apply_discounts(
-    api: &mut super::api::ITRApi,
-    sidedb: &mut super::sidedb::SideDb,
-    _settings: &super::settings::Settings,
-    args: &ArgMatches,
-) -> Result<()> {
-    let days = args.get_one::<u32>("days").unwrap();
-    let customer = args.get_one::<String>("email");
-    let noop = args.get_one::<bool>("noop").unwrap();
-    let normalize = (*days as f64) / 180.0;
+// The next tier up from a 180 day spend and how much more it takes; None at the top.
+pub fn next_tier(spend: f64) -> Option<(u8, f64)> {
+    LOYALTY_TIERS.iter().rev().find(|(t, _)| spend <= *t).map(|(t, d)| (*d, t - spend))
+}
+
+// Each customer's spend over `days`, with members' spend credited to their head of household.
+async fn household_spend(sidedb: &mut super::sidedb::SideDb, days: u32) -> Result<(HashMap<Uuid,Uuid>, HashMap<Uuid, f64>)> {
     let mut hoh_lookup: HashMap<Uuid,Uuid> = HashMap::new();
     for hoh in sidedb.get_customer_household().await? {
         hoh_lookup.insert(hoh.1, hoh.0);
     }
-    let spend_vec = sidedb.get_spend(*days).await?;
-    let customer_vec = sidedb.get_customers().await?;
-    let mut customers = HashMap::new();
-    for c in customer_vec.iter() {
-        if customer.is_none() || (c.email.is_some() && c.email.as_ref().unwrap() == customer.unwrap()) {
-            customers.insert(c.id.clone(), c);
-        }
-    }
+    let spend_vec = sidedb.get_spend(days).await?;
     let mut txn_totals: HashMap<Uuid, f64> = HashMap::new();
     for t in spend_vec.iter() {
         let hoh = match hoh_lookup.get(&t.0) {
@@ -67,6 +62,27 @@ pub async fn apply_discounts(
             txn_totals.insert(hoh.clone(), t.1.to_f64().unwrap());
         }
     }
+    Ok((hoh_lookup, txn_totals))
+}
+
+pub async fn apply_discounts(
+    api: &mut super::api::ITRApi,
+    sidedb: &mut super::sidedb::SideDb,
+    _settings: &super::settings::Settings,
+    args: &ArgMatches,
+) -> Result<()> {
+    let days = args.get_one::<u32>("days").unwrap();
+    let customer = args.get_one::<String>("email");
+    let noop = args.get_one::<bool>("noop").unwrap();
+    let normalize = (*days as f64) / 180.0;
+    let (hoh_lookup, txn_totals) = household_spend(sidedb, *days).await?;
+    let customer_vec = sidedb.get_customers().await?;
+    let mut customers = HashMap::new();
+    for c in customer_vec.iter() {
+        if customer.is_none() || (c.email.is_some() && c.email.as_ref().unwrap() == customer.unwrap()) {
+            customers.insert(c.id, c);
+        }
+    }
     let mut changes = 0;
     let mut inc = 0;
     let mut del = 0;
@@ -145,3 +161,127 @@ pub async fn apply_discounts(
 
     Ok(())
 }
+
+// One customer's tier for loyalty-export: what their spend earns next to what
+// IT Retail has them at, so a stale `loyalty` run shows up.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TierAssignment {
+    pub card_no: String,
+    pub name: String,
+    // Spend over the window, normalized to 180 days as the tiers are.
+    pub spend: f64,
+    pub tier: u8,
+    pub assigned: u8,
+    pub next_tier: Option<u8>,
+    pub to_next_tier: Option<f64>,
+}
+
+impl TierAssignment {
+    pub fn new(card_no: &str, name: &str, spend: f64, assigned: u8) -> Self {
+        let next = next_tier(spend);
+        TierAssignment {
+            card_no: card_no.to_string(),
+            name: name.to_string(),
+            spend: (spend * 100.0).round() / 100.0,
+            tier: spend_180_to_discount(spend),
+            assigned,
+            next_tier: next.map(|(d, _)| d),
+            to_next_tier: next.map(|(_, t)| (t * 100.0).round() / 100.0),
+        }
+    }
+    pub fn csv_line(&self) -> String {
+        format!("{},{},{:.2},{},{},{},{}", csv_field(&self.card_no), csv_field(&self.name), self.spend, self.tier, self.assigned,
+                self.next_tier.map(|d| d.to_string()).unwrap_or_default(),
+                self.to_next_tier.map(|t| format!("{:.2}", t)).unwrap_or_default())
+    }
+}
+
+// Every customer's tier by the same reckoning apply_discounts uses, highest tier
+// first and by name within a tier.
+pub async fn tier_assignments(sidedb: &mut super::sidedb::SideDb, args: &ArgMatches) -> Result<Vec<TierAssignment>> {
+    let days = *args.get_one::<u32>("days").unwrap();
+    let normalize = (days as f64) / 180.0;
+    let (hoh_lookup, txn_totals) = household_spend(sidedb, days).await?;
+    let mut tiers: Vec<TierAssignment> = sidedb.get_customers().await?.iter().map(|c| {
+        let hoh = hoh_lookup.get(&c.id).unwrap_or(&c.id);
+        let spend = txn_totals.get(hoh).unwrap_or(&0.0) / normalize;
+        TierAssignment::new(c.card_no.as_deref().unwrap_or(""),
+            format!("{} {}", c.first_name.trim(), c.last_name.trim()).trim(),
+            spend, c.discount.unwrap_or(0))
+    }).collect();
+    tiers.sort_by(|a, b| b.tier.cmp(&a.tier).then(a.name.cmp(&b.name)));
+    Ok(tiers)
+}
+
+// What an item costs a customer with the given tier discount.  IT Retail
+// only applies customer discounts to items marked discountable.
+pub fn discounted_price(price: f64, discount: u32, discountable: bool) -> f64 {
+    if !discountable {
+        return price;
+    }
+    (price * (100.0 - discount as f64)).round() / 100.0
+}
+
+pub async fn simulate(
+    sidedb: &mut super::sidedb::SideDb,
+    args: &ArgMatches,
+) -> Result<()> {
+    let discount = *args.get_one::<u32>("discount").unwrap();
+    let list = args.get_one::<String>("upc-list").unwrap();
+    if discount != 0 && !valid_loyalty_levels().contains(&discount) {
+        warn!("{}% is not a loyalty tier in use", discount);
+    }
+    let contents = std::fs::read_to_string(list)
+        .map_err(|e| anyhow!("cannot read {}: {}", list, e))?;
+    let products: HashMap<String, super::api::ProductData> = sidedb.get_products(None).await?
+        .into_iter()
+        .map(|p| (p.upc.clone(), p))
+        .collect();
+    let mut gross = 0.0;
+    let mut net = 0.0;
+    for line in contents.lines() {
+        let upc = line.trim();
+        if upc.is_empty() || upc.starts_with('#') {
+            continue;
+        }
+        let p = match products.get(upc) {
+            Some(p) => p,
+            None => {
+                warn!("{} is not a known product", upc);
+                continue;
+            }
+        };
+        let price = p.get_price();
+        let discountable = p.discountable != 0;
+        let tier_price = discounted_price(price, discount, discountable);
+        gross += price;
+        net += tier_price;
+        println!("{:14} {:32} ${:>8.2} ${:>8.2}{}", p.upc, p.description, price, tier_price,
+                 if discountable { "" } else { " (not discountable)" });
+    }
+    println!("{:14} {:32} ${:>8.2} ${:>8.2} (saves ${:.2})", "", "TOTAL", gross, net, gross - net);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiers() {
+        assert_eq!(spend_180_to_discount(300.0), 0);
+        assert_eq!(spend_180_to_discount(300.01), 3);
+        assert_eq!(spend_180_to_discount(7500.0), 10);
+        assert_eq!(next_tier(0.0), Some((3, 300.0)));
+        assert_eq!(next_tier(1500.0), Some((6, 500.0)));
+        assert_eq!(next_tier(7000.5), None);
+    }
+    #[test]
+    fn test_tier_assignment() {
+        let t = TierAssignment::new("1001", "Ann Smith, Jr.", 1234.567, 4);
+        assert_eq!((t.tier, t.next_tier), (5, Some(6)));
+        assert_eq!(t.csv_line(), "1001,\"Ann Smith, Jr.\",1234.57,5,4,6,765.43");
+        let t = TierAssignment::new("", "Top", 9000.0, 10);
+        assert_eq!(t.csv_line(), ",Top,9000.00,10,10,,");
+    }
+}
@@ -3,10 +3,17 @@ pub mod localexpress;
 #[cfg(target_os = "windows")]
 pub mod cas;
 pub mod customer;
+pub mod error;
+pub mod filter;
 pub mod sidedb;
+pub mod sqlite;
 pub mod label;
+pub mod logfile;
 pub mod loyalty;
+pub mod progress;
+pub mod scales;
 pub mod settings;
 pub mod square;
+pub mod stripe;
 pub mod tvmenu;
 pub mod tasmota;
@@ -0,0 +1,96 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+// How often the line is redrawn, at most.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+// Weight of the newest item in the moving average of seconds per item.  Square
+// backs off when rate limited, so recent items say more about the ETA than the
+// whole run's average does.
+const RATE_WEIGHT: f64 = 0.05;
+
+// A one line "label done/total rate ETA" bar on stderr for long interactive syncs.
+// It stays quiet unless asked for and stderr is a terminal.
+pub struct Progress {
+    label: String,
+    total: usize,
+    done: usize,
+    enabled: bool,
+    last_item: Instant,
+    last_draw: Option<Instant>,
+    // Moving average of seconds per item; None until the first item is done.
+    per_item: Option<f64>,
+}
+
+impl Progress {
+    pub fn new(label: &str, total: usize, enabled: bool) -> Self {
+        Progress {
+            label: label.to_string(),
+            total,
+            done: 0,
+            enabled: enabled && io::stderr().is_terminal(),
+            last_item: Instant::now(),
+            last_draw: None,
+            per_item: None,
+        }
+    }
+
+    pub fn inc(&mut self) {
+        let now = Instant::now();
+        let took = now.duration_since(self.last_item).as_secs_f64();
+        self.last_item = now;
+        self.per_item = Some(match self.per_item {
+            Some(avg) => avg + RATE_WEIGHT * (took - avg),
+            None => took,
+        });
+        self.done += 1;
+        if self.enabled && self.last_draw.is_none_or(|d| now.duration_since(d) >= REDRAW_INTERVAL) {
+            self.last_draw = Some(now);
+            self.draw();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if self.enabled && self.last_draw.is_some() {
+            self.draw();
+            eprintln!();
+        }
+        self.enabled = false;
+    }
+
+    fn draw(&self) {
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\r{}\x1b[K", render(&self.label, self.done, self.total, self.per_item));
+        let _ = stderr.flush();
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn render(label: &str, done: usize, total: usize, per_item: Option<f64>) -> String {
+    const WIDTH: usize = 30;
+    let done = done.min(total);
+    let filled = (done * WIDTH).checked_div(total).unwrap_or(WIDTH);
+    let mut line = format!("{} [{}{}] {}/{}", label, "#".repeat(filled), "-".repeat(WIDTH - filled), done, total);
+    if let Some(secs) = per_item.filter(|s| *s > 0.0) {
+        let eta = (secs * (total - done) as f64).round() as u64;
+        line.push_str(&format!(" {:.1}/s ETA {}:{:02}:{:02}", 1.0 / secs, eta / 3600, eta / 60 % 60, eta % 60));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render("products", 0, 10, None), "products [------------------------------] 0/10");
+        assert_eq!(render("customers", 5, 10, Some(0.5)), "customers [###############---------------] 5/10 2.0/s ETA 0:00:03");
+        assert_eq!(render("products", 3, 3, Some(2.0)), "products [##############################] 3/3 0.5/s ETA 0:00:00");
+        assert_eq!(render("products", 1, 7201, Some(1.0)), format!("products [{}] 1/7201 1.0/s ETA 2:00:00", "-".repeat(30)));
+    }
+}
@@ -0,0 +1,515 @@
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use clap::ArgMatches;
+use fancy_regex::Regex;
+use itertools::Itertools;
+use log::*;
+use rust_xlsxwriter::{Format, Workbook};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::label::{sort_products, SortField, SortKey};
+use super::api::{csv_bom, csv_field, parse_product_filter, price_num_format, round_price, InternalProducts, ItemCodeRange, PLUAssignment, PLURange, PriceCodeRange, ProductData, ProductFilter};
+
+// The PLU and scale spreadsheets scale-export writes, and the PLU assignment
+// behind them.  None of this needs the CAS library, so it builds everywhere;
+// pushing to the scales themselves is in cas.rs.
+
+#[derive(Debug)]
+pub struct DepartmentMap {
+    map: HashMap<i32, u16>,
+    default: u16,
+    origin: HashMap<i32, u16>,
+    sell_by_days: HashMap<i32, u32>,
+    item_codes: ItemCodeRange,
+    prices: PriceCodeRange,
+    labels: LabelTemplates,
+    warned: Mutex<HashSet<i32>>,
+}
+
+// Which of the scale's label templates an item prints on: shrink for items
+// matching scales.shrink_where, ingredient for items with ingredients (their
+// second description), standard for the rest.
+#[derive(Debug)]
+pub struct LabelTemplates {
+    pub standard: u16,
+    pub ingredient: u16,
+    pub shrink: u16,
+    shrink_filter: Option<ProductFilter>,
+}
+
+impl LabelTemplates {
+    pub fn new(settings: &super::settings::Settings) -> Result<Self> {
+        let expr = &settings.scales.shrink_where;
+        let shrink_filter = if expr.trim().is_empty() {
+            None
+        } else {
+            Some(parse_product_filter(expr).map_err(|e| anyhow!("bad scales.shrink_where: {}", e))?)
+        };
+        Ok(LabelTemplates {
+            standard: settings.scales.standard_label_id,
+            ingredient: settings.scales.ingredient_label_id,
+            shrink: settings.scales.shrink_label_id,
+            shrink_filter,
+        })
+    }
+    pub fn label_id(&self, item: &ProductData) -> u16 {
+        if self.shrink_filter.as_ref().is_some_and(|f| f.matches(item)) {
+            self.shrink
+        } else if ingredients(item).is_some() {
+            self.ingredient
+        } else {
+            self.standard
+        }
+    }
+}
+
+// The order of --by-section sheets: sections in `order` first, as listed, then the
+// rest by id, with products in no section (-1) last.
+pub fn sheet_order(ids: impl Iterator<Item = i32>, order: &[i32]) -> Vec<i32> {
+    ids.sorted_by_key(|id| (order.iter().position(|o| o == id).unwrap_or(order.len()), *id == -1, *id))
+        .collect()
+}
+
+// The item's ingredients for its label, if it has any.
+pub fn ingredients(item: &ProductData) -> Option<&String> {
+    item.second_description.as_ref().filter(|i| !i.is_empty())
+}
+
+fn by_department<T: Copy>(setting: &str, values: &HashMap<String, T>) -> HashMap<i32, T> {
+    let mut map = HashMap::new();
+    for (itr, value) in values.iter() {
+        match itr.parse::<i32>() {
+            Ok(id) => {
+                map.insert(id, *value);
+            }
+            Err(_) => warn!("Ignoring non-numeric department in scales.{}: {}", setting, itr),
+        }
+    }
+    map
+}
+
+impl DepartmentMap {
+    pub fn new(settings: &super::settings::Settings) -> Result<Self> {
+        Ok(DepartmentMap {
+            map: by_department("department_map", &settings.scales.department_map),
+            default: settings.scales.default_department,
+            origin: by_department("department_origin", &settings.scales.department_origin),
+            sell_by_days: by_department("department_sell_by_days", &settings.scales.department_sell_by_days),
+            item_codes: ItemCodeRange::new(settings.scales.item_code_offset, settings.scales.item_code_length),
+            prices: PriceCodeRange::new(settings.scales.price_offset, settings.scales.price_length, settings.scales.price_check_digit),
+            labels: LabelTemplates::new(settings)?,
+            warned: Mutex::new(HashSet::new()),
+        })
+    }
+    // The label template the item prints on.
+    pub fn label_id(&self, item: &ProductData) -> u16 {
+        self.labels.label_id(item)
+    }
+    // CAS origin number for the department's labels, 0 for none.
+    pub fn origin(&self, department_id: i32) -> u16 {
+        self.origin.get(&department_id).copied().unwrap_or(0)
+    }
+    // Days from packing to sell-by for the department's labels, 0 for none.
+    pub fn sell_by_days(&self, department_id: i32) -> u32 {
+        self.sell_by_days.get(&department_id).copied().unwrap_or(0)
+    }
+    // Scale item code embedded in the item's weighed UPC, 0 if it isn't one.
+    pub fn item_code(&self, item: &ProductData) -> u32 {
+        item.weighed_barcode(&self.item_codes, Some(&self.prices)).map(|b| b.item_code).unwrap_or(0)
+    }
+    pub fn scale_department(&self, department_id: i32) -> u16 {
+        if self.map.is_empty() {
+            return department_id as u16;
+        }
+        match self.map.get(&department_id) {
+            Some(d) => *d,
+            None => {
+                if self.warned.lock().unwrap().insert(department_id) {
+                    warn!(
+                        "Department {} has no scale mapping, using {}",
+                        department_id, self.default
+                    );
+                }
+                self.default
+            }
+        }
+    }
+}
+
+// One PLU scale-export assigned, for the --plu-changes audit file.
+#[derive(Debug, Serialize)]
+pub struct PLUChange {
+    pub upc: String,
+    pub old_plu: Option<u16>,
+    pub new_plu: u16,
+    pub description: String,
+    pub reason: &'static str,
+}
+impl PLUChange {
+    pub fn csv_line(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            csv_field(&self.upc),
+            self.old_plu.map(|p| p.to_string()).unwrap_or_default(),
+            self.new_plu,
+            csv_field(&self.description),
+            self.reason
+        )
+    }
+}
+
+// JSON when the file ends in .json, CSV otherwise.  Written even when nothing
+// changed so each run leaves a record.
+fn write_plu_changes(file: &str, changes: &[PLUChange], bom: bool) -> Result<()> {
+    let contents = if file.to_lowercase().ends_with(".json") {
+        serde_json::to_string_pretty(changes)?
+    } else {
+        let mut csv = "upc,old_plu,new_plu,description,reason\n".to_string();
+        for c in changes.iter() {
+            csv.push_str(&c.csv_line());
+            csv.push('\n');
+        }
+        csv_bom(csv, bom)
+    };
+    std::fs::write(file, contents)?;
+    Ok(())
+}
+
+pub struct Scales {}
+
+impl Scales {
+    pub async fn filtered_items(
+        &mut self,
+        api: &mut super::api::ITRApi,
+        settings: &super::settings::Settings,
+        args: &ArgMatches,
+        assign_plus: bool,
+    ) -> Result<Vec<super::api::ProductData>> {
+        let dump_internal = !args.get_flag("external");
+        let plu_range = PLURange::new(settings.scales.internal_plu_max, InternalProducts::from_settings(&settings.scales)?);
+        let item_codes = ItemCodeRange::new(settings.scales.item_code_offset, settings.scales.item_code_length);
+        let prices = PriceCodeRange::new(settings.scales.price_offset, settings.scales.price_length, settings.scales.price_check_digit);
+        let active_only = args.get_flag("active-only") || settings.itretail.active_only;
+        let re = args.get_one::<String>("upc").unwrap();
+        let upc_pat = Regex::new(re)?;
+        let filter = |x: &super::api::ProductData| !x.deleted && upc_pat.is_match(&x.upc).unwrap();
+
+        let json = api
+            .get(&"/api/ProductsData/GetAllProducts".to_string())
+            .await
+            .expect("no results from API call");
+        let mut items: Vec<super::api::ProductData> = serde_json::from_str(&json)?;
+        items = items
+            .into_iter()
+            .filter(filter)
+            .sorted_by(|x, y| x.description.cmp(&y.description))
+            .sorted_by_key(|x| x.section_id.unwrap_or(0))
+            .collect::<Vec<super::api::ProductData>>();
+
+        let mut existing_plu = HashSet::<u16>::new();
+        let mut seen_plu = HashSet::<u16>::new();
+        let mut plu_assignment: Vec<PLUAssignment> = Vec::new();
+        let mut plu_changes: Vec<PLUChange> = Vec::new();
+        // Inactive items keep their PLUs, so they must be reserved even if we won't send them.
+        for item in &items {
+            if let Some(plu) = &item.plu {
+                let plu = plu.parse::<u16>().unwrap();
+                existing_plu.insert(plu);
+            }
+        }
+        // scale-verify shares this and has no --where.
+        let where_filter = args.try_get_one::<super::api::ProductFilter>("where").ok().flatten();
+        items.retain(|x| x.is_available(active_only) && where_filter.is_none_or(|f| f.matches(x)));
+        let allow_zero_price = args.get_flag("allow-zero-price");
+        if !allow_zero_price {
+            let unpriced: Vec<&str> = items.iter().filter(|x| !x.has_price()).map(|x| x.upc.as_str()).collect();
+            if !unpriced.is_empty() {
+                warn!("Skipping {} items with no price (--allow-zero-price to send them): {}", unpriced.len(), unpriced.join(", "));
+                items.retain(|x| x.has_price());
+            }
+        }
+        for item in &items {
+            if let Some(plu) = &item.plu {
+                let plu = plu.parse::<u16>().unwrap();
+                let reason = if seen_plu.contains(&plu) {
+                    Some("duplicate")
+                } else if plu_range.wrong_range(item, plu) {
+                    Some("wrong_range")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    let new_plu = plu_range.next_plu(&mut existing_plu, item);
+                    info!(
+                        "PLU assigned {} bad previous was {} - {}",
+                        new_plu, plu, item.description
+                    );
+                    plu_assignment.push(PLUAssignment {
+                        upc: item.upc.to_string(),
+                        plu: new_plu,
+                    });
+                    plu_changes.push(PLUChange {
+                        upc: item.upc.to_string(),
+                        old_plu: Some(plu),
+                        new_plu,
+                        description: item.description.to_string(),
+                        reason,
+                    });
+                    seen_plu.insert(new_plu);
+                } else {
+                    seen_plu.insert(plu);
+                }
+            } else {
+                let new_plu = plu_range.next_plu(&mut existing_plu, item);
+                plu_assignment.push(PLUAssignment {
+                    upc: item.upc.to_string(),
+                    plu: new_plu,
+                });
+                plu_changes.push(PLUChange {
+                    upc: item.upc.to_string(),
+                    old_plu: None,
+                    new_plu,
+                    description: item.description.to_string(),
+                    reason: "missing",
+                });
+                info!("PLU assigned {} - {}", new_plu, item.description);
+                seen_plu.insert(new_plu);
+            }
+        }
+        if !assign_plus {
+            for pa in plu_assignment.iter() {
+                warn!("{} needs PLU {} assigned; run scale-export to fix", pa.upc, pa.plu);
+            }
+        } else if !plu_assignment.is_empty() {
+            let r = api.set_plu(plu_assignment).await;
+            if r.is_err() {
+                return Err(r.err().unwrap());
+            }
+            let json = api
+                .get(&"/api/ProductsData/GetAllProducts".to_string())
+                .await
+                .expect("no results from API call");
+            items = serde_json::from_str(&json)?;
+            items = items
+                .into_iter()
+                .filter(filter)
+                .filter(|x| x.is_available(active_only))
+                .filter(|x| allow_zero_price || x.has_price())
+                .sorted_by_key(|x| x.section_id.unwrap_or(0))
+                .collect::<Vec<super::api::ProductData>>();
+        }
+        if assign_plus {
+            if let Some(file) = args.try_get_one::<String>("plu-changes").ok().flatten() {
+                match write_plu_changes(file, &plu_changes, args.get_flag("bom")) {
+                    Ok(_) => info!("Wrote {} PLU changes to {}", plu_changes.len(), file),
+                    Err(e) => error!("Error writing PLU changes to {}: {}", file, e),
+                }
+            }
+        }
+
+        items = items
+            .into_iter()
+            .filter(|item| {
+                if item.plu.is_none() {
+                    return false;
+                }
+                let plu = item.plu.as_ref().unwrap().parse::<u16>();
+                if plu.is_err() {
+                    return false;
+                }
+                if !dump_internal && plu_range.is_internal(plu.unwrap()) {
+                    return false;
+                }
+                match item.weighed_barcode(&item_codes, Some(&prices)) {
+                    None => return false,
+                    Some(b) if b.price.unwrap_or(0.0) > 0.0 => {
+                        warn!("{} {} has a price embedded in its UPC, the scale will print its own", item.upc, item.description);
+                    }
+                    _ => {}
+                }
+                true
+            })
+            .collect::<Vec<super::api::ProductData>>();
+        Ok(items)
+    }
+
+    // Assign missing PLUs and write the PLU (and --scale-file) spreadsheets.
+    pub async fn export(
+        &mut self,
+        api: &mut super::api::ITRApi,
+        settings: &super::settings::Settings,
+        args: &ArgMatches,
+    ) -> Result<(Vec<ProductData>, DepartmentMap)> {
+        let weighed_items = self.filtered_items(api, settings, args, true).await?;
+        let plufile = super::api::export_filename(args, "PLU.xlsx")?;
+        let departments = DepartmentMap::new(settings)?;
+        let precision = settings.scales.price_precision;
+        self.build_plu_xlsx(api, &weighed_items, &plufile, precision, &settings.scales.section_order, args).await?;
+        if let Some(scalefile) = args.get_one::<String>("scale-file") {
+            self.build_scale_xlsx(&weighed_items, &departments, scalefile, precision)?;
+        }
+        Ok((weighed_items, departments))
+    }
+
+    pub async fn build_plu_xlsx(
+        &mut self,
+        api: &mut super::api::ITRApi,
+        weighed_items: &Vec<ProductData>,
+        filename: &String,
+        precision: u32,
+        section_order: &[i32],
+        args: &ArgMatches,
+    ) -> Result<()> {
+        let qlimit = args.get_one::<f32>("at-least").unwrap();
+        let by_section = args.get_flag("by-section");
+        let mut workbook = Workbook::new();
+        let bold_format = Format::new().set_bold();
+        let decimal_format = Format::new().set_num_format(&price_num_format(precision));
+
+        let sections: HashMap<i32, String> = api
+            .get_sections()
+            .await?
+            .iter()
+            .map(|s| (s.id.unwrap_or(0), s.name.to_owned()))
+            .collect();
+
+        const FIELDS: [&str; 3] = ["PLU", "Name", "Price"];
+
+        let mut by_sheet: HashMap<i32, Vec<&ProductData>> = HashMap::new();
+        for item in weighed_items {
+            if item.quantity_on_hand.unwrap_or(0.0) <= *qlimit {
+                continue;
+            }
+            let section_id = match by_section {
+                true => item.section_id.unwrap_or(-1),
+                false => 0,
+            };
+            by_sheet.entry(section_id).or_default().push(item);
+        }
+        // Without --sort, PLU order keeps the workbook the same from run to run.
+        let default_sort = vec![SortKey { field: SortField::Plu, descending: false }];
+        let sort_keys = args.get_one::<Vec<SortKey>>("sort").unwrap_or(&default_sort);
+        let unknown_section: String = match by_section {
+            true => String::from("Unknown"),
+            false => String::from("Products"),
+        };
+        for section_id in sheet_order(by_sheet.keys().copied(), section_order) {
+            let items = by_sheet.get_mut(&section_id).unwrap();
+            sort_products(items, sort_keys, &sections);
+            let section_name = sections.get(&section_id).unwrap_or(&unknown_section);
+            let worksheet = workbook.add_worksheet().set_name(section_name)?;
+            for (idx, field) in FIELDS.iter().enumerate() {
+                worksheet.write_with_format(
+                    0,
+                    idx.try_into().unwrap(),
+                    *field,
+                    &bold_format,
+                )?;
+            }
+            for (row, item) in (1..).zip(items.iter()) {
+                let plu = item.plu.as_ref().unwrap().parse::<u16>().unwrap();
+                worksheet.write_number(row, 0, plu)?;
+                worksheet.write_string(row, 1, &item.description)?;
+                worksheet.write_number_with_format(
+                    row,
+                    2,
+                    round_price(item.normal_price, precision),
+                    &decimal_format,
+                )?;
+            }
+        }
+
+        workbook.save(filename)?;
+
+        Ok(())
+    }
+    pub fn build_scale_xlsx(
+        &mut self,
+        weighed_items: &Vec<ProductData>,
+        departments: &DepartmentMap,
+        filename: &String,
+        precision: u32,
+    ) -> Result<()> {
+        const FIELDS: [&str; 19] = [
+            "Department No",
+            "PLU No",
+            "Name1",
+            "Name2",
+            "Itemcode",
+            "Unit Price",
+            "Origin No",
+            "Label No",
+            " Category No",
+            "Direct Ingredient",
+            "Sell By Time",
+            "Sell By Date",
+            "Packed Date",
+            "Group No",
+            "Unit Weight",
+            "Nutrifact No",
+            "PLU Type",
+            "Packed Time",
+            "Update Date",
+        ];
+
+        let mut workbook = Workbook::new();
+        let bold_format = Format::new().set_bold();
+        let decimal_format = Format::new().set_num_format(&price_num_format(precision));
+        let date_format = Format::new().set_num_format("yyyy-mm-dd");
+
+        let date = Local::now().naive_local();
+
+        let worksheet = workbook.add_worksheet();
+        for (idx, field) in FIELDS.iter().enumerate() {
+            worksheet.write_with_format(0, idx.try_into().unwrap(), *field, &bold_format)?;
+        }
+
+        for (row, item) in (1_u32..).zip(weighed_items) {
+            worksheet.write_number(row, 0, departments.scale_department(item.department_id))?;
+            let plu = item.plu.as_ref().unwrap().parse::<u16>().unwrap();
+            worksheet.write_number(row, 1, plu)?;
+            worksheet.write_string(row, 2, &item.description)?;
+            // 3 Name2 (blank)
+            worksheet.write_number(row, 4, departments.item_code(item))?;
+            worksheet.write_number_with_format(row, 5, round_price(item.normal_price, precision), &decimal_format)?;
+            worksheet.write_number(row, 6, departments.origin(item.department_id))?; // Origin
+            worksheet.write_number(row, 7, departments.label_id(item))?; // Label ID
+            worksheet.write_number(row, 8, 0)?; // Category
+            if let Some(ingredients) = ingredients(item) {
+                worksheet.write_string(row, 9, ingredients)?; // Direct Ingredient
+            }
+            worksheet.write_number(row, 10, 0)?; // Sell by Time
+            worksheet.write_number(row, 11, departments.sell_by_days(item.department_id))?; // Sell by Date (days)
+            worksheet.write_number(row, 12, 0)?; // Packed Date
+            worksheet.write_number(row, 13, 0)?; // Group No
+            worksheet.write_number(row, 14, 1)?; // Unit Weight (1 lb)
+            worksheet.write_number(row, 15, 0)?; // Nutrifact No
+            worksheet.write_number(row, 16, 1)?; // PLU Type: 1 - weighed
+            worksheet.write_number(row, 17, 0)?; // Packed Time
+            worksheet.write_with_format(row, 18, &date, &date_format)?;
+
+            info!(
+                "Writing: [{}] {} : {} : {}",
+                plu, item.upc, item.description, item.normal_price
+            );
+        }
+
+        workbook.save(filename)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sheet_order() {
+        assert_eq!(sheet_order([5, -1, 2, 9].into_iter(), &[]), vec![2, 5, 9, -1]);
+        assert_eq!(sheet_order([5, -1, 2, 9].into_iter(), &[9, 7, 5]), vec![9, 5, 2, -1]);
+        assert_eq!(sheet_order([0].into_iter(), &[3]), vec![0]);
+    }
+}
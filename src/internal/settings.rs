@@ -1,5 +1,6 @@
 use config::{Config, ConfigError, Environment, File};
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -8,7 +9,13 @@ pub struct ITRetail {
     pub username: String,
     pub password: String,
     pub store_id: String,
+    // Point at a staging (or mock) IT Retail instead of production.
+    pub base_url: String,
     pub external_sale_shrink_reason: u32,
+    pub active_only: bool,
+    pub update_batch_rows: usize,
+    pub connect_timeout_seconds: u64,
+    pub request_timeout_seconds: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -16,6 +23,8 @@ pub struct ITRetail {
 pub struct LocalExpress {
     pub username: String,
     pub password: String,
+    pub connect_timeout_seconds: u64,
+    pub request_timeout_seconds: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -23,6 +32,18 @@ pub struct LocalExpress {
 pub struct Mailchimp {
     pub token: String,
     pub dc: String,
+    pub full_sync_hours: u32,
+    // DNS-over-HTTPS JSON endpoint used by mailchimp-sync --check-mx
+    pub doh_url: String,
+    // MM/DD birthday merge field written by mailchimp-sync --birthday
+    pub birthday_merge_field: String,
+    // Merge field holding the IT Retail customer id, so email changes update the member.
+    pub id_merge_field: String,
+    // IT Retail field (first_name, last_name, phone, discount) -> the list's merge tag;
+    // an empty tag leaves that field out of Mailchimp.
+    pub merge_fields: HashMap<String, String>,
+    pub connect_timeout_seconds: u64,
+    pub request_timeout_seconds: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -30,6 +51,41 @@ pub struct Mailchimp {
 pub struct Scales {
     pub addresses: Vec<String>,
     pub timeout_seconds: u32,
+    // IT Retail department id -> scale department number; empty means use the ids as-is.
+    pub department_map: HashMap<String, u16>,
+    pub default_department: u16,
+    // IT Retail department id -> CAS origin number and sell-by days for its labels.
+    pub department_origin: HashMap<String, u16>,
+    pub department_sell_by_days: HashMap<String, u32>,
+    // PLUs up to this are reserved for internal items.
+    pub internal_plu_max: u16,
+    // A product is internal when its description starts with internal_prefix (empty
+    // for none), it's in one of these departments or sections, or it matches
+    // internal_where, a --where expression (empty for none).
+    pub internal_prefix: String,
+    pub internal_departments: Vec<i32>,
+    pub internal_sections: Vec<i32>,
+    pub internal_where: String,
+    // CAS label template numbers: ingredient for items with a second description,
+    // shrink for items matching shrink_where (a --where expression, empty for
+    // none), standard for everything else.
+    pub standard_label_id: u16,
+    pub ingredient_label_id: u16,
+    pub shrink_label_id: u16,
+    pub shrink_where: String,
+    // Decimal places for prices in the PLU and scale spreadsheets.
+    pub price_precision: u32,
+    // Section ids whose scale-export --by-section sheets come first, in this order;
+    // the rest follow by id.
+    pub section_order: Vec<i32>,
+    // Where weighed barcodes embed the scale item code: UPC digits offset..offset+length.
+    pub item_code_offset: usize,
+    pub item_code_length: usize,
+    // Where scanned price-embedded labels carry the price in cents, same numbering.
+    // price_check_digit when the digit before the price is a price check digit.
+    pub price_offset: usize,
+    pub price_length: usize,
+    pub price_check_digit: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -58,14 +114,75 @@ pub struct Square {
     pub production_secret: String,
     pub location: String,
     pub max_retries: u32,
+    pub retry_min_interval_seconds: u64,
+    pub retry_base: u32,
+    // The --period sync loop can afford to wait; interactive commands shouldn't look hung.
+    pub retry_max_interval_seconds: u64,
+    pub interactive_retry_max_interval_seconds: u64,
+    // Warn when the retry schedule can back off longer than this between attempts.
+    pub retry_warn_seconds: u64,
     pub weight_unit: String,
     pub weight_precision: i32,
+    // Measures for weighed items that don't sell by weight_unit, as UNIT[:PRECISION]
+    // (e.g. "unit:0" or "imperial_pound:2"), by UPC and by IT Retail department.
+    pub product_measures: HashMap<String, String>,
+    pub department_measures: HashMap<String, String>,
+    pub customers_full_sync_hours: u32,
+    pub timeout_seconds: u32,
+    // Square location names (or ids) catalog items are present at; empty means just `location`.
+    pub present_locations: Vec<String>,
+    // Use the IT Retail size as the variation name, when there is one.
+    pub size_variation_name: bool,
+    // Describe online items with the IT Retail second description (ingredients), when there is one.
+    pub item_descriptions: bool,
+    // Which side's email and phone survive a customer sync conflict:
+    // itr_wins, square_wins or newest_wins (by modification time).
+    pub contact_source: String,
+    // What IT Retail customers are matched to Square ones by, in order of trust:
+    // reference_id (the IT Retail id Square holds), email and phone.
+    pub match_keys: Vec<String>,
+    // IT Retail departments and sections kept out of the online store.
+    pub offline_departments: Vec<i32>,
+    pub offline_sections: Vec<i32>,
+    // IT Retail departments whose products without a tax class still get the location tax.
+    pub taxed_departments: Vec<i32>,
+    // ISO 4217 code of the Square account's currency (USD, CAD, ...).
+    pub currency: String,
+    // State whose tax to use when the Square location's address has none.
+    pub tax_state: String,
+    // How sure a --fuzzy-match customer match must be, 0.0 to 1.0.
+    pub fuzzy_match_threshold: f64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Postgres {
     pub connect_string: String,
+    // Large stores commit every this many rows; 0 keeps each store in one transaction.
+    pub commit_rows: u32,
+    // Days of itrproduct_archive snapshots to keep; 0 keeps them all.
+    pub archive_retention_days: u32,
+    // Square product sync reads products this many at a time; 0 reads them all at once.
+    pub product_page_rows: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Sidedb {
+    // postgres (postgres.connect_string) or sqlite (sqlite_path).  sqlite only
+    // holds the catalog: departments, sections, taxes and products.
+    pub backend: String,
+    pub sqlite_path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Stripe {
+    pub secret_key: String,
+    // Stripe customer metadata key holding the IT Retail customer id.
+    pub id_metadata_key: String,
+    pub connect_timeout_seconds: u64,
+    pub request_timeout_seconds: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -73,6 +190,28 @@ pub struct Postgres {
 pub struct Tasmota {
     pub light1: String,
     pub light2: String,
+    // More lights by name -> IP, for rules beyond light1 and light2.
+    pub lights: HashMap<String, String>,
+    // le-orders light rules, "[today.]<counter> <op> <n> -> <light> [on|off]"; see tasmota::parse_rule.
+    // A light no rule names is never switched, even outside store hours.
+    pub rules: Vec<String>,
+    // Store hours as HH:MM local time; lights are only lit between them.  Empty means always open.
+    pub open_time: String,
+    pub close_time: String,
+    // Tries per light command, waiting retry_base_ms and then twice as long each time between them.
+    pub attempts: u32,
+    pub retry_base_ms: u64,
+    // A plug that stops answering fails its attempt after these, so it can be retried.
+    pub connect_timeout_seconds: u64,
+    pub request_timeout_seconds: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct TVMenu {
+    // Category name -> backdrop image for that category's screen in tvmenu --pull all.
+    // Names match case-insensitively; unmapped categories use --backdrop or the built-in one.
+    pub category_backdrops: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -83,8 +222,11 @@ pub struct Settings {
     pub mailchimp: Mailchimp,
     pub postgres: Postgres,
     pub scales: Scales,
+    pub sidedb: Sidedb,
     pub square: Square,
+    pub stripe: Stripe,
     pub tasmota: Tasmota,
+    pub tvmenu: TVMenu,
 }
 
 impl Settings {
@@ -108,14 +250,58 @@ impl Settings {
             .add_source(Environment::with_prefix("app"))
             // You may also programmatically change settings?
             .set_default("itretail.store_id", "")?
+            .set_default("itretail.base_url", "https://retailnext.itretail.com")?
             .set_default("itretail.username", "")?
             .set_default("itretail.password", "")?
             .set_default("itretail.external_sale_shrink_reason", 5)?
+            .set_default("itretail.active_only", false)?
+            .set_default("itretail.update_batch_rows", 1000)?
+            .set_default("itretail.connect_timeout_seconds", 10)?
+            .set_default("itretail.request_timeout_seconds", 300)?
+            .set_default("localexpress.connect_timeout_seconds", 10)?
+            .set_default("localexpress.request_timeout_seconds", 60)?
             .set_default("postgres.connect_string", "")?
+            .set_default("postgres.commit_rows", 1000)?
+            .set_default("postgres.archive_retention_days", 0)?
+            .set_default("postgres.product_page_rows", 0)?
+            .set_default("sidedb.backend", "postgres")?
+            .set_default("sidedb.sqlite_path", format!("{}/sidedb.sqlite3", basepath))?
             .set_default("mailchimp.token", "")?
             .set_default("mailchimp.dc", "us21")?
+            .set_default("mailchimp.full_sync_hours", 24)?
+            .set_default("mailchimp.doh_url", "https://dns.google/resolve")?
+            .set_default("mailchimp.birthday_merge_field", "BIRTHDAY")?
+            .set_default("mailchimp.id_merge_field", "ITRID")?
+            .set_default("mailchimp.merge_fields", config::Map::<String, String>::from([
+                ("first_name".to_owned(), "FNAME".to_owned()),
+                ("last_name".to_owned(), "LNAME".to_owned()),
+                ("phone".to_owned(), "PHONE".to_owned()),
+                ("discount".to_owned(), "ITDISCOUNT".to_owned()),
+            ]))?
+            .set_default("mailchimp.connect_timeout_seconds", 10)?
+            .set_default("mailchimp.request_timeout_seconds", 60)?
             .set_default("scales.addresses", Vec::<String>::with_capacity(0))?
             .set_default("scales.timeout_seconds", 300)?
+            .set_default("scales.department_map", config::Map::<String, u16>::new())?
+            .set_default("scales.default_department", 1)?
+            .set_default("scales.department_origin", config::Map::<String, u16>::new())?
+            .set_default("scales.department_sell_by_days", config::Map::<String, u32>::new())?
+            .set_default("scales.internal_plu_max", 999)?
+            .set_default("scales.internal_prefix", "(I)")?
+            .set_default("scales.internal_departments", Vec::<i32>::with_capacity(0))?
+            .set_default("scales.internal_sections", Vec::<i32>::with_capacity(0))?
+            .set_default("scales.internal_where", "")?
+            .set_default("scales.standard_label_id", 61)?
+            .set_default("scales.ingredient_label_id", 62)?
+            .set_default("scales.shrink_label_id", 51)?
+            .set_default("scales.shrink_where", "")?
+            .set_default("scales.price_precision", 2)?
+            .set_default("scales.section_order", Vec::<i32>::with_capacity(0))?
+            .set_default("scales.item_code_offset", 3)?
+            .set_default("scales.item_code_length", 5)?
+            .set_default("scales.price_offset", 8)?
+            .set_default("scales.price_length", 5)?
+            .set_default("scales.price_check_digit", false)?
             .set_default("square.environment", "Production")?
             .set_default("square.sandbox_appid", "")?
             .set_default("square.sandbox_secret", "")?
@@ -124,10 +310,43 @@ impl Settings {
             .set_default("square.location", "")?
             .set_default("square.weight_unit", "IMPERIAL_POUND")?
             .set_default("square.weight_precision", 3)?
+            .set_default("square.product_measures", config::Map::<String, String>::new())?
+            .set_default("square.department_measures", config::Map::<String, String>::new())?
             .set_default("square.location", "")?
             .set_default("square.max_retries", 3)?
+            .set_default("square.retry_min_interval_seconds", 1)?
+            .set_default("square.retry_base", 3)?
+            .set_default("square.retry_max_interval_seconds", 30 * 60)?
+            .set_default("square.interactive_retry_max_interval_seconds", 60)?
+            .set_default("square.retry_warn_seconds", 300)?
+            .set_default("square.customers_full_sync_hours", 24)?
+            .set_default("square.timeout_seconds", 30)?
+            .set_default("square.present_locations", Vec::<String>::with_capacity(0))?
+            .set_default("square.size_variation_name", false)?
+            .set_default("square.item_descriptions", false)?
+            .set_default("square.contact_source", "itr_wins")?
+            .set_default("square.match_keys", vec!["reference_id", "email", "phone"])?
+            .set_default("square.currency", "USD")?
+            .set_default("square.offline_departments", Vec::<i32>::with_capacity(0))?
+            .set_default("square.offline_sections", Vec::<i32>::with_capacity(0))?
+            .set_default("square.taxed_departments", Vec::<i32>::with_capacity(0))?
+            .set_default("square.tax_state", "")?
+            .set_default("square.fuzzy_match_threshold", 0.9)?
+            .set_default("stripe.secret_key", "")?
+            .set_default("stripe.id_metadata_key", "itretail_id")?
+            .set_default("stripe.connect_timeout_seconds", 10)?
+            .set_default("stripe.request_timeout_seconds", 60)?
             .set_default("tasmota.light1", "192.168.202.7")?
             .set_default("tasmota.light2", "192.168.202.151")?
+            .set_default("tasmota.lights", config::Map::<String, String>::new())?
+            .set_default("tasmota.rules", vec!["today.active > 0 -> light1", "parked > 0 -> light2"])?
+            .set_default("tasmota.open_time", "")?
+            .set_default("tasmota.close_time", "")?
+            .set_default("tasmota.attempts", 3)?
+            .set_default("tasmota.retry_base_ms", 250)?
+            .set_default("tasmota.connect_timeout_seconds", 3)?
+            .set_default("tasmota.request_timeout_seconds", 5)?
+            .set_default("tvmenu.category_backdrops", config::Map::<String, String>::new())?
             .build()?;
 
         // You can deserialize (and thus freeze) the entire configuration as
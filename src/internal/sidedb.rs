@@ -1,17 +1,35 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use tokio::task::JoinHandle;
 use tokio_postgres::NoTls;
 use rust_decimal::prelude::*;
-use chrono::{NaiveDate, NaiveDateTime};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use log::*;
 use uuid::Uuid;
-use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
 
 use super::api::{Customer, Department, ITRTaxId, ProductData, Section, ShrinkAmount, Tax};
 
 use squareup::models::{enums::{Currency, OrderState, PaymentSourceType, PaymentStatus}, Money};
 
-struct SSql {}
+#[derive(Debug)]
+pub struct PriceChange {
+    pub upc: String,
+    pub price: f64,
+    pub special_price: Option<f64>,
+    pub effective_at: DateTime<Utc>,
+}
+
+// Who placed a LocalExpress order, as they gave it on that order.
+#[derive(Debug)]
+pub struct LECustomer {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+}
+
+pub(crate) struct SSql {}
 impl SSql {
     pub fn from_order_state(o: &Option<OrderState>) -> Option<String> {
         match o {
@@ -50,11 +68,12 @@ impl SSql {
             }.to_owned())
         }
     }
-    pub fn from_money(o: &Option<Money>) -> Option<Decimal> {
+    // Amounts in any currency other than the store's are refused rather than mixed in.
+    pub fn from_money(o: &Option<Money>, currency: &Currency) -> Option<Decimal> {
         match o {
             None => Some(Decimal::ZERO),
             Some(ov) => {
-                if ov.currency != Currency::Usd {
+                if &ov.currency != currency {
                     None
                 } else {
                     Some(Decimal::new(ov.amount as i64, 2))
@@ -64,26 +83,113 @@ impl SSql {
     }
 }
 
+// What a sidedb backend (sidedb.backend) keeps.  Both keep the catalog and sync
+// state.  Customers, orders and transactions are only kept in postgres; the
+// defaults refuse them, and make_sidedb refuses sqlite for commands that need them.
+#[async_trait]
+pub trait SideDbBackend: Send {
+    async fn get_sync_state(&mut self, name: &str) -> Result<Option<DateTime<Utc>>>;
+    async fn set_sync_state(&mut self, name: &str, when: &DateTime<Utc>) -> Result<()>;
+
+    async fn store_taxes(&mut self, taxes: &[&Tax]) -> Result<u32>;
+    async fn store_departments(&mut self, depts: &[&Department]) -> Result<u32>;
+    async fn get_departments(&self) -> Result<Vec<Department>>;
+    async fn associate_department_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool>;
+    async fn store_sections(&mut self, sections: &[&Section]) -> Result<u32>;
+    async fn get_sections(&self) -> Result<Vec<Section>>;
+    async fn associate_section_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool>;
+    async fn store_products(&mut self, products: &[&ProductData], archive: bool, commit_rows: u32) -> Result<u32>;
+    async fn associate_product_with_square(&mut self, upc: &str, squareup_id: &str) -> Result<bool>;
+    async fn prune_product_archive(&mut self, keep_days: u32) -> Result<u64>;
+    async fn get_price_history(&mut self, upc: &str) -> Result<Vec<PriceChange>>;
+    async fn query_products(&mut self, date: Option<&NaiveDate>, include_deleted: bool) -> Result<Vec<ProductData>>;
+    async fn query_products_page(&mut self, include_deleted: bool, after: Option<&str>, limit: u32) -> Result<Vec<ProductData>>;
+    async fn get_archived_products(&mut self, date: Option<&NaiveDate>) -> Result<Vec<ProductData>>;
+    async fn get_product_upcs(&mut self, include_deleted: bool) -> Result<Vec<String>>;
+    async fn get_product(&mut self, upc: &str, include_deleted: bool) -> Result<Option<ProductData>>;
+
+    async fn store_txns(&mut self, _txns: &[&super::api::EJTxn], _commit_rows: u32) -> Result<(u32, u32)> {
+        Err(postgres_only())
+    }
+    async fn store_customers(&mut self, _customers: &[Customer], _commit_rows: u32) -> Result<u32> {
+        Err(postgres_only())
+    }
+    async fn store_customer_batch(&mut self, _customers: &[Customer]) -> Result<u32> {
+        Err(postgres_only())
+    }
+    async fn get_customer_pull_resume(&mut self) -> Result<Option<Uuid>> {
+        Err(postgres_only())
+    }
+    async fn clear_customer_pull(&mut self) -> Result<()> {
+        Err(postgres_only())
+    }
+    async fn associate_customer_with_square(&mut self, _id: &Uuid, _squareup_id: &str) -> Result<bool> {
+        Err(postgres_only())
+    }
+    async fn delete_customer(&mut self, _id: &Uuid) -> Result<bool> {
+        Err(postgres_only())
+    }
+    async fn get_customer_household(&mut self) -> Result<Vec<(Uuid, Uuid)>> {
+        Err(postgres_only())
+    }
+    async fn get_customers_ex(&mut self, _deleted: bool) -> Result<Vec<Customer>> {
+        Err(postgres_only())
+    }
+    async fn store_orders(&mut self, _orders: &[&super::localexpress::Order], _commit_rows: u32) -> Result<u32> {
+        Err(postgres_only())
+    }
+    async fn get_le_customers(&mut self) -> Result<Vec<LECustomer>> {
+        Err(postgres_only())
+    }
+    async fn shrink_square_products_sold(&mut self, _itrapi: &mut super::api::ITRApi, _shrink_reason: u32) -> Result<u32> {
+        Err(postgres_only())
+    }
+    async fn store_square_transactions(&mut self, _payments: &[squareup::models::Payment], _currency: &Currency) -> Result<u32> {
+        Err(postgres_only())
+    }
+    async fn store_square_orders(&mut self, _orders: &[squareup::models::Order], _currency: &Currency) -> Result<u32> {
+        Err(postgres_only())
+    }
+    async fn get_spend(&mut self, _days: u32) -> Result<Vec<(Uuid, Decimal)>> {
+        Err(postgres_only())
+    }
+}
+
+fn postgres_only() -> anyhow::Error {
+    anyhow!("this needs the postgres sidedb backend (sidedb.backend)")
+}
+
+// What a command keeps in the sidedb; sqlite can only serve Catalog.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SideDbUse {
+    Catalog,
+    Everything,
+}
+
 pub struct SideDb {
-    client: tokio_postgres::Client,
-    handle: JoinHandle<()>,
+    backend: Box<dyn SideDbBackend>,
     shrink_reason: u32,
+    commit_rows: u32,
+    archive_products: bool,
+    product_page_rows: u32,
+    currency: Currency,
 }
 
-impl Drop for SideDb {
-    fn drop(&mut self) {
-        self.handle.abort();
-    }
+pub async fn make_sidedb(settings: super::settings::Settings, uses: SideDbUse) -> Result<SideDb> {
+    let backend: Box<dyn SideDbBackend> = match settings.sidedb.backend.to_lowercase().as_str() {
+        "postgres" => Box::new(PostgresSideDb::connect(&settings.postgres.connect_string).await?),
+        "sqlite" if uses == SideDbUse::Catalog => Box::new(super::sqlite::SqliteSideDb::open(&settings.sidedb.sqlite_path)?),
+        "sqlite" => return Err(anyhow!("the sqlite sidedb only holds the catalog; this needs sidedb.backend = \"postgres\"")),
+        _ => return Err(anyhow!("Invalid sidedb backend in config: {}", settings.sidedb.backend)),
+    };
+    Ok(SideDb{backend, shrink_reason: settings.itretail.external_sale_shrink_reason,
+              commit_rows: settings.postgres.commit_rows, archive_products: true, product_page_rows: settings.postgres.product_page_rows,
+              currency: super::square::parse_currency(&settings.square.currency)?})
 }
 
-pub async fn make_sidedb(settings: super::settings::Settings) -> Result<SideDb> {
-    let (client, connection) = tokio_postgres::connect(&settings.postgres.connect_string, NoTls).await?;
-    let handle = tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            error!("connection error: {}", e);
-        }
-    });
-    Ok(SideDb{client: client, handle: handle, shrink_reason: settings.itretail.external_sale_shrink_reason})
+// Whether a store that has written `rows` rows should commit and start a new transaction.
+fn commit_due(commit_rows: u32, rows: u32) -> bool {
+    commit_rows > 0 && rows > 0 && rows.is_multiple_of(commit_rows)
 }
 
 fn decimal_price(a: &str) -> Decimal {
@@ -94,150 +200,80 @@ fn some_f32_to_some_decimal(a: &Option<f32>) -> Option<Decimal> {
     else { Decimal::from_f32(a.unwrap()) }
 }
 impl SideDb {
-    pub async fn store_txns<'a, I>(&mut self, txns: I) -> Result<u32>
+    // Zero runs each store in a single transaction, all or nothing.
+    pub fn set_commit_rows(&mut self, rows: u32) {
+        self.commit_rows = rows;
+    }
+    // Skipping the itrproduct_archive snapshot keeps frequent product syncs cheap.
+    pub fn set_archive_products(&mut self, archive: bool) {
+        self.archive_products = archive;
+    }
+
+    // A transaction that can't be stored is logged and skipped rather than
+    // failing the whole window; returns (stored, skipped).
+    pub async fn store_txns<'a, I>(&mut self, txns: I) -> Result<(u32, u32)>
     where
         I: Iterator<Item = &'a super::api::EJTxn>
     {
-        let sqltxn = self.client.transaction().await?;
-        let mut cnt = 0;
-        for t in txns {
-            let td = NaiveDateTime::parse_from_str(&t.transaction_date, "%Y-%m-%dT%H:%M:%S%.f")?;
-            let num_rows = sqltxn.execute("INSERT INTO itrejtxn (transaction_id, customer_id, transaction_date, canceled, total)
-            VALUES($1,$2,$3,$4,$5) ON CONFLICT DO NOTHING",
-            &[&t.id, &t.customer_id, &td, &t.canceled, &Decimal::from_f64(t.total)]).await?;
-            if num_rows > 0 {
-                if let Some(products) = t.transaction_products.as_ref() {
-                    for p in products {
-                        let upc = match &p.product_change {
-                            Some(pc) => Some(pc.upc.clone()),
-                            None => None,
-                        };
-                        sqltxn.execute("INSERT INTO itrejtxn_products
-                            (transaction_subid, transaction_id, product_id, upc, is_voided, is_refunded, price, line_discount, quantity, weight)
-                            VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10) ON CONFLICT DO NOTHING",
-                        &[&p.id, &t.id, &p.product_id, &upc, &p.is_voided, &p.is_refunded,
-                          &Decimal::from_f64(p.price), &Decimal::from_f64(p.line_discount), &p.quantity, &p.weight]).await?;
-                    }
-                }
-                cnt += 1;
-            }
-        }
-        sqltxn.commit().await?;
-        Ok(cnt)
+        let txns: Vec<&super::api::EJTxn> = txns.collect();
+        self.backend.store_txns(&txns, self.commit_rows).await
     }
     pub async fn store_customers<'a, I>(&mut self, customers: I) -> Result<u32>
     where
         I: Iterator<Item = super::api::Customer>,
     {
+        let customers: Vec<Customer> = customers.collect();
+        let seen: HashSet<Uuid> = customers.iter().map(|c| c.id).collect();
+        let cnt = self.backend.store_customers(&customers, self.commit_rows).await?;
+        self.retire_customers(&seen).await?;
+        Ok(cnt)
+    }
+    // Store one batch of a full customer pull in its own transaction, along
+    // with the id of the last customer in it so an interrupted pull can be
+    // resumed.  Customers outside the batch are left alone.
+    pub async fn store_customer_batch(&mut self, customers: &[super::api::Customer]) -> Result<u32> {
+        self.backend.store_customer_batch(customers).await
+    }
+    pub async fn get_customer_pull_resume(&mut self) -> Result<Option<Uuid>> {
+        self.backend.get_customer_pull_resume().await
+    }
+    // A full pull completed: forget the resume point and retire customers
+    // IT Retail no longer lists.
+    pub async fn finish_customer_pull(&mut self, seen: &HashSet<Uuid>) -> Result<()> {
+        self.backend.clear_customer_pull().await?;
+        self.retire_customers(seen).await
+    }
+    async fn retire_customers(&mut self, seen: &HashSet<Uuid>) -> Result<()> {
         let existing = { self.get_customers().await? };
-        let mut to_delete: HashMap<Uuid, &Customer> = HashMap::new();
-        for c in existing.iter() {
-           to_delete.insert(c.id, &c);
-        }
-        let total_db_size = to_delete.len() as f64;
-
-        let txn = { self.client.transaction().await? };
-        let mut cnt = 0;
-
-        for c in customers {
-            debug!("copying {}", c.email.as_ref().unwrap_or(&"<unknown>".to_string()));
-            to_delete.remove(&c.id);
-            let bd = match c.birth_date.as_ref() {
-                Some(d) => {
-                    match NaiveDate::parse_from_str(&d, "%Y-%m-%d") {
-                        Ok(r) => Some(r),
-                        Err(_) => None,
-                    }
-                },
-                None => None,
-            };
-            let ed = match c.expiration_date.as_ref() {
-                Some(d) => {
-                    match NaiveDateTime::parse_from_str(&d, "%Y-%m-%dT%H:%M:%S%.f") {
-                        Ok(r) => Some(r),
-                        Err(_) => None,
-                    }
-                },
-                None => None,
-            };
-            let cd = match c.created.as_ref() {
-                Some(d) => {
-                    match NaiveDateTime::parse_from_str(&d, "%Y-%m-%dT%H:%M:%S%.f") {
-                        Ok(r) => Some(r),
-                        Err(e) => {
-                            error!("Can't convert '{}': {}", d, e);
-                            None
-                        },
-                    }
-                },
-                None => None,
-            };
-            let md = match c.modified.as_ref() {
-                Some(d) => {
-                    match NaiveDateTime::parse_from_str(&d, "%Y-%m-%dT%H:%M:%S%.f") {
-                        Ok(r) => Some(r),
-                        Err(_) => None,
-                    }
-                },
-                None => None,
-            };
-            let modified_by = match c.modified_by { Some(id) => Some(id as i32), None => None };
-            let inc = match c.inc { Some(id) => Some(id as i64), None => None };
-            let re = txn.execute("INSERT INTO customer
-                            (customer_id, card_no, first_name, last_name, birth_date, phone,
-                             discount, deleted, email, balance, balance_limit, loyalty_points, expiration_date,
-                             instore_charge_enabled, address1, address2, city, state, zipcode, created, modified, modified_by,
-                             frequent_shopper, cash_back, inc)
-                             VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16,$17,$18,$19,$20,$21,$22,$23,$24,$25)
-                             ON CONFLICT (customer_id) DO UPDATE SET card_no = EXCLUDED.card_no, first_name = EXCLUDED.first_name,
-                                last_name = EXCLUDED.last_name, birth_date = EXCLUDED.birth_date, phone = EXCLUDED.phone,
-                                discount = EXCLUDED.discount, deleted = EXCLUDED.deleted, email = EXCLUDED.email,
-                                balance = EXCLUDED.balance, balance_limit = EXCLUDED.balance_limit, loyalty_points = EXCLUDED.loyalty_points,
-                                expiration_date = EXCLUDED.expiration_date, instore_charge_enabled = EXCLUDED.instore_charge_enabled,
-                                address1 = coalesce(EXCLUDED.address1, customer.address1), address2 = coalesce(EXCLUDED.address2, customer.address2),
-                                city = coalesce(EXCLUDED.city, customer.city), state = coalesce(EXCLUDED.state, customer.state),
-                                zipcode = coalesce(EXCLUDED.zipcode, customer.zipcode),
-                                created = coalesce(EXCLUDED.created, customer.created), modified = coalesce(EXCLUDED.modified, customer.modified),
-                                modified_by = coalesce(EXCLUDED.modified_by, customer.modified_by), frequent_shopper = EXCLUDED.frequent_shopper,
-                                cash_back = coalesce(EXCLUDED.cash_back, customer.cash_back), inc = coalesce(EXCLUDED.inc, customer.inc)",
-                        &[&c.id, &c.card_no, &c.first_name, &c.last_name, &bd, &c.phone,
-                                  &(c.discount.unwrap_or(0) as i32), &c.deleted, &c.email,
-                                  &Decimal::from_f64(c.balance.unwrap_or(0.0)), &Decimal::from_f64(c.balance_limit.unwrap_or(0.0)),
-                                  &c.loyalty_points.unwrap_or(0), &ed, &(c.instore_charge_enabled.unwrap_or(false)),
-                                  &c.address1, &c.address2, &c.city, &c.state, &c.zipcode, &cd, &md, &modified_by,
-                                  &(c.frequent_shopper.unwrap_or(false)),&Decimal::from_f64(c.cash_back.unwrap_or(0.0)),&inc]).await?;
-            cnt = cnt + re as u32;
-        }
-        txn.commit().await?;
+        let total_db_size = existing.len() as f64;
+        let to_delete: Vec<&Customer> = existing.iter().filter(|c| !seen.contains(&c.id)).collect();
         if to_delete.len() as f64 / total_db_size > 0.02 {
             error!("We want to delete {} customers out of {}, that's scary high. You'll need to do that manually.",
                    to_delete.len(), total_db_size);
         }
         else {
             info!("Marking {} customers as deleted.", to_delete.len());
-            for (id, c) in to_delete {
-                info!("Marking {} ({} {} {} {}) as deleted.", id, c.first_name, c.last_name, c.email.as_ref().unwrap_or(&"n/a".to_string()), c.phone.as_ref().unwrap_or(&"n/a".to_string()));
-                let _ = self.delete_customer(&id).await;
+            for c in to_delete {
+                info!("Marking {} ({} {} {} {}) as deleted.", c.id, c.first_name, c.last_name, c.email.as_ref().unwrap_or(&"n/a".to_string()), c.phone.as_ref().unwrap_or(&"n/a".to_string()));
+                let _ = self.delete_customer(&c.id).await;
             }
         }
-        Ok(cnt)
+        Ok(())
     }
-    pub async fn associate_customer_with_square(&mut self, id: &Uuid, squareup_id: &String) -> Result<bool> {
-        let txn = self.client.transaction().await?;
-        let rc = txn.execute("UPDATE customer SET squareup_id=$1 WHERE customer_id = $2", &[squareup_id, id]).await?;
-        txn.commit().await?;
-        Ok(rc > 0)
+    pub async fn associate_customer_with_square(&mut self, id: &Uuid, squareup_id: &str) -> Result<bool> {
+        self.backend.associate_customer_with_square(id, squareup_id).await
+    }
+    pub async fn get_sync_state(&mut self, name: &str) -> Result<Option<DateTime<Utc>>> {
+        self.backend.get_sync_state(name).await
+    }
+    pub async fn set_sync_state(&mut self, name: &str, when: &DateTime<Utc>) -> Result<()> {
+        self.backend.set_sync_state(name, when).await
     }
     pub async fn delete_customer(&mut self, id: &Uuid) -> Result<bool> {
-        let txn = self.client.transaction().await?;
-        let rc = txn.execute("UPDATE customer SET deleted=true WHERE customer_id = $1", &[id]).await?;
-        txn.commit().await?;
-        Ok(rc > 0)
+        self.backend.delete_customer(id).await
     }
     pub async fn get_customer_household(&mut self) -> Result<Vec<(Uuid, Uuid)>> {
-        let rows = self.client.query("SELECT main, resident FROM customer_house", &[]).await?;
-        let rels = rows.iter().map(|x| { (x.get("main"), x.get("resident")) }).collect();
-        Ok(rels)
+        self.backend.get_customer_household().await
     }
     pub async fn get_customers(&mut self) -> Result<Vec<Customer>> {
         self.get_customers_ex(false).await
@@ -246,78 +282,185 @@ impl SideDb {
         self.get_customers_ex(true).await
     }
     pub async fn get_customers_ex(&mut self, deleted: bool) -> Result<Vec<Customer>> {
-        let sql = if deleted {
-            "SELECT * FROM customer"
-        } else {
-            "SELECT * FROM customer WHERE NOT deleted"
-        };
-        let rows = self.client.query(sql, &[]).await?;
-        let customers = rows.iter().map(|x| {
-            Customer{ id: x.get("customer_id"), card_no: x. get("card_no"),
-                      last_name: x.get("last_name"), first_name: x.get("first_name"),
-                      birth_date: x.get::<&str,Option<NaiveDate>>("birth_date").and_then(|x| Some(x.to_string())),
-                      phone: x.get("phone"), discount: Some(x.get::<&str,i32>("discount") as u8),
-                      deleted: x.get("deleted"), email: x.get("email"), balance: x.get::<&str,Option<Decimal>>("balance").and_then(|x| x.to_f64()),
-                      balance_limit: x.get::<&str,Option<Decimal>>("balance_limit").and_then(|x| x.to_f64()),
-                      loyalty_points: Some(x.get("loyalty_points")),
-                      expiration_date: x.get::<&str,Option<NaiveDateTime>>("expiration_date").and_then(|x| Some(x.to_string())),
-                      instore_charge_enabled: Some(x.get("instore_charge_enabled")),
-                      address1: x.get("address1"), address2: x.get("address2"),
-                      city: x.get("city"), state: x.get("state"), zipcode: x.get("zipcode"),
-                      created: x.get::<&str,Option<NaiveDateTime>>("created").and_then(|x| Some(x.to_string())),
-                      modified: x.get::<&str,Option<NaiveDateTime>>("modified").and_then(|x| Some(x.to_string())),
-                      modified_by: x.get::<&str,Option<i32>>("modified_by").and_then(|x| Some(x as u32)),
-                      frequent_shopper: x.get("frequent_shopper"),
-                      cash_back: x.get::<&str,Option<Decimal>>("cash_back").and_then(|x| x.to_f64()),
-                      inc: x.get::<&str,Option<i64>>("inc").and_then(|x| Some(x as u32)),
-                      squareup_id: x.get("squareup_id"),
-            }
-        }).collect();
-        Ok(customers)
+        self.backend.get_customers_ex(deleted).await
     }
     pub async fn store_orders<'a, I>(&mut self, orders: I) -> Result<u32>
     where
         I: Iterator<Item = &'a super::localexpress::Order>,
     {
-
-        let txn = self.client.transaction().await?;
-        let mut cnt = 0;
-        for o in orders {
-            let cd = o.delivery_time_period.split(" - ").collect::<Vec<&str>>();
-            let (st, et) = if cd.len() == 2 { (cd[0], cd[1]) }
-            else { ("00:00","23:59") };
-            let dd = o.delivery_date.format("%Y-%m-%d").to_string();
-            let (sd,ed) =
-                (NaiveDateTime::parse_from_str(&format!("{}T{}:00", dd, st), "%Y-%m-%dT%H:%M:%S")?,
-                NaiveDateTime::parse_from_str(&format!("{}T{}:00", dd, et),"%Y-%m-%dT%H:%M:%S")?);
-            let re = txn.execute("INSERT INTO leorder
-                           (id, uniqid, store_id, status,
-                            subtotal, tips, total,
-                            mode, payment_method, customer_first_name, customer_last_name,
-                            customer_phone_number, customer_email, creation_date, delivery_date, delivery_time_period)
-                            VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,tsrange($16,$17))
-                            ON CONFLICT (uniqid) DO UPDATE SET
-                            id = EXCLUDED.id, store_id = EXCLUDED.store_id, status = EXCLUDED.status,
-                            subtotal = EXCLUDED.subtotal, tips = EXCLUDED.tips, total = EXCLUDED.total,
-                            mode = EXCLUDED.mode, payment_method = EXCLUDED.payment_method,
-                            customer_first_name = EXCLUDED.customer_first_name, customer_last_name = EXCLUDED.customer_last_name,
-                            customer_phone_number = EXCLUDED.customer_phone_number, customer_email = EXCLUDED.customer_email,
-                            creation_date = EXCLUDED.creation_date, delivery_date = EXCLUDED.delivery_date,
-                            delivery_time_period = EXCLUDED.delivery_time_period",
-                    &[&(o.id as i64), &o.uniqid, &(o.store_id as i64), &o.status,
-                      &decimal_price(&o.subtotal), &decimal_price(&o.tips), &decimal_price(&o.total),
-                      &o.mode, &o.payment_method, &o.customer_first_name, &o.customer_last_name,
-                      &o.customer_phone_number, &o.customer_email, &o.creation_date, &o.delivery_date, &sd, &ed]).await?;
-            cnt += re as u32;
-        }
-        txn.commit().await?;
-        Ok(cnt)
+        let orders: Vec<&super::localexpress::Order> = orders.collect();
+        self.backend.store_orders(&orders, self.commit_rows).await
     }
 
     pub async fn store_taxes_itr<'a, I>(&mut self, taxes: I) -> Result<u32>
     where
         I: Iterator<Item = &'a Tax>,
     {
+        let taxes: Vec<&Tax> = taxes.collect();
+        self.backend.store_taxes(&taxes).await
+    }
+
+    pub async fn associate_product_with_square(&mut self, upc: &str, squareup_id: &str) -> Result<bool> {
+        self.backend.associate_product_with_square(upc, squareup_id).await
+    }
+
+    pub async fn store_departments<'a, I>(&mut self, depts: I) -> Result<u32>
+    where
+        I: Iterator<Item = &'a super::api::Department>,
+    {
+        let depts: Vec<&Department> = depts.collect();
+        self.backend.store_departments(&depts).await
+    }
+
+    pub async fn get_departments(&self) -> Result<Vec<Department>> {
+        self.backend.get_departments().await
+    }
+
+    pub async fn associate_department_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool> {
+        self.backend.associate_department_with_square(id, squareup_id).await
+    }
+
+    pub async fn store_sections<'a, I>(&mut self, sections: I) -> Result<u32>
+    where
+        I: Iterator<Item = &'a super::api::Section>,
+    {
+        let sections: Vec<&Section> = sections.collect();
+        self.backend.store_sections(&sections).await
+    }
+
+    pub async fn get_sections(&self) -> Result<Vec<Section>> {
+        self.backend.get_sections().await
+    }
+
+    pub async fn associate_section_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool> {
+        self.backend.associate_section_with_square(id, squareup_id).await
+    }
+
+    pub async fn store_products<'a, I>(&mut self, products: I) -> Result<u32>
+    where
+        I: Iterator<Item = &'a super::api::ProductData>,
+    {
+        let products: Vec<&ProductData> = products.collect();
+        self.backend.store_products(&products, self.archive_products, self.commit_rows).await
+    }
+
+    // Drop product snapshots older than `keep_days`, returning how many rows went.
+    pub async fn prune_product_archive(&mut self, keep_days: u32) -> Result<u64> {
+        self.backend.prune_product_archive(keep_days).await
+    }
+
+    // LocalExpress order customers, newest order first.
+    pub async fn get_le_customers(&mut self) -> Result<Vec<LECustomer>> {
+        self.backend.get_le_customers().await
+    }
+
+    pub async fn get_price_history(&mut self, upc: &str) -> Result<Vec<PriceChange>> {
+        self.backend.get_price_history(upc).await
+    }
+
+    pub async fn get_products(&mut self, date: Option<&NaiveDate>) -> Result<Vec<ProductData>> {
+        self.query_products(date, false).await
+    }
+    // As get_products, but deleted products are included when asked for.
+    pub async fn query_products(&mut self, date: Option<&NaiveDate>, include_deleted: bool) -> Result<Vec<ProductData>> {
+        self.backend.query_products(date, include_deleted).await
+    }
+    // Each product's latest archived snapshot, or its latest one recorded on `date`.
+    pub async fn get_archived_products(&mut self, date: Option<&NaiveDate>) -> Result<Vec<ProductData>> {
+        self.backend.get_archived_products(date).await
+    }
+    // The UPCs of current products, for checks that need all of them without the products.
+    pub async fn get_product_upcs(&mut self, include_deleted: bool) -> Result<Vec<String>> {
+        self.backend.get_product_upcs(include_deleted).await
+    }
+    // The current product with this IT Retail UPC.
+    pub async fn get_product(&mut self, upc: &str, include_deleted: bool) -> Result<Option<ProductData>> {
+        self.backend.get_product(upc, include_deleted).await
+    }
+    // Current products a page at a time (postgres.product_page_rows), in upc order.
+    pub fn product_pages(&self, include_deleted: bool) -> ProductPages {
+        ProductPages { include_deleted, page_rows: self.product_page_rows, after: None, page: VecDeque::new(), done: false }
+    }
+    async fn query_products_page(&mut self, include_deleted: bool, after: Option<&str>, limit: u32) -> Result<Vec<ProductData>> {
+        self.backend.query_products_page(include_deleted, after, limit).await
+    }
+
+    pub async fn shrink_square_products_sold(&mut self, itrapi: &mut super::api::ITRApi) -> Result<u32> {
+        self.backend.shrink_square_products_sold(itrapi, self.shrink_reason).await
+    }
+
+    pub async fn store_square_transactions(&mut self, payments: &[squareup::models::Payment]) -> Result<u32> {
+        self.backend.store_square_transactions(payments, &self.currency).await
+    }
+
+    pub async fn store_square_orders(&mut self, orders: &[squareup::models::Order]) -> Result<u32> {
+        self.backend.store_square_orders(orders, &self.currency).await
+    }
+
+    pub async fn get_spend(&mut self, days: u32) -> Result<Vec<(Uuid, Decimal)>> {
+        self.backend.get_spend(days).await
+    }
+}
+
+// Tables the sync keeps for itself, made on connect: the resume points of
+// incremental syncs and of an interrupted full customer pull, and the prices
+// each product has had.  Rows loaded in one transaction share now(), so
+// price_history is keyed by id rather than time; the DO block moves tables
+// from before the id over.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sync_state (name text PRIMARY KEY, last_sync timestamptz NOT NULL);
+    CREATE TABLE IF NOT EXISTS customer_pull (name text PRIMARY KEY, last_id uuid NOT NULL);
+    CREATE TABLE IF NOT EXISTS price_history (
+        id bigserial PRIMARY KEY,
+        upc text NOT NULL, price numeric NOT NULL, special_price numeric,
+        effective_at timestamptz NOT NULL DEFAULT now());
+    DO $$ BEGIN
+        IF NOT EXISTS (SELECT 1 FROM information_schema.columns
+                       WHERE table_name = 'price_history' AND column_name = 'id') THEN
+            ALTER TABLE price_history DROP CONSTRAINT IF EXISTS price_history_pkey;
+            ALTER TABLE price_history ADD COLUMN id bigserial PRIMARY KEY;
+        END IF;
+    END $$;
+    CREATE INDEX IF NOT EXISTS price_history_upc ON price_history (upc, effective_at);
+";
+
+pub struct PostgresSideDb {
+    client: tokio_postgres::Client,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for PostgresSideDb {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl PostgresSideDb {
+    pub async fn connect(connect_string: &str) -> Result<Self> {
+        let (client, connection) = tokio_postgres::connect(connect_string, NoTls).await?;
+        let handle = tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("connection error: {}", e);
+            }
+        });
+        let db = PostgresSideDb { client, handle };
+        db.client.batch_execute(SCHEMA).await?;
+        Ok(db)
+    }
+}
+
+#[async_trait]
+impl SideDbBackend for PostgresSideDb {
+    async fn get_sync_state(&mut self, name: &str) -> Result<Option<DateTime<Utc>>> {
+        let rows = self.client.query("SELECT last_sync FROM sync_state WHERE name = $1", &[&name]).await?;
+        Ok(rows.first().map(|x| x.get("last_sync")))
+    }
+    async fn set_sync_state(&mut self, name: &str, when: &DateTime<Utc>) -> Result<()> {
+        self.client.execute("INSERT INTO sync_state (name, last_sync) VALUES ($1, $2)
+                             ON CONFLICT (name) DO UPDATE SET last_sync = EXCLUDED.last_sync", &[&name, when]).await?;
+        Ok(())
+    }
+    async fn store_taxes(&mut self, taxes: &[&Tax]) -> Result<u32> {
         let txn = self.client.transaction().await?;
         let mut cnt = 0;
         for t in taxes {
@@ -330,18 +473,7 @@ impl SideDb {
         txn.commit().await?;
         Ok(cnt)
     }
-
-    pub async fn associate_product_with_square(&mut self, upc: &String, squareup_id: &String) -> Result<bool> {
-        let txn = self.client.transaction().await?;
-        let rc = txn.execute("UPDATE itrproduct SET squareup_id=$1 WHERE upc = $2", &[squareup_id, upc]).await?;
-        txn.commit().await?;
-        Ok(rc > 0)
-    }
-
-    pub async fn store_departments<'a, I>(&mut self, depts: I) -> Result<u32>
-    where
-        I: Iterator<Item = &'a super::api::Department>,
-    {
+    async fn store_departments(&mut self, depts: &[&Department]) -> Result<u32> {
         let txn = self.client.transaction().await?;
         let mut cnt = 0;
         for d in depts {
@@ -354,8 +486,7 @@ impl SideDb {
         txn.commit().await?;
         Ok(cnt)
     }
-
-    pub async fn get_departments(&self) -> Result<Vec<Department>> {
+    async fn get_departments(&self) -> Result<Vec<Department>> {
         let rows = self.client.query("SELECT * from itrdepartment", &[]).await?;
         Ok(rows.iter().map(|x| {
             Department {
@@ -365,18 +496,13 @@ impl SideDb {
             }
         }).collect())
     }
-
-    pub async fn associate_department_with_square(&mut self, id: &i32, squareup_id: &String) -> Result<bool> {
+    async fn associate_department_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool> {
         let txn = self.client.transaction().await?;
-        let rc = txn.execute("UPDATE itrdepartment SET squareup_id=$1 WHERE id = $2", &[squareup_id, id]).await?;
+        let rc = txn.execute("UPDATE itrdepartment SET squareup_id=$1 WHERE id = $2", &[&squareup_id, id]).await?;
         txn.commit().await?;
         Ok(rc > 0)
     }
-
-    pub async fn store_sections<'a, I>(&mut self, sections: I) -> Result<u32>
-    where
-        I: Iterator<Item = &'a super::api::Section>,
-    {
+    async fn store_sections(&mut self, sections: &[&Section]) -> Result<u32> {
         let txn = self.client.transaction().await?;
         let mut cnt = 0;
         for s in sections {
@@ -389,8 +515,7 @@ impl SideDb {
         txn.commit().await?;
         Ok(cnt)
     }
-
-    pub async fn get_sections(&self) -> Result<Vec<Section>> {
+    async fn get_sections(&self) -> Result<Vec<Section>> {
         let rows = self.client.query("SELECT * from itrsection", &[]).await?;
         Ok(rows.iter().map(|x| {
             Section {
@@ -402,22 +527,30 @@ impl SideDb {
             }
         }).collect())
     }
-
-    pub async fn associate_section_with_square(&mut self, id: &i32, squareup_id: &String) -> Result<bool> {
+    async fn associate_section_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool> {
         let txn = self.client.transaction().await?;
-        let rc = txn.execute("UPDATE itrsection SET squareup_id=$1 WHERE id = $2", &[squareup_id, id]).await?;
+        let rc = txn.execute("UPDATE itrsection SET squareup_id=$1 WHERE id = $2", &[&squareup_id, id]).await?;
         txn.commit().await?;
         Ok(rc > 0)
     }
-
-    pub async fn store_products<'a, I>(&mut self, products: I) -> Result<u32>
-    where
-        I: Iterator<Item = &'a super::api::ProductData>,
-    {
-        let txn = self.client.transaction().await?;
+    async fn store_products(&mut self, products: &[&ProductData], archive: bool, commit_rows: u32) -> Result<u32> {
+        let mut txn = self.client.transaction().await?;
         let mut cnt = 0;
-        txn.execute("INSERT INTO itrproduct_archive SELECT * FROM itrproduct ON CONFLICT DO NOTHING", &[]).await?;
+        if archive {
+            txn.execute("INSERT INTO itrproduct_archive SELECT * FROM itrproduct ON CONFLICT DO NOTHING", &[]).await?;
+        } else {
+            debug!("Skipping the itrproduct_archive snapshot.");
+        }
         for p in products {
+            // Only record a row when the price differs from the latest one we have.
+            let special_price = if p.start_date.is_some() && p.end_date.is_some() { p.special_price.and_then(Decimal::from_f64) } else { None };
+            txn.execute("INSERT INTO price_history (upc, price, special_price)
+                        SELECT $1::text, $2::numeric, $3::numeric
+                        WHERE NOT EXISTS (
+                            SELECT 1 FROM (SELECT price, special_price FROM price_history
+                                            WHERE upc = $1 ORDER BY effective_at DESC, id DESC LIMIT 1) latest
+                            WHERE latest.price = $2 AND latest.special_price IS NOT DISTINCT FROM $3)",
+                        &[&p.upc, &Decimal::from_f64(p.normal_price), &special_price]).await?;
             if p.special_price.is_some() && p.start_date.is_some() && p.end_date.is_some() {
                 txn.execute("INSERT INTO itrproduct
                             (upc, description, second_description, normal_price, special_price, special_date,
@@ -462,44 +595,251 @@ impl SideDb {
                         ]).await?;
             }
             cnt += 1;
+            if commit_due(commit_rows, cnt) {
+                txn.commit().await?;
+                info!("Committed {} products.", cnt);
+                txn = self.client.transaction().await?;
+            }
         }
         txn.commit().await?;
         Ok(cnt)
     }
-
-    pub async fn get_products(&mut self, date: Option<&NaiveDate>) -> Result<Vec<ProductData>> {
+    async fn associate_product_with_square(&mut self, upc: &str, squareup_id: &str) -> Result<bool> {
+        let txn = self.client.transaction().await?;
+        let rc = txn.execute("UPDATE itrproduct SET squareup_id=$1 WHERE upc = $2", &[&squareup_id, &upc]).await?;
+        txn.commit().await?;
+        Ok(rc > 0)
+    }
+    async fn prune_product_archive(&mut self, keep_days: u32) -> Result<u64> {
+        let txn = self.client.transaction().await?;
+        let pruned = txn.execute("DELETE FROM itrproduct_archive
+                                  WHERE recorded_at < current_timestamp - ($1::integer * INTERVAL '1 days')",
+                                 &[&(keep_days as i32)]).await?;
+        txn.commit().await?;
+        Ok(pruned)
+    }
+    async fn get_price_history(&mut self, upc: &str) -> Result<Vec<PriceChange>> {
+        let rows = self.client.query("SELECT upc, price, special_price, effective_at FROM price_history
+                                      WHERE upc = $1 ORDER BY effective_at, id", &[&upc]).await?;
+        Ok(rows.iter().map(|x| {
+            PriceChange { upc: x.get("upc"), price: x.get::<&str,Decimal>("price").to_f64().unwrap_or(0.0),
+                special_price: x.get::<&str,Option<Decimal>>("special_price").and_then(|x| x.to_f64()),
+                effective_at: x.get("effective_at") }
+        }).collect())
+    }
+    async fn query_products(&mut self, date: Option<&NaiveDate>, include_deleted: bool) -> Result<Vec<ProductData>> {
         let rows = if date.is_some() {
             let dr = date.unwrap();
             self.client.query("SELECT *, lower(special_date) as start_date, upper(special_date) as end_date
                 FROM itrproduct_archive
-                WHERE NOT deleted and date(timezone('US/Eastern',recorded_at)) = $1
-                ORDER BY department_id, section_id", &[dr]).await
+                WHERE ($2 OR NOT deleted) and date(timezone('US/Eastern',recorded_at)) = $1
+                ORDER BY department_id, section_id", &[dr, &include_deleted]).await
         } else {
             self.client.query("SELECT *, lower(special_date) as start_date, upper(special_date) as end_date
                 FROM itrproduct
-                WHERE NOT deleted
-                ORDER BY department_id, section_id", &[]).await
+                WHERE ($1 OR NOT deleted)
+                ORDER BY department_id, section_id", &[&include_deleted]).await
         }?;
-        let products = rows.iter().map(|x| {
-            ProductData { upc: x.get("upc"), description: x.get("description"),
-                second_description: x.get("second_description"), normal_price: x.get::<&str,Decimal>("normal_price").to_f64().unwrap(),
-                special_price: x.get::<&str,Option<Decimal>>("special_price").and_then(|x| x.to_f64()),
-                start_date: x.get::<&str,Option<NaiveDateTime>>("start_date").and_then(|x| Some(x.to_string())),
-                end_date: x.get::<&str,Option<NaiveDateTime>>("end_date").and_then(|x| Some(x.to_string())),
-                scale: x.get("scale"), active: x.get("active"),
-                discountable: if x.get::<&str,bool>("discount") { 1 } else { 0 }, plu: x.get("plu"),
-                deleted: x.get("deleted"), cert_code: x.get("cert_code"), vendor_id: x.get("vendor_id"),
-                department_id: x.get("department_id"), section_id: x.get("section_id"), wicable: x.get("wicable"),
-                foodstamp: x.get("foodstamp"), quantity_on_hand: x.get::<&str,Option<f64>>("quantity_on_hand").and_then(|x| Some(x as f32)), size: x.get("size"),
-                case_cost: x.get::<&str,Option<Decimal>>("case_cost").and_then(|x| x.to_f32()), pack: x.get("pack"),
-                cost: x.get::<&str,Option<Decimal>>("cost").and_then(|x| x.to_f32()),
-                taxclass: ITRTaxId(x.get("taxclass")), squareup_id: x.get("squareup_id"),
-             }
+        Ok(rows.iter().map(product_from_row).collect())
+    }
+    async fn query_products_page(&mut self, include_deleted: bool, after: Option<&str>, limit: u32) -> Result<Vec<ProductData>> {
+        let rows = self.client.query("SELECT *, lower(special_date) as start_date, upper(special_date) as end_date
+            FROM itrproduct
+            WHERE ($1 OR NOT deleted) AND ($2::text IS NULL OR upc > $2)
+            ORDER BY upc LIMIT $3", &[&include_deleted, &after, &(limit as i64)]).await?;
+        Ok(rows.iter().map(product_from_row).collect())
+    }
+    async fn get_archived_products(&mut self, date: Option<&NaiveDate>) -> Result<Vec<ProductData>> {
+        let rows = self.client.query("SELECT DISTINCT ON (upc) *, lower(special_date) as start_date, upper(special_date) as end_date
+            FROM itrproduct_archive
+            WHERE ($1::date IS NULL OR date(timezone('US/Eastern',recorded_at)) = $1)
+            ORDER BY upc, recorded_at DESC", &[&date]).await?;
+        Ok(rows.iter().map(product_from_row).collect())
+    }
+    async fn get_product_upcs(&mut self, include_deleted: bool) -> Result<Vec<String>> {
+        let rows = self.client.query("SELECT upc FROM itrproduct WHERE ($1 OR NOT deleted)", &[&include_deleted]).await?;
+        Ok(rows.iter().map(|x| x.get("upc")).collect())
+    }
+    async fn get_product(&mut self, upc: &str, include_deleted: bool) -> Result<Option<ProductData>> {
+        let row = self.client.query_opt("SELECT *, lower(special_date) as start_date, upper(special_date) as end_date
+            FROM itrproduct
+            WHERE upc = $1 AND ($2 OR NOT deleted)", &[&upc, &include_deleted]).await?;
+        Ok(row.as_ref().map(product_from_row))
+    }
+    async fn store_txns(&mut self, txns: &[&super::api::EJTxn], commit_rows: u32) -> Result<(u32, u32)> {
+        let mut sqltxn = self.client.transaction().await?;
+        let mut cnt = 0;
+        let mut skipped = 0;
+        let mut rows = 0;
+        for t in txns {
+            rows += 1;
+            match parse_txn_date(&t.transaction_date) {
+                Ok(td) => {
+                    let sp = sqltxn.savepoint("ejtxn").await?;
+                    match insert_txn(&sp, t, &td).await {
+                        Ok(num_rows) => {
+                            sp.commit().await?;
+                            if num_rows > 0 {
+                                cnt += 1;
+                            }
+                        },
+                        Err(e) => {
+                            sp.rollback().await?;
+                            error!("Skipping transaction {}: {}", t.id, e);
+                            skipped += 1;
+                        }
+                    }
+                },
+                Err(e) => {
+                    error!("Skipping transaction {}, bad date {:?}: {}", t.id, t.transaction_date, e);
+                    skipped += 1;
+                }
+            }
+            if commit_due(commit_rows, rows) {
+                sqltxn.commit().await?;
+                info!("Committed {} transactions.", rows);
+                sqltxn = self.client.transaction().await?;
+            }
+        }
+        sqltxn.commit().await?;
+        Ok((cnt, skipped))
+    }
+    async fn store_customers(&mut self, customers: &[Customer], commit_rows: u32) -> Result<u32> {
+        let mut txn = { self.client.transaction().await? };
+        let mut cnt = 0;
+        let mut rows = 0;
+
+        for c in customers {
+            rows += 1;
+            cnt += upsert_customer(&txn, c).await? as u32;
+            if commit_due(commit_rows, rows) {
+                txn.commit().await?;
+                info!("Committed {} customers.", rows);
+                txn = self.client.transaction().await?;
+            }
+        }
+        txn.commit().await?;
+        Ok(cnt)
+    }
+    async fn store_customer_batch(&mut self, customers: &[Customer]) -> Result<u32> {
+        let txn = self.client.transaction().await?;
+        let mut cnt = 0;
+        for c in customers {
+            cnt += upsert_customer(&txn, c).await? as u32;
+        }
+        if let Some(last) = customers.last() {
+            txn.execute("INSERT INTO customer_pull (name, last_id) VALUES ('full', $1)
+                         ON CONFLICT (name) DO UPDATE SET last_id = EXCLUDED.last_id", &[&last.id]).await?;
+        }
+        txn.commit().await?;
+        Ok(cnt)
+    }
+    async fn get_customer_pull_resume(&mut self) -> Result<Option<Uuid>> {
+        let rows = self.client.query("SELECT last_id FROM customer_pull WHERE name = 'full'", &[]).await?;
+        Ok(rows.first().map(|x| x.get("last_id")))
+    }
+    async fn clear_customer_pull(&mut self) -> Result<()> {
+        self.client.execute("DELETE FROM customer_pull WHERE name = 'full'", &[]).await?;
+        Ok(())
+    }
+    async fn associate_customer_with_square(&mut self, id: &Uuid, squareup_id: &str) -> Result<bool> {
+        let txn = self.client.transaction().await?;
+        let rc = txn.execute("UPDATE customer SET squareup_id=$1 WHERE customer_id = $2", &[&squareup_id, id]).await?;
+        txn.commit().await?;
+        Ok(rc > 0)
+    }
+    async fn delete_customer(&mut self, id: &Uuid) -> Result<bool> {
+        let txn = self.client.transaction().await?;
+        let rc = txn.execute("UPDATE customer SET deleted=true WHERE customer_id = $1", &[id]).await?;
+        txn.commit().await?;
+        Ok(rc > 0)
+    }
+    async fn get_customer_household(&mut self) -> Result<Vec<(Uuid, Uuid)>> {
+        let rows = self.client.query("SELECT main, resident FROM customer_house", &[]).await?;
+        let rels = rows.iter().map(|x| { (x.get("main"), x.get("resident")) }).collect();
+        Ok(rels)
+    }
+    async fn get_customers_ex(&mut self, deleted: bool) -> Result<Vec<Customer>> {
+        let sql = if deleted {
+            "SELECT * FROM customer"
+        } else {
+            "SELECT * FROM customer WHERE NOT deleted"
+        };
+        let rows = self.client.query(sql, &[]).await?;
+        let customers = rows.iter().map(|x| {
+            Customer{ id: x.get("customer_id"), card_no: x. get("card_no"),
+                      last_name: x.get("last_name"), first_name: x.get("first_name"),
+                      birth_date: x.get::<&str,Option<NaiveDate>>("birth_date").map(|x| x.to_string()),
+                      phone: x.get("phone"), discount: Some(x.get::<&str,i32>("discount") as u8),
+                      deleted: x.get("deleted"), email: x.get("email"), balance: x.get::<&str,Option<Decimal>>("balance").and_then(|x| x.to_f64()),
+                      balance_limit: x.get::<&str,Option<Decimal>>("balance_limit").and_then(|x| x.to_f64()),
+                      loyalty_points: Some(x.get("loyalty_points")),
+                      expiration_date: x.get::<&str,Option<NaiveDateTime>>("expiration_date").map(|x| x.to_string()),
+                      instore_charge_enabled: Some(x.get("instore_charge_enabled")),
+                      address1: x.get("address1"), address2: x.get("address2"),
+                      city: x.get("city"), state: x.get("state"), zipcode: x.get("zipcode"),
+                      created: x.get::<&str,Option<NaiveDateTime>>("created").map(|x| x.to_string()),
+                      modified: x.get::<&str,Option<NaiveDateTime>>("modified").map(|x| x.to_string()),
+                      modified_by: x.get::<&str,Option<i32>>("modified_by").map(|x| x as u32),
+                      frequent_shopper: x.get("frequent_shopper"),
+                      cash_back: x.get::<&str,Option<Decimal>>("cash_back").and_then(|x| x.to_f64()),
+                      inc: x.get::<&str,Option<i64>>("inc").map(|x| x as u32),
+                      squareup_id: x.get("squareup_id"),
+            }
         }).collect();
-        Ok(products)
+        Ok(customers)
     }
+    async fn store_orders(&mut self, orders: &[&super::localexpress::Order], commit_rows: u32) -> Result<u32> {
 
-    pub async fn shrink_square_products_sold(&mut self, itrapi: &mut super::api::ITRApi) -> Result<u32> {
+        let mut txn = self.client.transaction().await?;
+        let mut cnt = 0;
+        let mut rows = 0;
+        for o in orders {
+            let cd = o.delivery_time_period.split(" - ").collect::<Vec<&str>>();
+            let (st, et) = if cd.len() == 2 { (cd[0], cd[1]) }
+            else { ("00:00","23:59") };
+            let dd = o.delivery_date.format("%Y-%m-%d").to_string();
+            let (sd,ed) =
+                (NaiveDateTime::parse_from_str(&format!("{}T{}:00", dd, st), "%Y-%m-%dT%H:%M:%S")?,
+                NaiveDateTime::parse_from_str(&format!("{}T{}:00", dd, et),"%Y-%m-%dT%H:%M:%S")?);
+            let re = txn.execute("INSERT INTO leorder
+                           (id, uniqid, store_id, status,
+                            subtotal, tips, total,
+                            mode, payment_method, customer_first_name, customer_last_name,
+                            customer_phone_number, customer_email, creation_date, delivery_date, delivery_time_period)
+                            VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,tsrange($16,$17))
+                            ON CONFLICT (uniqid) DO UPDATE SET
+                            id = EXCLUDED.id, store_id = EXCLUDED.store_id, status = EXCLUDED.status,
+                            subtotal = EXCLUDED.subtotal, tips = EXCLUDED.tips, total = EXCLUDED.total,
+                            mode = EXCLUDED.mode, payment_method = EXCLUDED.payment_method,
+                            customer_first_name = EXCLUDED.customer_first_name, customer_last_name = EXCLUDED.customer_last_name,
+                            customer_phone_number = EXCLUDED.customer_phone_number, customer_email = EXCLUDED.customer_email,
+                            creation_date = EXCLUDED.creation_date, delivery_date = EXCLUDED.delivery_date,
+                            delivery_time_period = EXCLUDED.delivery_time_period",
+                    &[&(o.id as i64), &o.uniqid, &(o.store_id as i64), &o.status,
+                      &decimal_price(&o.subtotal), &decimal_price(&o.tips), &decimal_price(&o.total),
+                      &o.mode, &o.payment_method, &o.customer_first_name, &o.customer_last_name,
+                      &o.customer_phone_number, &o.customer_email, &o.creation_date, &o.delivery_date, &sd, &ed]).await?;
+            cnt += re as u32;
+            rows += 1;
+            if commit_due(commit_rows, rows) {
+                txn.commit().await?;
+                info!("Committed {} orders.", rows);
+                txn = self.client.transaction().await?;
+            }
+        }
+        txn.commit().await?;
+        Ok(cnt)
+    }
+    async fn get_le_customers(&mut self) -> Result<Vec<LECustomer>> {
+        let rows = self.client.query("SELECT customer_first_name, customer_last_name, customer_email, customer_phone_number
+                                      FROM leorder ORDER BY creation_date DESC", &[]).await?;
+        Ok(rows.iter().map(|x| {
+            LECustomer { first_name: x.get("customer_first_name"), last_name: x.get("customer_last_name"),
+                email: x.get("customer_email"), phone: x.get("customer_phone_number") }
+        }).collect())
+    }
+    async fn shrink_square_products_sold(&mut self, itrapi: &mut super::api::ITRApi, shrink_reason: u32) -> Result<u32> {
         let txn = self.client.transaction().await?;
         let rows = txn.query("
             with toshrink as
@@ -531,7 +871,7 @@ impl SideDb {
             let quantity = x.get::<&str,Decimal>("quantity").to_f32().unwrap();
             super::api::make_shrink_item(
                 &pd,
-                self.shrink_reason,
+                shrink_reason,
                 if pd.scale { ShrinkAmount::Weight(quantity)} else { ShrinkAmount::Quantity(quantity as u32) }
             )
         }).collect();
@@ -540,14 +880,13 @@ impl SideDb {
         txn.commit().await?;
         Ok(cnt)
     }
-
-    pub async fn store_square_transactions(&mut self, payments: &Vec<squareup::models::Payment>) -> Result<u32> {
+    async fn store_square_transactions(&mut self, payments: &[squareup::models::Payment], currency: &Currency) -> Result<u32> {
         let txn = self.client.transaction().await?;
         let mut cnt: u32 = 0;
         for p in payments {
             let processing_fees = Some(p.processing_fee.as_ref().unwrap_or(&vec![]).iter()
                 .fold(Decimal::ZERO, |acc, e| {
-                    let to_add = SSql::from_money(&e.amount_money).unwrap();
+                    let to_add = SSql::from_money(&e.amount_money, currency).unwrap();
                     to_add.checked_add(acc).unwrap()
                 }));
             let created_at: chrono::DateTime<chrono::Utc> = p.created_at.as_ref().unwrap().clone().into();
@@ -561,15 +900,14 @@ impl SideDb {
                                   created_at=EXCLUDED.created_at, updated_at=EXCLUDED.updated_at",
                                 &[&p.id, &p.customer_id, &SSql::from_payment_status(&p.status),
                                 &p.order_id, &SSql::from_payment_source_type(&p.source_type),
-                                &SSql::from_money(&p.amount_money), &SSql::from_money(&p.tip_money), &processing_fees,
-                                &SSql::from_money(&p.refunded_money), &created_at, &updated_at]).await?;
+                                &SSql::from_money(&p.amount_money, currency), &SSql::from_money(&p.tip_money, currency), &processing_fees,
+                                &SSql::from_money(&p.refunded_money, currency), &created_at, &updated_at]).await?;
             cnt += rv as u32;
         }
         txn.commit().await?;
         Ok(cnt)
     }
-
-    pub async fn store_square_orders(&mut self, orders: &Vec<squareup::models::Order>) -> Result<u32> {
+    async fn store_square_orders(&mut self, orders: &[squareup::models::Order], currency: &Currency) -> Result<u32> {
         let txn = self.client.transaction().await?;
         let mut cnt: u32 = 0;
         for o in orders {
@@ -588,8 +926,8 @@ impl SideDb {
                                   service_charge_money=EXCLUDED.service_charge_money, created_at=EXCLUDED.created_at, updated_at=EXCLUDED.updated_at,
                                   closed_at=EXCLUDED.closed_at",
                                 &[&o.id, &o.customer_id, &SSql::from_order_state(&o.state),
-                                &SSql::from_money(&o.total_money), &SSql::from_money(&o.total_tax_money), &SSql::from_money(&o.total_discount_money),
-                                &SSql::from_money(&o.total_tip_money), &SSql::from_money(&o.total_service_charge_money),
+                                &SSql::from_money(&o.total_money, currency), &SSql::from_money(&o.total_tax_money, currency), &SSql::from_money(&o.total_discount_money, currency),
+                                &SSql::from_money(&o.total_tip_money, currency), &SSql::from_money(&o.total_service_charge_money, currency),
                                 &created_at, &updated_at, &closed_at]).await?;
             cnt += rv as u32;
             if o.state == Some(OrderState::Completed) {
@@ -604,7 +942,7 @@ impl SideDb {
                             (order_id, uid, squareup_id, quantity, base_unit_price)
                             VALUES($1, $2, $3, $4, $5)
                             ON CONFLICT (order_id, uid) DO NOTHING",
-                            &[&o.id, &uid, &li.catalog_object_id, &Decimal::from_f64(qty), &SSql::from_money(&li.base_price_money)]).await?;
+                            &[&o.id, &uid, &li.catalog_object_id, &Decimal::from_f64(qty), &SSql::from_money(&li.base_price_money, currency)]).await?;
                         cnt += rv as u32;
                     }
                 }
@@ -614,8 +952,7 @@ impl SideDb {
         txn.commit().await?;
         Ok(cnt)
     }
-
-    pub async fn get_spend(&mut self, days: u32) -> Result<Vec<(Uuid, Decimal)>> {
+    async fn get_spend(&mut self, days: u32) -> Result<Vec<(Uuid, Decimal)>> {
         /* This query pull total spend for customers (by customer id) from itretail and
            joins that with the total spend from localexpress with a hopeful conversion of localexpress
            email address to (preferrably undeleted) itretail customer id. */
@@ -644,4 +981,119 @@ group by customer_id",
         let vec = rows.iter().map(|x| (x.get(0), x.get::<usize,Decimal>(1))).collect();
         Ok(vec)
     }
-}
\ No newline at end of file
+}
+
+fn product_from_row(x: &tokio_postgres::Row) -> ProductData {
+    ProductData { upc: x.get("upc"), description: x.get("description"),
+        second_description: x.get("second_description"), normal_price: x.get::<&str,Decimal>("normal_price").to_f64().unwrap(),
+        special_price: x.get::<&str,Option<Decimal>>("special_price").and_then(|x| x.to_f64()),
+        start_date: x.get::<&str,Option<NaiveDateTime>>("start_date").map(|x| x.to_string()),
+        end_date: x.get::<&str,Option<NaiveDateTime>>("end_date").map(|x| x.to_string()),
+        scale: x.get("scale"), active: x.get("active"),
+        discountable: if x.get::<&str,bool>("discount") { 1 } else { 0 }, plu: x.get("plu"),
+        deleted: x.get("deleted"), cert_code: x.get("cert_code"), vendor_id: x.get("vendor_id"),
+        department_id: x.get("department_id"), section_id: x.get("section_id"), wicable: x.get("wicable"),
+        foodstamp: x.get("foodstamp"), quantity_on_hand: x.get::<&str,Option<f64>>("quantity_on_hand").map(|x| x as f32), size: x.get("size"),
+        case_cost: x.get::<&str,Option<Decimal>>("case_cost").and_then(|x| x.to_f32()), pack: x.get("pack"),
+        cost: x.get::<&str,Option<Decimal>>("cost").and_then(|x| x.to_f32()),
+        taxclass: ITRTaxId(x.get("taxclass")), squareup_id: x.get("squareup_id"),
+    }
+}
+
+// Hands out products one at a time, fetching the next page from the sidedb as
+// each runs out, so a sync never holds the whole catalog.
+pub struct ProductPages {
+    include_deleted: bool,
+    page_rows: u32,
+    after: Option<String>,
+    page: VecDeque<ProductData>,
+    done: bool,
+}
+
+impl ProductPages {
+    pub async fn next(&mut self, sidedb: &mut SideDb) -> Result<Option<ProductData>> {
+        if self.page.is_empty() && !self.done {
+            let page = if self.page_rows == 0 {
+                self.done = true;
+                sidedb.query_products(None, self.include_deleted).await?
+            } else {
+                let page = sidedb.query_products_page(self.include_deleted, self.after.as_deref(), self.page_rows).await?;
+                self.done = page.len() < self.page_rows as usize;
+                page
+            };
+            self.after = page.last().map(|p| p.upc.clone());
+            self.page = page.into();
+        }
+        Ok(self.page.pop_front())
+    }
+}
+
+// The EJ normally omits the offset, but not always, and sometimes the fraction.
+fn parse_txn_date(s: &str) -> Result<NaiveDateTime> {
+    if let Ok(td) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Ok(td);
+    }
+    if let Ok(td) = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f") {
+        return Ok(td);
+    }
+    Ok(DateTime::parse_from_rfc3339(s)?.naive_local())
+}
+
+async fn insert_txn(txn: &tokio_postgres::Transaction<'_>, t: &super::api::EJTxn, td: &NaiveDateTime) -> Result<u64> {
+    let num_rows = txn.execute("INSERT INTO itrejtxn (transaction_id, customer_id, transaction_date, canceled, total)
+    VALUES($1,$2,$3,$4,$5) ON CONFLICT DO NOTHING",
+    &[&t.id, &t.customer_id, td, &t.canceled, &Decimal::from_f64(t.total)]).await?;
+    if num_rows > 0 {
+        if let Some(products) = t.transaction_products.as_ref() {
+            for p in products {
+                let upc = p.product_change.as_ref().map(|pc| pc.upc.clone());
+                txn.execute("INSERT INTO itrejtxn_products
+                    (transaction_subid, transaction_id, product_id, upc, is_voided, is_refunded, price, line_discount, quantity, weight)
+                    VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10) ON CONFLICT DO NOTHING",
+                &[&p.id, &t.id, &p.product_id, &upc, &p.is_voided, &p.is_refunded,
+                  &Decimal::from_f64(p.price), &Decimal::from_f64(p.line_discount), &p.quantity, &p.weight]).await?;
+            }
+        }
+    }
+    Ok(num_rows)
+}
+
+async fn upsert_customer(txn: &tokio_postgres::Transaction<'_>, c: &super::api::Customer) -> Result<u64> {
+    debug!("copying {}", c.email.as_ref().unwrap_or(&"<unknown>".to_string()));
+    let bd = c.birth_date.as_ref().and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    let ed = c.expiration_date.as_ref().and_then(|d| NaiveDateTime::parse_from_str(d, "%Y-%m-%dT%H:%M:%S%.f").ok());
+    let cd = c.created.as_ref().and_then(|d| match NaiveDateTime::parse_from_str(d, "%Y-%m-%dT%H:%M:%S%.f") {
+        Ok(r) => Some(r),
+        Err(e) => {
+            error!("Can't convert '{}': {}", d, e);
+            None
+        },
+    });
+    let md = c.modified.as_ref().and_then(|d| NaiveDateTime::parse_from_str(d, "%Y-%m-%dT%H:%M:%S%.f").ok());
+    let modified_by = c.modified_by.map(|id| id as i32);
+    let inc = c.inc.map(|id| id as i64);
+    let re = txn.execute("INSERT INTO customer
+                    (customer_id, card_no, first_name, last_name, birth_date, phone,
+                     discount, deleted, email, balance, balance_limit, loyalty_points, expiration_date,
+                     instore_charge_enabled, address1, address2, city, state, zipcode, created, modified, modified_by,
+                     frequent_shopper, cash_back, inc)
+                     VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16,$17,$18,$19,$20,$21,$22,$23,$24,$25)
+                     ON CONFLICT (customer_id) DO UPDATE SET card_no = EXCLUDED.card_no, first_name = EXCLUDED.first_name,
+                        last_name = EXCLUDED.last_name, birth_date = EXCLUDED.birth_date, phone = EXCLUDED.phone,
+                        discount = EXCLUDED.discount, deleted = EXCLUDED.deleted, email = EXCLUDED.email,
+                        balance = EXCLUDED.balance, balance_limit = EXCLUDED.balance_limit, loyalty_points = EXCLUDED.loyalty_points,
+                        expiration_date = EXCLUDED.expiration_date, instore_charge_enabled = EXCLUDED.instore_charge_enabled,
+                        address1 = coalesce(EXCLUDED.address1, customer.address1), address2 = coalesce(EXCLUDED.address2, customer.address2),
+                        city = coalesce(EXCLUDED.city, customer.city), state = coalesce(EXCLUDED.state, customer.state),
+                        zipcode = coalesce(EXCLUDED.zipcode, customer.zipcode),
+                        created = coalesce(EXCLUDED.created, customer.created), modified = coalesce(EXCLUDED.modified, customer.modified),
+                        modified_by = coalesce(EXCLUDED.modified_by, customer.modified_by), frequent_shopper = EXCLUDED.frequent_shopper,
+                        cash_back = coalesce(EXCLUDED.cash_back, customer.cash_back), inc = coalesce(EXCLUDED.inc, customer.inc)",
+                &[&c.id, &c.card_no, &c.first_name, &c.last_name, &bd, &c.phone,
+                          &(c.discount.unwrap_or(0) as i32), &c.deleted, &c.email,
+                          &Decimal::from_f64(c.balance.unwrap_or(0.0)), &Decimal::from_f64(c.balance_limit.unwrap_or(0.0)),
+                          &c.loyalty_points.unwrap_or(0), &ed, &(c.instore_charge_enabled.unwrap_or(false)),
+                          &c.address1, &c.address2, &c.city, &c.state, &c.zipcode, &cd, &md, &modified_by,
+                          &(c.frequent_shopper.unwrap_or(false)),&Decimal::from_f64(c.cash_back.unwrap_or(0.0)),&inc]).await?;
+    Ok(re)
+}
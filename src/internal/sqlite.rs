@@ -0,0 +1,440 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::sync::{Arc, Mutex};
+
+use super::api::{Department, ITRTaxId, ProductData, Section, Tax};
+use super::sidedb::{PriceChange, SideDbBackend};
+
+// The sidedb tables a sqlite backend keeps: the catalog and sync state.  The
+// postgres special_date tsrange is special_start/special_end here, and times
+// are UTC text ("YYYY-MM-DD HH:MM:SS.SSS", RFC 3339 for sync state and price
+// history) so they sort as strings.  Rows stored together share effective_at,
+// so price_history is keyed by id as on postgres.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sync_state (name text PRIMARY KEY, last_sync text NOT NULL);
+CREATE TABLE IF NOT EXISTS tax (id integer PRIMARY KEY, description text, rate real);
+CREATE TABLE IF NOT EXISTS itrdepartment (id integer PRIMARY KEY, name text NOT NULL, squareup_id text);
+CREATE TABLE IF NOT EXISTS itrsection (id integer PRIMARY KEY, name text NOT NULL, department_id integer NOT NULL,
+                                       deleted integer NOT NULL, squareup_id text);
+CREATE TABLE IF NOT EXISTS itrproduct (
+    upc text PRIMARY KEY, description text NOT NULL, second_description text, normal_price real NOT NULL,
+    special_price real, special_start text, special_end text, scale integer NOT NULL, active integer NOT NULL,
+    deleted integer NOT NULL, discount integer NOT NULL, plu text, cert_code text, vendor_id integer,
+    department_id integer NOT NULL, section_id integer, wicable integer, foodstamp integer, quantity_on_hand real,
+    size text, case_cost real, pack integer, cost real, taxclass integer, squareup_id text);
+CREATE TABLE IF NOT EXISTS itrproduct_archive (
+    upc text NOT NULL, description text NOT NULL, second_description text, normal_price real NOT NULL,
+    special_price real, special_start text, special_end text, scale integer NOT NULL, active integer NOT NULL,
+    deleted integer NOT NULL, discount integer NOT NULL, plu text, cert_code text, vendor_id integer,
+    department_id integer NOT NULL, section_id integer, wicable integer, foodstamp integer, quantity_on_hand real,
+    size text, case_cost real, pack integer, cost real, taxclass integer, squareup_id text,
+    recorded_at text NOT NULL, PRIMARY KEY (upc, recorded_at));
+CREATE TABLE IF NOT EXISTS price_history (id integer PRIMARY KEY, upc text NOT NULL, price real NOT NULL,
+                                          special_price real, effective_at text NOT NULL);
+CREATE INDEX IF NOT EXISTS price_history_upc ON price_history (upc, effective_at);
+";
+
+// A price_history from before the id column is rebuilt under SCHEMA's, oldest rows first.
+fn migrate_price_history(conn: &mut Connection) -> Result<()> {
+    let old: bool = conn.query_row("SELECT count(*) = 1 FROM sqlite_master WHERE type = 'table' AND name = 'price_history'
+                                    AND NOT EXISTS (SELECT 1 FROM pragma_table_info('price_history') WHERE name = 'id')",
+                                   [], |r| r.get(0))?;
+    if old {
+        let txn = conn.transaction()?;
+        txn.execute_batch("ALTER TABLE price_history RENAME TO price_history_old;")?;
+        txn.execute_batch(SCHEMA)?;
+        txn.execute_batch("INSERT INTO price_history (upc, price, special_price, effective_at)
+                             SELECT upc, price, special_price, effective_at FROM price_history_old ORDER BY effective_at, upc;
+                           DROP TABLE price_history_old;")?;
+        txn.commit()?;
+    }
+    Ok(())
+}
+
+const PRODUCT_COLUMNS: &str = "upc, description, second_description, normal_price, special_price, special_start, special_end,
+    scale, active, deleted, discount, plu, cert_code, vendor_id, department_id, section_id, wicable, foodstamp,
+    quantity_on_hand, size, case_cost, pack, cost, taxclass, squareup_id";
+
+fn recorded_at(when: DateTime<Utc>) -> String {
+    when.format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+pub struct SqliteSideDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteSideDb {
+    // Opens (creating if need be) the database file; ":memory:" for a throwaway one.
+    pub fn open(path: &str) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        migrate_price_history(&mut conn)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteSideDb { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    // rusqlite blocks, so every query runs on tokio's blocking pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(|_| anyhow!("sqlite connection poisoned"))?;
+            f(&mut conn)
+        }).await?
+    }
+}
+
+#[async_trait]
+impl SideDbBackend for SqliteSideDb {
+    async fn get_sync_state(&mut self, name: &str) -> Result<Option<DateTime<Utc>>> {
+        let name = name.to_string();
+        self.with_conn(move |conn| {
+            let last: Option<String> = conn.query_row("SELECT last_sync FROM sync_state WHERE name = ?1", [name], |r| r.get(0))
+                .optional()?;
+            Ok(match last {
+                Some(s) => Some(DateTime::parse_from_rfc3339(&s)?.with_timezone(&Utc)),
+                None => None,
+            })
+        }).await
+    }
+    async fn set_sync_state(&mut self, name: &str, when: &DateTime<Utc>) -> Result<()> {
+        let (name, when) = (name.to_string(), when.to_rfc3339_opts(SecondsFormat::Micros, true));
+        self.with_conn(move |conn| {
+            conn.execute("INSERT INTO sync_state (name, last_sync) VALUES (?1, ?2)
+                          ON CONFLICT (name) DO UPDATE SET last_sync = excluded.last_sync", params![name, when])?;
+            Ok(())
+        }).await
+    }
+
+    async fn store_taxes(&mut self, taxes: &[&Tax]) -> Result<u32> {
+        let taxes: Vec<Tax> = taxes.iter().map(|t| (*t).clone()).collect();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let mut cnt = 0;
+            for t in taxes.iter() {
+                txn.execute("INSERT INTO tax (id, description, rate) VALUES (?1, ?2, ?3)
+                             ON CONFLICT (id) DO UPDATE SET description = excluded.description, rate = excluded.rate",
+                            params![t.id.0, t.description, t.rate])?;
+                cnt += 1;
+            }
+            txn.commit()?;
+            Ok(cnt)
+        }).await
+    }
+
+    async fn associate_product_with_square(&mut self, upc: &str, squareup_id: &str) -> Result<bool> {
+        let (upc, squareup_id) = (upc.to_string(), squareup_id.to_string());
+        self.with_conn(move |conn| {
+            Ok(conn.execute("UPDATE itrproduct SET squareup_id = ?1 WHERE upc = ?2", params![squareup_id, upc])? > 0)
+        }).await
+    }
+    async fn associate_department_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool> {
+        let (id, squareup_id) = (*id, squareup_id.to_string());
+        self.with_conn(move |conn| {
+            Ok(conn.execute("UPDATE itrdepartment SET squareup_id = ?1 WHERE id = ?2", params![squareup_id, id])? > 0)
+        }).await
+    }
+    async fn associate_section_with_square(&mut self, id: &i32, squareup_id: &str) -> Result<bool> {
+        let (id, squareup_id) = (*id, squareup_id.to_string());
+        self.with_conn(move |conn| {
+            Ok(conn.execute("UPDATE itrsection SET squareup_id = ?1 WHERE id = ?2", params![squareup_id, id])? > 0)
+        }).await
+    }
+
+    async fn store_departments(&mut self, depts: &[&Department]) -> Result<u32> {
+        let depts: Vec<Department> = depts.iter().map(|d| (*d).clone()).collect();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let mut cnt = 0;
+            for d in depts.iter() {
+                txn.execute("INSERT INTO itrdepartment (id, name) VALUES (?1, ?2)
+                             ON CONFLICT (id) DO UPDATE SET name = excluded.name", params![d.id, d.name])?;
+                cnt += 1;
+            }
+            txn.commit()?;
+            Ok(cnt)
+        }).await
+    }
+    async fn get_departments(&self) -> Result<Vec<Department>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT id, name, squareup_id FROM itrdepartment")?;
+            let rows = stmt.query_map([], |x| {
+                Ok(Department { id: x.get("id")?, name: x.get("name")?, squareup_id: x.get("squareup_id")? })
+            })?;
+            Ok(rows.collect::<rusqlite::Result<Vec<Department>>>()?)
+        }).await
+    }
+
+    async fn store_sections(&mut self, sections: &[&Section]) -> Result<u32> {
+        let sections: Vec<Section> = sections.iter().map(|s| (*s).clone()).collect();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction()?;
+            let mut cnt = 0;
+            for s in sections.iter() {
+                txn.execute("INSERT INTO itrsection (id, name, department_id, deleted) VALUES (?1, ?2, ?3, ?4)
+                             ON CONFLICT (id) DO UPDATE SET name = excluded.name",
+                            params![s.id, s.name, s.department_id, s.deleted])?;
+                cnt += 1;
+            }
+            txn.commit()?;
+            Ok(cnt)
+        }).await
+    }
+    async fn get_sections(&self) -> Result<Vec<Section>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT id, name, department_id, deleted, squareup_id FROM itrsection")?;
+            let rows = stmt.query_map([], |x| {
+                Ok(Section { id: x.get("id")?, name: x.get("name")?, department_id: x.get("department_id")?,
+                             deleted: x.get("deleted")?, squareup_id: x.get("squareup_id")? })
+            })?;
+            Ok(rows.collect::<rusqlite::Result<Vec<Section>>>()?)
+        }).await
+    }
+
+    // Same as the postgres store: snapshot into the archive (when asked), record
+    // price changes, then upsert.  Always one transaction, whatever commit_rows.
+    async fn store_products(&mut self, products: &[&ProductData], archive: bool, _commit_rows: u32) -> Result<u32> {
+        let products: Vec<ProductData> = products.iter().map(|p| (*p).clone()).collect();
+        self.with_conn(move |conn| store_products(conn, &products, archive)).await
+    }
+
+    async fn prune_product_archive(&mut self, keep_days: u32) -> Result<u64> {
+        let cutoff = recorded_at(Utc::now() - Duration::days(keep_days as i64));
+        self.with_conn(move |conn| {
+            Ok(conn.execute("DELETE FROM itrproduct_archive WHERE recorded_at < ?1", [cutoff])? as u64)
+        }).await
+    }
+
+    async fn get_price_history(&mut self, upc: &str) -> Result<Vec<PriceChange>> {
+        let upc = upc.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("SELECT upc, price, special_price, effective_at FROM price_history
+                                         WHERE upc = ?1 ORDER BY effective_at, id")?;
+            let rows = stmt.query_map([upc], |x| {
+                Ok((x.get::<_, String>("upc")?, x.get::<_, f64>("price")?, x.get::<_, Option<f64>>("special_price")?,
+                    x.get::<_, String>("effective_at")?))
+            })?;
+            let mut changes = vec![];
+            for row in rows {
+                let (upc, price, special_price, effective_at) = row?;
+                changes.push(PriceChange { upc, price, special_price,
+                                           effective_at: DateTime::parse_from_rfc3339(&effective_at)?.with_timezone(&Utc) });
+            }
+            Ok(changes)
+        }).await
+    }
+
+    // Archive dates are local to this machine, where postgres pins US/Eastern.
+    async fn query_products(&mut self, date: Option<&NaiveDate>, include_deleted: bool) -> Result<Vec<ProductData>> {
+        let date = date.map(|d| d.format("%Y-%m-%d").to_string());
+        self.with_conn(move |conn| match date {
+            Some(d) => products(conn, &format!("SELECT {} FROM itrproduct_archive
+                                                WHERE (?1 OR NOT deleted) AND date(recorded_at, 'localtime') = ?2
+                                                ORDER BY department_id, section_id", PRODUCT_COLUMNS),
+                                params![include_deleted, d]),
+            None => products(conn, &format!("SELECT {} FROM itrproduct WHERE (?1 OR NOT deleted)
+                                             ORDER BY department_id, section_id", PRODUCT_COLUMNS),
+                             params![include_deleted]),
+        }).await
+    }
+    async fn get_archived_products(&mut self, date: Option<&NaiveDate>) -> Result<Vec<ProductData>> {
+        let date = date.map(|d| d.format("%Y-%m-%d").to_string());
+        self.with_conn(move |conn| {
+            products(conn, &format!("SELECT {} FROM (
+                                         SELECT *, row_number() OVER (PARTITION BY upc ORDER BY recorded_at DESC) AS rn
+                                         FROM itrproduct_archive
+                                         WHERE ?1 IS NULL OR date(recorded_at, 'localtime') = ?1)
+                                     WHERE rn = 1 ORDER BY upc", PRODUCT_COLUMNS),
+                     params![date])
+        }).await
+    }
+    async fn get_product_upcs(&mut self, include_deleted: bool) -> Result<Vec<String>> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("SELECT upc FROM itrproduct WHERE (?1 OR NOT deleted)")?;
+            let rows = stmt.query_map([include_deleted], |x| x.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+        }).await
+    }
+    async fn get_product(&mut self, upc: &str, include_deleted: bool) -> Result<Option<ProductData>> {
+        let upc = upc.to_string();
+        self.with_conn(move |conn| {
+            Ok(products(conn, &format!("SELECT {} FROM itrproduct WHERE upc = ?1 AND (?2 OR NOT deleted)", PRODUCT_COLUMNS),
+                        params![upc, include_deleted])?.into_iter().next())
+        }).await
+    }
+    async fn query_products_page(&mut self, include_deleted: bool, after: Option<&str>, limit: u32) -> Result<Vec<ProductData>> {
+        let after = after.map(|a| a.to_string());
+        self.with_conn(move |conn| {
+            products(conn, &format!("SELECT {} FROM itrproduct
+                                     WHERE (?1 OR NOT deleted) AND (?2 IS NULL OR upc > ?2)
+                                     ORDER BY upc LIMIT ?3", PRODUCT_COLUMNS),
+                     params![include_deleted, after, limit])
+        }).await
+    }
+}
+
+fn store_products(conn: &mut Connection, products: &[ProductData], archive: bool) -> Result<u32> {
+    let now = Utc::now();
+    let txn = conn.transaction()?;
+    if archive {
+        txn.execute(&format!("INSERT OR IGNORE INTO itrproduct_archive ({}, recorded_at) SELECT {}, ?1 FROM itrproduct",
+                             PRODUCT_COLUMNS, PRODUCT_COLUMNS), [recorded_at(now)])?;
+    }
+    let effective_at = now.to_rfc3339_opts(SecondsFormat::Micros, true);
+    let mut cnt = 0;
+    for p in products {
+        let (special_price, start, end) = match (p.special_price, &p.start_date, &p.end_date) {
+            (Some(price), Some(start), Some(end)) =>
+                (Some(price), Some(NaiveDateTime::parse_from_str(start, "%Y-%m-%dT%H:%M:%S")?.to_string()),
+                 Some(NaiveDateTime::parse_from_str(end, "%Y-%m-%dT%H:%M:%S")?.to_string())),
+            _ => (None, None, None),
+        };
+        // Only record a row when the price differs from the latest one we have.
+        txn.execute("INSERT INTO price_history (upc, price, special_price, effective_at)
+                     SELECT ?1, ?2, ?3, ?4
+                     WHERE NOT EXISTS (
+                         SELECT 1 FROM (SELECT price, special_price FROM price_history
+                                        WHERE upc = ?1 ORDER BY effective_at DESC, id DESC LIMIT 1) latest
+                         WHERE latest.price = ?2 AND latest.special_price IS ?3)",
+                    params![p.upc, p.normal_price, special_price, effective_at])?;
+        txn.execute(&format!("INSERT INTO itrproduct ({})
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, NULL)
+                     ON CONFLICT (upc) DO UPDATE SET
+                     description = excluded.description, second_description = excluded.second_description,
+                     normal_price = excluded.normal_price, special_price = excluded.special_price,
+                     special_start = excluded.special_start, special_end = excluded.special_end,
+                     scale = excluded.scale, active = excluded.active, deleted = excluded.deleted, discount = excluded.discount,
+                     plu = excluded.plu, cert_code = excluded.cert_code, vendor_id = excluded.vendor_id,
+                     department_id = excluded.department_id, section_id = excluded.section_id, wicable = excluded.wicable,
+                     foodstamp = excluded.foodstamp, quantity_on_hand = excluded.quantity_on_hand, size = excluded.size,
+                     case_cost = excluded.case_cost, pack = excluded.pack, cost = excluded.cost, taxclass = excluded.taxclass",
+                     PRODUCT_COLUMNS),
+                    params![p.upc, p.description, p.second_description, p.normal_price, special_price, start, end,
+                            p.scale, p.active, p.deleted, p.discountable != 0, p.plu, p.cert_code, p.vendor_id,
+                            p.department_id, p.section_id, p.wicable, p.foodstamp,
+                            p.quantity_on_hand.unwrap_or(0.0) as f64, p.size, p.case_cost.map(|x| x as f64), p.pack,
+                            p.cost.map(|x| x as f64), p.taxclass.0])?;
+        cnt += 1;
+    }
+    txn.commit()?;
+    Ok(cnt)
+}
+
+fn products(conn: &Connection, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<ProductData>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, product_from_row)?;
+    Ok(rows.collect::<rusqlite::Result<Vec<ProductData>>>()?)
+}
+
+fn product_from_row(x: &Row) -> rusqlite::Result<ProductData> {
+    Ok(ProductData { upc: x.get("upc")?, description: x.get("description")?,
+        second_description: x.get("second_description")?, normal_price: x.get("normal_price")?,
+        special_price: x.get("special_price")?, start_date: x.get("special_start")?, end_date: x.get("special_end")?,
+        scale: x.get("scale")?, active: x.get("active")?,
+        discountable: if x.get::<_, bool>("discount")? { 1 } else { 0 }, plu: x.get("plu")?,
+        deleted: x.get("deleted")?, cert_code: x.get("cert_code")?, vendor_id: x.get("vendor_id")?,
+        department_id: x.get("department_id")?, section_id: x.get("section_id")?, wicable: x.get("wicable")?,
+        foodstamp: x.get("foodstamp")?, quantity_on_hand: x.get::<_, Option<f64>>("quantity_on_hand")?.map(|x| x as f32),
+        size: x.get("size")?, case_cost: x.get::<_, Option<f64>>("case_cost")?.map(|x| x as f32), pack: x.get("pack")?,
+        cost: x.get::<_, Option<f64>>("cost")?.map(|x| x as f32),
+        taxclass: ITRTaxId(x.get("taxclass")?), squareup_id: x.get("squareup_id")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(upc: &str, price: f64, special: Option<(f64, &str, &str)>) -> ProductData {
+        let mut p = super::super::api::product(&format!(r#""upc":"{}","description":"Eggs","normal_price":{},"QuantityOnHand":12"#, upc, price));
+        if let Some((price, start, end)) = special {
+            p.special_price = Some(price);
+            p.start_date = Some(start.to_string());
+            p.end_date = Some(end.to_string());
+        }
+        p
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_products() {
+        let mut db = SqliteSideDb::open(":memory:").unwrap();
+        let first = [product("0001", 4.99, None), product("0002", 2.49, None)];
+        assert_eq!(db.store_products(&first.iter().collect::<Vec<_>>(), true, 0).await.unwrap(), 2);
+        let second = [product("0001", 5.49, Some((4.49, "2026-10-01T00:00:00", "2026-10-31T23:59:59"))),
+                          product("0002", 2.49, None)];
+        assert_eq!(db.store_products(&second.iter().collect::<Vec<_>>(), true, 0).await.unwrap(), 2);
+
+        let products = db.query_products(None, false).await.unwrap();
+        assert_eq!(products.len(), 2);
+        let eggs = products.iter().find(|p| p.upc == "0001").unwrap();
+        assert_eq!((eggs.normal_price, eggs.special_price), (5.49, Some(4.49)));
+        assert_eq!(eggs.start_date.as_deref(), Some("2026-10-01 00:00:00"));
+        assert_eq!(eggs.quantity_on_hand, Some(12.0));
+
+        // The second store archived the first one's products.
+        let archived = db.get_archived_products(None).await.unwrap();
+        assert_eq!(archived.iter().map(|p| (p.upc.as_str(), p.normal_price)).collect::<Vec<_>>(),
+                   vec![("0001", 4.99), ("0002", 2.49)]);
+        assert_eq!(db.prune_product_archive(1).await.unwrap(), 0);
+
+        let history = db.get_price_history("0001").await.unwrap();
+        assert_eq!(history.iter().map(|h| (h.price, h.special_price)).collect::<Vec<_>>(),
+                   vec![(4.99, None), (5.49, Some(4.49))]);
+        assert_eq!(db.get_price_history("0002").await.unwrap().len(), 1);
+
+        assert!(db.associate_product_with_square("0002", "SQ2").await.unwrap());
+        let page = db.query_products_page(false, Some("0001"), 10).await.unwrap();
+        assert_eq!((page.len(), page[0].squareup_id.as_deref()), (1, Some("SQ2")));
+        assert_eq!(db.get_product("0002", false).await.unwrap().map(|p| p.normal_price), Some(2.49));
+        assert!(db.get_product("0003", true).await.unwrap().is_none());
+
+        let mut gone = product("0002", 2.49, None);
+        gone.deleted = true;
+        assert_eq!(db.store_products(&[&product("0001", 5.49, None), &gone], false, 0).await.unwrap(), 2);
+        assert_eq!(db.query_products(None, false).await.unwrap().iter().map(|p| p.upc.as_str()).collect::<Vec<_>>(), vec!["0001"]);
+        assert_eq!(db.query_products(None, true).await.unwrap().len(), 2);
+        assert!(db.get_product("0002", false).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_repeated_upc() {
+        let mut db = SqliteSideDb::open(":memory:").unwrap();
+        // One store stamps every row with the same effective_at.
+        let batch = [product("0001", 4.99, None), product("0001", 5.49, None)];
+        assert_eq!(db.store_products(&batch.iter().collect::<Vec<_>>(), false, 0).await.unwrap(), 2);
+        let history = db.get_price_history("0001").await.unwrap();
+        assert_eq!(history.iter().map(|h| h.price).collect::<Vec<_>>(), vec![4.99, 5.49]);
+        // The latest of them is what a later store compares against.
+        assert_eq!(db.store_products(&[&product("0001", 5.49, None)], false, 0).await.unwrap(), 1);
+        assert_eq!(db.get_price_history("0001").await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_sqlite_migrate_price_history() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE price_history (upc text NOT NULL, price real NOT NULL, special_price real,
+                                                        effective_at text NOT NULL, PRIMARY KEY (upc, effective_at));
+                            INSERT INTO price_history VALUES ('0001', 5.49, NULL, '2026-10-02T00:00:00Z'),
+                                                             ('0001', 4.99, NULL, '2026-10-01T00:00:00Z');").unwrap();
+        migrate_price_history(&mut conn).unwrap();
+        migrate_price_history(&mut conn).unwrap();
+        let rows: Vec<(i64, f64)> = conn.prepare("SELECT id, price FROM price_history ORDER BY id").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(rows, vec![(1, 4.99), (2, 5.49)]);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_sync_state() {
+        let mut db = SqliteSideDb::open(":memory:").unwrap();
+        assert_eq!(db.get_sync_state("square_customers").await.unwrap(), None);
+        let when = Utc::now();
+        db.set_sync_state("square_customers", &when).await.unwrap();
+        db.set_sync_state("square_customers", &when).await.unwrap();
+        let got = db.get_sync_state("square_customers").await.unwrap().unwrap();
+        assert!((got - when).num_milliseconds().abs() < 1);
+    }
+}
@@ -1,29 +1,41 @@
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{Local, NaiveDateTime, TimeZone, Utc};
 use fancy_regex::Regex;
+use itertools::Itertools;
 use log::*;
+use serde::Serialize;
 use squareup::models::enums::{CatalogCategoryType, CatalogCustomAttributeDefinitionAppVisibility, CatalogCustomAttributeDefinitionSellerVisibility, CatalogCustomAttributeDefinitionType};
-use std::{collections::HashMap, fmt::Debug};
+use std::{collections::{HashMap, HashSet}, fmt::Debug};
+use std::future::Future;
 use std::time::Duration;
 use std::sync::Arc;
 use squareup::{api::LocationsApi,
               config::{BaseUri, Configuration},
-              models::enums::{CatalogItemProductType, CatalogObjectType, CatalogPricingType, Currency, InventoryChangeType, InventoryState, MeasurementUnitUnitType, MeasurementUnitWeight},
-              models::{BatchChangeInventoryRequest, CatalogItem, CatalogItemVariation, CatalogMeasurementUnit, CatalogObject, CatalogCustomAttributeDefinition, DateTime, InventoryChange, InventoryPhysicalCount, ItemVariationLocationOverrides, ListCatalogParameters, ListCustomersParameters, Location, MeasurementUnit, Money, Payment, Order, UpsertCatalogObjectRequest},
+              models::enums::{CatalogItemProductType, CatalogObjectType, CatalogPricingType, Currency, InventoryChangeType, InventoryState, MeasurementUnitGeneric, MeasurementUnitUnitType, MeasurementUnitWeight},
+              models::{BatchChangeInventoryRequest, BatchRetrieveInventoryCountsRequest, CatalogItem, CatalogItemVariation, CatalogMeasurementUnit, CatalogObject, CatalogCustomAttributeDefinition, DateTime, InventoryChange, InventoryPhysicalCount, ItemVariationLocationOverrides, ListCatalogParameters, ListCustomersParameters, Location, MeasurementUnit, Money, Payment, Order, UpsertCatalogObjectRequest},
               SquareClient};
 use squareup::http::{Headers, client::{HttpClientConfiguration, RetryConfiguration}};
 use squareup::api::{CatalogApi, CustomerGroupsApi, CustomersApi, InventoryApi, OrdersApi, PaymentsApi};
 use uuid::Uuid;
-use squareup::models::{CatalogCategory, CatalogCustomAttributeDefinitionNumberConfig, CatalogCustomAttributeValue, CatalogObjectCategory, CreateCustomerGroupRequest, Customer, CustomerGroup, ListCustomerGroupsParameters, ListPaymentsParameters, SearchOrdersDateTimeFilter, SearchOrdersFilter, SearchOrdersQuery, SearchOrdersRequest, TimeRange};
+use squareup::models::{CatalogCategory, CatalogQuery, CatalogQueryExact, SearchCatalogObjectsRequest, CatalogCustomAttributeDefinitionNumberConfig, CatalogCustomAttributeValue, CatalogObjectCategory, CreateCustomerGroupRequest, Customer, CustomerGroup, ListCustomerGroupsParameters, ListPaymentsParameters, SearchCustomersFilter, SearchCustomersQuery, SearchCustomersRequest, SearchCustomersTextFilter, SearchOrdersDateTimeFilter, SearchOrdersFilter, SearchOrdersQuery, SearchOrdersRequest, TimeRange};
 
-use super::api::{ITRCat, ProductData};
+use super::api::{csv_field, ITRCat, ProductData};
+use super::progress::Progress;
 
 const CA_BUTCHERS_PLU: &str = "butchers-plu";
+// Log target for the --trace-upc product sync decisions.
+pub const TRACE_TARGET: &str = "square::trace";
+// Buyer-facing cost data, only populated with --sync-cost: (key, name, precision).
+const CA_BUTCHERS_COST: [(&str, &str, i32); 4] = [
+    ("butchers-cost", "Cost", 2),
+    ("butchers-case-cost", "Case Cost", 2),
+    ("butchers-pack", "Pack", 0),
+    ("butchers-vendor", "Vendor", 0),
+];
 //const MD_LOYALTY_POINTS: &str = "loyalty-points";
 //const MD_LOYALTY_DISCOUNT: &str = "loyalty-discount";
 
 pub enum TaxLocation<'a> {
-    #[allow(dead_code)]
     State(String),
     Location(&'a Location),
 }
@@ -35,6 +47,137 @@ pub struct SquareSyncResult {
     pub added_down: u64,
     pub updated_up: u64,
     pub deleted_up: u64,
+    // What a report-only sync would have done; empty for a live sync.
+    pub plan: Vec<PlannedChange>,
+    // Square customers a customer sync couldn't tie to any IT Retail customer.
+    pub unmatched: Vec<UnmatchedCustomer>,
+    // Customers a customer sync tied together only by fuzzy matching.
+    pub fuzzy: Vec<FuzzyMatch>,
+}
+
+// An IT Retail customer fuzzy matched to a Square customer (same last name and
+// phone, or same last name and a similar email), for the owner to review.  The
+// two are only associated when the sync was asked to.
+#[derive(Debug, Serialize)]
+pub struct FuzzyMatch {
+    pub itretail_id: Uuid,
+    pub square_id: String,
+    pub email: Option<String>,
+    pub square_email: Option<String>,
+    pub phone: Option<String>,
+    pub square_phone: Option<String>,
+    // name+phone or email
+    pub reason: &'static str,
+    pub confidence: f64,
+    pub associated: bool,
+}
+
+impl FuzzyMatch {
+    pub fn csv_line(&self) -> String {
+        [self.itretail_id.to_string().as_str(), self.square_id.as_str(),
+         self.email.as_deref().unwrap_or(""), self.square_email.as_deref().unwrap_or(""),
+         self.phone.as_deref().unwrap_or(""), self.square_phone.as_deref().unwrap_or(""),
+         self.reason, format!("{:.2}", self.confidence).as_str(), if self.associated { "yes" } else { "no" }]
+            .iter().map(|f| csv_field(f)).collect::<Vec<String>>().join(",")
+    }
+}
+
+// A Square customer with no reference_id, squareup_id, email or phone match on our side.
+#[derive(Debug, Serialize)]
+pub struct UnmatchedCustomer {
+    pub id: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+}
+
+impl UnmatchedCustomer {
+    pub fn csv_line(&self) -> String {
+        [self.email.as_deref().unwrap_or(""), self.phone.as_deref().unwrap_or(""), self.id.as_str()]
+            .iter().map(|f| csv_field(f)).collect::<Vec<String>>().join(",")
+    }
+}
+
+// How a bulk customer delete went, by customer.
+#[derive(Debug, Default, PartialEq)]
+pub struct CustomerDeletes {
+    pub deleted: u32,
+    pub failed: u32,
+}
+
+// Square answers each id with an empty object on success or its errors; ids it
+// didn't answer at all (a top-level error) count as failed.
+fn count_customer_deletes(r: &squareup::models::BulkDeleteCustomersResponse, requested: usize) -> CustomerDeletes {
+    let mut counts = CustomerDeletes::default();
+    for e in r.errors.iter().flatten() {
+        error!("error deleting customers: {:?}", e);
+    }
+    for (id, response) in r.responses.iter().flatten() {
+        match &response.errors {
+            Some(errors) if !errors.is_empty() => {
+                error!("error deleting customer {}: {:?}", id, errors);
+                counts.failed += 1;
+            },
+            _ => counts.deleted += 1,
+        }
+    }
+    counts.failed += requested.saturating_sub((counts.deleted + counts.failed) as usize) as u32;
+    counts
+}
+
+// One decision of a report-only product sync.
+#[derive(Debug, Serialize)]
+pub struct PlannedChange {
+    // create, update, archive, create-category, create-measure, update-measure,
+    // create-custom-attribute or set-inventory
+    pub action: &'static str,
+    pub upc: Option<String>,
+    pub name: String,
+    pub detail: Option<String>,
+}
+
+// Read-back check of the counts pushed by an inventory sync.
+pub struct InventoryVerify {
+    pub tolerance: f64,
+    pub output: String,
+    pub bom: bool,
+}
+
+// A Square-managed item whose Square on-hand count differs from IT Retail's.
+#[derive(Debug, Serialize)]
+pub struct InventoryVariance {
+    pub upc: String,
+    pub description: String,
+    pub catalog_object_id: String,
+    pub itretail: f64,
+    // None when Square has no in-stock count for the item.
+    pub square: Option<f64>,
+    // square - itretail, counting a missing Square count as 0.
+    pub difference: f64,
+}
+
+impl InventoryVariance {
+    pub fn csv_line(&self) -> String {
+        format!("{},{},{},{},{},{}", csv_field(&self.upc), csv_field(&self.description), self.catalog_object_id,
+                self.itretail, self.square.map(|q| q.to_string()).unwrap_or_default(), self.difference)
+    }
+}
+
+// Items whose counts differ by more than min_difference, biggest difference first.
+fn inventory_variances(products: &[ProductData], counts: &HashMap<String, f64>, min_difference: f64) -> Vec<InventoryVariance> {
+    products.iter()
+        .filter_map(|p| {
+            let id = p.squareup_id.as_ref()?;
+            let itretail = p.quantity_on_hand.unwrap_or(0.0) as f64;
+            let square = counts.get(id).copied();
+            let difference = square.unwrap_or(0.0) - itretail;
+            if difference.abs() <= min_difference {
+                return None;
+            }
+            Some(InventoryVariance { upc: p.upc.to_string(), description: p.description.to_string(),
+                catalog_object_id: id.to_string(), itretail, square, difference })
+        })
+        .sorted_by(|a, b| b.difference.abs().total_cmp(&a.difference.abs()).then(a.upc.cmp(&b.upc)))
+        .collect()
 }
 
 #[allow(dead_code)]
@@ -43,39 +186,278 @@ pub struct SquareConnect {
     appid: String,
     location: String,
     state: Option<String>,
-    weight_unit: MeasurementUnitWeight,
-    weight_precision: i32,
+    // Weighed items sell by `measure` unless their UPC or department names another.
+    measure: MeasureSpec,
+    product_measures: HashMap<String, MeasureSpec>,
+    department_measures: HashMap<i32, MeasureSpec>,
+    customers_full_sync_hours: u32,
+    present_locations: Vec<String>,
+    sync_cost: bool,
+    report_only: bool,
+    allow_zero_price: bool,
+    trace_upc: Option<String>,
+    only_upcs: Option<HashSet<String>>,
+    dry_run_deletes: bool,
+    progress: bool,
+    fuzzy_match_threshold: Option<f64>,
+    fuzzy_associate: bool,
+    size_variation_name: bool,
+    item_descriptions: bool,
+    contact_source: ContactSource,
+    match_keys: Vec<MatchKey>,
+    offline_departments: HashSet<i32>,
+    offline_sections: HashSet<i32>,
+    taxed_departments: HashSet<i32>,
+    currency: Currency,
+}
+
+// Where a customer's email and phone come from when Square and IT Retail disagree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ContactSource {
+    Itr,
+    Square,
+    Newest,
+}
+
+// What a Square customer is matched to an IT Retail one by, tried in the order
+// square.match_keys gives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchKey {
+    ReferenceId,
+    Email,
+    Phone,
+}
+
+// Match keys are a list of reference_id, email and phone, each at most once.
+pub fn parse_match_keys(keys: &[String]) -> Result<Vec<MatchKey>> {
+    let mut parsed = vec![];
+    for k in keys {
+        let key = match k.trim().to_lowercase().as_str() {
+            "reference_id" => MatchKey::ReferenceId,
+            "email" => MatchKey::Email,
+            "phone" => MatchKey::Phone,
+            _ => return Err(anyhow!("unknown match key {:?}, expected reference_id, email or phone", k)),
+        };
+        if parsed.contains(&key) {
+            return Err(anyhow!("match key {:?} given twice", k));
+        }
+        parsed.push(key);
+    }
+    if parsed.is_empty() {
+        return Err(anyhow!("no match keys given"));
+    }
+    Ok(parsed)
+}
+
+// The Square customer `dbc` matches by the first of `keys` that finds one.
+fn match_square_customer<'m, 'a>(keys: &[MatchKey], dbc: &super::api::Customer,
+                                 by_itrid: &'m HashMap<Uuid, &'a Customer>,
+                                 by_email: &'m HashMap<&String, &'a Customer>,
+                                 by_phone: &'m HashMap<&String, &'a Customer>) -> Option<(MatchKey, &'m &'a Customer)> {
+    keys.iter().find_map(|key| match key {
+        MatchKey::ReferenceId => by_itrid.get(&dbc.id),
+        MatchKey::Email => dbc.email.as_ref().and_then(|e| by_email.get(e)),
+        MatchKey::Phone => square_phone(&dbc.phone).and_then(|p| by_phone.get(&p)),
+    }.map(|sc| (*key, sc)))
 }
 
 struct MetaBuilder {
     tax_id: String,
     location_id: String,
+    present_at_location_ids: Option<Vec<String>>,
+    // Square measurement unit ids, by UPC and by department, and for everything else.
+    product_measurement_ids: HashMap<String, String>,
+    department_measurement_ids: HashMap<i32, String>,
     measurement_id: String,
     plu_id: String,
+    sync_cost: bool,
+    size_variation_name: bool,
+    item_descriptions: bool,
+    offline_departments: HashSet<i32>,
+    offline_sections: HashSet<i32>,
+    taxed_departments: HashSet<i32>,
+    currency: Currency,
     categories: Arc<HashMap<ITRCat,String>>
 }
 impl<'a> MetaBuilder {
     pub fn build(&self, product: &'a ProductData) -> ProductDataWithMetadata<'a> {
+        let online = if self.offline_departments.is_empty() && self.offline_sections.is_empty() {
+            None
+        } else {
+            Some(!self.offline_departments.contains(&product.department_id) &&
+                 !product.section_id.is_some_and(|s| self.offline_sections.contains(&s)))
+        };
+        let taxed = product.taxclass.0.is_some() || self.taxed_departments.contains(&product.department_id);
+        if taxed && product.taxclass.0.is_none() {
+            info!("{} has no tax class, taxing it as department {}", product.upc, product.department_id);
+        }
         ProductDataWithMetadata {
             product: product,
+            taxed,
             tax_id: self.tax_id.clone(),
             location_id: self.location_id.clone(),
-            measurement_id: self.measurement_id.clone(),
+            present_at_location_ids: self.present_at_location_ids.clone(),
+            measurement_id: self.product_measurement_ids.get(&product.upc)
+                .or(self.department_measurement_ids.get(&product.department_id))
+                .unwrap_or(&self.measurement_id).clone(),
             plu_id: self.plu_id.clone(),
+            sync_cost: self.sync_cost,
+            size_variation_name: self.size_variation_name,
+            item_descriptions: self.item_descriptions,
+            online,
+            currency: self.currency.clone(),
             categories: Arc::clone(&self.categories),
         }
     }
 }
 struct ProductDataWithMetadata<'a> {
     product: &'a ProductData,
+    // The product's tax class, or failing that its department's default.
+    taxed: bool,
     tax_id: String,
     location_id: String,
+    // None means present at all locations.
+    present_at_location_ids: Option<Vec<String>>,
     measurement_id: String,
     #[allow(dead_code)]
     plu_id: String,
+    sync_cost: bool,
+    // Name the variation by the product's size ("16 oz") instead of "Regular".
+    size_variation_name: bool,
+    // Describe the item with the product's second description (its ingredients).
+    item_descriptions: bool,
+    // Whether the item may be sold in the online store; None leaves Square's setting alone.
+    online: Option<bool>,
+    currency: Currency,
     categories: Arc<HashMap<ITRCat,String>>,
 }
 
+// ISO 4217 code, case-insensitive, as Square names it.
+pub fn parse_currency(code: &str) -> Result<Currency> {
+    match serde_json::from_value(serde_json::Value::String(code.trim().to_uppercase())) {
+        Ok(Currency::UnknownCurrency) | Err(_) => Err(anyhow!("Unknown currency: {}", code)),
+        Ok(currency) => Ok(currency),
+    }
+}
+
+// A Square measurement unit for weighed items: a weight, or whole units when
+// `weight_unit` is None, to `precision` decimal places.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasureSpec {
+    pub weight_unit: Option<MeasurementUnitWeight>,
+    pub precision: i32,
+}
+
+// Measures are given as UNIT[:PRECISION], e.g. imperial_pound:3, metric_kilogram or
+// unit:0, as Square names the weight units; without one the precision is `default_precision`.
+pub fn parse_measure(arg: &str, default_precision: i32) -> Result<MeasureSpec> {
+    let (unit, precision) = match arg.trim().split_once(':') {
+        Some((u, p)) => (u.trim(), p.trim().parse::<i32>().map_err(|_| anyhow!("Bad precision in measure: {}", arg))?),
+        None => (arg.trim(), default_precision),
+    };
+    if !(0..=5).contains(&precision) {
+        return Err(anyhow!("Measure precision must be 0 to 5: {}", arg));
+    }
+    let weight_unit = match unit.to_uppercase().as_str() {
+        "UNIT" => None,
+        u => match serde_json::from_value(serde_json::Value::String(u.to_string())) {
+            Ok(w) => Some(w),
+            Err(_) => return Err(anyhow!("Unknown measure unit: {}", unit)),
+        },
+    };
+    Ok(MeasureSpec { weight_unit, precision })
+}
+
+// A measure as parse_measure takes it, e.g. imperial_pound:3 or unit:0.
+fn measure_name(m: &MeasureSpec) -> String {
+    let unit = match &m.weight_unit {
+        Some(w) => serde_json::to_value(w).ok().and_then(|v| v.as_str().map(|s| s.to_lowercase())).unwrap_or_default(),
+        None => "unit".to_string(),
+    };
+    format!("{}:{}", unit, m.precision)
+}
+
+// How get_measurement_ids comes by each measurement unit it wants.
+#[derive(Debug, PartialEq)]
+enum MeasureStep {
+    Existing(String),
+    // Move this unit (id, version) to the wanted precision.
+    Reprecision(String, Option<i64>),
+    Create,
+}
+
+fn measure_steps(existing: &[(&CatalogObject, MeasureSpec)], measures: &[MeasureSpec]) -> Vec<MeasureStep> {
+    let mut steps: Vec<MeasureStep> = measures.iter().map(|want| {
+        match existing.iter().find(|(_, have)| have == want) {
+            Some((m, _)) => MeasureStep::Existing(m.id.clone()),
+            None => MeasureStep::Create,
+        }
+    }).collect();
+    for (idx, want) in measures.iter().enumerate() {
+        if steps[idx] != MeasureStep::Create {
+            continue;
+        }
+        let taken = |id: &String| steps.iter().any(|s| matches!(s, MeasureStep::Existing(i) | MeasureStep::Reprecision(i, _) if i == id));
+        let reusable = existing.iter().find(|(m, have)| have.weight_unit == want.weight_unit
+            && !measures.contains(have) && !taken(&m.id));
+        if let Some((m, _)) = reusable {
+            steps[idx] = MeasureStep::Reprecision(m.id.clone(), m.version);
+        }
+    }
+    steps
+}
+
+// A report-only run's take on measure_steps: the writes become plan entries and
+// each unit that would be created gets a placeholder id.
+fn plan_measures(steps: &[MeasureStep], measures: &[MeasureSpec], plan: &mut Vec<PlannedChange>) -> Vec<String> {
+    steps.iter().zip(measures).enumerate().map(|(idx, (step, want))| match step {
+        MeasureStep::Existing(id) => id.clone(),
+        MeasureStep::Reprecision(id, _) => {
+            plan.push(PlannedChange { action: "update-measure", upc: None, name: measure_name(want), detail: Some(id.clone()) });
+            id.clone()
+        },
+        MeasureStep::Create => {
+            plan.push(PlannedChange { action: "create-measure", upc: None, name: measure_name(want), detail: None });
+            format!("#newmeasure-{}", idx)
+        },
+    }).collect()
+}
+
+// Square money is in the currency's minor unit.
+fn square_money(amount: f64, currency: &Currency) -> Money {
+    Money {
+        amount: (amount * 100.0).round() as i32,
+        currency: currency.clone(),
+    }
+}
+
+// Square item descriptions run to 4096 characters.
+const DESCRIPTION_MAX_CHARS: usize = 4096;
+
+// Text fit for an item description: no control characters, whitespace collapsed,
+// and cut to Square's limit on a word boundary.  None when nothing is left.
+fn square_description(text: &str) -> Option<String> {
+    let clean = text.split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|w| !w.is_empty())
+        .join(" ");
+    if clean.is_empty() {
+        return None;
+    }
+    if clean.chars().count() <= DESCRIPTION_MAX_CHARS {
+        return Some(clean);
+    }
+    let cut: String = clean.chars().take(DESCRIPTION_MAX_CHARS - 1).collect();
+    let cut = match cut.rfind(' ') {
+        Some(at) if at > 0 => cut[..at].to_string(),
+        _ => cut,
+    };
+    Some(format!("{}…", cut))
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
 fn square_phone(maybe_trash: &Option<String>) -> Option<String> {
     if let Some(trash) = maybe_trash {
         let dig = super::customer::normalize_phone(trash);
@@ -106,10 +488,7 @@ fn catalogobject_getsku(co: &CatalogObject) -> Result<String> {
 impl<'a> From<ProductDataWithMetadata<'a>> for CatalogObject {
     fn from(pwl: ProductDataWithMetadata) -> Self {
         let p = pwl.product;
-        let tax_ids = match p.taxclass.0 {
-            Some(_taxid) => Some(vec![pwl.tax_id.clone()]),
-            None => None
-        };
+        let tax_ids = if pwl.taxed { Some(vec![pwl.tax_id.clone()]) } else { None };
         let name = (&p.description).to_string();
         let mut attrs = HashMap::<String, CatalogCustomAttributeValue>::new();
         if let Some(plu_str) = &p.plu {
@@ -122,6 +501,31 @@ impl<'a> From<ProductDataWithMetadata<'a>> for CatalogObject {
                 }
             }
         }
+        if pwl.sync_cost {
+            let values = [
+                p.cost.map(|c| format!("{:.2}", c)),
+                p.case_cost.map(|c| format!("{:.2}", c)),
+                p.pack.map(|n| n.to_string()),
+                p.vendor_id.map(|n| n.to_string()),
+            ];
+            for ((key, _, _), value) in CA_BUTCHERS_COST.iter().zip(values) {
+                if let Some(value) = value {
+                    attrs.insert(key.to_string(), CatalogCustomAttributeValue{
+                        number_value: Some(value),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        let variation_name = match p.size.as_ref().map(|s| s.trim()) {
+            Some(size) if pwl.size_variation_name && !size.is_empty() => size.to_string(),
+            _ => "Regular".to_string(),
+        };
+        let description = if pwl.item_descriptions {
+            p.second_description.as_deref().and_then(square_description)
+        } else {
+            None
+        };
         let itrcat = match pwl.product.section_id {
             Some(id) => pwl.categories.get(&ITRCat::Section(id)),
             None => pwl.categories.get(&ITRCat::Department(pwl.product.department_id))
@@ -140,16 +544,19 @@ impl<'a> From<ProductDataWithMetadata<'a>> for CatalogObject {
             r#type: CatalogObjectType::Item,
             id: format!("#{}", p.upc),
             is_deleted: Some(p.deleted),
-            present_at_all_locations: Some(true),
+            present_at_all_locations: Some(pwl.present_at_location_ids.is_none()),
+            present_at_location_ids: pwl.present_at_location_ids.clone(),
             custom_attribute_values: if attrs.len() > 0 { Some(attrs) } else { None },
             item_data: Some(CatalogItem {
                 name: Some(name.to_string()),
                 is_taxable: Some(true), // tax_ids controls this
                 tax_ids: tax_ids,
-                available_for_pickup: Some(true),
+                available_for_pickup: Some(pwl.online.unwrap_or(true)),
+                available_online: pwl.online,
                 skip_modifier_screen: Some(true),
-                description_html: None,
-                description_plaintext: None,
+                description_html: description.as_ref().map(|d| format!("<p>{}</p>", html_escape(d))),
+                // Square derives this from the html; it's set so catalogitem_needs_update can compare.
+                description_plaintext: description,
                 product_type: Some(CatalogItemProductType::Regular),
                 is_archived: Some(p.deleted),
                 categories: categories,
@@ -159,18 +566,16 @@ impl<'a> From<ProductDataWithMetadata<'a>> for CatalogObject {
                         r#type: CatalogObjectType::ItemVariation,
                         id: format!("#{}-var1", p.upc),
                         is_deleted: Some(p.deleted),
-                        present_at_all_locations: Some(true),
+                        present_at_all_locations: Some(pwl.present_at_location_ids.is_none()),
+                        present_at_location_ids: pwl.present_at_location_ids.clone(),
                         item_variation_data: Some(
                             CatalogItemVariation {
                                 item_id: Some(format!("#{}", (&p.upc).to_string())),
-                                name: Some("Regular".to_string()),
+                                name: Some(variation_name),
                                 sku: p.upca(),
                                 ordinal: Some(1),
                                 pricing_type: Some(CatalogPricingType::FixedPricing),
-                                price_money: Some(Money{
-                                    amount: (p.get_price() * 100.0) as i32,
-                                    currency: Currency::Usd,
-                                }),
+                                price_money: Some(square_money(p.get_price(), &pwl.currency)),
                                 sellable: Some(true),
                                 stockable: Some(true),
                                 measurement_unit_id: if p.scale { Some(pwl.measurement_id) } else { None },
@@ -276,6 +681,13 @@ fn get_catalogitem_plu(i: &CatalogObject) -> Option<String> {
     None
 }
 
+fn get_catalogitem_number(i: &CatalogObject, key: &str) -> Option<f64> {
+    i.custom_attribute_values.as_ref()
+        .and_then(|avs| avs.get(key))
+        .and_then(|v| v.number_value.as_ref())
+        .and_then(|n| n.parse::<f64>().ok())
+}
+
 fn catalogitem_needs_update(a: &CatalogObject, b: &CatalogObject) -> Result<Option<String>> {
     // verify our structure [Object[0] -> Item[1] -> Object[2] -> ItemVariation[3] -> ItemVariableLocationOverrides[4] ]
     // Object[1]
@@ -287,12 +699,21 @@ fn catalogitem_needs_update(a: &CatalogObject, b: &CatalogObject) -> Result<Opti
     if a.absent_at_location_ids != b.absent_at_location_ids { return Ok(Some("present_at_location_ids".to_owned())); }
     // PLU?
     if get_catalogitem_plu(a) != get_catalogitem_plu(b) { return Ok(Some("plu differs".to_owned())); }
+    // Cost attributes are only compared when we're setting them.
+    for (key, _, _) in CA_BUTCHERS_COST.iter() {
+        if let Some(want) = get_catalogitem_number(b, key) {
+            if get_catalogitem_number(a, key).is_none_or(|have| (have - want).abs() > 0.005) {
+                return Ok(Some(format!("{} differs", key)));
+            }
+        }
+    }
     // Item
     let (a1, b1) = (a.item_data.as_ref().unwrap(), b.item_data.as_ref().unwrap());
     if a1.name != b1.name { return Ok(Some("name".to_owned())); }
     if a1.is_taxable != b1.is_taxable { return Ok(Some("is_taxable".to_owned())); }
     if a1.tax_ids != b1.tax_ids { return Ok(Some("tax_ids".to_owned())); }
     if a1.available_for_pickup != b1.available_for_pickup { return Ok(Some("available_for_pickup".to_owned())); }
+    if b1.available_online.is_some() && a1.available_online != b1.available_online { return Ok(Some("available_online".to_owned())); }
     if a1.skip_modifier_screen != b1.skip_modifier_screen { return Ok(Some("skip_modifier_screen".to_owned())); }
     if a1.description_plaintext != b1.description_plaintext { return Ok(Some("description_plaintext".to_owned())); }
     if a1.product_type != b1.product_type { return Ok(Some("product_type".to_owned())); }
@@ -328,6 +749,7 @@ fn catalogitem_needs_update(a: &CatalogObject, b: &CatalogObject) -> Result<Opti
     }
     if a2.is_deleted != b2.is_deleted { return Ok(Some("variation.is_deleted".to_owned())); }
     if a2.present_at_all_locations != b2.present_at_all_locations { return Ok(Some("variation.present_at_all_locations".to_owned())); }
+    if a2.present_at_location_ids != b2.present_at_location_ids { return Ok(Some("variation.present_at_location_ids".to_owned())); }
     // Variation
     if a2.item_variation_data.is_none() || b2.item_variation_data.is_none() {
         return Err(anyhow!("missing item_variation_data"));
@@ -351,6 +773,21 @@ fn catalogitem_needs_update(a: &CatalogObject, b: &CatalogObject) -> Result<Opti
     if a4[0].track_inventory != b4[0].track_inventory { return Ok(Some("variation.data.location_overrides.track_inventory".to_owned())); }
     Ok(None)
 }
+// Square SKUs (UPC-A, or the 5 digit code of a weighed item) shared by more than
+// one IT Retail product, with the UPCs that share each.  Syncing those would have
+// both products fighting over, or duplicating, one Square item.  `sku` is how the
+// sync turns an IT Retail UPC into a SKU, itr_upc_to_upca.
+fn upca_collisions<'a>(upcs: impl Iterator<Item = &'a String>, sku: impl Fn(&String) -> Option<String>) -> HashMap<String, Vec<String>> {
+    let mut by_upca = HashMap::<String, Vec<String>>::new();
+    for upc in upcs {
+        if let Some(upca) = sku(upc) {
+            by_upca.entry(upca).or_default().push(upc.to_string());
+        }
+    }
+    by_upca.retain(|_, upcs| upcs.len() > 1);
+    by_upca
+}
+
 fn catalogitem_adopt_ids(a: &mut CatalogObject, b: &CatalogObject) -> Result<()> {
     // This moves the id/item_id and versions into a from b.
     a.id = b.id.clone();
@@ -365,7 +802,42 @@ fn catalogitem_adopt_ids(a: &mut CatalogObject, b: &CatalogObject) -> Result<()>
     Ok(())
 }
 
+// Collect every page of a cursor-paged Square list call.  `fetch` is handed the
+// cursor for the next page (None for the first) and returns that page's items
+// along with the cursor to continue from, if any.
+async fn paginate<T, F, Fut>(mut fetch: F) -> Result<Vec<T>>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<(Vec<T>, Option<String>)>>,
+{
+    let mut cursor: Option<String> = None;
+    let mut all: Vec<T> = vec![];
+    loop {
+        let (page, next) = fetch(cursor).await?;
+        all.extend(page);
+        match next {
+            Some(next) if !next.is_empty() => cursor = Some(next),
+            _ => break,
+        }
+    }
+    Ok(all)
+}
+
 pub fn square_connect_create(settings: &super::settings::Settings) -> SquareConnect {
+    square_connect_create_for(settings, false)
+}
+
+// The longest wait between two attempts under an exponential retry schedule.
+fn longest_retry_backoff(retries: u32, min: Duration, max: Duration, base: u32) -> Duration {
+    if retries == 0 {
+        return Duration::ZERO;
+    }
+    let factor = base.checked_pow(retries - 1).unwrap_or(u32::MAX);
+    min.checked_mul(factor).unwrap_or(max).min(max)
+}
+
+// Background callers (the --period loop) get the long retry ceiling, everyone else the interactive one.
+pub fn square_connect_create_for(settings: &super::settings::Settings, background: bool) -> SquareConnect {
     let (env, auth, appid) = match settings.square.environment {
         super::settings::SquareEnvironment::Production => {
             (squareup::config::Environment::Production,
@@ -378,37 +850,182 @@ pub fn square_connect_create(settings: &super::settings::Settings) -> SquareConn
              settings.square.sandbox_appid.to_string())
         }
     };
+    let min_retry_interval = Duration::from_secs(settings.square.retry_min_interval_seconds);
+    let max_retry_interval = Duration::from_secs(if background {
+        settings.square.retry_max_interval_seconds
+    } else {
+        settings.square.interactive_retry_max_interval_seconds
+    });
+    let longest = longest_retry_backoff(settings.square.max_retries, min_retry_interval, max_retry_interval, settings.square.retry_base);
+    if longest.as_secs() > settings.square.retry_warn_seconds {
+        warn!("Square calls may back off up to {}s between retries (square.retry_* settings)", longest.as_secs());
+    }
     let headers = Headers::new(None, None, None, None, Some(auth));
     let config = Configuration {
         environment: env,
         http_client_config: HttpClientConfiguration {
-            timeout: 30,
+            timeout: settings.square.timeout_seconds,
             user_agent: String::from("itretail_automation"), // will override what's in headers
             default_headers: headers,
             retry_configuration: RetryConfiguration {
                 retries_count: settings.square.max_retries,
-                min_retry_interval: Duration::from_secs(1),
-                max_retry_interval: Duration::from_secs(30 * 60),
-                base: 3,
+                min_retry_interval,
+                max_retry_interval,
+                base: settings.square.retry_base,
             },
         },
         base_uri: BaseUri::default(),
     };
-    let unit = match settings.square.weight_unit.to_lowercase().as_str() {
-        "imperial_pound" => MeasurementUnitWeight::ImperialPound,
+    let contact_source = match settings.square.contact_source.to_lowercase().as_str() {
+        "itr_wins" => ContactSource::Itr,
+        "square_wins" => ContactSource::Square,
+        "newest_wins" => ContactSource::Newest,
+        _ => panic!("Invalid square contact_source in config: {}", settings.square.contact_source)
+    };
+    let match_keys = match parse_match_keys(&settings.square.match_keys) {
+        Ok(k) => k,
+        Err(e) => panic!("Invalid square match_keys in config: {}", e)
+    };
+    let currency = match parse_currency(&settings.square.currency) {
+        Ok(c) => c,
+        Err(_) => panic!("Invalid square currency in config: {}", settings.square.currency)
+    };
+    let measure = match parse_measure(&settings.square.weight_unit, settings.square.weight_precision) {
+        Ok(m) if m.weight_unit.is_some() => m,
         _ => panic!("Invalid square weight_units in config: {}", settings.square.weight_unit)
     };
+    let parse_measures = |setting: &str, measures: &HashMap<String, String>| -> HashMap<String, MeasureSpec> {
+        measures.iter().map(|(k, v)| match parse_measure(v, measure.precision) {
+            Ok(m) => (k.trim().to_string(), m),
+            Err(e) => panic!("Invalid square {} in config for {}: {}", setting, k, e),
+        }).collect()
+    };
+    let product_measures = parse_measures("product_measures", &settings.square.product_measures);
+    let department_measures = parse_measures("department_measures", &settings.square.department_measures)
+        .into_iter().map(|(k, v)| match k.parse::<i32>() {
+            Ok(d) => (d, v),
+            Err(_) => panic!("Invalid square department_measures in config, bad department: {}", k),
+        }).collect();
     SquareConnect {
         client: SquareClient::try_new(config).unwrap(),
         appid: appid,
         location: settings.square.location.to_string(),
-        state: None,
-        weight_unit: unit,
-        weight_precision: settings.square.weight_precision,
+        state: Some(settings.square.tax_state.to_string()).filter(|s| !s.is_empty()),
+        measure,
+        product_measures,
+        department_measures,
+        customers_full_sync_hours: settings.square.customers_full_sync_hours,
+        present_locations: settings.square.present_locations.clone(),
+        sync_cost: false,
+        report_only: false,
+        allow_zero_price: false,
+        trace_upc: None,
+        only_upcs: None,
+        dry_run_deletes: false,
+        progress: false,
+        fuzzy_match_threshold: None,
+        fuzzy_associate: false,
+        size_variation_name: settings.square.size_variation_name,
+        item_descriptions: settings.square.item_descriptions,
+        contact_source,
+        match_keys,
+        offline_departments: settings.square.offline_departments.iter().copied().collect(),
+        offline_sections: settings.square.offline_sections.iter().copied().collect(),
+        taxed_departments: settings.square.taxed_departments.iter().copied().collect(),
+        currency,
+    }
+}
+
+// 1.0 for identical strings down to 0.0, by edit distance over the longer length.
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == cb { diagonal } else { 1 + diagonal.min(above).min(row[j]) };
+            diagonal = above;
+        }
     }
+    1.0 - row[b.len()] as f64 / longest as f64
 }
 
-fn customer_needs_update(sc: &Customer, dc: &super::api::Customer) -> Option<String> {
+// The candidate most likely to be `dbc` under a mistyped email or differently
+// formatted phone: same last name and phone (0.95), or same last name and an
+// email at least `threshold` similar.  Returns it with the reason and confidence.
+fn fuzzy_customer<'a, 'b, I>(dbc: &super::api::Customer, candidates: I, threshold: f64) -> Option<(&'a &'b Customer, &'static str, f64)>
+where
+    I: Iterator<Item = &'a &'b Customer>,
+{
+    let last_name = dbc.last_name.trim().to_lowercase();
+    let phone = dbc.phone.as_ref().map(super::customer::normalize_phone).filter(|p| p.len() == 10);
+    let email = dbc.email.as_ref().map(|e| e.trim().to_lowercase()).filter(|e| !e.is_empty());
+    let mut best: Option<(&'a &'b Customer, &'static str, f64)> = None;
+    for sc in candidates {
+        let mut score: Option<(&'static str, f64)> = None;
+        if last_name.is_empty() || sc.family_name.as_ref().is_none_or(|n| n.trim().to_lowercase() != last_name) {
+            continue;
+        }
+        if phone.is_some()
+            && sc.phone_number.as_ref().map(super::customer::normalize_phone) == phone {
+            score = Some(("name+phone", 0.95));
+        }
+        if let (Some(e), Some(se)) = (&email, &sc.email_address) {
+            let s = similarity(e, &se.trim().to_lowercase());
+            if score.is_none_or(|(_, c)| s > c) {
+                score = Some(("email", s));
+            }
+        }
+        if let Some((reason, confidence)) = score {
+            if confidence >= threshold && best.is_none_or(|(_, _, b)| confidence > b) {
+                best = Some((sc, reason, confidence));
+            }
+        }
+    }
+    best
+}
+
+// sidedb customer timestamps are IT Retail local times.
+fn customer_modified(dc: &super::api::Customer) -> Option<chrono::DateTime<Utc>> {
+    dc.modified.as_ref()
+        .and_then(|m| NaiveDateTime::parse_from_str(m, "%Y-%m-%d %H:%M:%S%.f").ok())
+        .and_then(|ndt| Local.from_local_datetime(&ndt).earliest())
+        .map(|modified| modified.with_timezone(&Utc))
+}
+
+// Unparseable timestamps count as changed.
+fn customer_modified_since(dc: &super::api::Customer, since: &chrono::DateTime<Utc>) -> bool {
+    match customer_modified(dc) {
+        Some(modified) => modified >= *since,
+        None => true,
+    }
+}
+
+// Whether IT Retail's email and phone should be pushed over Square's.  With
+// newest_wins, a side without a usable timestamp loses.
+fn contact_from_itr(source: ContactSource, sc: &Customer, dc: &super::api::Customer) -> bool {
+    match source {
+        ContactSource::Itr => true,
+        ContactSource::Square => false,
+        ContactSource::Newest => {
+            let square_updated = sc.updated_at.clone().map(chrono::DateTime::<Utc>::from);
+            match (customer_modified(dc), square_updated) {
+                (Some(itr), Some(square)) => itr >= square,
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+        }
+    }
+}
+
+fn customer_needs_update(sc: &Customer, dc: &super::api::Customer, contact_from_itr: bool) -> Option<String> {
     match &sc.given_name {
         Some(a) => if a != &dc.first_name { return Some("given_name".to_owned()); },
         None => {}
@@ -417,18 +1034,20 @@ fn customer_needs_update(sc: &Customer, dc: &super::api::Customer) -> Option<Str
         Some(a) => if a != &dc.last_name { return Some("family_name".to_owned()); },
         None => {}
     }
-    // The dc.email can be blank (not null), sigh.
-    match (&sc.email_address, &dc.email) {
-        (Some(a), Some(b)) => if a != b { return Some("email".to_owned()); },
-        (Some(_), None) => { return Some("email".to_owned()); },
-        (None, Some(a)) => { if a.trim() != "" { return Some("email".to_owned()); } },
-        (None, None) => {}
-    }
-    match (&square_phone(&sc.phone_number), &square_phone(&dc.phone)) {
-        (Some(a), Some(b)) => if a != b { return Some("phone".to_owned()); },
-        (Some(_), None) => { return Some("phone".to_owned()); },
-        (None, Some(_)) => { return Some("phone".to_owned()); },
-        (None, None) => {}
+    if contact_from_itr {
+        // The dc.email can be blank (not null), sigh.
+        match (&sc.email_address, &dc.email) {
+            (Some(a), Some(b)) => if a != b { return Some("email".to_owned()); },
+            (Some(_), None) => { return Some("email".to_owned()); },
+            (None, Some(a)) => { if a.trim() != "" { return Some("email".to_owned()); } },
+            (None, None) => {}
+        }
+        match (&square_phone(&sc.phone_number), &square_phone(&dc.phone)) {
+            (Some(a), Some(b)) => if a != b { return Some("phone".to_owned()); },
+            (Some(_), None) => { return Some("phone".to_owned()); },
+            (None, Some(_)) => { return Some("phone".to_owned()); },
+            (None, None) => {}
+        }
     }
     match &sc.reference_id {
         Some(a) => if a != &dc.id.to_string() { return Some("reference_id".to_owned()); },
@@ -438,61 +1057,113 @@ fn customer_needs_update(sc: &Customer, dc: &super::api::Customer) -> Option<Str
 }
 
 impl SquareConnect {
+    // Cost data is sensitive, so it's only pushed to Square items when asked for.
+    pub fn set_sync_cost(&mut self, sync_cost: bool) {
+        self.sync_cost = sync_cost;
+    }
+
+    // Product syncs only work out what they'd change (SquareSyncResult.plan) and write nothing.
+    pub fn set_report_only(&mut self, report_only: bool) {
+        self.report_only = report_only;
+    }
+
+    // Push products with no price (as free) rather than skipping them.
+    pub fn set_allow_zero_price(&mut self, allow_zero_price: bool) {
+        self.allow_zero_price = allow_zero_price;
+    }
+
+    // Log every product sync decision for this UPC at info on TRACE_TARGET.
+    pub fn set_trace_upc(&mut self, upc: Option<String>) {
+        self.trace_upc = upc;
+    }
+
+    // Restrict the product sync to these IT Retail UPCs; None syncs them all.
+    pub fn set_only_upcs(&mut self, upcs: Option<HashSet<String>>) {
+        self.only_upcs = upcs;
+    }
+
+    // Customer syncs log the Square customers they'd delete instead of deleting them.
+    pub fn set_dry_run_deletes(&mut self, dry_run_deletes: bool) {
+        self.dry_run_deletes = dry_run_deletes;
+    }
+
+    // Show a progress bar through the customer and product syncs, when on a terminal.
+    pub fn set_progress(&mut self, progress: bool) {
+        self.progress = progress;
+    }
+
+    // Before creating a Square customer, try a fuzzy match at least this confident.
+    pub fn set_fuzzy_match(&mut self, threshold: Option<f64>) {
+        self.fuzzy_match_threshold = threshold;
+    }
+
+    // Associate fuzzy matches and update the Square customer from IT Retail,
+    // rather than only reporting them.
+    pub fn set_fuzzy_associate(&mut self, associate: bool) {
+        self.fuzzy_associate = associate;
+    }
+
     pub async fn get_customer_groups(&self, make: bool) -> Result<HashMap<u32,String>> {
+        if make {
+            return Ok(self.ensure_customer_groups().await?.0);
+        }
+        self.list_customer_groups().await
+    }
+    async fn list_customer_groups(&self) -> Result<HashMap<u32,String>> {
         let groupapi = CustomerGroupsApi::new(self.client.clone());
-        let mut cursor: Option<String> = None;
         let mut groups = HashMap::<u32,String>::new();
         let matcher = Regex::new(r"^Loyalty-Tier-(\d+)$").unwrap();
-        loop {
-            match groupapi.list_customer_groups(&ListCustomerGroupsParameters{cursor: cursor, limit: Some(50)}).await {
-                Ok(r) => {
-                    if let Some(groups_partial) = r.groups.as_ref() {
-                        for group in groups_partial {
-                            if let Ok(Some(mat)) = matcher.captures(group.name.as_ref()) {
-                                if let Some(level_str) = mat.get(1) {
-                                    let level = level_str.as_str().parse::<u32>()?;
-                                    groups.insert(level, group.id.as_ref().unwrap().clone());
-                                }
-                            }
-
-                        }
-                    }
-                    if let Some(new_cursor) = &r.cursor {
-                        cursor = Some(new_cursor.clone());
-                    } else {
-                        break;
-                    }
-                },
-                Err(e) => {
-                    return Err(e.into())
+        let all_groups = paginate(|cursor| {
+            let groupapi = &groupapi;
+            async move {
+                let r = groupapi.list_customer_groups(&ListCustomerGroupsParameters{cursor, limit: Some(50)}).await?;
+                Ok((r.groups.unwrap_or_default(), r.cursor))
+            }
+        }).await?;
+        for group in &all_groups {
+            if let Ok(Some(mat)) = matcher.captures(group.name.as_ref()) {
+                if let Some(level_str) = mat.get(1) {
+                    let level = level_str.as_str().parse::<u32>()?;
+                    groups.insert(level, group.id.as_ref().unwrap().clone());
                 }
             }
         }
-        if make {
-            for expected in super::loyalty::valid_loyalty_levels() {
-                if groups.get(&expected).is_none() {
-                    match groupapi.create_customer_group(&CreateCustomerGroupRequest {
-                        idempotency_key: Some(Uuid::new_v4().to_string()),
-                        group: CustomerGroup {
-                            name: format!("Loyalty-Tier-{}", expected),
-                            ..Default::default()
-                        }
-                    }).await {
-                        Ok(r) => {
-                            if let Some(id) = &r.group.id {
-                                groups.insert(expected, id.clone());
-                            } else {
-                                return Err(anyhow!("Group creation didn't result in id!"));
-                            }
-                        },
-                        Err(e) => {
-                            return Err(e.into());
+        Ok(groups)
+    }
+    // Create any missing Loyalty-Tier-N groups, returning all of them and the levels created.
+    pub async fn ensure_customer_groups(&self) -> Result<(HashMap<u32,String>, Vec<u32>)> {
+        let groupapi = CustomerGroupsApi::new(self.client.clone());
+        let mut groups = self.list_customer_groups().await?;
+        let mut created = vec![];
+        for expected in super::loyalty::valid_loyalty_levels() {
+            if let std::collections::hash_map::Entry::Vacant(slot) = groups.entry(expected) {
+                match groupapi.create_customer_group(&CreateCustomerGroupRequest {
+                    idempotency_key: Some(Uuid::new_v4().to_string()),
+                    group: CustomerGroup {
+                        name: format!("Loyalty-Tier-{}", expected),
+                        ..Default::default()
+                    }
+                }).await {
+                    Ok(r) => {
+                        if let Some(id) = &r.group.id {
+                            slot.insert(id.clone());
+                            created.push(expected);
+                        } else {
+                            return Err(anyhow!("Group creation didn't result in id!"));
                         }
+                    },
+                    Err(e) => {
+                        return Err(e.into());
                     }
                 }
             }
         }
-        Ok(groups)
+        Ok((groups, created))
+    }
+    // The loyalty levels with no Loyalty-Tier-N group in Square.
+    pub async fn missing_customer_groups(&self) -> Result<Vec<u32>> {
+        let groups = self.list_customer_groups().await?;
+        Ok(super::loyalty::valid_loyalty_levels().into_iter().filter(|l| !groups.contains_key(l)).collect())
     }
     async fn set_customer_loyalty(&self, capi: Option<&CustomersApi>, groups: &HashMap<u32, String>, cust: &&Customer, dbc: &super::api::Customer) -> Result<bool> {
         // There must be a better dance to make this live long enough
@@ -533,51 +1204,100 @@ impl SquareConnect {
             None => Some(CustomersApi::new(self.client.clone()))
         };
         let customers_api = capi.unwrap_or_else(|| { local_api.as_ref().unwrap() });
-        let mut cursor: String = String::from("");
-        let mut customers: Vec<Customer> = vec![];
-        loop {
+        paginate(|cursor| async move {
             let res = customers_api.list_customers(&ListCustomersParameters {
-                cursor: cursor,
+                cursor: cursor.unwrap_or_default(),
                 count: Some(true),
                 ..Default::default()
             }).await?;
-            if let Some(page) = res.customers {
-                for c in page {
-                    customers.push(c);
+            Ok((res.customers.unwrap_or_default(), res.cursor))
+        }).await
+    }
+
+    pub async fn get_customers_since(&self, capi: Option<&CustomersApi>, since: &chrono::DateTime<Utc>) -> Result<Vec<Customer>> {
+        let local_api = match capi {
+            Some(_) => None,
+            None => Some(CustomersApi::new(self.client.clone()))
+        };
+        let customers_api = capi.unwrap_or_else(|| { local_api.as_ref().unwrap() });
+        paginate(|cursor| async move {
+            let res = customers_api.search_customers(&SearchCustomersRequest {
+                cursor,
+                limit: Some(100),
+                query: Some(SearchCustomersQuery {
+                    filter: Some(SearchCustomersFilter {
+                        updated_at: Some(TimeRange {
+                            start_at: Some(since.into()),
+                            end_at: None,
+                        }),
+                        ..Default::default()
+                    }),
+                    sort: None,
+                }),
+                count: None,
+            }).await?;
+            Ok((res.customers.unwrap_or_default(), res.cursor))
+        }).await
+    }
+
+    // Returns each Square customer found along with how it was matched (id, email or phone).
+    pub async fn find_customers(&self, email: Option<&String>, phone: Option<&String>, ids: &[String]) -> Result<Vec<(&'static str, Customer)>> {
+        let customers_api = CustomersApi::new(self.client.clone());
+        let mut found: Vec<(&'static str, Customer)> = vec![];
+        for id in ids {
+            if let Ok(r) = customers_api.retrieve_customer(id).await {
+                found.push(("id", r.customer));
+            }
+        }
+        let mut filters = vec![];
+        if let Some(email) = email {
+            filters.push(("email", SearchCustomersFilter {
+                email_address: Some(SearchCustomersTextFilter { exact: Some(email.to_string()), fuzzy: None }),
+                ..Default::default()
+            }));
+        }
+        if let Some(phone) = square_phone(&phone.cloned()) {
+            filters.push(("phone", SearchCustomersFilter {
+                phone_number: Some(SearchCustomersTextFilter { exact: None, fuzzy: Some(phone) }),
+                ..Default::default()
+            }));
+        }
+        for (how, filter) in filters {
+            let res = customers_api.search_customers(&SearchCustomersRequest {
+                cursor: None,
+                limit: Some(100),
+                query: Some(SearchCustomersQuery { filter: Some(filter), sort: None }),
+                count: None,
+            }).await?;
+            for c in res.customers.unwrap_or_default() {
+                if !found.iter().any(|(_, f)| f.id == c.id) {
+                    found.push((how, c));
                 }
             }
-            if res.cursor.is_none() { break; }
-            cursor = res.cursor.unwrap();
         }
-        Ok(customers)
+        Ok(found)
     }
 
-    pub async fn delete_customer(&self, capi: Option<&CustomersApi>, customers: Vec<String>) -> Result<u32> {
+    // With dry_run the ids are only logged; nothing is deleted.
+    pub async fn delete_customer(&self, capi: Option<&CustomersApi>, customers: Vec<String>, dry_run: bool) -> Result<CustomerDeletes> {
+        if dry_run {
+            for id in &customers {
+                info!("Would delete Square customer {}", id);
+            }
+            return Ok(CustomerDeletes::default());
+        }
         // There must be a better dance to make this live long enough
         let local_api = match capi {
             Some(_) => None,
             None => Some(CustomersApi::new(self.client.clone()))
         };
         let customers_api = capi.unwrap_or_else(|| { local_api.as_ref().unwrap() });
+        let requested = customers.len();
         let delete_request = squareup::models::BulkDeleteCustomersRequest {
            customer_ids: customers
         };
         match customers_api.bulk_delete_customers(&delete_request).await {
-            Ok(r) => {
-                Ok(if let Some(map) = r.responses {
-                    let mut deletes: u32 = 0;
-                    for (_id, response) in map {
-                        match response.errors {
-                            Some(e) => { deletes += e.len() as u32; },
-                            None => {},
-                        }
-                    }
-                    deletes
-                }
-                else {
-                    0
-                })
-            },
+            Ok(r) => Ok(count_customer_deletes(&r, requested)),
             Err(e) => {
                 Err(e.into())
             }
@@ -615,17 +1335,19 @@ impl SquareConnect {
             None => Some(CustomersApi::new(self.client.clone()))
         };
         let customers_api = capi.unwrap_or_else(|| { local_api.as_ref().unwrap() });
-        let maybe_change = customer_needs_update(sc, c);
+        let itr_contact = contact_from_itr(self.contact_source, sc, c);
+        let maybe_change = customer_needs_update(sc, c, itr_contact);
         if maybe_change.is_some() || force {
-            debug!("customer needs update: {}", maybe_change.unwrap());
+            debug!("customer needs update: {}", maybe_change.unwrap_or("forced".to_owned()));
+            // Leaving email and phone unset keeps what's in Square.
             let customer = squareup::models::UpdateCustomerRequest {
                 given_name: Some(c.first_name.to_string()),
                 family_name: Some(c.last_name.to_string()),
                 email_address: match &c.email {
-                    Some(email) => Some(email.to_string()),
-                    None => None
+                    Some(email) if itr_contact => Some(email.to_string()),
+                    _ => None
                 },
-                phone_number: square_phone(&c.phone),
+                phone_number: if itr_contact { square_phone(&c.phone) } else { None },
                 reference_id: Some(c.id.to_string()),
                 ..Default::default()
             };
@@ -637,11 +1359,54 @@ impl SquareConnect {
         }
     }
 
-    pub async fn sync_customers_with_sidedb(&self, sidedb: &mut super::sidedb::SideDb) -> Result<SquareSyncResult> {
+    pub async fn sync_customers_with_sidedb(&self, sidedb: &mut super::sidedb::SideDb, since_override: Option<chrono::DateTime<Utc>>) -> Result<SquareSyncResult> {
+        const SYNC_STATE: &str = "square_customers";
+        const SYNC_STATE_FULL: &str = "square_customers_full";
+        let started = Utc::now();
         let customersapi = CustomersApi::new(self.client.clone());
         let groups = self.get_customer_groups(true).await?;
         let dbcusts = sidedb.get_customers_all().await?;
-        let square_custs = self.get_customers(Some(&customersapi)).await?;
+
+        // An incremental pull can't see unchanged Square customers, so anyone not yet associated
+        // (who might match one by email or phone) forces a full pull to avoid creating duplicates.
+        let unassociated = dbcusts.iter().any(|c| !c.deleted && c.squareup_id.is_none());
+        let full_due = match sidedb.get_sync_state(SYNC_STATE_FULL).await? {
+            Some(last) => started - last >= chrono::Duration::hours(self.customers_full_sync_hours as i64),
+            None => true,
+        };
+        let since = if unassociated {
+            None
+        } else if since_override.is_some() {
+            since_override
+        } else if full_due {
+            None
+        } else {
+            sidedb.get_sync_state(SYNC_STATE).await?
+        };
+        let square_custs = match since {
+            Some(since) => {
+                info!("Pulling Square customers updated since {}", since);
+                let mut custs = self.get_customers_since(Some(&customersapi), &since).await?;
+                // Customers changed on our side need their Square record even if it didn't change.
+                let have: HashSet<String> = custs.iter().filter_map(|c| c.id.clone()).collect();
+                for dbc in &dbcusts {
+                    if dbc.deleted || !customer_modified_since(dbc, &since) {
+                        continue;
+                    }
+                    if let Some(sqid) = &dbc.squareup_id {
+                        if !have.contains(sqid) {
+                            match customersapi.retrieve_customer(sqid).await {
+                                Ok(r) => custs.push(r.customer),
+                                Err(e) => error!("Failed to retrieve Square customer {}: {:?}", sqid, e),
+                            }
+                        }
+                    }
+                }
+                custs
+            },
+            None => self.get_customers(Some(&customersapi)).await?,
+        };
+        let square_ids: HashSet<&String> = square_custs.iter().filter_map(|c| c.id.as_ref()).collect();
         let mut square_custs_by_itrid = HashMap::<Uuid, &Customer>::new();
         let mut square_custs_by_email = HashMap::<&String, &Customer>::new();
         let mut square_custs_by_phone = HashMap::<&String, &Customer>::new();
@@ -658,11 +1423,29 @@ impl SquareConnect {
                 square_custs_by_phone.insert(phone, sc);
             }
         }
+        // Square customers an exact match will claim; fuzzy matching leaves them be.
+        let claimed: HashSet<&String> = dbcusts.iter()
+            .filter(|c| !c.deleted)
+            .filter_map(|c| match_square_customer(&self.match_keys, c, &square_custs_by_itrid, &square_custs_by_email, &square_custs_by_phone))
+            .filter_map(|(_, sc)| sc.id.as_ref())
+            .collect();
+        let square_cust_refs: Vec<&Customer> = square_custs.iter().collect();
+        let mut fuzzy: Vec<FuzzyMatch> = vec![];
         let mut added_up: u64 = 0;
         let mut updated_up: u64 = 0;
+        // Anything already associated counts as matched, even if skipped below.
+        let mut matched: HashSet<&String> = dbcusts.iter().filter_map(|c| c.squareup_id.as_ref()).collect();
+        let mut progress = Progress::new("Square customers", dbcusts.len(), self.progress);
 
         for dbc in &dbcusts {
+            progress.inc();
             if dbc.deleted {
+                if let Some(id) = square_custs_by_itrid.get(&dbc.id).and_then(|sc| sc.id.as_ref()) {
+                    matched.insert(id);
+                }
+                continue;
+            }
+            if since.is_some() && !dbc.squareup_id.as_ref().is_some_and(|id| square_ids.contains(id)) {
                 continue;
             }
             let t_email = match &dbc.email {
@@ -674,27 +1457,86 @@ impl SquareConnect {
                 None => " nope ".to_string()
             };
             if let Some(cust) =
-            if let Some(sc) = square_custs_by_itrid.get(&dbc.id) {
-                trace!("found associated customer {:?} : {}", sc.id, dbc.id);
-                match self.update_customer(Some(&customersapi), sc, &dbc, false).await {
-                    Ok(true) => {
-                        debug!("updated customer: {:?} {:?}/{:?}", sc.id, t_email, t_phone);
-                        updated_up += 1;
+            if let Some((key, sc)) = match_square_customer(&self.match_keys, dbc, &square_custs_by_itrid, &square_custs_by_email, &square_custs_by_phone) {
+                match key {
+                    MatchKey::ReferenceId => {
+                        trace!("found associated customer {:?} : {}", sc.id, dbc.id);
+                        match self.update_customer(Some(&customersapi), sc, &dbc, false).await {
+                            Ok(true) => {
+                                debug!("updated customer: {:?} {:?}/{:?}", sc.id, t_email, t_phone);
+                                updated_up += 1;
+                            }
+                            Ok(false) => {
+                                trace!("noop customer: {:?} {:?}/{:?}", sc.id, t_email, t_phone);
+                            }
+                            Err(e) => {
+                                error!("Failed to update customer: {:?}", e);
+                            }
+                        }
                     }
-                    Ok(false) => {
-                        trace!("noop customer: {:?} {:?}/{:?}", sc.id, t_email, t_phone);
+                    MatchKey::Email => {
+                        debug!("found customer by email {:?} : {}", sc.id, dbc.id);
+                        if dbc.squareup_id != sc.id {
+                            match sidedb.associate_customer_with_square(&dbc.id, &sc.id.as_ref().unwrap().to_string()).await {
+                                Ok(true) => {
+                                    match self.update_customer(Some(&customersapi), sc, dbc, false).await {
+                                        Ok(true) => {
+                                            debug!("updated customer");
+                                            updated_up += 1;
+                                        }
+                                        Ok(false) => {
+                                            debug!("no update needed");
+                                        }
+                                        Err(e) => {
+                                            error!("failed to update customer: {:?}", e);
+                                        }
+                                    }
+                                },
+                                Ok(false) => { error!("could not find record association for {:?}", sc.email_address); }
+                                Err(e) => { error!("could build association for {:?} {:?}", sc.email_address, e); }
+                            }
+                        }
                     }
-                    Err(e) => {
-                        error!("Failed to update customer: {:?}", e);
+                    MatchKey::Phone => {
+                        debug!("found customer by phone {:?} : {}", sc.id, dbc.id);
+                        match sidedb.associate_customer_with_square(&dbc.id, &sc.id.as_ref().unwrap().to_string()).await {
+                            Ok(true) => {
+                                match self.update_customer(Some(&customersapi), sc, dbc, false).await {
+                                    Ok(true) => {
+                                        debug!("updated customer");
+                                        updated_up += 1;
+                                    }
+                                    Ok(false) => {
+                                        debug!("no update needed");
+                                    }
+                                    Err(e) => {
+                                        error!("failed to update customer: {:?}", e);
+                                    }
+                                }
+                            },
+                            Ok(false) => { error!("could not find record association for {:?}", sc.phone_number); }
+                            Err(e) => { error!("could build association for {:?} {:?}", sc.phone_number, e); }
+                        }
                     }
                 }
                 Some(sc)
-            } else if let Some(sc) = square_custs_by_email.get(&t_email) {
-                debug!("found customer by email {:?} : {}", sc.id, dbc.id);
-                if dbc.squareup_id != sc.id {
+            } else if let Some((sc, reason, confidence)) = self.fuzzy_match_threshold.and_then(|threshold| {
+                let candidates = square_cust_refs.iter().filter(|sc| sc.reference_id.is_none()
+                    && sc.id.as_ref().is_some_and(|id| !claimed.contains(id) && !matched.contains(id)));
+                fuzzy_customer(dbc, candidates, threshold)
+            }) {
+                info!("fuzzy matched {} to Square customer {:?} by {} ({:.2})", dbc.id, sc.id, reason, confidence);
+                let mut m = FuzzyMatch { itretail_id: dbc.id, square_id: sc.id.clone().unwrap(),
+                                         email: dbc.email.clone(), square_email: sc.email_address.clone(),
+                                         phone: dbc.phone.clone(), square_phone: sc.phone_number.clone(),
+                                         reason, confidence, associated: false };
+                // Unless asked to associate, leave both sides alone for the owner
+                // to review; creating a customer here would make a duplicate.
+                let found = if self.fuzzy_associate {
                     match sidedb.associate_customer_with_square(&dbc.id, &sc.id.as_ref().unwrap().to_string()).await {
                         Ok(true) => {
-                            match self.update_customer(Some(&customersapi), sc, &dbc, false).await {
+                            m.associated = true;
+                            match self.update_customer(Some(&customersapi), sc, dbc, false).await {
                                 Ok(true) => {
                                     debug!("updated customer");
                                     updated_up += 1;
@@ -706,33 +1548,16 @@ impl SquareConnect {
                                     error!("failed to update customer: {:?}", e);
                                 }
                             }
+                            Some(sc)
                         },
-                        Ok(false) => { error!("could not find record association for {:?}", sc.email_address); }
-                        Err(e) => { error!("could build association for {:?} {:?}", sc.email_address, e); }
+                        Ok(false) => { error!("could not find record association for {}", dbc.id); None }
+                        Err(e) => { error!("could build association for {} {:?}", dbc.id, e); None }
                     }
-                }
-                Some(sc)
-            } else if let Some(sc) = square_custs_by_phone.get(&t_phone) {
-                debug!("found customer by phone {:?} : {}", sc.id, dbc.id);
-                match sidedb.associate_customer_with_square(&dbc.id, &sc.id.as_ref().unwrap().to_string()).await {
-                    Ok(true) => {
-                        match self.update_customer(Some(&customersapi), sc, &dbc, false).await {
-                            Ok(true) => {
-                                debug!("updated customer");
-                                updated_up += 1;
-                            }
-                            Ok(false) => {
-                                debug!("no update needed");
-                            }
-                            Err(e) => {
-                                error!("failed to update customer: {:?}", e);
-                            }
-                        }
-                    },
-                    Ok(false) => { error!("could not find record association for {:?}", sc.phone_number); }
-                    Err(e) => { error!("could build association for {:?} {:?}", sc.phone_number, e); }
-                }
-                Some(sc)
+                } else {
+                    None
+                };
+                fuzzy.push(m);
+                found
             } else {
                 debug!("Creating new customer {:?}", dbc.phone);
                 match self.add_customer(Some(&customersapi), &dbc).await {
@@ -755,16 +1580,19 @@ impl SquareConnect {
                     }
                 }
             } {
+                if let Some(id) = &cust.id {
+                    matched.insert(id);
+                }
                 // Fix the groups for cust
                 if self.set_customer_loyalty(Some(&customersapi), &groups, cust, dbc).await? {
                     debug!("Updated loyalty for {}", cust.id.as_ref().unwrap());
                 }
             }
         }
-        // Deletes
+        // Deletes, only reconciled against a full pull
         let mut to_delete: Vec<String> = vec![];
         for dbc in &dbcusts {
-            if dbc.deleted {
+            if dbc.deleted && since.is_none() {
                 if let Some(sqc) = square_custs_by_itrid.get(&dbc.id) {
                     if let Some(id) = &sqc.id {
                         to_delete.push(id.to_owned());
@@ -773,8 +1601,13 @@ impl SquareConnect {
             }
         }
         let deleted_up = if to_delete.len() > 0 {
-            match self.delete_customer(Some(&customersapi), to_delete).await {
-                Ok(count) => { count as u64 },
+            match self.delete_customer(Some(&customersapi), to_delete, self.dry_run_deletes).await {
+                Ok(counts) => {
+                    if counts.failed > 0 {
+                        warn!("{} deleted customers could not be removed from Square", counts.failed);
+                    }
+                    counts.deleted as u64
+                },
                 Err(e) => {
                     error!("error removing deleted customers: {}", e.to_string());
                     0
@@ -783,11 +1616,29 @@ impl SquareConnect {
         } else {
             0
         };
-        Ok(SquareSyncResult { added_up: added_up, added_down: 0, updated_up: updated_up, deleted_up: deleted_up, set_inv_up: 0 })
+        let unmatched: Vec<UnmatchedCustomer> = square_custs.iter()
+            .filter(|sc| sc.id.as_ref().is_some_and(|id| !matched.contains(id)))
+            .map(|sc| UnmatchedCustomer {
+                id: sc.id.clone().unwrap(),
+                email: sc.email_address.clone(),
+                phone: sc.phone_number.clone(),
+            })
+            .collect();
+        if !unmatched.is_empty() {
+            info!("{} Square customers not matched to an IT Retail customer{}", unmatched.len(),
+                  if since.is_some() { " (incremental pull, partial)" } else { "" });
+        }
+        sidedb.set_sync_state(SYNC_STATE, &started).await?;
+        if since.is_none() {
+            sidedb.set_sync_state(SYNC_STATE_FULL, &started).await?;
+        }
+        Ok(SquareSyncResult { added_up, added_down: 0, updated_up, deleted_up, set_inv_up: 0, plan: vec![], unmatched, fuzzy })
     }
 
+    // A location without a state is only usable when square.tax_state stands in for it.
     pub async fn get_location(&self, name: String) -> Result<Location> {
         let locations = self.get_locations().await?;
+        let mut stateless: Option<Location> = None;
         for location in locations {
             if location.name.as_ref().is_some_and(|x| x == &name) {
                 if let Some(address) = location.address.as_ref() {
@@ -795,102 +1646,163 @@ impl SquareConnect {
                         return Ok(location)
                     }
                 }
+                if stateless.is_none() {
+                    stateless = Some(location);
+                }
             }
         }
-        return Err(anyhow!("Cannot find state in location for {}", self.location));
+        match (stateless, &self.state) {
+            (Some(location), Some(_)) => Ok(location),
+            (Some(_), None) => Err(anyhow!("Cannot find state in location for {}; set square.tax_state", self.location)),
+            (None, _) => Err(anyhow!("Cannot find location {}", self.location)),
+        }
+    }
+
+    // The location's tax, or the configured square.tax_state's when its address has no state.
+    pub async fn get_location_tax(&self, location: &Location) -> Result<CatalogObject> {
+        let location_state = location.address.as_ref()
+            .and_then(|a| a.administrative_district_level_1.as_ref());
+        match (location_state, &self.state) {
+            (Some(state), _) => {
+                info!("Using tax for state {} from Square location {}", state, self.location);
+                self.get_tax(TaxLocation::Location(location)).await
+            }
+            (None, Some(state)) => {
+                info!("Square location {} has no state, using tax for configured square.tax_state {}", self.location, state);
+                self.get_tax(TaxLocation::State(state.to_string())).await
+            }
+            (None, None) => Err(anyhow!("Cannot find state in location for {}; set square.tax_state", self.location)),
+        }
     }
 
-    pub async fn get_measurement_id(&self) -> Result<String> {
+    // The Square measurement unit id for each of `measures`, in order.  A unit already
+    // in the catalog at another precision is moved to the one wanted, unless another of
+    // `measures` uses it; anything else missing is created.  A report-only run writes
+    // neither: it plans them, standing in a placeholder id for each unit it would create.
+    pub async fn get_measurement_ids(&self, measures: &[MeasureSpec], plan: &mut Vec<PlannedChange>) -> Result<Vec<String>> {
         let catalogapi = CatalogApi::new(self.client.clone());
-        let mut id = "#newmeasure".to_owned();
-        let mut version: Option<i64> = None;
         let response = catalogapi.list_catalog(&ListCatalogParameters{
             types: Some(vec![CatalogObjectType::MeasurementUnit]),
             ..Default::default()
         }).await?;
-        if let Some(measures) = response.objects {
-            for m in &measures {
-                if m.is_deleted.unwrap_or(false) {
+        let existing: Vec<(&CatalogObject, MeasureSpec)> = response.objects.iter().flatten()
+            .filter(|m| !m.is_deleted.unwrap_or(false))
+            .filter_map(|m| {
+                let mud = m.measurement_unit_data.as_ref()?;
+                let mu = mud.measurement_unit.as_ref()?;
+                let weight_unit = match (&mu.weight_unit, &mu.generic_unit) {
+                    (Some(w), _) => Some(w.clone()),
+                    (None, Some(_)) => None,
+                    (None, None) => return None,
+                };
+                Some((m, MeasureSpec { weight_unit, precision: mud.precision.unwrap_or(0) }))
+            })
+            .collect();
+        let steps = measure_steps(&existing, measures);
+        if self.report_only {
+            return Ok(plan_measures(&steps, measures, plan));
+        }
+        let mut ids = vec![];
+        for (step, want) in steps.into_iter().zip(measures) {
+            let (id, version) = match step {
+                MeasureStep::Existing(id) => {
+                    debug!("Found existing measurement {:?}: {}", want, id);
+                    ids.push(id);
                     continue;
+                },
+                MeasureStep::Reprecision(id, version) => {
+                    debug!("Found measurement {} at wrong precision.", id);
+                    (id, version)
+                },
+                MeasureStep::Create => ("#newmeasure".to_owned(), None),
+            };
+            let response = catalogapi.upsert_catalog_object(&UpsertCatalogObjectRequest{
+                idempotency_key: Uuid::new_v4().to_string(),
+                object: CatalogObject {
+                    r#type: CatalogObjectType::MeasurementUnit,
+                    id,
+                    present_at_all_locations: Some(true),
+                    measurement_unit_data: Some(CatalogMeasurementUnit{
+                        measurement_unit: Some(match &want.weight_unit {
+                            Some(w) => MeasurementUnit {
+                                r#type: Some(MeasurementUnitUnitType::TypeWeight),
+                                weight_unit: Some(w.clone()),
+                                ..Default::default()
+                            },
+                            None => MeasurementUnit {
+                                r#type: Some(MeasurementUnitUnitType::TypeGeneric),
+                                generic_unit: Some(MeasurementUnitGeneric::Unit),
+                                ..Default::default()
+                            },
+                        }),
+                        precision: Some(want.precision),
+                    }),
+                    version,
+                    ..Default::default()
                 }
-                if let Some(mud) = &m.measurement_unit_data {
-                    if let Some(mu) = &mud.measurement_unit {
-                        if mu.weight_unit == Some(self.weight_unit.clone()) {
-                            id = m.id.clone();
-                            version = m.version.clone();
-                            if mud.precision == Some(self.weight_precision) {
-                                debug!("Found existing weight-based measurement: {}", m.id);
-                                return Ok(m.id.to_owned())
-                            }
-                            debug!("Found weight-based measure at wrong precision.");
-                        }
-                    }
+            }).await?;
+            match response.catalog_object {
+                Some(o) => {
+                    debug!("Created new measurement {:?}: {}", want, o.id);
+                    ids.push(o.id.clone());
                 }
+                None => return Err(anyhow!("Failed to create required measurement unit {:?}.", want)),
             }
         }
-        // Must create this.
-        let response = catalogapi.upsert_catalog_object(&UpsertCatalogObjectRequest{
-            idempotency_key: Uuid::new_v4().to_string(),
-            object: CatalogObject {
-                r#type: CatalogObjectType::MeasurementUnit,
-                id: id,
-                present_at_all_locations: Some(true),
-                measurement_unit_data: Some(CatalogMeasurementUnit{
-                    measurement_unit: Some(MeasurementUnit {
-                        r#type: Some(MeasurementUnitUnitType::TypeWeight),
-                        weight_unit: Some(self.weight_unit.clone()),
-                        ..Default::default()
-                    }),
-                    precision: Some(self.weight_precision),
-                }),
-                version: version,
-                ..Default::default()
-            }
-        }).await?;
-        if let Some(o) = response.catalog_object {
-            debug!("Created new weight-based measurement: {}", o.id);
-            return Ok(o.id.clone());
+        Ok(ids)
+    }
+
+    pub async fn get_plu_custom_id(&self, plan: &mut Vec<PlannedChange>) -> Result<String> {
+        self.get_custom_attribute_id(CA_BUTCHERS_PLU, "PLU", "PLU for programming external scales.", 0, plan).await
+    }
+
+    // Make sure the cost attribute definitions exist before items reference them.
+    async fn ensure_cost_custom_ids(&self, plan: &mut Vec<PlannedChange>) -> Result<()> {
+        for (key, name, precision) in CA_BUTCHERS_COST.iter() {
+            self.get_custom_attribute_id(key, name, &format!("{} from IT Retail.", name), *precision, plan).await?;
         }
-        Err(anyhow!("Failed to create required weight-based measurement units."))
+        Ok(())
     }
 
-    pub async fn get_plu_custom_id(&self) -> Result<String> {
+    // A missing definition is created, or in a report-only run planned, with the
+    // id it would be created under standing in.
+    async fn get_custom_attribute_id(&self, attr_key: &str, name: &str, description: &str, precision: i32, plan: &mut Vec<PlannedChange>) -> Result<String> {
         let catalogapi = CatalogApi::new(self.client.clone());
-        let response = catalogapi.list_catalog(&ListCatalogParameters{
-            types: Some(vec![CatalogObjectType::CustomAttributeDefinition]),
-            ..Default::default()
-        }).await?;
-        if let Some(attrs) = response.objects {
-            for a in &attrs {
-                if a.is_deleted.unwrap_or(false) || a.r#type != CatalogObjectType::CustomAttributeDefinition {
-                    continue;
-                }
-                if let Some(attr_def) = &a.custom_attribute_definition_data {
-                    if let Some(key) = &attr_def.key {
-                        if key == CA_BUTCHERS_PLU {
-                                debug!("Found existing custom PLU definition: {}", a.id);
-                            return Ok(a.id.to_owned())
-                        }
+        let attrs = self.get_catalog_objects(vec![CatalogObjectType::CustomAttributeDefinition]).await?;
+        for a in &attrs {
+            if a.is_deleted.unwrap_or(false) || a.r#type != CatalogObjectType::CustomAttributeDefinition {
+                continue;
+            }
+            if let Some(attr_def) = &a.custom_attribute_definition_data {
+                if let Some(key) = &attr_def.key {
+                    if key == attr_key {
+                        debug!("Found existing custom {} definition: {}", name, a.id);
+                        return Ok(a.id.to_owned())
                     }
                 }
             }
         }
         // Must create this.
+        let id = format!("#new-{}", attr_key);
+        if self.report_only {
+            plan.push(PlannedChange { action: "create-custom-attribute", upc: None, name: name.to_string(), detail: Some(attr_key.to_string()) });
+            return Ok(id);
+        }
         let response = catalogapi.upsert_catalog_object(&UpsertCatalogObjectRequest{
             idempotency_key: Uuid::new_v4().to_string(),
             object: CatalogObject {
                 r#type: CatalogObjectType::CustomAttributeDefinition,
-                id: "#newplu".to_owned(),
+                id,
                 custom_attribute_definition_data: Some(CatalogCustomAttributeDefinition{
                     r#type: CatalogCustomAttributeDefinitionType::Number,
-                    name: "PLU".to_owned(),
-                    description: Some("PLU for programming external scales.".to_owned()),
+                    name: name.to_owned(),
+                    description: Some(description.to_owned()),
                     allowed_object_types: vec![CatalogObjectType::Item],
                     seller_visibility: Some(CatalogCustomAttributeDefinitionSellerVisibility::SellerVisibilityReadWriteValues),
                     app_visibility: Some(CatalogCustomAttributeDefinitionAppVisibility::AppVisibilityReadWriteValues),
-                    key: Some(CA_BUTCHERS_PLU.to_owned()),
+                    key: Some(attr_key.to_owned()),
                     number_config: Some(CatalogCustomAttributeDefinitionNumberConfig{
-                        precision: Some(0)
+                        precision: Some(precision)
                     }),
                     ..Default::default()
                 }),
@@ -898,10 +1810,10 @@ impl SquareConnect {
             }
         }).await?;
         if let Some(o) = response.catalog_object {
-            debug!("Created new custom PLU attribute for items: {}", o.id);
+            debug!("Created new custom {} attribute for items: {}", name, o.id);
             return Ok(o.id.clone());
         }
-        Err(anyhow!("Failed to create required PLU attribute."))
+        Err(anyhow!("Failed to create required {} attribute.", name))
     }
 
     pub async fn get_tax(&self, which: TaxLocation<'_>) -> Result<CatalogObject> {
@@ -919,35 +1831,21 @@ impl SquareConnect {
                 }
             }
         };
-        let catalog_api = CatalogApi::new(self.client.clone());
-        let mut cursor: Option<String> = None;
         let mut all: Vec<CatalogObject> = vec![];
         let mut cnt = 0;
-        loop {
-            let types = vec![CatalogObjectType::Tax];
-            let res = catalog_api.list_catalog(&ListCatalogParameters {
-                cursor: cursor,
-                types: Some(types),
-                ..Default::default()
-            }).await?;
-            if let Some(objs) = res.objects {
-                for o in objs {
-                    if o.r#type == CatalogObjectType::Tax &&
-                       o.is_deleted.as_ref() == Some(&false) {
-                        if let Some(tax) = o.tax_data.as_ref() {
-                            if tax.name.as_ref().unwrap().contains(state.as_str()) {
-                                all.push(o);
-                                cnt += 1;
-                            }
-                        }
+        for o in self.get_catalog_objects(vec![CatalogObjectType::Tax]).await? {
+            if o.r#type == CatalogObjectType::Tax &&
+               o.is_deleted.as_ref() == Some(&false) {
+                if let Some(tax) = o.tax_data.as_ref() {
+                    if tax.name.as_ref().unwrap().contains(state.as_str()) {
+                        all.push(o);
+                        cnt += 1;
                     }
                 }
             }
-            if res.cursor.is_none() { break; }
-            cursor = res.cursor;
         }
         if cnt != 1 {
-            Err(anyhow!("{} taxes matched", 0))
+            Err(anyhow!("{} taxes matched state {}", cnt, state))
         } else {
             for o in all {
                 return Ok(o)
@@ -956,6 +1854,29 @@ impl SquareConnect {
         }
     }
 
+    // Items are present at square.present_locations (or just our own location), which
+    // is every location for a single-location account; None means all locations.
+    async fn get_present_location_ids(&self, location: &Location) -> Result<Option<Vec<String>>> {
+        let locations = self.get_locations().await?;
+        let wanted: Vec<String> = if self.present_locations.is_empty() {
+            vec![location.id.as_ref().unwrap().clone()]
+        } else {
+            let mut ids = vec![];
+            for name in self.present_locations.iter() {
+                match locations.iter().find(|l| l.name.as_ref() == Some(name) || l.id.as_ref() == Some(name)) {
+                    Some(l) => ids.push(l.id.as_ref().unwrap().clone()),
+                    None => return Err(anyhow!("Unknown Square location in present_locations: {}", name)),
+                }
+            }
+            ids
+        };
+        if locations.iter().all(|l| l.id.as_ref().is_some_and(|id| wanted.contains(id))) {
+            Ok(None)
+        } else {
+            Ok(Some(wanted.into_iter().sorted().dedup().collect()))
+        }
+    }
+
     pub async fn get_locations(&self) -> Result<Vec<Location>> {
         let locations_api = LocationsApi::new(self.client.clone());
         let res = locations_api.list_locations().await?;
@@ -967,27 +1888,59 @@ impl SquareConnect {
 
     pub async fn get_catalog_objects(&self, types: Vec<CatalogObjectType>) -> Result<Vec<CatalogObject>> {
         let catalog_api = CatalogApi::new(self.client.clone());
-        let mut cursor: Option<String> = None;
-        let mut products: Vec<CatalogObject> = vec![];
-        loop {
-            let res = catalog_api.list_catalog(&ListCatalogParameters {
-                cursor: cursor,
-                types: Some(types.clone()),
-                ..Default::default()
-            }).await?;
-            if let Some(objs) = res.objects {
-                products.extend(objs);
+        paginate(|cursor| {
+            let (catalog_api, types) = (&catalog_api, types.clone());
+            async move {
+                let res = catalog_api.list_catalog(&ListCatalogParameters {
+                    cursor,
+                    types: Some(types),
+                    ..Default::default()
+                }).await?;
+                Ok((res.objects.unwrap_or_default(), res.cursor))
             }
-            if res.cursor.is_none() { break; }
-            cursor = res.cursor;
-        }
-        Ok(products)
+        }).await
     }
 
     pub async fn get_products(&self) -> Result<Vec<CatalogObject>> {
         self.get_catalog_objects(vec![CatalogObjectType::Item]).await
     }
 
+    // The catalog item with this SKU, as lookup shows it.  Square finds the
+    // variation by SKU and sends its item along as a related object.
+    pub async fn lookup_product(&self, sku: &str) -> Result<Option<super::api::ProductLookup>> {
+        let catalogapi = CatalogApi::new(self.client.clone());
+        let response = catalogapi.search_catalog_objects(&SearchCatalogObjectsRequest {
+            object_types: Some(vec![CatalogObjectType::ItemVariation]),
+            include_related_objects: Some(true),
+            query: Some(CatalogQuery {
+                exact_query: Some(CatalogQueryExact { attribute_name: "sku".to_string(), attribute_value: sku.to_string() }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }).await?;
+        if let Some(err) = response.errors.as_ref().and_then(|e| e.first()) {
+            return Err(anyhow!("{:?}", err));
+        }
+        let items = response.related_objects.unwrap_or_default();
+        let item = match items.iter().find(|i| catalogobject_getsku(i).is_ok_and(|s| s == sku)) {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let data = item.item_data.as_ref();
+        let variation = data.and_then(|d| d.variations.as_ref()).and_then(|v| v.first())
+            .and_then(|v| v.item_variation_data.as_ref());
+        let taxes = data.and_then(|d| d.tax_ids.as_ref()).map(|t| t.len()).unwrap_or(0);
+        Ok(Some(super::api::ProductLookup {
+            description: data.and_then(|d| d.name.clone()).unwrap_or_default(),
+            price: variation.and_then(|v| v.price_money.as_ref()).map(|m| m.amount as f64 / 100.0),
+            price_with_tax: None,
+            tax: Some(if data.and_then(|d| d.is_taxable).unwrap_or(false) { format!("taxable, {} taxes", taxes) } else { "none".to_string() }),
+            plu: get_catalogitem_plu(item),
+            scale: Some(variation.is_some_and(|v| v.measurement_unit_id.is_some())),
+            active: Some(!item.is_deleted.unwrap_or(false) && !data.and_then(|d| d.is_archived).unwrap_or(false)),
+            square_id: get_variant_item_id(item),
+        }))
+    }
     pub async fn update_product(&self, p: CatalogObject) -> Result<CatalogObject> {
         let catalogapi = CatalogApi::new(self.client.clone());
         let response =
@@ -1047,7 +2000,7 @@ impl SquareConnect {
         }
     }
 
-    pub async fn sync_categories_with_sidedb(&self, sidedb: &mut super::sidedb::SideDb) -> Result<HashMap<ITRCat,String>> {
+    pub async fn sync_categories_with_sidedb(&self, sidedb: &mut super::sidedb::SideDb, plan: &mut Vec<PlannedChange>) -> Result<HashMap<ITRCat,String>> {
         let depts = sidedb.get_departments().await?;
         let sections = sidedb.get_sections().await?;
         let catalogapi = CatalogApi::new(self.client.clone());
@@ -1084,24 +2037,24 @@ impl SquareConnect {
                             if cdata.is_top_level.unwrap_or(true) {
                                 // a Department in ITR
                                 if let Some(found) = depts.iter().find(|x| Some(&x.name) == cdata.name.as_ref()) {
-                                    if found.squareup_id.is_none() && found.id.is_some() {
-                                        let rv = sidedb.associate_department_with_square(&found.id.unwrap(), &c.id).await?;
-                                        debug!("succeeded associating cat({}) with department({}): {}", c.id, found.id.unwrap(), rv);
+                                    if let (None, Some(id)) = (&found.squareup_id, found.id) {
+                                        let rv = self.report_only || sidedb.associate_department_with_square(&id, &c.id).await?;
+                                        debug!("succeeded associating cat({}) with department({}): {}", c.id, id, rv);
                                         if rv {
-                                            squaremap.insert(&c.id, ITRCat::Department(found.id.unwrap()));
-                                            itrmap.insert(ITRCat::Department(found.id.unwrap()), c.id.clone()); 
+                                            squaremap.insert(&c.id, ITRCat::Department(id));
+                                            itrmap.insert(ITRCat::Department(id), c.id.clone()); 
                                         }
                                     }
                                 }
                             } else {
                                 // a Section in ITR
                                 if let Some(found) = sections.iter().find(|x| Some(&x.name) == cdata.name.as_ref()) {
-                                    if found.squareup_id.is_none() && found.id.is_some() {
-                                        let rv = sidedb.associate_section_with_square(&found.id.unwrap(), &c.id).await?;
-                                        debug!("succeeded associating cat({}) with section({}): {}", c.id, found.id.unwrap(), rv);
+                                    if let (None, Some(id)) = (&found.squareup_id, found.id) {
+                                        let rv = self.report_only || sidedb.associate_section_with_square(&id, &c.id).await?;
+                                        debug!("succeeded associating cat({}) with section({}): {}", c.id, id, rv);
                                         if rv {
-                                            squaremap.insert(&c.id, ITRCat::Section(found.id.unwrap()));
-                                            itrmap.insert(ITRCat::Section(found.id.unwrap()), c.id.clone()); 
+                                            squaremap.insert(&c.id, ITRCat::Section(id));
+                                            itrmap.insert(ITRCat::Section(id), c.id.clone()); 
                                         }
                                     }
                                 }
@@ -1114,6 +2067,10 @@ impl SquareConnect {
             if let Some(dept_id) = d.id {
                 if None == itrmap.get(&ITRCat::Department(dept_id)) {
                     debug!("Need to create square category for department: {}/{}", dept_id, d.name);
+                    if self.report_only {
+                        plan.push(PlannedChange { action: "create-category", upc: None, name: d.name.to_string(), detail: Some(format!("department {}", dept_id)) });
+                        continue;
+                    }
                     match catalogapi.upsert_catalog_object(&UpsertCatalogObjectRequest {
                         idempotency_key: Uuid::new_v4().to_string(),
                         object: make_category(&ITRCat::Department(dept_id), &d.name, None, true),
@@ -1135,6 +2092,10 @@ impl SquareConnect {
             if let Some(section_id) = s.id {
                 if None == itrmap.get(&ITRCat::Section(section_id)) {
                     debug!("Need to create square category for section: {}/{}", section_id, s.name);
+                    if self.report_only {
+                        plan.push(PlannedChange { action: "create-category", upc: None, name: s.name.to_string(), detail: Some(format!("section {}", section_id)) });
+                        continue;
+                    }
                     if let Some(parent) = itrmap.get(&ITRCat::Department(s.department_id)) {
                         match catalogapi.upsert_catalog_object(&UpsertCatalogObjectRequest {
                             idempotency_key: Uuid::new_v4().to_string(),
@@ -1156,22 +2117,47 @@ impl SquareConnect {
         }
         Ok(itrmap)
     }
-    pub async fn sync_products_with_sidedb(&self, sidedb: &mut super::sidedb::SideDb, set_inventory: bool) -> Result<SquareSyncResult> {
+    pub async fn sync_products_with_sidedb(&self, sidedb: &mut super::sidedb::SideDb, set_inventory: bool, active_only: bool, include_deleted: bool, max_products: Option<usize>, verify: Option<&InventoryVerify>) -> Result<SquareSyncResult> {
         let mut added_up: u64 = 0;
         let mut updated_up: u64 = 0;
         let mut inv_count: Vec<InventoryChange> = vec![];
+        let mut inv_expected: HashMap<String, (String, f32)> = HashMap::new();
         let now = DateTime::now();
+        let mut plan: Vec<PlannedChange> = vec![];
 
         let location = self.get_location(self.location.to_string()).await?;
-        let tax = self.get_tax(TaxLocation::Location(&location)).await?;
-        let categories = self.sync_categories_with_sidedb(sidedb).await?;
-        let weight_measure_id = self.get_measurement_id().await?;
-        let plu_id = self.get_plu_custom_id().await?;
+        let tax = self.get_location_tax(&location).await?;
+        let categories = self.sync_categories_with_sidedb(sidedb, &mut plan).await?;
+        // Measurement units and custom attributes are created on first use; a report-only run plans them.
+        // Weighed items' measurement units: the default first, then each distinct one configured.
+        let mut measures = vec![self.measure.clone()];
+        for m in self.product_measures.values().chain(self.department_measures.values()) {
+            if !measures.contains(m) {
+                measures.push(m.clone());
+            }
+        }
+        let measure_ids = self.get_measurement_ids(&measures, &mut plan).await?;
+        let measure_id = |m: &MeasureSpec| measures.iter().position(|x| x == m).map(|i| measure_ids[i].clone()).unwrap();
+        let plu_id = self.get_plu_custom_id(&mut plan).await?;
+        if self.sync_cost {
+            self.ensure_cost_custom_ids(&mut plan).await?;
+        }
+        let present_at_location_ids = self.get_present_location_ids(&location).await?;
         let meta_builder = MetaBuilder {
             location_id: location.id.as_ref().unwrap().clone(),
+            present_at_location_ids,
             tax_id: tax.id.clone(),
-            measurement_id: weight_measure_id,
-            plu_id: plu_id,
+            product_measurement_ids: self.product_measures.iter().map(|(upc, m)| (upc.clone(), measure_id(m))).collect(),
+            department_measurement_ids: self.department_measures.iter().map(|(d, m)| (*d, measure_id(m))).collect(),
+            measurement_id: measure_ids[0].clone(),
+            plu_id,
+            sync_cost: self.sync_cost,
+            size_variation_name: self.size_variation_name,
+            item_descriptions: self.item_descriptions,
+            offline_departments: self.offline_departments.clone(),
+            offline_sections: self.offline_sections.clone(),
+            taxed_departments: self.taxed_departments.clone(),
+            currency: self.currency.clone(),
             categories: Arc::new(categories),
         };
         let items = self.get_products().await?;
@@ -1192,46 +2178,114 @@ impl SquareConnect {
                 }
             }
         }
-        let dbprods = sidedb.get_products(None).await?;
-        for dbprod in &dbprods {
+        let upcs = sidedb.get_product_upcs(include_deleted).await?;
+        let mut progress = Progress::new("Square products", upcs.len(), self.progress);
+        let collisions = upca_collisions(upcs.iter(), super::api::itr_upc_to_upca);
+        for (upca, upcs) in collisions.iter() {
+            warn!("IT Retail products {} all map to Square SKU {}, skipping them", upcs.join(", "), upca);
+        }
+        let mut processed: usize = 0;
+        let mut unpriced: Vec<String> = vec![];
+        let mut dbprods = sidedb.product_pages(include_deleted);
+        while let Some(dbprod) = dbprods.next(sidedb).await? {
+            progress.inc();
+            let dbprod = &dbprod;
+            let traced = self.trace_upc.as_ref().is_some_and(|t| t == &dbprod.upc);
+            macro_rules! trace_upc {
+                ($($arg:tt)+) => {
+                    if traced { info!(target: TRACE_TARGET, "{}: {}", dbprod.upc, format_args!($($arg)+)); }
+                }
+            }
+            if self.only_upcs.as_ref().is_some_and(|only| !only.contains(&dbprod.upc)) {
+                continue;
+            }
             let maybe_upca = dbprod.upca();
             if maybe_upca.is_none() {
                 info!("IT Retail product skipped, invalid UPC {}", dbprod.upc);
                 continue;
             }
             let upca = maybe_upca.unwrap();
+            if collisions.contains_key(&upca) {
+                trace_upc!("skipped, SKU {} collides with another IT Retail product", upca);
+                continue;
+            }
+            if dbprod.deleted {
+                // Deleted products only ever archive an existing item, never create one.
+                if !product_by_sku.contains_key(&upca) {
+                    trace_upc!("skipped, deleted and no Square item has SKU {}", upca);
+                    debug!("IT Retail product skipped, deleted {}", dbprod.upc);
+                    continue;
+                }
+            } else if !dbprod.is_available(active_only) {
+                trace_upc!("skipped, inactive");
+                debug!("IT Retail product skipped, inactive {}", dbprod.upc);
+                continue;
+            } else if !self.allow_zero_price && !dbprod.has_price() {
+                trace_upc!("skipped, no price");
+                unpriced.push(dbprod.upc.to_string());
+                continue;
+            }
+            if let Some(max) = max_products {
+                if processed >= max {
+                    trace_upc!("not reached, --max-products {} hit first", max);
+                    warn!("Stopping Square product sync, --max-products {} reached", max);
+                    break;
+                }
+            }
+            processed += 1;
+            // Products archived this run keep whatever count Square has.
+            let plan_inventory = set_inventory && !dbprod.deleted;
 
             if let Some(variant_item_id) = if let Some(existing) = product_by_sku.get(&upca) {
+                trace_upc!("matched Square item {} by SKU {}", existing.id, upca);
                 let mut updated: CatalogObject = meta_builder.build(dbprod).into();
                 catalogobject_getsku(&updated)?; // NEEDS A SKU
                 match catalogitem_needs_update(existing, &updated) {
+                    Ok(Some(changed)) if self.report_only => {
+                        trace_upc!("needs update ({}), report only", changed);
+                        plan.push(PlannedChange {
+                            action: if dbprod.deleted { "archive" } else { "update" },
+                            upc: Some(dbprod.upc.to_string()),
+                            name: dbprod.description.to_string(),
+                            detail: Some(changed),
+                        });
+                    },
                     Ok(Some(changed)) => {
                         debug!("detectect change: {}\n{:#?}\n{:#?}\n", changed, &existing, &updated);
+                        trace_upc!("needs update ({})", changed);
                         match catalogitem_adopt_ids(&mut updated, &existing) {
                             Ok(_) => {
+                                trace_upc!("adopted ids from Square item {}", existing.id);
                                 match self.update_product(updated).await {
                                     Ok(o) => {
+                                        trace_upc!("updated in Square");
                                         updated_up += 1;
                                         debug!("{:#?}", o);
                                     },
                                     Err(e) => {
+                                        trace_upc!("update failed: {}", e);
                                         error!("Failed to update item in square: {}", e.to_string());
                                     }
                                 }
                             },
                             Err(e) => {
+                                trace_upc!("could not adopt ids: {}", e);
                                 error!("Failed to prepare item for update in square: {}", e.to_string());
                             }
                         }
                     },
-                    Ok(None) => {}
+                    Ok(None) => {
+                        trace_upc!("up to date in Square");
+                    }
                     Err(e) => {
+                        trace_upc!("Square item is malformed: {}", e);
                        error!("Existing product {}/{} is malformed, please fix or delete it: {:?}", dbprod.upc, existing.id, e);
                     }
                 }
                 let maybe_variant_item_id = get_variant_item_id(existing);
                 if let Some(variant_item_id) = maybe_variant_item_id {
-                    if dbprod.squareup_id.is_none() || &variant_item_id != dbprod.squareup_id.as_ref().unwrap() {
+                    if !self.report_only && (dbprod.squareup_id.is_none() || &variant_item_id != dbprod.squareup_id.as_ref().unwrap()) {
+                        trace_upc!("associating with Square variation {} (was {:?})", variant_item_id, dbprod.squareup_id);
                         debug!("updating sidedb association {} <-> {:?} -> {}", dbprod.upc, dbprod.squareup_id, variant_item_id);
                         match sidedb.associate_product_with_square(&dbprod.upc, &variant_item_id).await {
                             Ok(success) => debug!("successfully updated: {}", success),
@@ -1242,7 +2296,16 @@ impl SquareConnect {
                 } else {
                     None
                 }
+            } else if self.report_only {
+                trace_upc!("no Square item has SKU {}, would create, report only", upca);
+                plan.push(PlannedChange { action: "create", upc: Some(dbprod.upc.to_string()), name: dbprod.description.to_string(), detail: Some(upca.to_string()) });
+                if plan_inventory {
+                    plan.push(PlannedChange { action: "set-inventory", upc: Some(dbprod.upc.to_string()), name: dbprod.description.to_string(),
+                        detail: Some(dbprod.quantity_on_hand.unwrap_or(0.0).to_string()) });
+                }
+                None
             } else {
+                trace_upc!("no Square item has SKU {}, creating", upca);
                 debug!("{} needs creation as {}", dbprod.upc, upca);
                 let result = self.create_product(&dbprod, &meta_builder).await;
                 match result {
@@ -1255,6 +2318,7 @@ impl SquareConnect {
                                 Err(e) => debug!("failed to update: {}", e.to_string())
                             }
                             debug!("created with id: {:?}", variant_item_id);
+                            trace_upc!("created as Square variation {}", variant_item_id);
                             added_up +=1;
                             Some(variant_item_id.to_owned())
                         } else {
@@ -1263,19 +2327,28 @@ impl SquareConnect {
                         }
                     },
                     Err(e) => {
+                        trace_upc!("create failed: {}", e);
                         error!("error creating {}: {}", dbprod.upc, e);
                         None
                     }
                 }
             } {
-                if set_inventory {
+                if plan_inventory && self.report_only {
+                    plan.push(PlannedChange { action: "set-inventory", upc: Some(dbprod.upc.to_string()), name: dbprod.description.to_string(),
+                        detail: Some(dbprod.quantity_on_hand.unwrap_or(0.0).to_string()) });
+                } else if plan_inventory {
+                    trace_upc!("inventory {} queued for Square variation {}", dbprod.quantity_on_hand.unwrap_or(0.0), variant_item_id);
                     debug!{"inv_count adding: {}", &variant_item_id};
                     inv_count.push(new_inventory_physical_count(&variant_item_id, &now, location.id.as_ref().unwrap(), dbprod.quantity_on_hand.unwrap_or(0.0)));
+                    inv_expected.insert(variant_item_id.to_string(), (dbprod.upc.to_string(), dbprod.quantity_on_hand.unwrap_or(0.0)));
                 }
             }
         }
+        if !unpriced.is_empty() {
+            warn!("Skipped {} IT Retail products with no price (--allow-zero-price to sync them): {}", unpriced.len(), unpriced.join(", "));
+        }
         let mut set_inv_up: u64 = 0;
-        if set_inventory && inv_count.len() > 0 {
+        if set_inventory && !inv_count.is_empty() {
             let inventoryapi = InventoryApi::new(self.client.clone());
             let mut offset: usize= 0;
             const MAX_BATCH:usize = 100;
@@ -1307,8 +2380,263 @@ impl SquareConnect {
                     Err(e) => { return Err(e.into()) },
                 }
             }
+            if let Some(verify) = verify {
+                let mismatched = self.verify_inventory(location.id.as_ref().unwrap(), &inv_expected, verify).await?;
+                if mismatched > 0 {
+                    warn!("{} inventory counts differ in Square, see {}", mismatched, verify.output);
+                } else {
+                    info!("Square inventory counts verified for {} items", inv_expected.len());
+                }
+            }
+        }
+        Ok(SquareSyncResult { added_up, added_down: 0, deleted_up: 0, updated_up, set_inv_up, plan, unmatched: vec![], fuzzy: vec![] })
+    }
+
+    // In-stock counts at the location by catalog object id; ids Square has no count for are absent.
+    async fn get_inventory_counts(&self, location_id: &String, ids: &[String]) -> Result<HashMap<String, f64>> {
+        let inventoryapi = InventoryApi::new(self.client.clone());
+        let mut actual: HashMap<String, f64> = HashMap::new();
+        const MAX_BATCH: usize = 100;
+        for chunk in ids.chunks(MAX_BATCH) {
+            let mut cursor: Option<String> = None;
+            loop {
+                let response = inventoryapi.batch_retrieve_inventory_counts(&BatchRetrieveInventoryCountsRequest {
+                    catalog_object_ids: Some(chunk.to_vec()),
+                    location_ids: Some(vec![location_id.to_string()]),
+                    updated_after: None,
+                    cursor,
+                    states: Some(vec![InventoryState::InStock]),
+                }).await?;
+                if let Some(errors) = response.errors {
+                    for e in &errors {
+                        error!("error retrieving inventory: {:?}", e);
+                    }
+                }
+                for count in response.counts.unwrap_or(vec![]) {
+                    let qty = count.quantity.parse::<f64>().unwrap_or(0.0);
+                    *actual.entry(count.catalog_object_id).or_insert(0.0) += qty;
+                }
+                cursor = response.cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+        }
+        Ok(actual)
+    }
+
+    async fn verify_inventory(&self, location_id: &String, expected: &HashMap<String, (String, f32)>, verify: &InventoryVerify) -> Result<usize> {
+        let ids: Vec<String> = expected.keys().cloned().collect();
+        let actual = self.get_inventory_counts(location_id, &ids).await?;
+
+        let mut csv = String::from("upc,catalog_object_id,expected,square\r\n");
+        let mut mismatched = 0;
+        for (id, (upc, qoh)) in expected.iter().sorted_by(|a, b| a.1.0.cmp(&b.1.0)) {
+            let want = *qoh as f64;
+            let line = match actual.get(id) {
+                Some(have) if (have - want).abs() <= verify.tolerance => continue,
+                Some(have) => format!("{},{},{},{}\r\n", upc, id, want, have),
+                None => format!("{},{},{},\r\n", upc, id, want),
+            };
+            debug!("inventory mismatch: {}", line.trim_end());
+            csv.push_str(&line);
+            mismatched += 1;
         }
-        Ok(SquareSyncResult { added_up: added_up, added_down: 0, deleted_up: 0, updated_up: updated_up, set_inv_up: set_inv_up })
+        std::fs::write(&verify.output, super::api::csv_bom(csv, verify.bom))?;
+        Ok(mismatched)
+    }
+
+    // Square's on-hand counts against sidedb's for every current product associated
+    // with a Square item.  Nothing is written anywhere.
+    pub async fn inventory_variance(&self, sidedb: &mut super::sidedb::SideDb, min_difference: f64) -> Result<Vec<InventoryVariance>> {
+        let location = self.get_location(self.location.to_string()).await?;
+        let products: Vec<ProductData> = sidedb.get_products(None).await?
+            .into_iter()
+            .filter(|p| p.squareup_id.is_some())
+            .collect();
+        let ids: Vec<String> = products.iter().filter_map(|p| p.squareup_id.clone()).collect();
+        let counts = self.get_inventory_counts(location.id.as_ref().unwrap(), &ids).await?;
+        info!("Compared {} Square-managed items, {} with Square counts", products.len(), counts.len());
+        Ok(inventory_variances(&products, &counts, min_difference))
+    }
+
+    // Hand-made Square items have no SKU, so the product sync can't match them and
+    // would create duplicates.  Adopt them by giving them the UPC-A SKU of the
+    // IT Retail product they match, by barcode first and then by unique name.
+    // Returns the number of items fixed (or that would be, unless apply is set).
+    pub async fn fix_skus(&self, sidedb: &mut super::sidedb::SideDb, apply: bool) -> Result<u32> {
+        let items = self.get_products().await?;
+        let dbprods = sidedb.get_products(None).await?;
+        let mut by_barcode = HashMap::<String, &ProductData>::new();
+        let mut by_name = HashMap::<String, Vec<&ProductData>>::new();
+        for dbprod in dbprods.iter().filter(|p| !p.deleted) {
+            if let Some(upca) = dbprod.upca() {
+                by_barcode.insert(upca, dbprod);
+                by_barcode.insert(dbprod.upc.to_string(), dbprod);
+                by_name.entry(dbprod.description.trim().to_lowercase()).or_default().push(dbprod);
+            }
+        }
+        let mut used_skus = HashSet::<String>::new();
+        for item in &items {
+            if let Ok(sku) = catalogobject_getsku(item) {
+                used_skus.insert(sku);
+            }
+        }
+
+        let mut fixed = 0;
+        for item in &items {
+            let name = item.item_data.as_ref().and_then(|d| d.name.clone()).unwrap_or_default();
+            let variation = match item.item_data.as_ref().and_then(|d| d.variations.as_ref()) {
+                Some(v) if v.len() == 1 => &v[0],
+                _ => {
+                    debug!("skipping {} ({}), not a single variation item", item.id, name);
+                    continue;
+                }
+            };
+            let vd = match &variation.item_variation_data {
+                Some(vd) => vd,
+                None => continue,
+            };
+            if vd.sku.as_ref().is_some_and(|sku| !sku.is_empty()) {
+                continue;
+            }
+            let matched = match vd.upc.as_ref().and_then(|upc| by_barcode.get(upc)) {
+                Some(p) => Some(*p),
+                None => match by_name.get(&name.trim().to_lowercase()) {
+                    Some(ps) if ps.len() == 1 => Some(ps[0]),
+                    Some(ps) => {
+                        warn!("Square item {} ({}) matches {} IT Retail products by name, skipping", item.id, name, ps.len());
+                        None
+                    },
+                    None => None,
+                },
+            };
+            let dbprod = match matched {
+                Some(p) => p,
+                None => {
+                    info!("Square item {} ({}) has no SKU and no IT Retail match", item.id, name);
+                    continue;
+                }
+            };
+            let upca = dbprod.upca().unwrap();
+            if !used_skus.insert(upca.to_string()) {
+                warn!("Square item {} ({}) matches {}, but SKU {} is already in use", item.id, name, dbprod.upc, upca);
+                continue;
+            }
+            if !apply {
+                info!("would set SKU {} on Square item {} ({}) for {}", upca, item.id, name, dbprod.upc);
+                fixed += 1;
+                continue;
+            }
+            let mut updated = item.clone();
+            if let Some(vd) = updated.item_data.as_mut()
+                .and_then(|d| d.variations.as_mut())
+                .and_then(|v| v[0].item_variation_data.as_mut()) {
+                vd.sku = Some(upca.to_string());
+            }
+            match self.update_product(updated).await {
+                Ok(o) => {
+                    info!("set SKU {} on Square item {} ({}) for {}", upca, item.id, name, dbprod.upc);
+                    fixed += 1;
+                    if let Some(variant_item_id) = get_variant_item_id(&o) {
+                        if let Err(e) = sidedb.associate_product_with_square(&dbprod.upc, &variant_item_id).await {
+                            error!("failed to associate {} with {}: {}", dbprod.upc, variant_item_id, e);
+                        }
+                    }
+                },
+                Err(e) => error!("Failed to set SKU on Square item {}: {}", item.id, e),
+            }
+        }
+        Ok(fixed)
+    }
+
+    // Rebuild sidedb product associations from the catalog by SKU.  Nothing in Square changes.
+    pub async fn reassociate_products(&self, sidedb: &mut super::sidedb::SideDb) -> Result<u32> {
+        let items = self.get_products().await?;
+        let mut variant_by_sku = HashMap::<String, String>::new();
+        let mut duplicated = HashSet::<String>::new();
+        for item in &items {
+            if let (Ok(sku), Some(variant_item_id)) = (catalogobject_getsku(item), get_variant_item_id(item)) {
+                if variant_by_sku.insert(sku.to_string(), variant_item_id).is_some() {
+                    warn!("SKU {} is duplicated in Square, not associating it", sku);
+                    duplicated.insert(sku);
+                }
+            }
+        }
+        let collisions = upca_collisions(sidedb.get_product_upcs(true).await?.iter(), super::api::itr_upc_to_upca);
+        let mut associated = 0;
+        let mut dbprods = sidedb.product_pages(true);
+        while let Some(dbprod) = dbprods.next(sidedb).await? {
+            let upca = match dbprod.upca() {
+                Some(upca) if !collisions.contains_key(&upca) && !duplicated.contains(&upca) => upca,
+                _ => continue,
+            };
+            if let Some(variant_item_id) = variant_by_sku.get(&upca) {
+                if dbprod.squareup_id.as_ref() != Some(variant_item_id) {
+                    debug!("associating {} <-> {:?} -> {}", dbprod.upc, dbprod.squareup_id, variant_item_id);
+                    if sidedb.associate_product_with_square(&dbprod.upc, variant_item_id).await? {
+                        associated += 1;
+                    }
+                }
+            }
+        }
+        Ok(associated)
+    }
+
+    // Rebuild sidedb customer associations by square.match_keys, as the customer
+    // sync matches them.  Nothing in Square changes.
+    pub async fn reassociate_customers(&self, sidedb: &mut super::sidedb::SideDb) -> Result<u32> {
+        let square_custs = self.get_customers(None).await?;
+        let mut by_itrid = HashMap::<Uuid, &Customer>::new();
+        let mut by_email = HashMap::<&String, &Customer>::new();
+        let mut by_phone = HashMap::<&String, &Customer>::new();
+        for sc in &square_custs {
+            if let Some(uuid) = sc.reference_id.as_ref().and_then(|r| Uuid::parse_str(r).ok()) {
+                by_itrid.insert(uuid, sc);
+            }
+            if let Some(email) = &sc.email_address {
+                by_email.insert(email, sc);
+            }
+            if let Some(phone) = &sc.phone_number {
+                by_phone.insert(phone, sc);
+            }
+        }
+        let mut associated = 0;
+        for dbc in sidedb.get_customers_ex(false).await? {
+            let matched = match_square_customer(&self.match_keys, &dbc, &by_itrid, &by_email, &by_phone);
+            if let Some(sqid) = matched.and_then(|(_, sc)| sc.id.as_ref()) {
+                if dbc.squareup_id.as_ref() != Some(sqid) {
+                    debug!("associating customer {} <-> {:?} -> {}", dbc.id, dbc.squareup_id, sqid);
+                    if sidedb.associate_customer_with_square(&dbc.id, sqid).await? {
+                        associated += 1;
+                    }
+                }
+            }
+        }
+        Ok(associated)
+    }
+
+    // Point sidedb customers at the Square customers whose reference_id carries
+    // their IT Retail id.  No matching by email or phone; nothing in Square changes.
+    pub async fn associate_customers_by_reference(&self, sidedb: &mut super::sidedb::SideDb) -> Result<u32> {
+        let mut associated = 0;
+        for sc in self.get_customers(None).await? {
+            let (sqid, reference_id) = match (sc.id.as_ref(), sc.reference_id.as_ref()) {
+                (Some(sqid), Some(reference_id)) => (sqid, reference_id),
+                _ => continue,
+            };
+            match Uuid::parse_str(reference_id) {
+                Ok(uuid) => {
+                    if sidedb.associate_customer_with_square(&uuid, sqid).await? {
+                        associated += 1;
+                    } else {
+                        debug!("Square customer {} references unknown IT Retail customer {}", sqid, uuid);
+                    }
+                },
+                Err(_) => debug!("Square customer {} has non-UUID reference_id {}", sqid, reference_id),
+            }
+        }
+        Ok(associated)
     }
 
     pub async fn sync_transactions_with_sidedb(&self, sidedb: &mut super::sidedb::SideDb) -> Result<u32> {
@@ -1381,6 +2709,151 @@ impl SquareConnect {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_upca_collisions() {
+        let upcs = ["0021234500000".to_owned(), "0021234500017".to_owned(), "0021234600000".to_owned(), "0088579290537".to_owned()];
+        // A weighed item's price-embedded variant gets no SKU, so can't take the item's.
+        assert!(upca_collisions(upcs.iter(), super::super::api::itr_upc_to_upca).is_empty());
+        // Grouped by the weighed item code, ignoring the embedded price.
+        let item_code = |upc: &String| upc.get(2..8).filter(|c| c.starts_with('2')).map(|c| c.to_string());
+        let collisions = upca_collisions(upcs.iter(), item_code);
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions.get("212345"), Some(&vec!["0021234500000".to_owned(), "0021234500017".to_owned()]));
+        assert!(upca_collisions(upcs[1..].iter(), item_code).is_empty());
+    }
+    #[test]
+    fn test_longest_retry_backoff() {
+        let (min, max) = (Duration::from_secs(1), Duration::from_secs(60));
+        assert_eq!(longest_retry_backoff(0, min, max, 3), Duration::ZERO);
+        assert_eq!(longest_retry_backoff(3, min, max, 3), Duration::from_secs(9));
+        assert_eq!(longest_retry_backoff(5, min, max, 3), max);
+        assert_eq!(longest_retry_backoff(40, min, max, 3), max);
+    }
+    #[test]
+    fn test_fuzzy_customer() {
+        let dbc: super::super::api::Customer = serde_json::from_str(r#"{"Id":"6c3f1a52-1f0e-4bde-9d43-0e7d2f6a5b11","LastName":"Smith",
+            "FirstName":"Bob","Deleted":false,"Phone":"555-123-4567","Email":"bob.smith@example.com"}"#).unwrap();
+        let square = |id: &str, family: Option<&str>, phone: Option<&str>, email: Option<&str>| Customer {
+            id: Some(id.to_string()), family_name: family.map(|s| s.to_string()),
+            phone_number: phone.map(|s| s.to_string()), email_address: email.map(|s| s.to_string()),
+            ..Default::default()
+        };
+        let custs = [square("A", Some("SMITH"), Some("+1 (555) 123-4567"), None),
+                         square("B", Some("Smith"), None, Some("bob.smiht@example.com")),
+                         square("C", Some("Smith"), Some("555-999-0000"), Some("ann@example.org")),
+                         square("D", Some("Jones"), Some("555-123-4567"), Some("bob.smith@example.com"))];
+        let custs: Vec<&Customer> = custs.iter().collect();
+        let (sc, reason, _) = fuzzy_customer(&dbc, custs.iter(), 0.9).unwrap();
+        assert_eq!((sc.id.as_deref(), reason), (Some("A"), "name+phone"));
+        let (sc, reason, confidence) = fuzzy_customer(&dbc, custs[1..].iter(), 0.85).unwrap();
+        assert_eq!((sc.id.as_deref(), reason), (Some("B"), "email"));
+        assert!(confidence > 0.85 && confidence < 0.95);
+        assert!(fuzzy_customer(&dbc, custs[1..].iter(), 0.95).is_none());
+        // D has the phone and email but another last name.
+        assert!(fuzzy_customer(&dbc, custs[2..].iter(), 0.5).is_none());
+        assert_eq!(similarity("kitten", "sitting"), 1.0 - 3.0 / 7.0);
+    }
+    #[test]
+    fn test_match_keys() {
+        let keys = |k: &[&str]| parse_match_keys(&k.iter().map(|s| s.to_string()).collect::<Vec<String>>());
+        assert_eq!(keys(&["Phone", " email "]).unwrap(), vec![MatchKey::Phone, MatchKey::Email]);
+        assert!(keys(&["email", "fax"]).is_err());
+        assert!(keys(&["email", "EMAIL"]).is_err());
+        assert!(keys(&[]).is_err());
+
+        let dbc: super::super::api::Customer = serde_json::from_str(r#"{"Id":"6c3f1a52-1f0e-4bde-9d43-0e7d2f6a5b11","LastName":"Smith",
+            "FirstName":"Bob","Deleted":false,"Phone":"555-123-4567","Email":"bob.smith@example.com"}"#).unwrap();
+        let by_email_sc = Customer { id: Some("E".to_string()), ..Default::default() };
+        let by_phone_sc = Customer { id: Some("P".to_string()), ..Default::default() };
+        let email = "bob.smith@example.com".to_string();
+        let phone = "(555) 123-4567".to_string();
+        let by_itrid = HashMap::new();
+        let by_email = HashMap::from([(&email, &by_email_sc)]);
+        let by_phone = HashMap::from([(&phone, &by_phone_sc)]);
+        let matched = |k: &[&str]| match_square_customer(&keys(k).unwrap(), &dbc, &by_itrid, &by_email, &by_phone)
+            .map(|(key, sc)| (key, sc.id.clone().unwrap()));
+        assert_eq!(matched(&["reference_id", "email", "phone"]), Some((MatchKey::Email, "E".to_string())));
+        assert_eq!(matched(&["phone", "email"]), Some((MatchKey::Phone, "P".to_string())));
+        assert_eq!(matched(&["reference_id"]), None);
+    }
+    #[test]
+    fn test_inventory_variances() {
+        let item = |upc: &str, qoh: f32, squareup_id: Option<&str>| -> ProductData {
+            let mut p = super::super::api::product(&format!(r#""upc":"{}","description":"Eggs","QuantityOnHand":{}"#, upc, qoh));
+            p.squareup_id = squareup_id.map(|s| s.to_string());
+            p
+        };
+        let products = vec![item("0001", 10.0, Some("A")), item("0002", 5.0, Some("B")),
+                            item("0003", 3.0, Some("C")), item("0004", 8.0, None)];
+        let counts = HashMap::from([("A".to_string(), 10.5), ("B".to_string(), 12.0)]);
+        let variances = inventory_variances(&products, &counts, 1.0);
+        assert_eq!(variances.len(), 2);
+        assert_eq!(variances[0].upc, "0002");
+        assert_eq!(variances[0].difference, 7.0);
+        assert_eq!(variances[1].square, None);
+        assert_eq!(variances[1].csv_line(), "0003,Eggs,C,3,,-3");
+    }
+    #[test]
+    fn test_currency_cad() {
+        let cad = parse_currency("cad").unwrap();
+        assert_eq!(cad, Currency::Cad);
+        assert!(parse_currency("XYZ").is_err());
+        let money = square_money(4.99, &cad);
+        assert_eq!(money.amount, 499);
+        assert_eq!(square_money(4.35, &cad).amount, 435);
+        assert_eq!(money.currency, Currency::Cad);
+        let money = Some(money);
+        assert_eq!(super::super::sidedb::SSql::from_money(&money, &cad), Some(rust_decimal::Decimal::new(499, 2)));
+        assert_eq!(super::super::sidedb::SSql::from_money(&money, &Currency::Usd), None);
+    }
+    #[test]
+    fn test_square_description() {
+        assert_eq!(square_description("  Pork,\r\n salt,\tpepper "), Some("Pork, salt, pepper".to_owned()));
+        assert_eq!(square_description(" \n "), None);
+        let long = square_description(&"flour ".repeat(1000)).unwrap();
+        assert!(long.chars().count() <= DESCRIPTION_MAX_CHARS);
+        assert!(long.ends_with("flour…"));
+        assert_eq!(html_escape("Salt & <b>"), "Salt &amp; &lt;b&gt;");
+    }
+    #[test]
+    fn test_parse_measure() {
+        assert_eq!(parse_measure("imperial_pound", 3).unwrap(),
+            MeasureSpec { weight_unit: Some(MeasurementUnitWeight::ImperialPound), precision: 3 });
+        assert_eq!(parse_measure(" Metric_Kilogram:2 ", 3).unwrap(),
+            MeasureSpec { weight_unit: Some(MeasurementUnitWeight::MetricKilogram), precision: 2 });
+        assert_eq!(parse_measure("unit:0", 3).unwrap(), MeasureSpec { weight_unit: None, precision: 0 });
+        assert!(parse_measure("imperial_pound:6", 3).is_err());
+        assert!(parse_measure("bushel", 3).is_err());
+    }
+    #[test]
+    fn test_report_only_measures() {
+        let unit = |id: &str, version: i64| CatalogObject { id: id.to_string(), version: Some(version), ..Default::default() };
+        let (lb, kg) = (unit("LB", 1), unit("KG", 2));
+        let existing = [(&lb, parse_measure("imperial_pound:3", 3).unwrap()), (&kg, parse_measure("metric_kilogram:2", 3).unwrap())];
+        // The default, a product measure at another precision and a department measure Square lacks.
+        let measures = ["imperial_pound:3", "metric_kilogram:3", "unit:0"].map(|m| parse_measure(m, 3).unwrap());
+        let steps = measure_steps(&existing, &measures);
+        assert_eq!(steps, vec![MeasureStep::Existing("LB".to_string()), MeasureStep::Reprecision("KG".to_string(), Some(2)), MeasureStep::Create]);
+        let mut plan = vec![];
+        assert_eq!(plan_measures(&steps, &measures, &mut plan), vec!["LB", "KG", "#newmeasure-2"]);
+        assert_eq!(plan.iter().map(|c| (c.action, c.name.as_str(), c.detail.as_deref())).collect::<Vec<_>>(),
+                   vec![("update-measure", "metric_kilogram:3", Some("KG")), ("create-measure", "unit:0", None)]);
+        // A unit another measure already has isn't moved out from under it.
+        let steps = measure_steps(&existing, &[measures[0].clone(), parse_measure("imperial_pound:2", 3).unwrap()]);
+        assert_eq!(steps[1], MeasureStep::Create);
+    }
+    #[test]
+    fn test_count_customer_deletes() {
+        let r: squareup::models::BulkDeleteCustomersResponse = serde_json::from_str(r#"{"responses":{
+            "A":{},
+            "B":{"errors":[{"category":"INVALID_REQUEST_ERROR","code":"NOT_FOUND","detail":"Customer not found"}]},
+            "C":{"errors":[]}
+        }}"#).unwrap();
+        assert_eq!(count_customer_deletes(&r, 3), CustomerDeletes { deleted: 2, failed: 1 });
+        let r: squareup::models::BulkDeleteCustomersResponse = serde_json::from_str(r#"{"errors":[
+            {"category":"RATE_LIMIT_ERROR","code":"RATE_LIMITED","detail":"slow down"}]}"#).unwrap();
+        assert_eq!(count_customer_deletes(&r, 2), CustomerDeletes { deleted: 0, failed: 2 });
+    }
     #[test]
     fn test_phone1() {
         assert_eq!(square_phone(&Some("US+15553431212".to_owned())), Some("(555) 343-1212".to_owned()));
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+use super::customer::{same_email, same_phone};
+use super::error::{http_client, ApiError};
+
+const STRIPE_API: &str = "https://api.stripe.com/v1";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StripeCustomer {
+    pub id: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StripeList {
+    data: Vec<StripeCustomer>,
+    has_more: bool,
+}
+
+pub struct StripeApi {
+    secret_key: String,
+    connect_timeout_seconds: u64,
+    request_timeout_seconds: u64,
+}
+
+pub fn stripe_api_new(settings: &super::settings::Settings) -> Result<StripeApi> {
+    let secret_key = std::env::var("STRIPE_SECRET_KEY").unwrap_or(settings.stripe.secret_key.to_string());
+    if secret_key.is_empty() {
+        return Err(anyhow!("No Stripe secret key, set stripe.secret_key or STRIPE_SECRET_KEY"));
+    }
+    Ok(StripeApi {
+        secret_key,
+        connect_timeout_seconds: settings.stripe.connect_timeout_seconds,
+        request_timeout_seconds: settings.stripe.request_timeout_seconds,
+    })
+}
+
+impl StripeApi {
+    pub async fn get_customers(&self) -> Result<Vec<StripeCustomer>> {
+        let client = http_client(self.connect_timeout_seconds, self.request_timeout_seconds).build()?;
+        let mut customers: Vec<StripeCustomer> = vec![];
+        loop {
+            let mut query = vec![("limit", "100".to_string())];
+            if let Some(last) = customers.last() {
+                query.push(("starting_after", last.id.clone()));
+            }
+            let result = client
+                .get(format!("{}/customers", STRIPE_API))
+                .bearer_auth(&self.secret_key)
+                .query(&query)
+                .send().await
+                .map_err(ApiError::from_reqwest)?;
+            let status = result.status();
+            let body = result.text().await.map_err(ApiError::from_reqwest)?;
+            if !status.is_success() {
+                return Err(anyhow!("Stripe customers: {} {}", status, body));
+            }
+            let page: StripeList = serde_json::from_str(&body)?;
+            debug!("Stripe customers page of {}", page.data.len());
+            let done = !page.has_more || page.data.is_empty();
+            customers.extend(page.data);
+            if done {
+                break;
+            }
+        }
+        Ok(customers)
+    }
+}
+
+// One difference between the Stripe and Square customers for an IT Retail id.
+// field is "missing" when only one side has the customer; stripe and square
+// then hold the customer id of the side that has it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StripeSquareDiff {
+    pub itretail_id: String,
+    pub field: String,
+    pub stripe: String,
+    pub square: String,
+}
+
+impl StripeSquareDiff {
+    pub fn csv_line(&self) -> String {
+        format!("{},{},{},{}", self.itretail_id, self.field,
+                super::api::csv_field(&self.stripe), super::api::csv_field(&self.square))
+    }
+}
+
+// Square customers are tied to IT Retail by the squareup_id the Square sync
+// stores in sidedb; Stripe customers carry the IT Retail id in their metadata
+// under id_key.  Deleted IT Retail customers are left out on the Square side.
+pub fn compare_customers(
+    itr: &[super::api::Customer],
+    square: &[squareup::models::Customer],
+    stripe: &[StripeCustomer],
+    id_key: &str,
+) -> Vec<StripeSquareDiff> {
+    let square_by_id: HashMap<&str, &squareup::models::Customer> = square.iter()
+        .filter_map(|sc| sc.id.as_deref().map(|id| (id, sc)))
+        .collect();
+    let by_square: HashMap<String, &squareup::models::Customer> = itr.iter()
+        .filter(|c| !c.deleted)
+        .filter_map(|c| c.squareup_id.as_deref().and_then(|s| square_by_id.get(s)).map(|sc| (c.id.to_string(), *sc)))
+        .collect();
+    let by_stripe: HashMap<String, &StripeCustomer> = stripe.iter()
+        .filter_map(|sc| sc.metadata.get(id_key).map(|id| (id.trim().to_lowercase(), sc)))
+        .filter(|(id, _)| !id.is_empty())
+        .collect();
+
+    let ids: BTreeSet<&String> = by_square.keys().chain(by_stripe.keys()).collect();
+    let mut diffs = vec![];
+    for id in ids {
+        let diff = |field: &str, stripe: Option<&String>, square: Option<&String>| StripeSquareDiff {
+            itretail_id: id.to_string(),
+            field: field.to_string(),
+            stripe: stripe.cloned().unwrap_or_default(),
+            square: square.cloned().unwrap_or_default(),
+        };
+        match (by_stripe.get(id), by_square.get(id)) {
+            (Some(st), Some(sq)) => {
+                if !same_email(&st.email, &sq.email_address) {
+                    diffs.push(diff("email", st.email.as_ref(), sq.email_address.as_ref()));
+                }
+                if !same_phone(&st.phone, &sq.phone_number) {
+                    diffs.push(diff("phone", st.phone.as_ref(), sq.phone_number.as_ref()));
+                }
+            },
+            (Some(st), None) => diffs.push(diff("missing", Some(&st.id), None)),
+            (None, Some(sq)) => diffs.push(diff("missing", None, sq.id.as_ref())),
+            (None, None) => {},
+        }
+    }
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_customers() {
+        let mut itr: Vec<super::super::api::Customer> = serde_json::from_str(r#"[
+            {"Id":"00000000-0000-0000-0000-000000000001","LastName":"A","FirstName":"A","Deleted":false,
+             "Email":"a@example.com","Phone":"5551110000"},
+            {"Id":"00000000-0000-0000-0000-000000000002","LastName":"B","FirstName":"B","Deleted":false},
+            {"Id":"00000000-0000-0000-0000-000000000003","LastName":"C","FirstName":"C","Deleted":false}
+        ]"#).unwrap();
+        for (c, sq) in itr.iter_mut().zip(["SQ1", "SQ2", "GONE"]) {
+            c.squareup_id = Some(sq.to_string());
+        }
+        let square: Vec<squareup::models::Customer> = serde_json::from_str(r#"[
+            {"id":"SQ1","email_address":"A@example.com ","phone_number":"+1 555 111 0001"},
+            {"id":"SQ2"}
+        ]"#).unwrap();
+        let stripe: Vec<StripeCustomer> = serde_json::from_str(r#"[
+            {"id":"cus_1","email":"a@example.com","phone":"555-111-0000","metadata":{"itretail_id":"00000000-0000-0000-0000-000000000001"}},
+            {"id":"cus_3","email":null,"phone":null,"metadata":{"itretail_id":"00000000-0000-0000-0000-000000000003"}},
+            {"id":"cus_x","email":null,"phone":null,"metadata":{}}
+        ]"#).unwrap();
+        let diffs = compare_customers(&itr, &square, &stripe, "itretail_id");
+        let got: Vec<(char, &str, &str, &str)> = diffs.iter()
+            .map(|d| (d.itretail_id.chars().last().unwrap(), d.field.as_str(), d.stripe.as_str(), d.square.as_str()))
+            .collect();
+        assert_eq!(got, vec![
+            ('1', "phone", "555-111-0000", "+1 555 111 0001"),
+            ('2', "missing", "", "SQ2"),
+            ('3', "missing", "cus_3", ""),
+        ]);
+    }
+}
@@ -1,24 +1,291 @@
-use reqwest;
 use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, NaiveTime};
+use log::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use super::error::http_client;
+use super::localexpress::{Order, ORDER_STATUSES};
+
+const POWER_ATTEMPTS: u32 = 3;
+const POWER_RETRY_BASE_MS: u64 = 250;
+const CONNECT_TIMEOUT_SECONDS: u64 = 3;
+const REQUEST_TIMEOUT_SECONDS: u64 = 5;
+
+#[derive(Deserialize, Debug)]
+struct PowerStatus {
+    #[serde(rename = "POWER")]
+    power: String,
+}
 
 pub struct Light {
     ip: String,
+    verify: bool,
+    attempts: u32,
+    retry_base_ms: u64,
+    connect_timeout_seconds: u64,
+    request_timeout_seconds: u64,
+}
+
+pub fn new_light(ip: String, verify: bool) -> Light {
+  Light{ ip, verify, attempts: POWER_ATTEMPTS, retry_base_ms: POWER_RETRY_BASE_MS,
+         connect_timeout_seconds: CONNECT_TIMEOUT_SECONDS, request_timeout_seconds: REQUEST_TIMEOUT_SECONDS }
+}
+
+// How long to wait after failed attempt number `attempt` (from 1): the base, doubling each time.
+pub fn retry_delay(base_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(base_ms.saturating_mul(1 << attempt.saturating_sub(1).min(16)))
+}
+
+// Whether `now` falls within store hours.  A close time before the open time
+// spans midnight; either time being empty means the store is always open.
+pub fn within_hours(open: &str, close: &str, now: NaiveTime) -> Result<bool> {
+    if open.is_empty() || close.is_empty() {
+        return Ok(true);
+    }
+    let open = NaiveTime::parse_from_str(open, "%H:%M").map_err(|e| anyhow!("bad open_time {}: {}", open, e))?;
+    let close = NaiveTime::parse_from_str(close, "%H:%M").map_err(|e| anyhow!("bad close_time {}: {}", close, e))?;
+    if open <= close {
+        Ok(now >= open && now < close)
+    } else {
+        Ok(now >= open || now < close)
+    }
+}
+
+// Order counts a rule can test besides the statuses themselves.
+const ORDER_COUNTERS: [&str; 3] = ["active", "parked", "all"];
+
+// One le-orders light rule: "[today.]<counter> <op> <n> -> <light> [on|off]".
+// The counter is an order status, active, parked (assembled curbside orders)
+// or all; "today." counts only orders due today.
+#[derive(Debug, PartialEq)]
+pub struct LightRule {
+    today: bool,
+    counter: String,
+    op: String,
+    value: usize,
+    pub light: String,
+    pub on: bool,
+}
+
+pub fn parse_rule(rule: &str) -> Result<LightRule> {
+    let bad = |why: &str| anyhow!("bad light rule \"{}\": {}", rule, why);
+    let (test, action) = rule.split_once("->").ok_or_else(|| bad("missing ->"))?;
+    let mut action = action.split_whitespace();
+    let light = action.next().ok_or_else(|| bad("missing light"))?.to_string();
+    let on = match action.next() {
+        None | Some("on") => true,
+        Some("off") => false,
+        Some(other) => return Err(bad(&format!("expected on or off, not {}", other))),
+    };
+    if action.next().is_some() {
+        return Err(bad("trailing words after the light"));
+    }
+    let test: String = test.split_whitespace().collect();
+    let at = test.find(|c| "<>=!".contains(c)).ok_or_else(|| bad("missing comparison"))?;
+    let (counter, rest) = test.split_at(at);
+    let oplen = rest.find(|c: char| !"<>=!".contains(c)).unwrap_or(rest.len());
+    let (op, value) = rest.split_at(oplen);
+    if !["<", "<=", ">", ">=", "==", "!="].contains(&op) {
+        return Err(bad(&format!("unknown comparison {}", op)));
+    }
+    let value = value.parse::<usize>().map_err(|_| bad(&format!("{} is not a count", value)))?;
+    let (today, counter) = match counter.strip_prefix("today.") {
+        Some(c) => (true, c),
+        None => (false, counter),
+    };
+    if !ORDER_STATUSES.contains(&counter) && !ORDER_COUNTERS.contains(&counter) {
+        return Err(bad(&format!("unknown counter {} (expected {} or {})", counter, ORDER_COUNTERS.join(", "), ORDER_STATUSES.join(", "))));
+    }
+    Ok(LightRule { today, counter: counter.to_string(), op: op.to_string(), value, light, on })
+}
+
+// Orders a rule counter counts, only those due `today` when today_only.
+pub fn count_orders(orders: &[Order], counter: &str, today_only: bool, today: NaiveDate) -> usize {
+    orders.iter()
+        .filter(|o| !today_only || o.delivery_date == today)
+        .filter(|o| match counter {
+            "all" => true,
+            "active" => o.active(),
+            "parked" => o.curbside_pickup_info.is_some() && o.status == "assembled",
+            status => o.status == status,
+        })
+        .count()
+}
+
+impl LightRule {
+    pub fn count(&self, orders: &[Order], today: NaiveDate) -> usize {
+        count_orders(orders, &self.counter, self.today, today)
+    }
+
+    pub fn holds(&self, count: usize) -> bool {
+        match self.op.as_str() {
+            "<" => count < self.value,
+            "<=" => count <= self.value,
+            ">" => count > self.value,
+            ">=" => count >= self.value,
+            "==" => count == self.value,
+            _ => count != self.value,
+        }
+    }
 }
 
-pub fn new_light(ip: String) -> Light {
-  Light{ ip: ip }
+// Every light named by a rule and whether it should be lit: on when some
+// "on" rule holds and no "off" rule does.  Lights no rule names are left out,
+// so le-orders leaves them as they are.
+pub fn evaluate_rules(rules: &[LightRule], orders: &[Order], today: NaiveDate) -> BTreeMap<String, bool> {
+    let mut want: BTreeMap<String, (bool, bool)> = BTreeMap::new();
+    for rule in rules {
+        let count = rule.count(orders, today);
+        let holds = rule.holds(count);
+        debug!("light rule {:?}: count {} holds {}", rule, count, holds);
+        let w = want.entry(rule.light.to_string()).or_insert((false, false));
+        if holds && rule.on { w.0 = true; }
+        if holds && !rule.on { w.1 = true; }
+    }
+    want.into_iter().map(|(light, (on, off))| (light, on && !off)).collect()
 }
 
 impl Light {
-    pub async fn power(&mut self, state: bool) -> Result<()> {
-        let client = reqwest::Client::new();
-        let res = client.get(format!("http://{}/cm?cmnd=Power%20{}", self.ip, if state { "on" } else { "off" })).send().await;
+    // Only a 2xx counts; anything else, or no answer in time, is an error to retry.
+    async fn command(&self, cmnd: &str) -> Result<String> {
+        let client = http_client(self.connect_timeout_seconds, self.request_timeout_seconds).build()?;
+        let res = client.get(format!("http://{}/cm?cmnd={}", self.ip, cmnd)).send().await;
         match res {
-            Ok(result) => {
-                result.text().await?;
-                Ok(())
-            },
+            Ok(result) if !result.status().is_success() => Err(anyhow!("light {} answered {}", self.ip, result.status())),
+            Ok(result) => Ok(result.text().await?),
             Err(e) => Err(anyhow!("{}", e.to_string())),
         }
     }
-}
\ No newline at end of file
+    pub async fn status(&mut self) -> Result<bool> {
+        let body = self.command("Power").await?;
+        let status: PowerStatus = serde_json::from_str(&body)?;
+        match status.power.to_uppercase().as_str() {
+            "ON" => Ok(true),
+            "OFF" => Ok(false),
+            other => Err(anyhow!("unexpected power state from {}: {}", self.ip, other)),
+        }
+    }
+    // Plug wifi is flaky, so a failed command (or, with verify, a state that didn't
+    // stick) is retried with exponential backoff until the attempts run out.
+    pub fn set_retries(&mut self, attempts: u32, retry_base_ms: u64) {
+        self.attempts = attempts.max(1);
+        self.retry_base_ms = retry_base_ms;
+    }
+    pub fn set_timeouts(&mut self, connect_seconds: u64, request_seconds: u64) {
+        self.connect_timeout_seconds = connect_seconds;
+        self.request_timeout_seconds = request_seconds;
+    }
+    pub async fn power(&mut self, state: bool) -> Result<()> {
+        let cmnd = format!("Power%20{}", if state { "on" } else { "off" });
+        let mut last_err = anyhow!("no attempts made");
+        for attempt in 1..=self.attempts {
+            if attempt > 1 {
+                let delay = retry_delay(self.retry_base_ms, attempt - 1);
+                debug!("Light {} retrying in {}ms", self.ip, delay.as_millis());
+                tokio::time::sleep(delay).await;
+            }
+            debug!("Light {} power {} (attempt {} of {})", self.ip, state, attempt, self.attempts);
+            let observed = match self.command(&cmnd).await {
+                Ok(_) if !self.verify => return Ok(()),
+                Ok(_) => self.status().await,
+                Err(e) => Err(e),
+            };
+            match observed {
+                Ok(s) if s == state => return Ok(()),
+                Ok(s) => {
+                    warn!("Light {} reports {} after setting {} (attempt {})", self.ip, s, state, attempt);
+                    last_err = anyhow!("light {} did not change state", self.ip);
+                },
+                Err(e) => {
+                    warn!("Light {} error (attempt {}): {}", self.ip, attempt, e);
+                    last_err = e;
+                },
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{path, query_param};
+
+    fn mock_light(server: &MockServer, attempts: u32) -> Light {
+        let mut light = new_light(server.address().to_string(), false);
+        light.set_retries(attempts, 1);
+        light.set_timeouts(1, 1);
+        light
+    }
+
+    fn order(status: &str, delivery_date: &str, curbside: bool) -> Order {
+        let curbside = if curbside { r#"{"notes":"","parking_slot":{"name":"1","description":""}}"# } else { "null" };
+        serde_json::from_str(&format!(r#"{{"id":"1","uniqid":"a","store_id":"1","status":"{}","subtotal":"0","tips":"0","total":"0",
+            "mode":"pickup","payment_method":"card","customer_first_name":"A","customer_last_name":"B",
+            "creation_date":"2026-10-16 09:00:00","delivery_date":"{}","delivery_time_period":"","curbsidePickupInfo":{}}}"#,
+            status, delivery_date, curbside)).unwrap()
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(250, 1), Duration::from_millis(250));
+        assert_eq!(retry_delay(250, 2), Duration::from_millis(500));
+        assert_eq!(retry_delay(250, 4), Duration::from_millis(2000));
+        assert_eq!(retry_delay(0, 3), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_power_retries_errors() {
+        let server = MockServer::start().await;
+        Mock::given(path("/cm"))
+            .and(query_param("cmnd", "Power on"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server).await;
+        assert!(mock_light(&server, 3).power(true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_power_retries_timeouts() {
+        let server = MockServer::start().await;
+        Mock::given(path("/cm"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server).await;
+        Mock::given(path("/cm"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"POWER":"OFF"}"#))
+            .expect(1)
+            .mount(&server).await;
+        // The stalled first attempt times out and the second one lands.
+        mock_light(&server, 3).power(false).await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let r = parse_rule("today.active > 0 -> light1").unwrap();
+        assert_eq!(r, LightRule { today: true, counter: "active".into(), op: ">".into(), value: 0, light: "light1".into(), on: true });
+        let r = parse_rule("delivering>=2 -> light3 off").unwrap();
+        assert_eq!((r.today, r.counter.as_str(), r.op.as_str(), r.value, r.on), (false, "delivering", ">=", 2, false));
+        assert!(parse_rule("delivering > 0").is_err());
+        assert!(parse_rule("shipped > 0 -> light3").is_err());
+        assert!(parse_rule("new => 0 -> light3").is_err());
+        assert!(parse_rule("new > x -> light3").is_err());
+        assert!(parse_rule("new > 0 -> light3 blink").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_rules() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 17).unwrap();
+        let orders = [order("new", "2026-10-18", false), order("assembled", "2026-10-17", true), order("delivering", "2026-10-17", false)];
+        let rules: Vec<LightRule> = ["today.active>0 -> light1", "parked>0 -> light2", "delivering>0 -> light3",
+                                     "new>0 -> light4", "today.new==0 -> light4 off"]
+            .iter().map(|r| parse_rule(r).unwrap()).collect();
+        let lit = evaluate_rules(&rules, &orders, today);
+        assert_eq!(lit.into_iter().collect::<Vec<_>>(),
+                   vec![("light1".to_string(), false), ("light2".to_string(), true), ("light3".to_string(), true), ("light4".to_string(), false)]);
+    }
+}
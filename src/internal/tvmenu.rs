@@ -1,4 +1,5 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDateTime};
 use clap::ArgMatches;
 use image::Rgba;
 use imageproc::drawing::{draw_text_mut, text_size};
@@ -28,7 +29,7 @@ lazy_static! {
             .into_rgba8();
 }
 
-pub async fn make_listing(api: &mut super::api::ITRApi, args: &ArgMatches) -> Result<String> {
+pub async fn make_listing(api: &mut super::api::ITRApi, settings: &super::settings::Settings, args: &ArgMatches) -> Result<String> {
     let menu = args.get_one::<String>("menu").unwrap().to_string();
     let title = args.get_one::<String>("title");
     let (output_file, req_cats) = 
@@ -42,49 +43,175 @@ pub async fn make_listing(api: &mut super::api::ITRApi, args: &ArgMatches) -> Re
         let cats: Vec<String> = [cat.nth(0).unwrap()].map(|s| { s.to_string() }).to_vec();
         (output_file, cats)
     };
+    let source = fetch_listing_source(api, settings, args).await?;
+    write_listing(&source, &output_file, req_cats, title);
+    Ok(output_file)
+}
+
+// Display tweaks for product names, applied only to the menu output.
+struct NameTransform {
+    strip_prefixes: Vec<String>,
+    title_case: bool,
+    // UPC or IT Retail description -> name to show instead
+    overrides: HashMap<String, String>,
+}
+
+impl NameTransform {
+    fn from_args(args: &ArgMatches) -> Result<Self> {
+        let mut overrides = HashMap::new();
+        if let Some(file) = args.get_one::<String>("overrides") {
+            // One "UPC or description = display name" per line, # for comments.
+            for line in std::fs::read_to_string(file)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match line.split_once('=') {
+                    Some((key, name)) => { overrides.insert(key.trim().to_string(), name.trim().to_string()); },
+                    None => return Err(anyhow!("bad line in {}: {}", file, line)),
+                }
+            }
+        }
+        Ok(NameTransform {
+            strip_prefixes: args.get_many::<String>("strip-prefix").map(|v| v.cloned().collect()).unwrap_or_default(),
+            title_case: args.get_flag("title-case"),
+            overrides,
+        })
+    }
+
+    fn display_name(&self, item: &super::api::ProductData) -> String {
+        if let Some(name) = self.overrides.get(&item.upc).or_else(|| self.overrides.get(item.description.trim())) {
+            return name.to_string();
+        }
+        let mut name = item.description.trim();
+        for prefix in self.strip_prefixes.iter() {
+            if let Some(rest) = name.strip_prefix(prefix.as_str()) {
+                name = rest.trim_start();
+            }
+        }
+        if !self.title_case {
+            return name.to_string();
+        }
+        name.split_whitespace().map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect(),
+                None => String::new(),
+            }
+        }).collect::<Vec<String>>().join(" ")
+    }
+}
+
+// Everything a listing needs from IT Retail, fetched once so many listings can share it.
+pub struct ListingSource {
+    weighed_items: Vec<super::api::ProductData>,
+    cats: Vec<super::api::Category>,
+    tax_rates: HashMap<i32, f64>,
+    names: NameTransform,
+    // Prices are those in effect at this time, so a menu can be printed ahead of a sale.
+    as_of: DateTime<Local>,
+}
+
+async fn fetch_listing_source(api: &mut super::api::ITRApi, settings: &super::settings::Settings, args: &ArgMatches) -> Result<ListingSource> {
     let json = api
         .get(&"/api/ProductsData/GetAllProducts".to_string())
         .await
         .expect("no results from API call");
     let items: Vec<super::api::ProductData> = serde_json::from_str(&json)?;
     let items_iter = items.into_iter();
+    let active_only = args.get_flag("active-only") || settings.itretail.active_only;
     let weighed_items: Vec<super::api::ProductData> = items_iter
-        .filter(|x| !x.deleted && x.upc.starts_with("002"))
+        .filter(|x| x.is_available(active_only) && x.upc.starts_with("002"))
         .collect();
+    let tax_rates = if args.get_flag("tax-inclusive") {
+        api.get_tax_rates().await?
+    } else {
+        HashMap::new()
+    };
+    let cats: Vec<super::api::Category> = api
+        .get_categories()
+        .await
+        .expect("no results from category request");
+    let names = NameTransform::from_args(args)?;
+    let as_of = match args.get_one::<NaiveDateTime>("as-of") {
+        Some(ndt) => ndt.and_local_timezone(Local).earliest().ok_or_else(|| anyhow!("invalid local time {}", ndt))?,
+        None => Local::now(),
+    };
+    Ok(ListingSource { weighed_items, cats, tax_rates, names, as_of })
+}
+
+// Writes one listing file per named category, returning (category, listing file) pairs.
+pub async fn make_all_listings(api: &mut super::api::ITRApi, settings: &super::settings::Settings, args: &ArgMatches, dir: &str) -> Result<Vec<(String, String)>> {
+    let title = args.get_one::<String>("title");
+    let source = fetch_listing_source(api, settings, args).await?;
+    let names: Vec<String> = source.cats.iter()
+        .filter_map(|c| c.text.clone())
+        .filter(|t| !t.trim().is_empty())
+        .collect();
+    let mut listings = vec![];
+    for name in names {
+        let output_file = Path::new(dir).join(format!("{}.txt", category_filename(&name))).to_string_lossy().to_string();
+        write_listing(&source, &output_file, vec![name.clone()], title);
+        listings.push((name, output_file));
+    }
+    Ok(listings)
+}
+
+// tvmenu.category_backdrops keyed by lowercased category name (the config
+// loader lowercases keys anyway), with every mapped file checked up front so
+// a typo fails before any screen is drawn.
+pub fn category_backdrops(settings: &super::settings::Settings) -> Result<HashMap<String, String>> {
+    let mut backdrops = HashMap::new();
+    let mut missing = vec![];
+    for (cat, file) in settings.tvmenu.category_backdrops.iter() {
+        if !Path::new(file).is_file() {
+            missing.push(format!("{} ({})", file, cat));
+        }
+        backdrops.insert(cat.to_lowercase(), file.to_string());
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!("missing tvmenu backdrops: {}", missing.join(", ")));
+    }
+    Ok(backdrops)
+}
+
+pub fn category_filename(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+fn write_listing(source: &ListingSource, output_file: &String, req_cats: Vec<String>, title: Option<&String>) {
+    let tax_rates = &source.tax_rates;
     let mut item_map = HashMap::new();
-    for item in weighed_items.iter() {
+    for item in source.weighed_items.iter() {
         item_map.insert(item.upc.clone(), item);
     }
     let mut menu_file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(&output_file)
+        .open(output_file)
         .expect("Could not open menu file");
-    let cats: Vec<super::api::Category> = api
-        .get_categories()
-        .await
-        .expect("no results from category request");
+    let cats = &source.cats;
     let mut set = false;
-    if title.is_some() {
-        menu_file.write(&format!("{}\r\n", title.unwrap()).as_bytes()).expect("writing title");
+    if let Some(title) = title {
+        menu_file.write_all(format!("{}\r\n", title).as_bytes()).expect("writing title");
     }
     for cat_name in req_cats {
         for cat in cats.iter() {
             if cat.text.is_some() && cat.text.as_ref().unwrap().eq(&cat_name) {
                 info!("Using {} for product list", cat_name);
                 if set {
-                    menu_file.write("\r\n".as_bytes()).expect("writing spacer");
+                    menu_file.write_all("\r\n".as_bytes()).expect("writing spacer");
                 }
                 if title.is_none() {
-                    menu_file.write(&format!("{}\r\n", cat_name).as_bytes()).expect("writing category title");
+                    menu_file.write_all(format!("{}\r\n", cat_name).as_bytes()).expect("writing category title");
                 }
                 for choice in cat.product_shortcuts.iter() {
                     if choice.keystrokes.is_some() {
                         if let Some(item) = item_map.get(choice.keystrokes.as_ref().unwrap()) {
                             menu_file
-                                .write(
-                                    &format!("{} = ${:.2}/lb\r\n", item.description, item.get_price())
+                                .write_all(
+                                    format!("{} = ${:.2}/lb\r\n", source.names.display_name(item), item.with_tax(item.get_price_as_of(source.as_of), tax_rates))
                                         .as_bytes(),
                                 )
                                 .expect("writing menu item");
@@ -98,16 +225,15 @@ pub async fn make_listing(api: &mut super::api::ITRApi, args: &ArgMatches) -> Re
     }
     if !set {
         info!("Using all products");
-        for item in weighed_items {
+        for item in source.weighed_items.iter() {
             menu_file
-                .write(
-                    &format!("{} = ${:.2}/lb\r\n", item.description, item.get_price()).as_bytes(),
+                .write_all(
+                    format!("{} = ${:.2}/lb\r\n", source.names.display_name(item), item.with_tax(item.get_price_as_of(source.as_of), tax_rates)).as_bytes(),
                 )
                 .expect("writing menu item");
         }
     }
     menu_file.sync_all().expect("saving menu file");
-    Ok(output_file)
 }
 pub fn make_menu(
     output_file: &str,
@@ -1,13 +1,42 @@
 mod internal;
 
 use tokio;
-use chrono::{DateTime, Local, NaiveDateTime, NaiveDate, ParseError, TimeZone};
+use chrono::{DateTime, Local, NaiveDateTime, NaiveDate, ParseError, TimeZone, Utc};
 use clap::{Arg, ArgAction, Command};
+use internal::error::ApiError;
+use internal::label::{SortField, SortKey};
 use log::*;
 use simplelog::*;
-use std::fs::OpenOptions;
+use std::collections::HashMap;
 use std::{env, fs, thread, time};
 
+// sidedb-sync phases, in the order a sync runs them; each also has its own flag.
+const SIDEDB_SYNC_PHASES: [&str; 7] = [
+    "customers", "customers-square", "orders", "products", "products-square", "inventory-square", "transactions",
+];
+
+// A command's rows as --format csv (\r\n lines, --bom) or json, to --output or
+// stdout.
+fn write_report<T: serde::Serialize>(scmd: &clap::ArgMatches, header: &str, rows: &[T], csv_line: fn(&T) -> String) {
+    let report = if scmd.get_one::<String>("format").is_some_and(|f| f == "json") {
+        serde_json::to_string_pretty(rows).unwrap() + "\n"
+    } else {
+        let mut csv = format!("{}\r\n", header);
+        for row in rows {
+            csv.push_str(&csv_line(row));
+            csv.push_str("\r\n");
+        }
+        internal::api::csv_bom(csv, scmd.get_flag("bom"))
+    };
+    match scmd.get_one::<String>("output") {
+        Some(file) => if let Err(e) = fs::write(file, report) {
+            error!("Error writing {}: {}", file, e);
+            std::process::exit(exitcode::CANTCREAT);
+        },
+        None => print!("{}", report),
+    }
+}
+
 fn parse_timestamp(arg: &str) -> Result<NaiveDateTime,ParseError> {
     let dt = NaiveDateTime::parse_from_str(arg, "%Y-%m-%dT%H:%M:%S");
     dt
@@ -16,10 +45,37 @@ fn parse_date(arg: &str) -> Result<NaiveDate,ParseError> {
     let dt = NaiveDate::parse_from_str(arg, "%Y-%m-%d");
     dt
 }
+fn parse_sheet_layout(arg: &str) -> Result<internal::label::SheetLayout,String> {
+    internal::label::parse_sheet_layout(arg).map_err(|e| e.to_string())
+}
+fn parse_headers(arg: &str) -> Result<Vec<String>,String> {
+    internal::label::parse_headers(arg).map_err(|e| e.to_string())
+}
+fn parse_order_status(arg: &str) -> Result<Vec<String>,String> {
+    internal::localexpress::parse_order_status(arg).map_err(|e| e.to_string())
+}
+fn parse_sort_keys(arg: &str) -> Result<Vec<SortKey>,String> {
+    internal::label::parse_sort_keys(arg).map_err(|e| e.to_string())
+}
+fn parse_product_filter(arg: &str) -> Result<internal::api::ProductFilter,String> {
+    internal::api::parse_product_filter(arg).map_err(|e| e.to_string())
+}
+
+async fn scale_export_only(api: &mut internal::api::ITRApi, settings: &internal::settings::Settings, scmd: &clap::ArgMatches) {
+    let mut scale_file = internal::scales::Scales {};
+    if let Err(e) = scale_file.export(api, settings, scmd).await {
+        error!("Error: {}", e);
+        std::process::exit(exitcode::SOFTWARE);
+    }
+    std::process::exit(exitcode::OK);
+}
 
 #[cfg(windows)]
 async fn scale_export(mut api: &mut internal::api::ITRApi, settings: &internal::settings::Settings, scmd: &clap::ArgMatches) {
-    let mut scale_file = internal::cas::Scales {};
+    if scmd.get_flag("export-only") {
+        return scale_export_only(api, settings, scmd).await;
+    }
+    let mut scale_file = internal::scales::Scales {};
     let r = scale_file.send(&mut api, &settings, &scmd).await;
     if r.is_err() {
         error!("Error: {}", r.err().unwrap());
@@ -28,12 +84,33 @@ async fn scale_export(mut api: &mut internal::api::ITRApi, settings: &internal::
     std::process::exit(exitcode::OK);
 }
 
+#[cfg(windows)]
+async fn scale_verify(api: &mut internal::api::ITRApi, settings: &internal::settings::Settings, scmd: &clap::ArgMatches) {
+    let mut scale_file = internal::scales::Scales {};
+    match scale_file.verify(api, settings, scmd).await {
+        Ok(0) => std::process::exit(exitcode::OK),
+        Ok(_) => std::process::exit(exitcode::DATAERR),
+        Err(e) => {
+            error!("Error: {}", e);
+            std::process::exit(exitcode::SOFTWARE);
+        }
+    }
+}
+
 #[cfg(not(windows))]
 #[allow(unused_variables)]
-fn scale_export(api: &mut internal::api::ITRApi, settings: &internal::settings::Settings, scmd: &clap::ArgMatches) {
+async fn scale_verify(api: &mut internal::api::ITRApi, settings: &internal::settings::Settings, scmd: &clap::ArgMatches) {
     error!("CAS Scale integration only supported on Windows.")
 }
 
+#[cfg(not(windows))]
+async fn scale_export(api: &mut internal::api::ITRApi, settings: &internal::settings::Settings, scmd: &clap::ArgMatches) {
+    if scmd.get_flag("export-only") {
+        return scale_export_only(api, settings, scmd).await;
+    }
+    error!("CAS Scale integration only supported on Windows; use --export-only.")
+}
+
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -56,6 +133,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .action(ArgAction::Set)
                 .value_name("FILE"),
         )
+        .arg(
+            Arg::new("log-max-size")
+                .long("log-max-size")
+                .action(ArgAction::Set)
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("10485760"),
+        )
+        .arg(
+            Arg::new("log-keep")
+                .long("log-keep")
+                .action(ArgAction::Set)
+                .value_name("FILES")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("5"),
+        )
+        .arg(
+            Arg::new("bom")
+                .long("bom")
+                .action(ArgAction::SetTrue)
+                .num_args(0)
+                .global(true)
+                .help("start CSV output with a UTF-8 byte order mark, for Excel"),
+        )
         .arg(Arg::new("username").long("username").short('u'))
         .arg(Arg::new("password").long("password").short('p'))
         .arg(Arg::new("leusername").long("leusername"))
@@ -77,8 +178,55 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .action(ArgAction::SetTrue)
                     .num_args(0))
         )
+        .subcommand(
+            Command::new("loyalty-export")
+            .arg(Arg::new("days")
+                    .long("days")
+                    .short('d')
+                    .action(ArgAction::Set)
+                    .value_name("DAYS")
+                    .value_parser(clap::value_parser!(u32).range(1..))
+                    .default_value("180"))
+            .arg(Arg::new("format")
+                    .long("format")
+                    .action(ArgAction::Set)
+                    .value_parser(["csv", "json"])
+                    .default_value("csv"))
+            .arg(Arg::new("output")
+                    .long("output")
+                    .action(ArgAction::Set)
+                    .value_name("FILE"))
+        )
+        .subcommand(
+            Command::new("loyalty-simulate")
+            .arg(Arg::new("discount")
+                    .long("discount")
+                    .action(ArgAction::Set)
+                    .value_name("PERCENT")
+                    .value_parser(clap::value_parser!(u32).range(0..=100))
+                    .required(true))
+            .arg(Arg::new("upc-list")
+                    .long("upc-list")
+                    .action(ArgAction::Set)
+                    .value_name("FILE")
+                    .required(true))
+        )
         .subcommand(
             Command::new("sidedb-sync")
+                .after_help("With no phase named, every phase runs, Square customers, products and inventory included.")
+                .arg(Arg::new("all")
+                         .long("all")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0)
+                         .conflicts_with("phases")
+                         .help("run every phase"))
+                .arg(Arg::new("phases")
+                         .long("phases")
+                         .action(ArgAction::Set)
+                         .value_name("PHASE,...")
+                         .value_delimiter(',')
+                         .value_parser(SIDEDB_SYNC_PHASES)
+                         .help("run just these phases"))
                 .arg(Arg::new("customers")
                          .long("customers")
                          .action(ArgAction::SetTrue)
@@ -87,10 +235,56 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                          .long("customers-square")
                          .action(ArgAction::SetTrue)
                          .num_args(0))
+                .arg(Arg::new("customers-since")
+                         .long("customers-since")
+                         .action(ArgAction::Set)
+                         .value_name("DATETIME")
+                         .value_parser(parse_timestamp))
+                .arg(Arg::new("unmatched-output")
+                         .long("unmatched-output")
+                         .action(ArgAction::Set)
+                         .value_name("FILE"))
+                .arg(Arg::new("fuzzy-match")
+                         .long("fuzzy-match")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0)
+                         .help("before creating a Square customer, look for one with the same last name and phone or email similar within square.fuzzy_match_threshold; matches are only reported"))
+                .arg(Arg::new("fuzzy-associate")
+                         .long("fuzzy-associate")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0)
+                         .requires("fuzzy-match")
+                         .help("associate fuzzy matches and update the Square customer from IT Retail"))
+                .arg(Arg::new("fuzzy-output")
+                         .long("fuzzy-output")
+                         .action(ArgAction::Set)
+                         .value_name("FILE")
+                         .requires("fuzzy-match"))
+                .arg(Arg::new("progress")
+                         .long("progress")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0)
+                         .help("show a progress bar through the Square customer and product syncs (terminals only)"))
+                .arg(Arg::new("dry-run-deletes")
+                         .long("dry-run-deletes")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0)
+                         .help("list the Square customers a customer sync would delete instead of deleting them"))
                 .arg(Arg::new("customers-full")
                          .long("customers-full")
                          .action(ArgAction::SetTrue)
                          .num_args(0))
+                .arg(Arg::new("customers-batch")
+                         .long("customers-batch")
+                         .action(ArgAction::Set)
+                         .value_name("N")
+                         .requires("customers-full")
+                         .value_parser(clap::value_parser!(usize)))
+                .arg(Arg::new("resume")
+                         .long("resume")
+                         .action(ArgAction::SetTrue)
+                         .requires("customers-batch")
+                         .num_args(0))
                 .arg(Arg::new("transactions")
                          .long("transactions")
                          .action(ArgAction::SetTrue)
@@ -109,18 +303,97 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                          .long("products")
                          .action(ArgAction::SetTrue)
                          .num_args(0))
+                .arg(Arg::new("no-archive")
+                         .long("no-archive")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0)
+                         .help("don't snapshot itrproduct into itrproduct_archive before storing products"))
                 .arg(Arg::new("products-square")
                          .long("products-square")
                          .action(ArgAction::SetTrue)
                          .num_args(0))
+                .arg(Arg::new("max-products")
+                         .long("max-products")
+                         .action(ArgAction::Set)
+                         .value_name("N")
+                         .value_parser(clap::value_parser!(usize))
+                         .help("stop the Square product sync after N products, counted after --only-upc"))
+                .arg(Arg::new("only-upc")
+                         .long("only-upc")
+                         .action(ArgAction::Append)
+                         .value_name("UPC")
+                         .help("sync only this IT Retail UPC to Square, may be repeated"))
+                .arg(Arg::new("report-only")
+                         .long("report-only")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
+                .arg(Arg::new("plan-output")
+                         .long("plan-output")
+                         .action(ArgAction::Set)
+                         .value_name("FILE")
+                         .requires("report-only"))
+                .arg(Arg::new("sync-cost")
+                         .long("sync-cost")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
+                .arg(Arg::new("include-deleted")
+                         .long("include-deleted")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
+                .arg(Arg::new("allow-zero-price")
+                         .long("allow-zero-price")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
+                .arg(Arg::new("trace-upc")
+                         .long("trace-upc")
+                         .action(ArgAction::Set)
+                         .value_name("UPC")
+                         .help("log each Square product sync decision for this IT Retail UPC at info, whatever --log-level is"))
                 .arg(Arg::new("inventory-square")
                          .long("inventory-square")
                          .action(ArgAction::SetTrue)
                          .num_args(0))
+                .arg(Arg::new("verify-inventory")
+                         .long("verify-inventory")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
+                .arg(Arg::new("inventory-tolerance")
+                         .long("inventory-tolerance")
+                         .action(ArgAction::Set)
+                         .value_name("QTY")
+                         .value_parser(clap::value_parser!(f64))
+                         .default_value("0.001"))
+                .arg(Arg::new("verify-output")
+                         .long("verify-output")
+                         .action(ArgAction::Set)
+                         .value_name("FILE")
+                         .default_value("inventory-verify.csv"))
+                .arg(Arg::new("atomic")
+                         .long("atomic")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
                 .arg(Arg::new("orders")
                          .long("orders")
                          .action(ArgAction::SetTrue)
                          .num_args(0))
+                .arg(Arg::new("orders-status")
+                         .long("orders-status")
+                         .action(ArgAction::Append)
+                         .value_name("STATUS")
+                         .requires("orders")
+                         .value_parser(parse_order_status))
+                .arg(Arg::new("orders-since")
+                         .long("orders-since")
+                         .action(ArgAction::Set)
+                         .value_name("DATE")
+                         .requires("orders")
+                         .value_parser(parse_date))
+                .arg(Arg::new("orders-until")
+                         .long("orders-until")
+                         .action(ArgAction::Set)
+                         .value_name("DATE")
+                         .requires("orders")
+                         .value_parser(parse_date))
                 .arg(Arg::new("period")
                          .long("period")
                          .short('t')
@@ -128,15 +401,86 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                          .value_name("SECONDS")
                          .value_parser(clap::value_parser!(u32))
                          .default_value("0"))
+                .arg(Arg::new("active-only")
+                         .long("active-only")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
         )
         .subcommand(
             Command::new("le-orders")
+                .arg(Arg::new("verify-lights")
+                         .long("verify-lights")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0))
+        )
+        .subcommand(
+            Command::new("le-customers")
+                .arg(Arg::new("dry-run")
+                         .long("dry-run")
+                         .action(ArgAction::SetTrue)
+                         .num_args(0)
+                         .help("list who would be added without adding them"))
         )
         .subcommand(
             Command::new("set-plu")
                 .arg(Arg::new("upc").required(true))
                 .arg(Arg::new("plu").required(true)),
         )
+        .subcommand(
+            Command::new("scale-verify")
+                .arg(
+                    Arg::new("external")
+                        .long("external")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("upc")
+                        .long("upc")
+                        .action(ArgAction::Set)
+                        .value_name("Regex")
+                        .default_value("^002"),
+                )
+                .arg(
+                    Arg::new("scale")
+                        .long("scale")
+                        .action(ArgAction::Append)
+                        .value_name("IP Address"),
+                )
+                .arg(
+                    Arg::new("scan-to")
+                        .long("scan-to")
+                        .action(ArgAction::Set)
+                        .value_name("PLU")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("timeout-seconds")
+                        .long("timeout-seconds")
+                        .short('w')
+                        .action(ArgAction::Set)
+                        .value_name("seconds")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("progress")
+                        .long("progress")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("active-only")
+                        .long("active-only")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("allow-zero-price")
+                        .long("allow-zero-price")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
         .subcommand(
             Command::new("scale-export")
                 .arg(
@@ -145,7 +489,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .short('o')
                         .action(ArgAction::Set)
                         .value_name("FILE")
-                        .default_value("PLU.xlsx"),
+                        .conflicts_with_all(["output-dir", "timestamp"])
+                        .help("defaults to PLU.xlsx"),
+                )
+                .arg(
+                    Arg::new("output-dir")
+                        .long("output-dir")
+                        .action(ArgAction::Set)
+                        .value_name("DIR"),
+                )
+                .arg(
+                    Arg::new("timestamp")
+                        .long("timestamp")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
                 )
                 .arg(
                     Arg::new("by-section")
@@ -153,6 +510,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .action(ArgAction::SetTrue)
                         .num_args(0),
                 )
+                .arg(
+                    Arg::new("sort")
+                        .long("sort")
+                        .action(ArgAction::Set)
+                        .value_name("KEY[:desc],...")
+                        .value_parser(parse_sort_keys)
+                        .help("order of products within each PLU sheet; defaults to plu"),
+                )
                 .arg(
                     Arg::new("scale-file")
                         .long("scale-file")
@@ -196,6 +561,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .action(ArgAction::SetTrue)
                         .num_args(0),
                 )
+                .arg(
+                    Arg::new("plu-changes")
+                        .long("plu-changes")
+                        .action(ArgAction::Set)
+                        .value_name("FILE")
+                        .help("record assigned PLUs; JSON if FILE ends in .json, otherwise CSV"),
+                )
+                .arg(
+                    Arg::new("allow-zero-price")
+                        .long("allow-zero-price")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue)
+                        .help("send items with no price instead of skipping them"),
+                )
+                .arg(
+                    Arg::new("export-only")
+                        .long("export-only")
+                        .conflicts_with_all(["scale", "no-scales", "wipe"])
+                        .action(ArgAction::SetTrue)
+                        .num_args(0)
+                        .help("write the PLU and scale files without loading the CAS library"),
+                )
                 .arg(
                     Arg::new("timeout-seconds")
                         .long("timeout-seconds")
@@ -224,6 +611,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .long("progress")
                         .num_args(0)
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("where")
+                        .long("where")
+                        .action(ArgAction::Set)
+                        .value_name("EXPR")
+                        .value_parser(parse_product_filter),
+                )
+                .arg(
+                    Arg::new("active-only")
+                        .long("active-only")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -239,7 +639,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .short('o')
                         .action(ArgAction::Set)
                         .value_name("FILE")
-                        .default_value("labels.xlsx"),
+                        .conflicts_with_all(["output-dir", "timestamp"])
+                        .help("defaults to labels.xlsx"),
+                )
+                .arg(
+                    Arg::new("output-dir")
+                        .long("output-dir")
+                        .action(ArgAction::Set)
+                        .value_name("DIR"),
+                )
+                .arg(
+                    Arg::new("timestamp")
+                        .long("timestamp")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
                 )
                 .arg(
                     Arg::new("sheets")
@@ -247,6 +660,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .num_args(0)
                         .action(ArgAction::SetTrue)
                 )
+                .arg(
+                    Arg::new("sheet-layout")
+                        .long("sheet-layout")
+                        .action(ArgAction::Set)
+                        .value_name("COLUMNSxROWS")
+                        .value_parser(parse_sheet_layout),
+                )
+                .arg(
+                    Arg::new("tax-inclusive")
+                        .long("tax-inclusive")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("upc")
                         .long("upc")
@@ -277,15 +703,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .value_parser(clap::value_parser!(f32))
                         .default_value("-10000000.0"),
                 )
+                .arg(
+                    Arg::new("sort")
+                        .long("sort")
+                        .action(ArgAction::Set)
+                        .value_name("KEY[:desc],...")
+                        .value_parser(parse_sort_keys),
+                )
                 .arg(
                     Arg::new("headers")
                         .long("headers")
                         .action(ArgAction::Set)
+                        .value_parser(parse_headers)
                         .default_value("name,plu,upc,price")
+                )
+                .arg(
+                    Arg::new("list-headers")
+                        .long("list-headers")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("where")
+                        .long("where")
+                        .action(ArgAction::Set)
+                        .value_name("EXPR")
+                        .value_parser(parse_product_filter),
+                )
+                .arg(
+                    Arg::new("active-only")
+                        .long("active-only")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("include-deleted")
+                        .long("include-deleted")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue)
+                        .help("also export products deleted in IT Retail, adding a status column"),
+                )
+                .arg(
+                    Arg::new("include-inactive")
+                        .long("include-inactive")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("active-only")
+                        .help("also export inactive products, even with itretail.active_only, adding a status column"),
                 ),
         )
         .subcommand(
             Command::new("get-plu")
+                .arg(
+                    Arg::new("sort")
+                        .long("sort")
+                        .action(ArgAction::Set)
+                        .value_name("KEY[:desc],...")
+                        .value_parser(parse_sort_keys),
+                )
                 .arg(
                     Arg::new("upc")
                         .long("upc")
@@ -315,6 +790,153 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .value_name("weight/qty")
                         .value_parser(clap::value_parser!(f32))
                         .default_value("-10000000.0"),
+                )
+                .arg(
+                    Arg::new("where")
+                        .long("where")
+                        .action(ArgAction::Set)
+                        .value_name("EXPR")
+                        .value_parser(parse_product_filter),
+                )
+                .arg(
+                    Arg::new("active-only")
+                        .long("active-only")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("offline")
+                        .long("offline")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("shrink")
+                .arg(
+                    Arg::new("upc")
+                        .long("upc")
+                        .action(ArgAction::Set)
+                        .value_name("UPC")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("amount")
+                        .long("amount")
+                        .action(ArgAction::Set)
+                        .value_name("weight/qty")
+                        .value_parser(clap::value_parser!(f32))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("reason-code")
+                        .long("reason-code")
+                        .action(ArgAction::Set)
+                        .value_name("CODE")
+                        .value_parser(clap::value_parser!(u32))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("product-drift")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["csv", "json"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .action(ArgAction::Set)
+                        .value_name("FILE"),
+                ),
+        )
+        .subcommand(
+            Command::new("cost-alert")
+                .arg(
+                    Arg::new("threshold-pct")
+                        .long("threshold-pct")
+                        .action(ArgAction::Set)
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(f64))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("date")
+                        .long("date")
+                        .action(ArgAction::Set)
+                        .value_name("DATE")
+                        .value_parser(parse_date)
+                        .help("compare against that day's snapshot instead of the one before the last sync"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["csv", "json"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .action(ArgAction::Set)
+                        .value_name("FILE"),
+                ),
+        )
+        .subcommand(
+            Command::new("sidedb-prune")
+                .arg(
+                    Arg::new("days")
+                        .long("days")
+                        .action(ArgAction::Set)
+                        .value_name("DAYS")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                ),
+        )
+        .subcommand(
+            Command::new("lookup")
+                .arg(
+                    Arg::new("upc")
+                        .action(ArgAction::Set)
+                        .value_name("UPC")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            Command::new("explain-price")
+                .arg(
+                    Arg::new("upc")
+                        .long("upc")
+                        .action(ArgAction::Set)
+                        .value_name("UPC")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("as-of")
+                        .long("as-of")
+                        .action(ArgAction::Set)
+                        .value_name("DATETIME")
+                        .value_parser(parse_timestamp)
+                        .help("local time, YYYY-MM-DDTHH:MM:SS; defaults to now"),
+                ),
+        )
+        .subcommand(
+            Command::new("price-history")
+                .arg(
+                    Arg::new("upc")
+                        .long("upc")
+                        .action(ArgAction::Set)
+                        .value_name("UPC")
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -336,6 +958,179 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .long("email")
                         .action(ArgAction::Set)
                         .value_name("EMAIL")
+                )
+                .arg(
+                    Arg::new("full")
+                        .long("full")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("check-mx")
+                        .long("check-mx")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("birthday")
+                        .long("birthday")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("mailchimp-pending")
+                .arg(
+                    Arg::new("mc_token")
+                        .long("mc_token")
+                        .action(ArgAction::Set)
+                        .value_name("API_TOKEN"),
+                )
+                .arg(
+                    Arg::new("listid")
+                        .long("listid")
+                        .action(ArgAction::Set)
+                        .value_name("LISTID"),
+                )
+                .arg(
+                    Arg::new("min-days")
+                        .long("min-days")
+                        .action(ArgAction::Set)
+                        .value_name("DAYS")
+                        .value_parser(clap::value_parser!(i64).range(0..))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("resend")
+                        .long("resend")
+                        .action(ArgAction::Append)
+                        .value_name("EMAIL")
+                        .help("send this pending member their opt-in confirmation again by archiving and re-adding them as pending; only merge fields are kept; may be repeated"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["csv", "json"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .action(ArgAction::Set)
+                        .value_name("FILE"),
+                ),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            Command::new("square-fix-skus")
+                .arg(
+                    Arg::new("apply")
+                        .long("apply")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("square-reassociate")
+                .arg(
+                    Arg::new("products")
+                        .long("products")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("customers")
+                        .long("customers")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(Command::new("square-sync-associations"))
+        .subcommand(
+            Command::new("square-inventory-variance")
+                .arg(
+                    Arg::new("min-difference")
+                        .long("min-difference")
+                        .action(ArgAction::Set)
+                        .value_name("QTY")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["csv", "json"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .action(ArgAction::Set)
+                        .value_name("FILE"),
+                ),
+        )
+        .subcommand(
+            Command::new("square-ensure-groups")
+                .arg(
+                    Arg::new("check")
+                        .long("check")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue)
+                        .help("only report missing groups, don't create them"),
+                ),
+        )
+        .subcommand(
+            Command::new("identity-audit")
+                .arg(
+                    Arg::new("email")
+                        .long("email")
+                        .action(ArgAction::Set)
+                        .value_name("EMAIL"),
+                )
+                .arg(
+                    Arg::new("phone")
+                        .long("phone")
+                        .action(ArgAction::Set)
+                        .value_name("PHONE"),
+                )
+                .arg(
+                    Arg::new("mc_token")
+                        .long("mc_token")
+                        .action(ArgAction::Set)
+                        .value_name("API_TOKEN"),
+                )
+                .arg(
+                    Arg::new("listid")
+                        .long("listid")
+                        .action(ArgAction::Set)
+                        .value_name("LISTID"),
+                ),
+        )
+        .subcommand(
+            Command::new("compare-stripe-square")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["csv", "json"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .action(ArgAction::Set)
+                        .value_name("FILE"),
                 ),
         )
         .subcommand(
@@ -361,24 +1156,59 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .default_value("menu.txt"),
                 )
                 .arg(
-                    Arg::new("output")
-                        .long("output")
-                        .action(ArgAction::Set)
-                        .value_name("FILENAME")
+                    Arg::new("output")
+                        .long("output")
+                        .action(ArgAction::Set)
+                        .value_name("FILENAME")
+                )
+                .arg(
+                    Arg::new("invert")
+                        .long("invert")
+                        .short('i')
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("tax-inclusive")
+                        .long("tax-inclusive")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(Arg::new("as-of")
+                         .long("as-of")
+                         .action(ArgAction::Set)
+                         .value_name("YYYY-MM-DDTHH:MM:SS")
+                         .value_parser(parse_timestamp))
+                .arg(
+                    Arg::new("strip-prefix")
+                        .long("strip-prefix")
+                        .action(ArgAction::Append)
+                        .value_name("PREFIX"),
                 )
                 .arg(
-                    Arg::new("invert")
-                        .long("invert")
-                        .short('i')
+                    Arg::new("title-case")
+                        .long("title-case")
                         .num_args(0)
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("overrides")
+                        .long("overrides")
+                        .action(ArgAction::Set)
+                        .value_name("FILENAME"),
+                )
                 .arg(
                     Arg::new("pull")
                         .long("pull")
                         .short('u')
                         .action(ArgAction::Set)
                         .conflicts_with("menu"),
+                )
+                .arg(
+                    Arg::new("active-only")
+                        .long("active-only")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
                 ),
         );
     let help = cmd.render_help();
@@ -403,24 +1233,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             LevelFilter::Warn
         }
     };
-    let lconfig = ConfigBuilder::new()
+    let mut lbuilder = ConfigBuilder::new();
+    lbuilder
         .set_level_color(Level::Error, Some(Color::Red))
         .set_level_color(Level::Warn, Some(Color::Magenta))
         .set_target_level(LevelFilter::Error)
         .set_time_format_rfc3339()
         .set_time_level(LevelFilter::Error)
-        .set_max_level(LevelFilter::Error)
-        .build();
+        .set_max_level(LevelFilter::Error);
+    let lconfig = lbuilder.build();
     let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
     if let Some(logfile) = m.get_one::<String>("log-file") {
         loggers.push(WriteLogger::new(
             llevel,
             lconfig.clone(),
-            OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(logfile)
-                .unwrap(),
+            internal::logfile::RotatingFile::new(
+                logfile,
+                *m.get_one::<u64>("log-max-size").unwrap(),
+                *m.get_one::<u32>("log-keep").unwrap(),
+            )
+            .unwrap(),
         ));
     } else {
         loggers.push(TermLogger::new(
@@ -430,6 +1262,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ColorChoice::Always,
         ));
     }
+    // --trace-upc lines are logged at info; below that, give them a terminal logger of their own.
+    if llevel < LevelFilter::Info && m.subcommand_matches("sidedb-sync").is_some_and(|s| s.contains_id("trace-upc")) {
+        loggers.push(TermLogger::new(
+            LevelFilter::Info,
+            lbuilder.add_filter_allow_str(internal::square::TRACE_TARGET).build(),
+            TerminalMode::Mixed,
+            ColorChoice::Always,
+        ));
+    }
     CombinedLogger::init(loggers).unwrap();
 
     if let Some(cli_lepass) = m.get_one::<String>("lepassword") {
@@ -462,10 +1303,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         panic!("{}", handle.err().unwrap())
     }
     let mut api = handle.ok().unwrap();
+    api.set_update_batch_rows(settings.itretail.update_batch_rows);
+    api.set_timeouts(settings.itretail.connect_timeout_seconds, settings.itretail.request_timeout_seconds);
+    api.set_base_url(&settings.itretail.base_url);
 
     let auth_result = api.auth().await;
     if let Some(err) = auth_result.err() {
-        if err.to_string().contains("no username provided") {
+        if matches!(err.downcast_ref::<ApiError>(), Some(ApiError::MissingUsername)) {
             println!(
                 r"A username is needed and is not present in the environment. Add one.
 
@@ -477,7 +1321,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 "
             )
         }
-        if err.to_string().contains("no password provided") {
+        if matches!(err.downcast_ref::<ApiError>(), Some(ApiError::MissingPassword)) {
             println!(
                 r"A password is needed and is not present in the environment. Add one.
 
@@ -494,21 +1338,70 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match m.subcommand() {
         Some(("loyalty", scmd)) => {
-            let mut sidedb = internal::sidedb::make_sidedb(settings.clone()).await.unwrap();
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Everything).await.unwrap();
             let r = internal::loyalty::apply_discounts(&mut api, &mut sidedb, &settings, &scmd).await;
             if r.is_err() {
                 error!("Error reading electronic journal: {}", r.err().unwrap());
                 std::process::exit(exitcode::SOFTWARE);
             }
         }
+        Some(("loyalty-export", scmd)) => {
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Everything).await.unwrap();
+            let tiers = match internal::loyalty::tier_assignments(&mut sidedb, scmd).await {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("Error computing loyalty tiers: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            write_report(scmd, "card_no,name,spend,tier,assigned,next_tier,to_next_tier", &tiers, internal::loyalty::TierAssignment::csv_line);
+            let stale = tiers.iter().filter(|t| t.tier != t.assigned).count();
+            info!("{} customers, {} with an IT Retail discount other than their tier", tiers.len(), stale);
+            std::process::exit(exitcode::OK);
+        }
+        Some(("loyalty-simulate", scmd)) => {
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Everything).await.unwrap();
+            let r = internal::loyalty::simulate(&mut sidedb, scmd).await;
+            if r.is_err() {
+                error!("Error simulating loyalty discount: {}", r.err().unwrap());
+                std::process::exit(exitcode::SOFTWARE);
+            }
+        }
         Some(("scale-export", scmd)) => { scale_export(&mut api, &settings, &scmd).await }
+        Some(("scale-verify", scmd)) => { scale_verify(&mut api, &settings, scmd).await }
         Some(("get-plu", scmd)) => {
             let mut label_file = internal::label::create_label_file(&"".to_owned());
-            let results = api
-                .get(&"/api/ProductsData/GetAllProducts".to_string())
-                .await
-                .expect("no results from API call");
-            let r = label_file.output_from_itretail_products(&results, &scmd);
+            if !scmd.get_flag("offline") && scmd.get_one::<Vec<SortKey>>("sort").is_some_and(|k| k.iter().any(|k| k.field == SortField::Section)) {
+                match api.get_sections().await {
+                    Ok(sections) => label_file.set_sections(&sections),
+                    Err(e) => {
+                        error!("Error fetching IT Retail sections: {}", e);
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                }
+            }
+            let results = if scmd.get_flag("offline") {
+                match internal::api::cached_products() {
+                    Ok((json, age)) => {
+                        warn!("Using products cached {:.1} hours ago.", age.as_secs_f64() / 3600.0);
+                        json
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(exitcode::NOINPUT);
+                    }
+                }
+            } else {
+                let json = api
+                    .get(&"/api/ProductsData/GetAllProducts".to_string())
+                    .await
+                    .expect("no results from API call");
+                if let Err(e) = internal::api::cache_products(&json) {
+                    warn!("Could not cache products: {}", e);
+                }
+                json
+            };
+            let r = label_file.output_from_itretail_products(&results, &settings, scmd);
             if r.is_err() {
                 error!("{}", r.err().unwrap());
                 std::process::exit(exitcode::SOFTWARE);
@@ -516,18 +1409,488 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             std::process::exit(exitcode::OK);
         }
         Some(("label-export", scmd)) => {
-            let filename = scmd.get_one::<String>("output").unwrap();
+            if scmd.get_flag("list-headers") {
+                for (name, description) in internal::label::LABEL_HEADERS.iter() {
+                    println!("{:8} {}", name, description);
+                }
+                std::process::exit(exitcode::OK);
+            }
+            let filename = match internal::api::export_filename(scmd, "labels.xlsx") {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Error preparing output: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
             let asof = scmd.get_one::<NaiveDate>("as-of");
-            let mut label_file = internal::label::create_label_file(filename);
-            let mut sidedb = internal::sidedb::make_sidedb(settings).await.unwrap();
-            let items = sidedb.get_products(asof).await.unwrap();
-            let r = label_file.build_from_itretail_products(&items, &scmd);
+            let mut label_file = internal::label::create_label_file(&filename);
+            // Labels show the prices in effect on the --as-of date.  There's no snapshot of
+            // the future, so a date ahead of today prices today's products for that day.
+            let snapshot = asof.filter(|d| **d <= Local::now().date_naive());
+            if let Some(d) = asof {
+                match d.and_hms_opt(0, 0, 0).and_then(|ndt| ndt.and_local_timezone(Local).earliest()) {
+                    Some(whence) => label_file.set_price_as_of(whence),
+                    None => {
+                        error!("Invalid --as-of date {}", d);
+                        std::process::exit(exitcode::USAGE);
+                    }
+                }
+            }
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            let items = sidedb.query_products(snapshot, scmd.get_flag("include-deleted")).await.unwrap();
+            if scmd.get_one::<Vec<SortKey>>("sort").is_some_and(|k| k.iter().any(|k| k.field == SortField::Section)) {
+                match sidedb.get_sections().await {
+                    Ok(sections) => label_file.set_sections(&sections),
+                    Err(e) => {
+                        error!("Error reading sections: {}", e);
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                }
+            }
+            let tax_rates = if scmd.get_flag("tax-inclusive") {
+                match api.get_tax_rates().await {
+                    Ok(rates) => rates,
+                    Err(e) => {
+                        error!("Error fetching IT Retail taxes: {}", e);
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                }
+            } else {
+                HashMap::new()
+            };
+            let r = label_file.build_from_itretail_products(&items, &tax_rates, &settings, scmd);
             if r.is_err() {
                 error!("{}", r.err().unwrap());
                 std::process::exit(exitcode::SOFTWARE);
             }
             std::process::exit(exitcode::OK);
         }
+        Some(("shrink", scmd)) => {
+            let upc = scmd.get_one::<String>("upc").unwrap();
+            let amount = *scmd.get_one::<f32>("amount").unwrap();
+            let reason = *scmd.get_one::<u32>("reason-code").unwrap();
+            let products = match api.get_products().await {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error fetching IT Retail products: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            let item = match products.iter().find(|p| &p.upc == upc && !p.deleted) {
+                Some(item) => item,
+                None => {
+                    error!("No such product: {}", upc);
+                    std::process::exit(exitcode::DATAERR);
+                }
+            };
+            let shrink_amount = match internal::api::shrink_amount_for(item, amount) {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(exitcode::DATAERR);
+                }
+            };
+            let shrink = internal::api::make_shrink_item(item, reason, shrink_amount);
+            if let Err(e) = api.shrink_product(vec![shrink]).await {
+                error!("Error posting shrink: {}", e);
+                std::process::exit(exitcode::SOFTWARE);
+            }
+            info!("Shrunk {} {} of {} ({}) with reason {}", amount, if item.scale { "lb" } else { "units" }, item.upc, item.description, reason);
+            std::process::exit(exitcode::OK);
+        }
+        Some(("sidedb-prune", scmd)) => {
+            let days = scmd.get_one::<u32>("days").copied().unwrap_or(settings.postgres.archive_retention_days);
+            if days == 0 {
+                error!("No retention given; use --days or set postgres.archive_retention_days.");
+                std::process::exit(exitcode::USAGE);
+            }
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            match sidedb.prune_product_archive(days).await {
+                Ok(n) => info!("Pruned {} product archive rows older than {} days.", n, days),
+                Err(e) => {
+                    error!("Error pruning product archive: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            }
+            std::process::exit(exitcode::OK);
+        }
+        Some(("lookup", scmd)) => {
+            // Read-only: whatever one system can't answer is left out rather than failing the rest.
+            let upc = scmd.get_one::<String>("upc").unwrap();
+            let tax_rates = api.get_tax_rates().await.unwrap_or_else(|e| {
+                warn!("Error fetching IT Retail tax rates: {}", e);
+                HashMap::new()
+            });
+            let live = match api.get_products().await {
+                Ok(products) => products.into_iter().find(|p| &p.upc == upc || p.upca().as_ref() == Some(upc)),
+                Err(e) => {
+                    error!("Error fetching IT Retail products: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            let upc = live.as_ref().map(|p| p.upc.clone()).unwrap_or(upc.to_string());
+            let side = match internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await {
+                Ok(mut sidedb) => match sidedb.get_product(&upc, false).await {
+                    Ok(product) => product,
+                    Err(e) => {
+                        warn!("Error reading sidedb products: {}", e);
+                        None
+                    }
+                },
+                Err(e) => {
+                    warn!("Error connecting to sidedb: {}", e);
+                    None
+                }
+            };
+            let upca = internal::api::itr_upc_to_upca(&upc);
+            let square = match &upca {
+                Some(upca) => match internal::square::square_connect_create(&settings).lookup_product(upca).await {
+                    Ok(item) => item,
+                    Err(e) => {
+                        warn!("Error reading the Square catalog: {}", e);
+                        None
+                    }
+                },
+                None => None,
+            };
+            let lookup = internal::api::Lookup {
+                itretail: live.map(|p| p.lookup(&tax_rates)),
+                sidedb: side.map(|p| p.lookup(&tax_rates)),
+                square,
+                upc,
+                upca,
+            };
+            if scmd.get_one::<String>("format").unwrap() == "json" {
+                println!("{}", serde_json::to_string_pretty(&lookup).unwrap());
+            } else {
+                print!("{}", lookup.table());
+            }
+            std::process::exit(if lookup.itretail.is_some() { exitcode::OK } else { exitcode::DATAERR });
+        }
+        Some(("explain-price", scmd)) => {
+            let upc = scmd.get_one::<String>("upc").unwrap();
+            let whence = match scmd.get_one::<NaiveDateTime>("as-of") {
+                Some(dt) => match Local.from_local_datetime(dt).earliest() {
+                    Some(whence) => whence,
+                    None => {
+                        error!("Invalid --as-of time {}", dt);
+                        std::process::exit(exitcode::USAGE);
+                    }
+                },
+                None => Local::now(),
+            };
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            // Given as IT Retail stores it, or as the UPC-A or PLU it prints as.
+            let mut product = None;
+            for candidate in std::iter::once(upc.to_string()).chain(internal::api::upca_to_itr_upc(upc)) {
+                match sidedb.get_product(&candidate, true).await {
+                    Ok(None) => continue,
+                    Ok(p) => {
+                        product = p;
+                        break;
+                    },
+                    Err(e) => {
+                        error!("Error reading sidedb products: {}", e);
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                }
+            }
+            match product {
+                Some(p) => print!("{}", p.explain_price_as_of(whence).text()),
+                None => {
+                    error!("No product {}", upc);
+                    std::process::exit(exitcode::DATAERR);
+                }
+            }
+            std::process::exit(exitcode::OK);
+        }
+        Some(("price-history", scmd)) => {
+            let upc = scmd.get_one::<String>("upc").unwrap();
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            let r = sidedb.get_price_history(upc).await;
+            if r.is_err() {
+                error!("Error reading price history: {}", r.err().unwrap());
+                std::process::exit(exitcode::SOFTWARE);
+            }
+            let changes = r.unwrap();
+            if changes.is_empty() {
+                println!("No price history for {}", upc);
+            }
+            for change in changes {
+                println!(
+                    "{} : {} : ${:.2}{}",
+                    change.effective_at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+                    change.upc,
+                    change.price,
+                    change.special_price.map(|sp| format!(" (special ${:.2})", sp)).unwrap_or_default()
+                );
+            }
+            std::process::exit(exitcode::OK);
+        }
+        Some(("product-drift", scmd)) => {
+            let live = match api.get_products().await {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error fetching IT Retail products: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            let side = match sidedb.get_products(None).await {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error reading sidedb products: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            let drift = internal::api::product_drift(&live, &side);
+            write_report(scmd, "upc,field,sidedb,live", &drift, internal::api::ProductDrift::csv_line);
+            let missing = drift.iter().filter(|d| d.field == "product" && d.sidedb.is_empty()).count();
+            let extra = drift.iter().filter(|d| d.field == "product" && d.live.is_empty()).count();
+            info!("{} live products, {} in sidedb: {} missing from sidedb, {} only in sidedb, {} field differences",
+                  live.iter().filter(|p| !p.deleted).count(), side.len(), missing, extra, drift.len() - missing - extra);
+            std::process::exit(if drift.is_empty() { exitcode::OK } else { exitcode::DATAERR });
+        }
+        Some(("cost-alert", scmd)) => {
+            let threshold = *scmd.get_one::<f64>("threshold-pct").unwrap();
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            let current = match sidedb.get_products(None).await {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error reading sidedb products: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            let previous = match sidedb.get_archived_products(scmd.get_one::<NaiveDate>("date")).await {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error reading archived sidedb products: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            if previous.is_empty() {
+                warn!("No archived product snapshot to compare costs against.");
+            }
+            let alerts = internal::api::cost_alerts(&current, &previous, threshold);
+            write_report(scmd, "upc,description,field,old,new,pct", &alerts, internal::api::CostAlert::csv_line);
+            info!("{} cost increases over {}% across {} products", alerts.len(), threshold, current.len());
+            std::process::exit(exitcode::OK);
+        }
+        Some(("doctor", scmd)) => {
+            use internal::api::HealthCheck;
+            let mut checks = match api.check_schema().await {
+                Ok(checks) => checks,
+                Err(e) => vec![HealthCheck::new("itretail_schema", false, &e.to_string())],
+            };
+            if !settings.postgres.connect_string.is_empty() {
+                checks.push(match internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await {
+                    Ok(_) => HealthCheck::new("sidedb", true, "connected"),
+                    Err(e) => HealthCheck::new("sidedb", false, &e.to_string()),
+                });
+            }
+            let square_secret = match settings.square.environment {
+                internal::settings::SquareEnvironment::Production => &settings.square.production_secret,
+                internal::settings::SquareEnvironment::Sandbox => &settings.square.sandbox_secret,
+            };
+            if !square_secret.is_empty() {
+                let square = internal::square::square_connect_create(&settings);
+                checks.push(match square.get_locations().await {
+                    Ok(locations) => HealthCheck::new("square_auth", true, &format!("{} locations", locations.len())),
+                    Err(e) => HealthCheck::new("square_auth", false, &e.to_string()),
+                });
+            }
+            if scmd.get_one::<String>("format").unwrap() == "json" {
+                println!("{}", serde_json::to_string_pretty(&checks).unwrap());
+            } else {
+                for c in checks.iter() {
+                    if c.ok {
+                        info!("{}: {}", c.name, c.detail);
+                    } else {
+                        error!("{}: {}", c.name, c.detail);
+                    }
+                }
+            }
+            std::process::exit(if checks.iter().all(|c| c.ok) { exitcode::OK } else { exitcode::DATAERR });
+        }
+        Some(("square-fix-skus", scmd)) => {
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            let square = internal::square::square_connect_create(&settings);
+            let apply = scmd.get_flag("apply");
+            match square.fix_skus(&mut sidedb, apply).await {
+                Ok(n) => {
+                    if apply {
+                        info!("Set SKUs on {} Square items", n);
+                    } else {
+                        info!("{} Square items would get SKUs, rerun with --apply", n);
+                    }
+                    std::process::exit(exitcode::OK);
+                }
+                Err(e) => {
+                    error!("Error fixing Square SKUs: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            }
+        }
+        Some(("square-reassociate", scmd)) => {
+            // Customer associations need the whole sidedb, products only the catalog.
+            let uses = if scmd.get_flag("products") && !scmd.get_flag("customers") {
+                internal::sidedb::SideDbUse::Catalog
+            } else {
+                internal::sidedb::SideDbUse::Everything
+            };
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), uses).await.unwrap();
+            let square = internal::square::square_connect_create(&settings);
+            // Neither flag means both.
+            let both = !scmd.get_flag("products") && !scmd.get_flag("customers");
+            if both || scmd.get_flag("products") {
+                match square.reassociate_products(&mut sidedb).await {
+                    Ok(n) => info!("Associated {} sidedb products with Square items", n),
+                    Err(e) => {
+                        error!("Error reassociating Square products: {}", e);
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                }
+            }
+            if both || scmd.get_flag("customers") {
+                match square.reassociate_customers(&mut sidedb).await {
+                    Ok(n) => info!("Associated {} sidedb customers with Square customers", n),
+                    Err(e) => {
+                        error!("Error reassociating Square customers: {}", e);
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                }
+            }
+            std::process::exit(exitcode::OK);
+        }
+        Some(("square-sync-associations", _)) => {
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Everything).await.unwrap();
+            let square = internal::square::square_connect_create(&settings);
+            match square.associate_customers_by_reference(&mut sidedb).await {
+                Ok(n) => {
+                    info!("Associated {} sidedb customers with Square customers by reference_id", n);
+                    std::process::exit(exitcode::OK);
+                }
+                Err(e) => {
+                    error!("Error associating Square customers: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            }
+        }
+        Some(("square-inventory-variance", scmd)) => {
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Catalog).await.unwrap();
+            let square = internal::square::square_connect_create(&settings);
+            let variances = match square.inventory_variance(&mut sidedb, *scmd.get_one::<f64>("min-difference").unwrap()).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Error comparing Square inventory: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            write_report(scmd, "upc,description,catalog_object_id,itretail,square,difference", &variances, internal::square::InventoryVariance::csv_line);
+            info!("{} items where Square and IT Retail on-hand counts differ", variances.len());
+            std::process::exit(exitcode::OK);
+        }
+        Some(("square-ensure-groups", scmd)) => {
+            let square = internal::square::square_connect_create(&settings);
+            if scmd.get_flag("check") {
+                match square.missing_customer_groups().await {
+                    Ok(missing) if missing.is_empty() => {
+                        info!("All loyalty groups present in Square");
+                        std::process::exit(exitcode::OK);
+                    }
+                    Ok(missing) => {
+                        for level in missing.iter() {
+                            warn!("Missing Square group Loyalty-Tier-{}", level);
+                        }
+                        std::process::exit(exitcode::DATAERR);
+                    }
+                    Err(e) => {
+                        error!("Error listing Square customer groups: {}", e);
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                }
+            }
+            let (groups, created) = match square.ensure_customer_groups().await {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("Error creating Square customer groups: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            for level in internal::loyalty::valid_loyalty_levels() {
+                let state = if created.contains(&level) { "created" } else { "present" };
+                info!("Loyalty-Tier-{}: {} ({})", level, state, groups.get(&level).map(|id| id.as_str()).unwrap_or(""));
+            }
+            // Read them back so a create Square accepted but didn't keep shows up now.
+            match square.missing_customer_groups().await {
+                Ok(missing) if missing.is_empty() => {
+                    info!("{} loyalty groups created, {} already present", created.len(), groups.len() - created.len());
+                    std::process::exit(exitcode::OK);
+                }
+                Ok(missing) => {
+                    error!("Square groups still missing after create: {}",
+                           missing.iter().map(|l| format!("Loyalty-Tier-{}", l)).collect::<Vec<String>>().join(", "));
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+                Err(e) => {
+                    error!("Error verifying Square customer groups: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            }
+        }
+        Some(("identity-audit", scmd)) => {
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Everything).await.unwrap();
+            let square = internal::square::square_connect_create(&settings);
+            let r = internal::customer::identity_audit(&mut sidedb, &square, &settings, scmd).await;
+            match r {
+                Ok(0) => std::process::exit(exitcode::OK),
+                Ok(_) => std::process::exit(exitcode::DATAERR),
+                Err(e) => {
+                    error!("Error running identity audit: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            }
+        }
+        Some(("compare-stripe-square", scmd)) => {
+            let stripe = match internal::stripe::stripe_api_new(&settings) {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(exitcode::CONFIG);
+                }
+            };
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Everything).await.unwrap();
+            let square = internal::square::square_connect_create(&settings);
+            let diffs = async {
+                let itr = sidedb.get_customers().await?;
+                let sq = square.get_customers(None).await?;
+                let st = stripe.get_customers().await?;
+                info!("{} IT Retail, {} Square and {} Stripe customers", itr.len(), sq.len(), st.len());
+                anyhow::Ok(internal::stripe::compare_customers(&itr, &sq, &st, &settings.stripe.id_metadata_key))
+            }.await;
+            let diffs = match diffs {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("Error comparing Stripe and Square customers: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            write_report(scmd, "itretail_id,field,stripe,square", &diffs, internal::stripe::StripeSquareDiff::csv_line);
+            info!("{} Stripe/Square differences", diffs.len());
+            std::process::exit(if diffs.is_empty() { exitcode::OK } else { exitcode::DATAERR });
+        }
+        Some(("mailchimp-pending", scmd)) => {
+            let pending = match internal::customer::mailchimp_pending(&settings, scmd).await {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error reading pending Mailchimp members: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            };
+            write_report(scmd, "email,source,signup,age_days", &pending, internal::customer::PendingMember::csv_line);
+            info!("{} Mailchimp members pending confirmation", pending.len());
+            std::process::exit(exitcode::OK);
+        }
         Some(("mailchimp-sync", scmd)) => {
             let r = internal::customer::mailchimp_sync(&mut api, &settings, &scmd).await;
             if r.is_err() {
@@ -537,9 +1900,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             std::process::exit(exitcode::OK);
         }
         Some(("tvmenu", scmd)) => {
+            if scmd.get_one::<String>("pull").is_some_and(|p| p == "all") {
+                let dir = scmd.get_one::<String>("output").map(|s| s.as_str()).unwrap_or(".");
+                let backdrops = match internal::tvmenu::category_backdrops(&settings) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        error!("Error: {}", e);
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                };
+                let r = internal::tvmenu::make_all_listings(&mut api, &settings, scmd, dir).await;
+                if r.is_err() {
+                    error!("Error constructing menus from IT Retail: {}", r.err().unwrap());
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+                for (cat, menu_file) in r.unwrap() {
+                    let output_file = std::path::Path::new(dir)
+                        .join(internal::tvmenu::category_filename(&cat) + ".png")
+                        .to_string_lossy().to_string();
+                    let mut menu_txt = match scmd.get_one::<String>("title") {
+                        Some(title) => title.to_owned() + "\n\n",
+                        None => "".to_string()
+                    };
+                    menu_txt.push_str(&fs::read_to_string(&menu_file).expect("Could not open file."));
+                    let backdrop = backdrops.get(&cat.to_lowercase()).or(scmd.get_one::<String>("backdrop"));
+                    info!("Using backdrop {} for {}", backdrop.map(|b| b.as_str()).unwrap_or("(built-in)"), cat);
+                    let r = internal::tvmenu::make_menu(
+                        &output_file,
+                        &menu_txt,
+                        backdrop,
+                        scmd.get_flag("invert"),
+                    );
+                    if r.is_err() {
+                        error!("Error creating TV menu image for {}: {}", cat, r.err().unwrap());
+                        std::process::exit(exitcode::SOFTWARE);
+                    }
+                    info!("Wrote {} for {}", output_file, cat);
+                }
+                std::process::exit(exitcode::OK);
+            }
             let (menu_file, output_file) = match scmd.get_one::<String>("pull") {
                 Some(cat) => {
-                    let r = internal::tvmenu::make_listing(&mut api, &scmd).await;
+                    let r = internal::tvmenu::make_listing(&mut api, &settings, scmd).await;
                     if r.is_err() {
                         error!(
                             "Error constructing menu from IT Retail: {}",
@@ -595,12 +1997,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 std::process::exit(exitcode::SOFTWARE);
             }
         }
-        Some(("le-orders", _scmd)) => {
+        Some(("le-customers", scmd)) => {
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), internal::sidedb::SideDbUse::Everything).await.unwrap();
+            match internal::customer::le_customers(&mut api, &mut sidedb, scmd.get_flag("dry-run")).await {
+                Ok(n) => {
+                    info!("{} {} LocalExpress customers to IT Retail.", if scmd.get_flag("dry-run") { "Would add" } else { "Added" }, n);
+                    std::process::exit(exitcode::OK);
+                }
+                Err(e) => {
+                    error!("Error adding LocalExpress customers: {}", e);
+                    std::process::exit(exitcode::SOFTWARE);
+                }
+            }
+        }
+        Some(("le-orders", scmd)) => {
             let lehandle = internal::localexpress::create_api();
             if lehandle.is_err() {
                 panic!("{}", lehandle.err().unwrap())
             }
             let mut leapi = lehandle.ok().unwrap();
+            leapi.set_timeouts(settings.localexpress.connect_timeout_seconds, settings.localexpress.request_timeout_seconds);
             match leapi.auth().await  {
                 Err(err) => {
                     error!("Error authenticating with LocalExpress: {}", err);
@@ -610,38 +2026,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let r = leapi.get_current_orders().await;
             if r.is_ok() {
                 let orders = r.unwrap();
-                let new_order_cnt = orders.iter().fold(0, |a,x| { if x.status == "new" { a + 1 } else { a + 0 } });
-
                 let today = Local::now().date_naive();
-                let todays_unfinished_cnt = orders.iter().fold(0, |a, x| {
-                    if x.delivery_date == today && x.active() {
-                        a + 1
-                    }
-                    else {
-                        a + 0
+                debug!("{:#?}", orders);
+                info!("New Orders: {}", internal::tasmota::count_orders(&orders, "new", false, today));
+                info!("Today's Unfinished Orders: {}", internal::tasmota::count_orders(&orders, "active", true, today));
+                info!("Customers waiting curbside: {}", internal::tasmota::count_orders(&orders, "parked", false, today));
+                let verify_lights = scmd.get_flag("verify-lights");
+                let open = match internal::tasmota::within_hours(&settings.tasmota.open_time, &settings.tasmota.close_time, Local::now().time()) {
+                    Ok(open) => open,
+                    Err(e) => {
+                        error!("Error reading store hours: {}", e);
+                        true
                     }
-                });
-                let parked_cnt = orders.iter().fold(0, |a, x| {
-                    if x.curbside_pickup_info.is_some() && x.status == "assembled" {
-                        a + 1
+                };
+                if !open {
+                    info!("Outside store hours, turning the lights rules name off");
+                }
+                let rules = match settings.tasmota.rules.iter().map(|r| internal::tasmota::parse_rule(r)).collect::<Result<Vec<_>, _>>() {
+                    Ok(rules) => rules,
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(exitcode::CONFIG);
                     }
-                    else {
-                        a
+                };
+                for (name, lit) in internal::tasmota::evaluate_rules(&rules, &orders, today) {
+                    let ip = match name.as_str() {
+                        "light1" => &settings.tasmota.light1,
+                        "light2" => &settings.tasmota.light2,
+                        _ => match settings.tasmota.lights.get(&name) {
+                            Some(ip) => ip,
+                            None => {
+                                error!("Light rule names {}, which isn't in tasmota.lights", name);
+                                continue;
+                            }
+                        },
+                    };
+                    let mut light = internal::tasmota::new_light(ip.to_string(), verify_lights);
+                    light.set_retries(settings.tasmota.attempts, settings.tasmota.retry_base_ms);
+                    light.set_timeouts(settings.tasmota.connect_timeout_seconds, settings.tasmota.request_timeout_seconds);
+                    if let Err(e) = light.power(open && lit).await {
+                        error!("Error actuating {}: {}", name, e);
                     }
-                });
-                debug!("{:#?}", orders);
-                info!("New Orders: {}", new_order_cnt);
-                info!("Today's Unfinished Orders: {}", todays_unfinished_cnt);
-                info!("Customers waiting curbside: {}", parked_cnt);
-                let mut light1 = internal::tasmota::new_light(settings.tasmota.light1);
-                match light1.power(todays_unfinished_cnt > 0).await {
-                    Err(e) => error!("Error actuating light1: {}", e.to_string()),
-                    Ok(_) => {}
-                }
-                let mut light2 = internal::tasmota::new_light(settings.tasmota.light2);
-                match light2.power(parked_cnt > 0).await {
-                    Err(e) => error!("Error actuating light2: {}", e.to_string()),
-                    Ok(_) => {}
                 }
                 std::process::exit(exitcode::OK);
             }
@@ -649,38 +2074,115 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             std::process::exit(exitcode::SOFTWARE);
         }
         Some(("sidedb-sync", scmd)) => {
-            let mut sidedb = internal::sidedb::make_sidedb(settings.clone()).await.unwrap();
+            match api.check_schema().await {
+                Ok(checks) => {
+                    for c in checks.iter().filter(|c| !c.ok) {
+                        warn!("IT Retail API changed, {}: {} (see doctor)", c.name, c.detail);
+                    }
+                }
+                Err(e) => warn!("Could not check IT Retail API schema: {}", e),
+            }
             let period = *scmd.get_one::<u32>("period").unwrap();
-            let do_products = scmd.get_flag("products");
-            let do_square_products = scmd.get_flag("products-square");
-            let do_square_inventory = scmd.get_flag("inventory-square");
-            let do_customers = scmd.get_flag("customers");
-            let do_square_customers = scmd.get_flag("customers-square");
+            let phases: Vec<&String> = scmd.get_many::<String>("phases").map(|v| v.collect()).unwrap_or_default();
+            let phase = |name: &str| scmd.get_flag(name) || phases.iter().any(|p| p.as_str() == name);
+            let do_products = phase("products");
+            let do_square_products = phase("products-square");
+            let do_square_inventory = phase("inventory-square");
+            let do_customers = phase("customers");
+            let do_square_customers = phase("customers-square");
             let full_customer = scmd.get_flag("customers-full");
-            let do_txns = scmd.get_flag("transactions");
-            let do_orders = scmd.get_flag("orders");
-            let do_all = !do_txns && !do_orders && !do_products && !do_customers && !full_customer && !do_square_customers && !do_square_products;
+            let customers_batch = scmd.get_one::<usize>("customers-batch").copied();
+            let do_txns = phase("transactions");
+            let do_orders = phase("orders");
+            let orders_status: Vec<String> = scmd.get_many::<Vec<String>>("orders-status")
+                .map(|v| v.flatten().cloned().collect())
+                .unwrap_or_default();
+            let orders_since = scmd.get_one::<NaiveDate>("orders-since").copied();
+            let orders_until = scmd.get_one::<NaiveDate>("orders-until").copied();
+            let active_only = scmd.get_flag("active-only") || settings.itretail.active_only;
+            let inventory_verify = if scmd.get_flag("verify-inventory") {
+                Some(internal::square::InventoryVerify {
+                    tolerance: *scmd.get_one::<f64>("inventory-tolerance").unwrap(),
+                    output: scmd.get_one::<String>("verify-output").unwrap().to_string(),
+                    bom: scmd.get_flag("bom"),
+                })
+            } else {
+                None
+            };
+            let do_all = scmd.get_flag("all") ||
+                (!do_txns && !do_orders && !do_products && !do_customers && !full_customer && !do_square_customers && !do_square_products && !do_square_inventory);
+            // The product phases only touch the catalog, which a sqlite sidedb can hold.
+            let uses = if do_all || do_customers || full_customer || do_square_customers || do_txns || do_orders {
+                internal::sidedb::SideDbUse::Everything
+            } else {
+                internal::sidedb::SideDbUse::Catalog
+            };
+            let mut sidedb = internal::sidedb::make_sidedb(settings.clone(), uses).await.unwrap();
+            if scmd.get_flag("atomic") {
+                sidedb.set_commit_rows(0);
+            }
+            if scmd.get_flag("no-archive") {
+                sidedb.set_archive_products(false);
+            }
 
             let mut progress = false;
-            info!("Starting sync process.");
+            if do_all {
+                info!("Starting sync process, phases: all ({}).", SIDEDB_SYNC_PHASES.join(","));
+            } else {
+                let selected: Vec<&str> = SIDEDB_SYNC_PHASES.iter().copied()
+                    .filter(|p| phase(p) || (*p == "customers" && full_customer))
+                    .collect();
+                info!("Starting sync process, phases: {}.", selected.join(","));
+            }
 
             loop {
                 if do_customers || full_customer || do_all {
                     info!("Starting customer sync.");
                     let r= api.get_customers().await;
-                    if r.is_err() {
-                        error!("Error fetching IT Retail customers: {}", r.err().unwrap());
+                    if let Err(e) = &r {
+                        error!("Error fetching IT Retail customers: {}", e);
                         std::process::exit(exitcode::SOFTWARE);
                     } else {
                         let ro = 
                         if full_customer {
-                            let mut full_customers: Vec<internal::api::Customer> = vec![];
-                            for skel_c in &r.unwrap() {
-                                if let Some(full_c) = api.get_customer(&skel_c.id).await? {
-                                    full_customers.push(full_c);
+                            let skels = r.unwrap();
+                            if let Some(batch_size) = customers_batch {
+                                let batch_size = batch_size.max(1);
+                                let mut skip = 0;
+                                if scmd.get_flag("resume") {
+                                    if let Some(last_id) = sidedb.get_customer_pull_resume().await? {
+                                        match skels.iter().position(|c| c.id == last_id) {
+                                            Some(idx) => {
+                                                skip = idx + 1;
+                                                info!("Resuming full customer pull after {} ({} already stored).", last_id, skip);
+                                            },
+                                            None => warn!("Resume point {} no longer listed, starting over.", last_id),
+                                        }
+                                    }
+                                }
+                                let mut stored = 0;
+                                let mut batch: Vec<internal::api::Customer> = vec![];
+                                for skel_c in skels.iter().skip(skip) {
+                                    if let Some(full_c) = api.get_customer(&skel_c.id).await? {
+                                        batch.push(full_c);
+                                    }
+                                    if batch.len() >= batch_size {
+                                        stored += sidedb.store_customer_batch(&batch).await?;
+                                        batch.clear();
+                                    }
+                                }
+                                stored += sidedb.store_customer_batch(&batch).await?;
+                                let seen = skels.iter().map(|c| c.id).collect();
+                                sidedb.finish_customer_pull(&seen).await.map(|_| stored)
+                            } else {
+                                let mut full_customers: Vec<internal::api::Customer> = vec![];
+                                for skel_c in &skels {
+                                    if let Some(full_c) = api.get_customer(&skel_c.id).await? {
+                                        full_customers.push(full_c);
+                                    }
                                 }
+                                sidedb.store_customers(full_customers.into_iter()).await
                             }
-                            sidedb.store_customers(full_customers.into_iter()).await
                         } else {
                             sidedb.store_customers(r.unwrap().into_iter()).await
                         };
@@ -695,16 +2197,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 if do_square_customers || do_all {
                     info!("Starting square customer sync.");
-                    let r = internal::square::square_connect_create(&settings);
-                    match r.sync_customers_with_sidedb(&mut sidedb).await {
-                        Ok(v) => info!("{:?}", v),
+                    let mut r = internal::square::square_connect_create_for(&settings, period > 0);
+                    if scmd.get_flag("fuzzy-match") {
+                        r.set_fuzzy_match(Some(settings.square.fuzzy_match_threshold));
+                        r.set_fuzzy_associate(scmd.get_flag("fuzzy-associate"));
+                    }
+                    r.set_dry_run_deletes(scmd.get_flag("dry-run-deletes"));
+                    r.set_progress(scmd.get_flag("progress"));
+                    let since = scmd.get_one::<NaiveDateTime>("customers-since")
+                        .and_then(|dt| Local.from_local_datetime(dt).earliest())
+                        .map(|dt| dt.with_timezone(&Utc));
+                    match r.sync_customers_with_sidedb(&mut sidedb, since).await {
+                        Ok(v) => {
+                            info!("added {}, updated {}, deleted {}, unmatched {}, fuzzy matched {}",
+                                  v.added_up, v.updated_up, v.deleted_up, v.unmatched.len(), v.fuzzy.len());
+                            if let Some(file) = scmd.get_one::<String>("unmatched-output") {
+                                let mut csv = "email,phone,id\n".to_string();
+                                for c in v.unmatched.iter() {
+                                    csv.push_str(&c.csv_line());
+                                    csv.push('\n');
+                                }
+                                match fs::write(file, internal::api::csv_bom(csv, scmd.get_flag("bom"))) {
+                                    Ok(_) => info!("Wrote {} unmatched Square customers to {}", v.unmatched.len(), file),
+                                    Err(e) => error!("Error writing unmatched customers to {}: {}", file, e),
+                                }
+                            }
+                            if let Some(file) = scmd.get_one::<String>("fuzzy-output") {
+                                let mut csv = "itretail_id,square_id,email,square_email,phone,square_phone,reason,confidence,associated\n".to_string();
+                                for m in v.fuzzy.iter() {
+                                    csv.push_str(&m.csv_line());
+                                    csv.push('\n');
+                                }
+                                match fs::write(file, internal::api::csv_bom(csv, scmd.get_flag("bom"))) {
+                                    Ok(_) => info!("Wrote {} fuzzy customer matches to {}", v.fuzzy.len(), file),
+                                    Err(e) => error!("Error writing fuzzy customer matches to {}: {}", file, e),
+                                }
+                            }
+                        },
                         Err(e) => error!("Square customer sync error: {}", e)
                     }
                 }
 
                 if do_orders || do_all {
                     info!("Starting Square orders sync.");
-                    let r = internal::square::square_connect_create(&settings);
+                    let r = internal::square::square_connect_create_for(&settings, period > 0);
                     match r.sync_transactions_with_sidedb(&mut sidedb).await {
                         Ok(_v) => {}
                         Err(e) => error!("Square Payments/Orders sync error: {}", e)
@@ -717,12 +2253,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                     info!("Starting LocalExpress orders sync.");
                     let mut auth_error = false;
+                    let mut timed_out = false;
                     loop {
                         let lehandle = internal::localexpress::create_api();
                         if lehandle.is_err() {
                             panic!("{}", lehandle.err().unwrap())
                         }
                         let mut leapi = lehandle.ok().unwrap();
+                        leapi.set_timeouts(settings.localexpress.connect_timeout_seconds, settings.localexpress.request_timeout_seconds);
                         match leapi.auth().await  {
                             Err(err) => {
                                 error!("Error authenticating with LocalExpress: {}", err);
@@ -730,13 +2268,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             },
                             _ => {}
                         }
-                        let r = leapi.get_orders().await;
+                        let r = leapi.get_orders(&orders_status, orders_since, orders_until).await;
                         if r.is_err() {
-                            if !auth_error && r.as_ref().err().unwrap().to_string().eq("Unauthorized") {
+                            if !auth_error && internal::error::is_unauthorized(r.as_ref().err().unwrap()) {
                                 warn!("Reauthorizing LocalExpress: {}", r.as_ref().err().unwrap());
                                 auth_error = true;
                                 continue;
                             }
+                            if !timed_out && internal::error::is_timeout(r.as_ref().err().unwrap()) {
+                                warn!("Retrying LocalExpress after timeout: {}", r.as_ref().err().unwrap());
+                                timed_out = true;
+                                continue;
+                            }
                             error!("Error fetching LocalExpress orders: {}", r.err().unwrap());
                             std::process::exit(exitcode::SOFTWARE);
                         } else {
@@ -817,14 +2360,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         } else {
                             info!("Pushed {} IT Retail products.", ro.unwrap());
                         }
+                        let keep_days = settings.postgres.archive_retention_days;
+                        if keep_days > 0 {
+                            match sidedb.prune_product_archive(keep_days).await {
+                                Ok(n) => info!("Pruned {} product archive rows older than {} days.", n, keep_days),
+                                Err(e) => warn!("Failed to prune product archive: {}", e),
+                            }
+                        }
                     }
                     progress = true;
                 }
 
                 if do_square_products || do_square_inventory || do_all {
                     info!("Starting square product sync.");
-                    let r = internal::square::square_connect_create(&settings);
-                    match r.sync_products_with_sidedb(&mut sidedb, do_square_inventory || do_all).await {
+                    let mut r = internal::square::square_connect_create_for(&settings, period > 0);
+                    r.set_sync_cost(scmd.get_flag("sync-cost"));
+                    r.set_report_only(scmd.get_flag("report-only"));
+                    r.set_allow_zero_price(scmd.get_flag("allow-zero-price"));
+                    r.set_trace_upc(scmd.get_one::<String>("trace-upc").cloned());
+                    r.set_only_upcs(scmd.get_many::<String>("only-upc").map(|v| v.cloned().collect()));
+                    r.set_progress(scmd.get_flag("progress"));
+                    match r.sync_products_with_sidedb(&mut sidedb, do_square_inventory || do_all, active_only, scmd.get_flag("include-deleted"), scmd.get_one::<usize>("max-products").copied(), inventory_verify.as_ref()).await {
+                        Ok(v) if scmd.get_flag("report-only") => {
+                            match scmd.get_one::<String>("plan-output") {
+                                Some(file) => match serde_json::to_string_pretty(&v.plan).map_err(anyhow::Error::from).and_then(|j| Ok(fs::write(file, j)?)) {
+                                    Ok(_) => info!("Wrote {} planned Square changes to {}", v.plan.len(), file),
+                                    Err(e) => error!("Error writing plan to {}: {}", file, e),
+                                },
+                                None => {
+                                    for c in v.plan.iter() {
+                                        println!("{}\t{}\t{}\t{}", c.action, c.upc.as_deref().unwrap_or("-"), c.name, c.detail.as_deref().unwrap_or(""));
+                                    }
+                                    info!("{} planned Square changes", v.plan.len());
+                                }
+                            }
+                        }
                         Ok(v) => info!("{:?}", v),
                         Err(e) => error!("Square customer sync error: {}", e)
                     }
@@ -857,11 +2427,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     } else {
                         let txns = r.unwrap();
                         let ro = sidedb.store_txns(txns.iter()).await;
-                        if ro.is_err() {
-                            error!("Failed to store IT Retail transactions: {}", ro.err().unwrap());
-                            std::process::exit(exitcode::SOFTWARE);
-                        } else {
-                            info!("Pushed {} IT Retail transactions.", ro.unwrap());
+                        match ro {
+                            Err(e) => {
+                                error!("Failed to store IT Retail transactions: {}", e);
+                                std::process::exit(exitcode::SOFTWARE);
+                            },
+                            Ok((stored, skipped)) => {
+                                info!("Pushed {} IT Retail transactions.", stored);
+                                if skipped > 0 {
+                                    warn!("Skipped {} IT Retail transactions that could not be stored.", skipped);
+                                }
+                            },
                         }
                     }
                 }